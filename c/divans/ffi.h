@@ -31,6 +31,91 @@ typedef uint8_t DivansOptionSelect;
 #define DIVANS_OPTION_PRIOR_BITMASK_DETECTION 17
 #define DIVANS_OPTION_Q9_5 18
 #define DIVANS_OPTION_FORCE_LITERAL_CONTEXT_MODE 19
+#define DIVANS_OPTION_IR_OPTIMIZER 20
+#define DIVANS_OPTION_BLOCK_SPLIT 21
+#define DIVANS_OPTION_ENTROPY_CODER 22
+#define DIVANS_OPTION_CDF_FAMILY 23
+#define DIVANS_OPTION_MUX_POLICY 24
+#define DIVANS_OPTION_STREAM_COUNT 25
+
+/// selectors for divans_set_decompressor_option and divans_decompress_buffer
+/// 1 skips checksum verification, 0 (the default) verifies it
+#define DIVANS_DECOMPRESSOR_OPTION_SKIP_CRC 1
+/// streams whose window buffer would need more than this many bytes fail before it is allocated.
+/// the decoder's model state, a fixed size, is not counted. 0 (the default) means no limit
+#define DIVANS_DECOMPRESSOR_OPTION_MEMORY_LIMIT 2
+/// 1 keeps a copy of every decoded command for divans_take_decoded_commands. needs a std build of divans
+#define DIVANS_DECOMPRESSOR_OPTION_CAPTURE_COMMANDS 3
+
+/// bytes consumed and produced so far
+struct DivansStats {
+    uint64_t total_in;
+    uint64_t total_out;
+};
+
+typedef uint8_t DivansCommandType;
+
+#define DIVANS_COMMAND_COPY 1
+#define DIVANS_COMMAND_DICT 2
+#define DIVANS_COMMAND_LITERAL 3
+#define DIVANS_COMMAND_BLOCK_SWITCH_COMMAND 4
+#define DIVANS_COMMAND_BLOCK_SWITCH_LITERAL 5
+#define DIVANS_COMMAND_BLOCK_SWITCH_DISTANCE 6
+#define DIVANS_COMMAND_PREDICTION_MODE 7
+
+/// repeats num_bytes bytes starting distance bytes back
+struct DivansCopyCommand {
+    uint32_t distance;
+    uint32_t num_bytes;
+};
+
+/// a word of the brotli dictionary with one of its transforms applied
+struct DivansDictCommand {
+    uint8_t word_size;
+    uint8_t transform;
+    uint8_t final_size;
+    uint8_t empty;
+    uint32_t word_id;
+};
+
+struct DivansLiteralCommand {
+    const uint8_t *data;
+    size_t data_size;
+    /// nonzero marks bytes unlikely to repeat, so the model adapts to them more slowly
+    uint8_t high_entropy;
+};
+
+/// used by all three block switch commands; stride only applies to DIVANS_COMMAND_BLOCK_SWITCH_LITERAL
+struct DivansBlockSwitchCommand {
+    uint8_t block_type;
+    uint8_t stride;
+};
+
+/// the maps laid out as in brotli's PredictionModeContextMap
+struct DivansPredictionModeCommand {
+    const uint8_t *literal_context_map;
+    size_t literal_context_map_size;
+    const uint8_t *predmode_speed_and_distance_context_map;
+    size_t predmode_speed_and_distance_context_map_size;
+};
+
+struct DivansCommand {
+    /// selects the member of data that is set
+    DivansCommandType command_type;
+    union {
+        struct DivansCopyCommand copy;
+        struct DivansDictCommand dict;
+        struct DivansLiteralCommand literal;
+        struct DivansBlockSwitchCommand block_switch;
+        struct DivansPredictionModeCommand prediction_mode;
+    } data;
+};
+
+/// receives one decoded command; it and the buffers it points to are only valid during the call
+typedef void (*DivansCommandCallback)(void *opaque, const struct DivansCommand *command);
+
+/// a static description of a result code
+const char* divans_result_message(DivansResult result);
 
 
 /// a struct specifying custom allocators for divans to use instead of the builtin rust allocators.
@@ -54,17 +139,72 @@ DivansResult divans_encode(struct DivansCompressorState* state,
 DivansResult divans_encode_flush(struct DivansCompressorState* state,
                                  uint8_t *output_buf_ptr, size_t output_size, size_t *output_offset);
 
+/// encodes commands from *command_offset on instead of raw bytes, advancing *command_offset past the
+/// ones consumed. the stream is finished with divans_encode_flush. set DIVANS_OPTION_WINDOW_SIZE to cover
+/// the largest copy distance. returns DIVANS_FAILURE for an unknown command type
+DivansResult divans_encode_commands(struct DivansCompressorState* state,
+                                    const struct DivansCommand *commands, size_t num_commands, size_t *command_offset,
+                                    uint8_t *output_buf_ptr, size_t output_size, size_t *output_offset);
+
+/// estimated compressed bits spent on each part of the stream
+struct DivansCostEstimate {
+    double unknown;
+    double copy;
+    double dict;
+    double literal;
+    double cross_command;
+    double pred_mode_ctx_map;
+    double block_type;
+    /// the header and checksum
+    double framing;
+};
+/// estimates the compressed size of input under the options set so far without compressing it.
+/// must be called before divans_encode
+DivansResult divans_estimate_cost(struct DivansCompressorState* state,
+                                  const uint8_t *input_buf_ptr, size_t input_size,
+                                  struct DivansCostEstimate *estimate);
+
+DivansResult divans_get_stats(const struct DivansCompressorState* state, struct DivansStats *stats);
+
 void divans_free_compressor(struct DivansCompressorState* mfd);
 
+/// an output size that always holds the compressed stream: input that does not compress to fit is
+/// coded as literals, which take a byte per input byte plus under 1/1024 of the input and 1024 bytes of framing
+size_t divans_max_compressed_size(size_t input_size);
+/// compresses a whole buffer after applying num_options selector and value pairs; the arrays may be NULL if num_options is 0.
+/// *output_size is the capacity of output_buf_ptr on entry and the compressed size on success.
+/// returns DIVANS_NEEDS_MORE_OUTPUT if output_buf_ptr is too small, which it never is at divans_max_compressed_size(input_size)
+DivansResult divans_compress_buffer(const DivansOptionSelect *option_selectors, const uint32_t *option_values, size_t num_options,
+                                    const uint8_t *input_buf_ptr, size_t input_size,
+                                    uint8_t *output_buf_ptr, size_t *output_size);
+
 
 struct DivansDecompressorState* divans_new_decompressor();
-struct DivansDecompressorState* divans_new_decompressor_with_custom_alloc(struct CAllocator alloc, uint8_t skip_crc);
+struct DivansDecompressorState* divans_new_serial_decompressor();
+struct DivansDecompressorState* divans_new_decompressor_with_custom_alloc(struct CAllocator alloc, uint8_t skip_crc, uint8_t multithread);
+/// must be called before the first divans_decode
+DivansResult divans_set_decompressor_option(struct DivansDecompressorState* state, DivansOptionSelect selector, uint32_t value);
 DivansResult divans_decode(struct DivansDecompressorState* state,
                            const uint8_t *input_buf_ptr, size_t input_size, size_t*input_offset,
                            uint8_t *output_buf_ptr, size_t output_size, size_t *output_offset);
 
+/// calls callback with each command decoded since the last call, in stream order.
+/// needs DIVANS_DECOMPRESSOR_OPTION_CAPTURE_COMMANDS set before decoding starts.
+/// prediction mode commands carry the context map entries that were in the stream.
+DivansResult divans_take_decoded_commands(struct DivansDecompressorState* state,
+                                          DivansCommandCallback callback, void *opaque);
+
+DivansResult divans_get_decompressor_stats(const struct DivansDecompressorState* state, struct DivansStats *stats);
+
 void divans_free_decompressor(struct DivansDecompressorState* mfd);
 
+/// decompresses a whole stream on the calling thread after applying num_options DIVANS_DECOMPRESSOR_OPTION pairs.
+/// *output_size is the capacity of output_buf_ptr on entry and the decompressed size on success.
+/// returns DIVANS_NEEDS_MORE_OUTPUT if output_buf_ptr is too small and DIVANS_NEEDS_MORE_INPUT if the stream is truncated
+DivansResult divans_decompress_buffer(const DivansOptionSelect *option_selectors, const uint32_t *option_values, size_t num_options,
+                                      const uint8_t *input_buf_ptr, size_t input_size,
+                                      uint8_t *output_buf_ptr, size_t *output_size);
+
 
 
 #endif
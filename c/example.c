@@ -52,6 +52,10 @@ DivansResult compress(const unsigned char *data, size_t len, struct VecU8 *ret_b
         }
         push_vec_u8(ret_buffer, buf, buf_offset);
     } while(res != DIVANS_SUCCESS);
+    struct DivansStats stats;
+    res = divans_get_stats(state, &stats);
+    assert(res == DIVANS_SUCCESS);
+    assert(stats.total_out == ret_buffer->size);
     divans_free_compressor(state);
     return DIVANS_SUCCESS;
 }
@@ -59,8 +63,10 @@ DivansResult compress(const unsigned char *data, size_t len, struct VecU8 *ret_b
 DivansResult decompress(const unsigned char *data, size_t len, struct VecU8 *ret_buffer) {
     unsigned char buf[BUF_SIZE];
     struct CAllocator alloc = {custom_malloc, custom_free, custom_alloc_opaque};
-    struct DivansDecompressorState *state = divans_new_decompressor_with_custom_alloc(alloc, 0);
-    DivansResult res;
+    struct DivansDecompressorState *state = divans_new_decompressor_with_custom_alloc(alloc, 0, 1);
+    size_t total_in = len;
+    DivansResult res = divans_set_decompressor_option(state, DIVANS_DECOMPRESSOR_OPTION_MEMORY_LIMIT, 1 << 24);
+    assert(res == DIVANS_SUCCESS);
     do {
         size_t read_offset = 0;
         size_t buf_offset = 0;
@@ -75,10 +81,199 @@ DivansResult decompress(const unsigned char *data, size_t len, struct VecU8 *ret
         len -= read_offset;
         push_vec_u8(ret_buffer, buf, buf_offset);
     } while (res != DIVANS_SUCCESS);
+    struct DivansStats stats;
+    res = divans_get_decompressor_stats(state, &stats);
+    assert(res == DIVANS_SUCCESS);
+    assert(stats.total_in == total_in - len);
+    assert(stats.total_out == ret_buffer->size);
+    // options are fixed once decoding starts
+    assert(divans_set_decompressor_option(state, DIVANS_DECOMPRESSOR_OPTION_SKIP_CRC, 1) == DIVANS_FAILURE);
     divans_free_decompressor(state);
     return DIVANS_SUCCESS;
 }
 
+void check_one_shot(const unsigned char *data, size_t len) {
+    size_t compressed_size = divans_max_compressed_size(len);
+    unsigned char *compressed = malloc(compressed_size);
+    unsigned char *rt = malloc(len + 1);
+    DivansOptionSelect selectors[] = {DIVANS_OPTION_QUALITY, DIVANS_OPTION_WINDOW_SIZE};
+    uint32_t values[] = {10, 20};
+    DivansResult res = divans_compress_buffer(selectors, values, 2, data, len, compressed, &compressed_size);
+    if (res != DIVANS_SUCCESS) {
+        fprintf(stderr, "One-shot compression failed: %s\n", divans_result_message(res));
+        abort();
+    }
+    size_t rt_size = len;
+    res = divans_decompress_buffer(NULL, NULL, 0, compressed, compressed_size, rt, &rt_size);
+    if (res != DIVANS_SUCCESS || rt_size != len || memcmp(rt, data, len) != 0) {
+        fprintf(stderr, "One-shot roundtrip failed: %s\n", divans_result_message(res));
+        abort();
+    }
+    if (len != 0) {
+        rt_size = len - 1;
+        res = divans_decompress_buffer(NULL, NULL, 0, compressed, compressed_size, rt, &rt_size);
+        assert(res == DIVANS_NEEDS_MORE_OUTPUT);
+    }
+    rt_size = len;
+    res = divans_decompress_buffer(NULL, NULL, 0, compressed, compressed_size - 1, rt, &rt_size);
+    assert(res == DIVANS_NEEDS_MORE_INPUT);
+    // a 1MB window does not fit in 64KB
+    DivansOptionSelect limit_selector = DIVANS_DECOMPRESSOR_OPTION_MEMORY_LIMIT;
+    uint32_t limit = 65536;
+    rt_size = len;
+    res = divans_decompress_buffer(&limit_selector, &limit, 1, compressed, compressed_size, rt, &rt_size);
+    assert(res == DIVANS_FAILURE);
+    assert(strcmp(divans_result_message(DIVANS_SUCCESS), "success") == 0);
+    compressed_size = 4;
+    res = divans_compress_buffer(NULL, NULL, 0, data, len, compressed, &compressed_size);
+    assert(res == DIVANS_NEEDS_MORE_OUTPUT);
+    free(compressed);
+    free(rt);
+}
+
+// the setup bytes, mixing table and speeds that precede the distance context map
+#define PREDMODE_SETUP_SIZE (4 + 2 * 16 * 256 + 12)
+
+struct CommandCheck {
+    const struct DivansCommand *expected;
+    size_t num_expected;
+    size_t num_seen;
+};
+
+static int same_bytes(const uint8_t *a, size_t a_size, const uint8_t *b, size_t b_size) {
+    return a_size == b_size && (a_size == 0 || memcmp(a, b, a_size) == 0);
+}
+
+// a is the encoded command, b the decoded one
+static int same_command(const struct DivansCommand *a, const struct DivansCommand *b) {
+    if (a->command_type != b->command_type) {
+        return 0;
+    }
+    switch (a->command_type) {
+      case DIVANS_COMMAND_COPY:
+        return a->data.copy.distance == b->data.copy.distance && a->data.copy.num_bytes == b->data.copy.num_bytes;
+      case DIVANS_COMMAND_DICT:
+        return a->data.dict.word_size == b->data.dict.word_size && a->data.dict.transform == b->data.dict.transform
+            && a->data.dict.final_size == b->data.dict.final_size && a->data.dict.word_id == b->data.dict.word_id;
+      case DIVANS_COMMAND_LITERAL:
+        return same_bytes(a->data.literal.data, a->data.literal.data_size, b->data.literal.data, b->data.literal.data_size)
+            && !a->data.literal.high_entropy == !b->data.literal.high_entropy;
+      case DIVANS_COMMAND_BLOCK_SWITCH_LITERAL:
+        return a->data.block_switch.block_type == b->data.block_switch.block_type
+            && a->data.block_switch.stride == b->data.block_switch.stride;
+      case DIVANS_COMMAND_BLOCK_SWITCH_COMMAND:
+      case DIVANS_COMMAND_BLOCK_SWITCH_DISTANCE:
+        return a->data.block_switch.block_type == b->data.block_switch.block_type;
+      case DIVANS_COMMAND_PREDICTION_MODE:
+        return same_bytes(a->data.prediction_mode.literal_context_map, a->data.prediction_mode.literal_context_map_size,
+                          b->data.prediction_mode.literal_context_map, b->data.prediction_mode.literal_context_map_size)
+            && same_bytes(a->data.prediction_mode.predmode_speed_and_distance_context_map,
+                          a->data.prediction_mode.predmode_speed_and_distance_context_map_size,
+                          b->data.prediction_mode.predmode_speed_and_distance_context_map,
+                          b->data.prediction_mode.predmode_speed_and_distance_context_map_size);
+    }
+    return 0;
+}
+
+static void check_decoded_command(void *opaque, const struct DivansCommand *command) {
+    struct CommandCheck *check = (struct CommandCheck *)opaque;
+    if (check->num_seen >= check->num_expected || !same_command(&check->expected[check->num_seen], command)) {
+        fprintf(stderr, "Decoded command %ld does not match the encoded one\n", (long)check->num_seen);
+        abort();
+    }
+    ++check->num_seen;
+}
+
+void check_commands() {
+    static const unsigned char expected[] = "Mary had a timeMary had a time!\n";
+    static uint8_t predmode[PREDMODE_SETUP_SIZE];
+    static uint8_t literal_context_map[64];
+    unsigned char buf[16];
+    struct DivansCommand commands[8];
+    size_t num_commands = sizeof(commands) / sizeof(commands[0]);
+    memset(commands, 0, sizeof(commands));
+    static const uint8_t speeds[] = {40, 40, 112, 112, 40, 40, 112, 112};
+    predmode[0] = 2; // utf8 context
+    predmode[1] = 1; // mixing math
+    memcpy(predmode + 4 + 2 * 16 * 256, speeds, sizeof(speeds));
+    commands[0].command_type = DIVANS_COMMAND_PREDICTION_MODE;
+    commands[0].data.prediction_mode.literal_context_map = literal_context_map;
+    commands[0].data.prediction_mode.literal_context_map_size = sizeof(literal_context_map);
+    commands[0].data.prediction_mode.predmode_speed_and_distance_context_map = predmode;
+    commands[0].data.prediction_mode.predmode_speed_and_distance_context_map_size = sizeof(predmode);
+    commands[1].command_type = DIVANS_COMMAND_BLOCK_SWITCH_LITERAL;
+    commands[1].data.block_switch.stride = 1;
+    commands[2].command_type = DIVANS_COMMAND_LITERAL;
+    commands[2].data.literal.data = expected;
+    commands[2].data.literal.data_size = 11;
+    // word 0 of length 4 is "time"; transform 0 leaves it as is
+    commands[3].command_type = DIVANS_COMMAND_DICT;
+    commands[3].data.dict.word_size = 4;
+    commands[3].data.dict.final_size = 4;
+    commands[4].command_type = DIVANS_COMMAND_COPY;
+    commands[4].data.copy.distance = 15;
+    commands[4].data.copy.num_bytes = 15;
+    commands[5].command_type = DIVANS_COMMAND_BLOCK_SWITCH_COMMAND;
+    commands[6].command_type = DIVANS_COMMAND_BLOCK_SWITCH_DISTANCE;
+    commands[7].command_type = DIVANS_COMMAND_LITERAL;
+    commands[7].data.literal.data = expected + 30;
+    commands[7].data.literal.data_size = 2;
+    commands[7].data.literal.high_entropy = 1;
+
+    struct CAllocator alloc = {custom_malloc, custom_free, custom_alloc_opaque};
+    struct DivansCompressorState *state = divans_new_compressor_with_custom_alloc(alloc);
+    struct VecU8 divans_file = new_vec_u8();
+    DivansResult res = divans_set_option(state, DIVANS_OPTION_WINDOW_SIZE, 16);
+    assert(res == DIVANS_SUCCESS);
+    struct DivansCommand unknown;
+    memset(&unknown, 0, sizeof(unknown));
+    unknown.command_type = 99;
+    size_t command_offset = 0;
+    size_t buf_offset = 0;
+    res = divans_encode_commands(state, &unknown, 1, &command_offset, buf, sizeof(buf), &buf_offset);
+    assert(res == DIVANS_FAILURE && command_offset == 0);
+    // the small output buffer makes the encoder stop and resume inside commands
+    while (command_offset < num_commands) {
+        buf_offset = 0;
+        res = divans_encode_commands(state, commands, num_commands, &command_offset, buf, sizeof(buf), &buf_offset);
+        assert(res == DIVANS_SUCCESS || res == DIVANS_NEEDS_MORE_OUTPUT);
+        push_vec_u8(&divans_file, buf, buf_offset);
+    }
+    do {
+        buf_offset = 0;
+        res = divans_encode_flush(state, buf, sizeof(buf), &buf_offset);
+        assert(res != DIVANS_FAILURE);
+        push_vec_u8(&divans_file, buf, buf_offset);
+    } while (res != DIVANS_SUCCESS);
+    divans_free_compressor(state);
+
+    struct DivansDecompressorState *dstate = divans_new_decompressor_with_custom_alloc(alloc, 0, 1);
+    res = divans_set_decompressor_option(dstate, DIVANS_DECOMPRESSOR_OPTION_CAPTURE_COMMANDS, 1);
+    assert(res == DIVANS_SUCCESS);
+    struct VecU8 rt_file = new_vec_u8();
+    struct CommandCheck check = {commands, num_commands, 0};
+    size_t read_offset = 0;
+    do {
+        buf_offset = 0;
+        res = divans_decode(dstate, divans_file.data, divans_file.size, &read_offset, buf, sizeof(buf), &buf_offset);
+        assert(res != DIVANS_FAILURE && !(res == DIVANS_NEEDS_MORE_INPUT && read_offset == divans_file.size));
+        push_vec_u8(&rt_file, buf, buf_offset);
+        DivansResult take_res = divans_take_decoded_commands(dstate, check_decoded_command, &check);
+        assert(take_res == DIVANS_SUCCESS);
+    } while (res != DIVANS_SUCCESS);
+    if (check.num_seen != num_commands) {
+        fprintf(stderr, "Decoded %ld commands instead of %ld\n", (long)check.num_seen, (long)num_commands);
+        abort();
+    }
+    if (rt_file.size != sizeof(expected) - 1 || memcmp(rt_file.data, expected, rt_file.size) != 0) {
+        fprintf(stderr, "Commands decoded to the wrong bytes\n");
+        abort();
+    }
+    divans_free_decompressor(dstate);
+    release_vec_u8(&divans_file);
+    release_vec_u8(&rt_file);
+}
+
 int main(int argc, char**argv) {
     custom_free_f(&use_fake_malloc, memset(custom_malloc_f(&use_fake_malloc, 127), 0x7e, 127));
     if (getenv("NO_MALLOC")) {
@@ -116,6 +311,8 @@ int main(int argc, char**argv) {
             fprintf(stderr, "Failed to compress code:%d\n", (int) res);
             abort();
         }
+        check_one_shot(data, len);
+        check_commands();
         res = decompress(divans_file.data, divans_file.size, &rt_file);
         if (res != DIVANS_SUCCESS) {
             fprintf(stderr, "Failed to compress code:%d\n", (int)res);
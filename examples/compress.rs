@@ -29,6 +29,12 @@ fn main() {
                 stride_detection_quality: example_opts.stride_detection_quality,
                 prior_bitmask_detection: example_opts.prior_bitmask_detection,
                 divans_ir_optimizer:example_opts.divans_ir_optimizer,
+                block_split:example_opts.block_split,
+                entropy_coder:example_opts.entropy_coder,
+                cdf_family:example_opts.cdf_family,
+                mux_policy:example_opts.mux_policy,
+                stream_count:example_opts.stream_count,
+                filters: example_opts.filters,
             },
             4096, // internal buffer size
         );
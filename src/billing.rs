@@ -17,7 +17,7 @@ use core::mem;
 use core::iter::FromIterator;
 use core::marker::PhantomData;
 use alloc::{Allocator};
-use interface::{ArithmeticEncoderOrDecoder, BillingDesignation, NewWithAllocator, BillingCapability};
+use interface::{ArithmeticEncoderOrDecoder, BillingDesignation, NewWithAllocator, BillingCapability, EntropyCoderSelection};
 use super::probability::{CDF16, ProbRange};
 use interface::{DivansResult, ReadableBytes, WritableBytes};
 
@@ -37,6 +37,22 @@ macro_rules! println_stderr(
     } }
 );
 
+// the information content of a coded bit or nibble; coding it raw would take 1 or 4 virtual bits
+#[cfg(feature="std")]
+pub fn bit_cost(bit: bool, prob_of_false: u8) -> f64 {
+    let mut actual_prob = (prob_of_false as f64 + 0.5) / 256.0;
+    if bit {
+        actual_prob = 1.0 - actual_prob;
+    }
+    -actual_prob.log2()
+}
+
+#[cfg(feature="std")]
+pub fn nibble_cost<C: CDF16>(nibble: u8, prob: &C) -> f64 {
+    let actual_prob = prob.pdf(nibble) as f64 / (prob.max() as f64);
+    -actual_prob.log2()
+}
+
 #[cfg(feature="billing")]
 pub struct BillingArithmeticCoder<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> {
     coder: Coder,
@@ -55,6 +71,14 @@ impl<AllocU8:Allocator<u8>,
            movd:false,
            _phantom:PhantomData::<AllocU8>::default(),
        }
+   }
+   fn new_with_entropy_coder(m8: &mut AllocU8, entropy_coder: EntropyCoderSelection) -> Self {
+       BillingArithmeticCoder::<AllocU8, Coder>{
+           coder: Coder::new_with_entropy_coder(m8, entropy_coder),
+           counter: billing::HashMap::new(),
+           movd:false,
+           _phantom:PhantomData::<AllocU8>::default(),
+       }
    }
     fn free(&mut self, m8: &mut AllocU8) {
         self.coder.free(m8);
@@ -138,12 +162,8 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> ArithmeticEncoderO
                       prob_of_false: u8,
                       billing: BillingDesignation) {
         self.coder.get_or_put_bit_without_billing(bit, prob_of_false);
-        let mut actual_prob = (prob_of_false as f64 + 0.5) / 256.0;
-        if *bit {
-            actual_prob = 1.0 - actual_prob;
-        }
         let v = self.counter.entry(billing).or_insert((0.0, 0.0));
-        (*v).0 += -actual_prob.log2();
+        (*v).0 += bit_cost(*bit, prob_of_false);
         (*v).1 += 1.0;
     }
     fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
@@ -156,9 +176,8 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> ArithmeticEncoderO
                                    prob: &C,
                                    billing: BillingDesignation) -> ProbRange {
         let ret = self.coder.get_or_put_nibble_without_billing(nibble, prob);
-        let actual_prob = prob.pdf(*nibble) as f64 / (prob.max() as f64);
         let v = self.counter.entry(billing).or_insert((0.0, 0.0));
-        (*v).0 += -actual_prob.log2();
+        (*v).0 += nibble_cost(*nibble, prob);
         (*v).1 += 4.0;
         ret
     }
@@ -177,21 +196,21 @@ impl<AllocU8:Allocator<u8>, Coder:ArithmeticEncoderOrDecoder> BillingCapability
 
 #[cfg(not(feature="billing"))]
 macro_rules! DefaultEncoderType(
-    () => {::ans::ANSEncoder<AllocU8>}
+    () => {::selectable_coder::SelectableEncoder<AllocU8>}
 );
 
 #[cfg(not(feature="billing"))]
 macro_rules! DefaultDecoderType(
-    () => {::ans::ANSDecoder}
+    () => {::selectable_coder::SelectableDecoder}
 );
 
 
 #[cfg(feature="billing")]
 macro_rules! DefaultEncoderType(
-    () => { ::billing::BillingArithmeticCoder<AllocU8, ::ans::ANSEncoder<AllocU8>> }
+    () => { ::billing::BillingArithmeticCoder<AllocU8, ::selectable_coder::SelectableEncoder<AllocU8>> }
 );
 
 #[cfg(feature="billing")]
 macro_rules! DefaultDecoderType(
-    () => { ::billing::BillingArithmeticCoder<AllocU8, ::ans::ANSDecoder> }
+    () => { ::billing::BillingArithmeticCoder<AllocU8, ::selectable_coder::SelectableDecoder> }
 );
@@ -0,0 +1,430 @@
+#![cfg(feature="std")]
+// Where the bits of a stream go: a pass over already selected commands with a coder that writes
+// nothing and counts the cost of every symbol by BillingDesignation and by uncompressed region.
+// Unlike the billing feature this needs no special build, and normal compression pays nothing.
+use std::io::{self, Write};
+use std::vec::Vec;
+use alloc::{Allocator, SliceWrapper};
+use alloc_stdlib::HeapAlloc;
+use interface::{ArithmeticEncoderOrDecoder, BillingDesignation, Command, Compressor, CrossCommandBilling, DefaultCDF16,
+                DivansCompressorOptions, DivansOutputResult, DivansResult, ErrMsg, NewWithAllocator, ReadableBytes, WritableBytes};
+use codec::{ContextMapType, ThreadContext};
+use codec::copy::CopySubstate;
+use codec::dict::DictSubstate;
+use codec::literal::LiteralSubstate;
+use codec::context_map::PredictionModeSubstate;
+use codec::block_type::BlockTypeState;
+use billing::{bit_cost, nibble_cost};
+use divans_compressor::DivansCompressor;
+use filter::FilterSelection;
+use mux::DevNull;
+use probability::{CDF16, ProbRange};
+
+pub const NUM_BILLING_KEYS: usize = 51;
+
+// the designation and the state within it, without the counters some states carry
+pub const BILLING_KEYS: [(&str, &str); NUM_BILLING_KEYS] = [
+    ("Unknown", "Unknown"),
+    ("CopyCommand", "Begin"),
+    ("CopyCommand", "CountSmall"),
+    ("CopyCommand", "CountLengthFirst"),
+    ("CopyCommand", "CountLengthGreater18Less25"),
+    ("CopyCommand", "CountMantissaNibbles"),
+    ("CopyCommand", "CountDecoded"),
+    ("CopyCommand", "DistanceLengthMnemonic"),
+    ("CopyCommand", "DistanceLengthFirst"),
+    ("CopyCommand", "DistanceLengthGreater14Less25"),
+    ("CopyCommand", "DistanceMantissaNibbles"),
+    ("CopyCommand", "FullyDecoded"),
+    ("DictCommand", "Begin"),
+    ("DictCommand", "WordSizeFirst"),
+    ("DictCommand", "WordSizeGreater18Less25"),
+    ("DictCommand", "WordIndexMantissa"),
+    ("DictCommand", "TransformHigh"),
+    ("DictCommand", "TransformLow"),
+    ("DictCommand", "FullyDecoded"),
+    ("LiteralCommand", "Begin"),
+    ("LiteralCommand", "LiteralCountSmall"),
+    ("LiteralCommand", "LiteralCountFirst"),
+    ("LiteralCommand", "LiteralCountLengthGreater14Less25"),
+    ("LiteralCommand", "LiteralCountMantissaNibbles"),
+    ("LiteralCommand", "LiteralNibbleIndex"),
+    ("LiteralCommand", "SafeLiteralNibbleIndex"),
+    ("LiteralCommand", "LiteralNibbleLowerHalf"),
+    ("LiteralCommand", "LiteralNibbleIndexWithECDF"),
+    ("LiteralCommand", "FullyDecoded"),
+    ("CrossCommand", "Unknown"),
+    ("CrossCommand", "CopyIndicator"),
+    ("CrossCommand", "DictIndicator"),
+    ("CrossCommand", "EndIndicator"),
+    ("CrossCommand", "BlockSwitchType"),
+    ("CrossCommand", "FullSelection"),
+    ("PredModeCtxMap", "Begin"),
+    ("PredModeCtxMap", "DynamicContextMixing"),
+    ("PredModeCtxMap", "PriorDepth"),
+    ("PredModeCtxMap", "AdaptationSpeed"),
+    ("PredModeCtxMap", "LiteralContextMapMnemonic"),
+    ("PredModeCtxMap", "DistanceContextMapMnemonic"),
+    ("PredModeCtxMap", "LiteralContextMapFirstNibble"),
+    ("PredModeCtxMap", "DistanceContextMapFirstNibble"),
+    ("PredModeCtxMap", "LiteralContextMapSecondNibble"),
+    ("PredModeCtxMap", "DistanceContextMapSecondNibble"),
+    ("PredModeCtxMap", "MixingValues"),
+    ("PredModeCtxMap", "FullyDecoded"),
+    ("BlockType", "Begin"),
+    ("BlockType", "TwoNibbleType"),
+    ("BlockType", "FinalNibble"),
+    ("BlockType", "FullyDecoded"),
+];
+
+pub fn billing_key(billing: &BillingDesignation) -> usize {
+    match *billing {
+        BillingDesignation::Unknown => 0,
+        BillingDesignation::CopyCommand(CopySubstate::Begin) => 1,
+        BillingDesignation::CopyCommand(CopySubstate::CountSmall) => 2,
+        BillingDesignation::CopyCommand(CopySubstate::CountLengthFirst) => 3,
+        BillingDesignation::CopyCommand(CopySubstate::CountLengthGreater18Less25) => 4,
+        BillingDesignation::CopyCommand(CopySubstate::CountMantissaNibbles(..)) => 5,
+        BillingDesignation::CopyCommand(CopySubstate::CountDecoded) => 6,
+        BillingDesignation::CopyCommand(CopySubstate::DistanceLengthMnemonic) => 7,
+        BillingDesignation::CopyCommand(CopySubstate::DistanceLengthFirst) => 8,
+        BillingDesignation::CopyCommand(CopySubstate::DistanceLengthGreater14Less25) => 9,
+        BillingDesignation::CopyCommand(CopySubstate::DistanceMantissaNibbles(..)) => 10,
+        BillingDesignation::CopyCommand(CopySubstate::FullyDecoded) => 11,
+        BillingDesignation::DictCommand(DictSubstate::Begin) => 12,
+        BillingDesignation::DictCommand(DictSubstate::WordSizeFirst) => 13,
+        BillingDesignation::DictCommand(DictSubstate::WordSizeGreater18Less25) => 14,
+        BillingDesignation::DictCommand(DictSubstate::WordIndexMantissa(..)) => 15,
+        BillingDesignation::DictCommand(DictSubstate::TransformHigh) => 16,
+        BillingDesignation::DictCommand(DictSubstate::TransformLow) => 17,
+        BillingDesignation::DictCommand(DictSubstate::FullyDecoded) => 18,
+        BillingDesignation::LiteralCommand(LiteralSubstate::Begin) => 19,
+        BillingDesignation::LiteralCommand(LiteralSubstate::LiteralCountSmall(_)) => 20,
+        BillingDesignation::LiteralCommand(LiteralSubstate::LiteralCountFirst) => 21,
+        BillingDesignation::LiteralCommand(LiteralSubstate::LiteralCountLengthGreater14Less25) => 22,
+        BillingDesignation::LiteralCommand(LiteralSubstate::LiteralCountMantissaNibbles(..)) => 23,
+        BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(_)) => 24,
+        BillingDesignation::LiteralCommand(LiteralSubstate::SafeLiteralNibbleIndex(_)) => 25,
+        BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleLowerHalf(_)) => 26,
+        BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndexWithECDF(_)) => 27,
+        BillingDesignation::LiteralCommand(LiteralSubstate::FullyDecoded) => 28,
+        BillingDesignation::CrossCommand(CrossCommandBilling::Unknown) => 29,
+        BillingDesignation::CrossCommand(CrossCommandBilling::CopyIndicator) => 30,
+        BillingDesignation::CrossCommand(CrossCommandBilling::DictIndicator) => 31,
+        BillingDesignation::CrossCommand(CrossCommandBilling::EndIndicator) => 32,
+        BillingDesignation::CrossCommand(CrossCommandBilling::BlockSwitchType) => 33,
+        BillingDesignation::CrossCommand(CrossCommandBilling::FullSelection) => 34,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::Begin) => 35,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::DynamicContextMixing) => 36,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::PriorDepth(_)) => 37,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::AdaptationSpeed(..)) => 38,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::ContextMapMnemonic(_, ContextMapType::Literal, _)) => 39,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::ContextMapMnemonic(_, ContextMapType::Distance, _)) => 40,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::ContextMapFirstNibble(_, ContextMapType::Literal, _)) => 41,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::ContextMapFirstNibble(_, ContextMapType::Distance, _)) => 42,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::ContextMapSecondNibble(_, ContextMapType::Literal, _, _)) => 43,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::ContextMapSecondNibble(_, ContextMapType::Distance, _, _)) => 44,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::MixingValues(..)) => 45,
+        BillingDesignation::PredModeCtxMap(PredictionModeSubstate::FullyDecoded) => 46,
+        BillingDesignation::BlockType(BlockTypeState::Begin) => 47,
+        BillingDesignation::BlockType(BlockTypeState::TwoNibbleType) => 48,
+        BillingDesignation::BlockType(BlockTypeState::FinalNibble(_)) => 49,
+        BillingDesignation::BlockType(BlockTypeState::FullyDecoded(_)) => 50,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BillingCost {
+    pub bits: f64,
+    // what the symbols would take uncompressed: 1 per binary decision and 4 per nibble
+    pub virtual_bits: f64,
+}
+
+impl BillingCost {
+    fn add(&mut self, other: &BillingCost) {
+        self.bits += other.bits;
+        self.virtual_bits += other.virtual_bits;
+    }
+}
+
+fn sum(costs: &[BillingCost]) -> BillingCost {
+    let mut ret = BillingCost::default();
+    for cost in costs.iter() {
+        ret.add(cost);
+    }
+    ret
+}
+
+#[derive(Clone, Copy)]
+pub struct ReportingArithmeticEncoder {
+    cost: [BillingCost; NUM_BILLING_KEYS],
+}
+
+impl Default for ReportingArithmeticEncoder {
+    fn default() -> Self {
+        ReportingArithmeticEncoder {
+            cost: [BillingCost::default(); NUM_BILLING_KEYS],
+        }
+    }
+}
+
+impl ReportingArithmeticEncoder {
+    pub fn cost(&self) -> &[BillingCost; NUM_BILLING_KEYS] {
+        &self.cost
+    }
+}
+
+impl<AllocU8:Allocator<u8>> NewWithAllocator<AllocU8> for ReportingArithmeticEncoder {
+    fn new(_m8: &mut AllocU8) -> Self {
+        ReportingArithmeticEncoder::default()
+    }
+    fn free(&mut self, _m8: &mut AllocU8) {}
+}
+
+impl ArithmeticEncoderOrDecoder for ReportingArithmeticEncoder {
+    // the counts move with the coder, so nothing is counted twice
+    fn mov(&mut self) -> Self {
+        core::mem::take(self)
+    }
+    fn has_data_to_drain_or_fill(&self) -> bool {
+        false
+    }
+    fn drain_or_fill_internal_buffer_unchecked(&mut self,
+                                               _input: &mut ReadableBytes,
+                                               _output: &mut WritableBytes) -> DivansResult {
+        DivansResult::Success
+    }
+    fn close(&mut self) -> DivansResult {
+        DivansResult::Success
+    }
+    fn get_or_put_bit_without_billing(&mut self,
+                                      bit: &mut bool,
+                                      prob_of_false: u8) {
+        self.get_or_put_bit(bit, prob_of_false, BillingDesignation::Unknown)
+    }
+    fn get_or_put_bit(&mut self,
+                      bit: &mut bool,
+                      prob_of_false: u8,
+                      billing: BillingDesignation) {
+        let cost = &mut self.cost[billing_key(&billing)];
+        cost.bits += bit_cost(*bit, prob_of_false);
+        cost.virtual_bits += 1.0;
+    }
+    fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
+                                                   nibble: &mut u8,
+                                                   prob: &C) -> ProbRange {
+        self.get_or_put_nibble(nibble, prob, BillingDesignation::Unknown)
+    }
+    fn get_or_put_nibble<C: CDF16>(&mut self,
+                                   nibble: &mut u8,
+                                   prob: &C,
+                                   billing: BillingDesignation) -> ProbRange {
+        let cost = &mut self.cost[billing_key(&billing)];
+        cost.bits += nibble_cost(*nibble, prob);
+        cost.virtual_bits += 4.0;
+        prob.sym_to_start_and_freq(*nibble).range
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BillingRegion {
+    // uncompressed byte range; a command is charged to the region it starts in
+    pub start: usize,
+    pub end: usize,
+    pub cost: [BillingCost; NUM_BILLING_KEYS],
+}
+
+#[derive(Clone, Debug)]
+pub struct BillingReport {
+    pub original_bytes: usize,
+    pub region_size: usize,
+    // the header and checksum, which are written verbatim
+    pub framing_bytes: usize,
+    pub cost: [BillingCost; NUM_BILLING_KEYS],
+    pub regions: Vec<BillingRegion>,
+}
+
+fn write_json_costs<W: Write>(w: &mut W, costs: &[BillingCost; NUM_BILLING_KEYS]) -> io::Result<()> {
+    let total = sum(costs);
+    write!(w, "\"bits\":{:.3},\"virtual_bits\":{},\"designations\":[", total.bits, total.virtual_bits)?;
+    let mut first = true;
+    for (&(designation, state), cost) in BILLING_KEYS.iter().zip(costs.iter()) {
+        if cost.virtual_bits == 0.0 {
+            continue;
+        }
+        write!(w, "{}{{\"designation\":\"{}\",\"state\":\"{}\",\"bits\":{:.3},\"virtual_bits\":{}}}",
+               if first {""} else {","}, designation, state, cost.bits, cost.virtual_bits)?;
+        first = false;
+    }
+    write!(w, "]")
+}
+
+impl BillingReport {
+    pub fn total(&self) -> BillingCost {
+        sum(&self.cost)
+    }
+    pub fn total_bytes(&self) -> f64 {
+        self.total().bits / 8.0 + self.framing_bytes as f64
+    }
+    // designations that were never coded are left out
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "{{\"original_bytes\":{},\"region_size\":{},\"framing_bits\":{},",
+               self.original_bytes, self.region_size, self.framing_bytes * 8)?;
+        write_json_costs(w, &self.cost)?;
+        write!(w, ",\"regions\":[")?;
+        for (index, region) in self.regions.iter().enumerate() {
+            write!(w, "{}\n{{\"start\":{},\"end\":{},", if index == 0 {""} else {","}, region.start, region.end)?;
+            write_json_costs(w, &region.cost)?;
+            write!(w, "}}")?;
+        }
+        writeln!(w, "]}}")
+    }
+}
+
+fn command_length<SliceType: SliceWrapper<u8>>(cmd: &Command<SliceType>) -> usize {
+    match *cmd {
+        Command::Copy(ref cp) => cp.num_bytes as usize,
+        Command::Dict(ref dc) => dc.final_size as usize,
+        Command::Literal(ref lit) => lit.data.slice().len(),
+        _ => 0,
+    }
+}
+
+type ReportingCompressor = DivansCompressor<ReportingArithmeticEncoder,
+                                            HeapAlloc<u8>,
+                                            HeapAlloc<u32>,
+                                            HeapAlloc<DefaultCDF16>,
+                                            DevNull<HeapAlloc<u8>>>;
+
+fn current_cost(compressor: &ReportingCompressor) -> [BillingCost; NUM_BILLING_KEYS] {
+    let codec = compressor.get_codec();
+    let mut ret = *codec.cross_command_state.coder.cost();
+    if let ThreadContext::MainThread(ref ctx) = codec.cross_command_state.thread_ctx {
+        for (total, lit_cost) in ret.iter_mut().zip(ctx.lit_coder.cost().iter()) {
+            total.add(lit_cost);
+        }
+    }
+    ret
+}
+
+// codes the commands and returns how many bytes were still written out
+fn encode_commands<SliceType: SliceWrapper<u8>+Default>(compressor: &mut ReportingCompressor,
+                                                        commands: &[Command<SliceType>]) -> Result<usize, ErrMsg> {
+    let mut framing = [0u8; 256];
+    let mut framing_bytes = 0usize;
+    let mut command_offset = 0usize;
+    while command_offset < commands.len() {
+        let mut output_offset = 0usize;
+        let ret = compressor.encode_commands(commands, &mut command_offset, &mut framing[..], &mut output_offset);
+        framing_bytes += output_offset;
+        if let DivansOutputResult::Failure(m) = ret {
+            return Err(m);
+        }
+    }
+    Ok(framing_bytes)
+}
+
+fn flush(compressor: &mut ReportingCompressor) -> Result<usize, ErrMsg> {
+    let mut framing = [0u8; 256];
+    let mut framing_bytes = 0usize;
+    loop {
+        let mut output_offset = 0usize;
+        let ret = compressor.flush(&mut framing[..], &mut output_offset);
+        framing_bytes += output_offset;
+        match ret {
+            DivansOutputResult::Success => return Ok(framing_bytes),
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => return Err(m),
+        }
+    }
+}
+
+fn run_report<SliceType: SliceWrapper<u8>+Default>(compressor: &mut ReportingCompressor,
+                                                   commands: &[Command<SliceType>],
+                                                   region_size: usize) -> Result<BillingReport, ErrMsg> {
+    let mut report = BillingReport {
+        original_bytes: commands.iter().map(command_length).sum(),
+        region_size,
+        framing_bytes: 0,
+        cost: [BillingCost::default(); NUM_BILLING_KEYS],
+        regions: Vec::new(),
+    };
+    let mut command_offset = 0usize;
+    let mut position = 0usize;
+    let mut region_start = 0usize;
+    loop {
+        let region_end = region_start.saturating_add(region_size);
+        let chunk_start = command_offset;
+        while command_offset < commands.len() && position < region_end {
+            position += command_length(&commands[command_offset]);
+            command_offset += 1;
+        }
+        report.framing_bytes += encode_commands(compressor, &commands[chunk_start..command_offset])?;
+        if command_offset == commands.len() {
+            report.framing_bytes += flush(compressor)?;
+        }
+        let cost = current_cost(compressor);
+        let mut region = BillingRegion {
+            start: region_start,
+            end: core::cmp::min(region_end, report.original_bytes),
+            cost,
+        };
+        for (region_cost, previous) in region.cost.iter_mut().zip(report.cost.iter()) {
+            region_cost.bits -= previous.bits;
+            region_cost.virtual_bits -= previous.virtual_bits;
+        }
+        report.cost = cost;
+        report.regions.push(region);
+        if command_offset == commands.len() {
+            return Ok(report);
+        }
+        region_start = region_end;
+    }
+}
+
+/// Bills coding `commands` with `opts`, splitting the cost into regions of `region_size`
+/// uncompressed bytes; 0 puts the whole stream in one region.
+pub fn billing_report<SliceType: SliceWrapper<u8>+Default>(commands: &[Command<SliceType>],
+                                                           opts: DivansCompressorOptions,
+                                                           region_size: usize) -> Result<BillingReport, ErrMsg> {
+    let region_size = if region_size == 0 { usize::MAX } else { region_size };
+    let mut compressor = ReportingCompressor::new(HeapAlloc::<u8>::new(0),
+                                                  HeapAlloc::<u32>::new(0),
+                                                  HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                                                  opts);
+    let ret = run_report(&mut compressor, commands, region_size);
+    compressor.free();
+    ret
+}
+
+/// Bills compressing `input` with `opts`, using brotli's command selection as the compressor does.
+/// Filters are not applied: the commands have to describe the bytes of `input`.
+pub fn billing_report_for_input(input: &[u8],
+                                mut opts: DivansCompressorOptions,
+                                region_size: usize) -> Result<BillingReport, ErrMsg> {
+    opts.filters = FilterSelection::Disabled;
+    let commands = ::exhaustive::capture_brotli_ir(input, opts)?;
+    billing_report(&commands[..], opts, region_size)
+}
+
+#[cfg(test)]
+mod test {
+    use interface::{BillingDesignation, CrossCommandBilling};
+    use codec::ContextMapType;
+    use codec::literal::LiteralSubstate;
+    use codec::context_map::PredictionModeSubstate;
+    use super::{billing_key, BILLING_KEYS};
+    #[test]
+    fn test_billing_keys() {
+        for (index, key) in BILLING_KEYS.iter().enumerate() {
+            assert!(BILLING_KEYS[..index].iter().all(|other| other != key), "{:?} listed twice", key);
+        }
+        assert_eq!(BILLING_KEYS[billing_key(&BillingDesignation::LiteralCommand(LiteralSubstate::LiteralNibbleIndex(17)))],
+                   ("LiteralCommand", "LiteralNibbleIndex"));
+        assert_eq!(BILLING_KEYS[billing_key(&BillingDesignation::CrossCommand(CrossCommandBilling::FullSelection))],
+                   ("CrossCommand", "FullSelection"));
+        assert_eq!(BILLING_KEYS[billing_key(&BillingDesignation::PredModeCtxMap(
+            PredictionModeSubstate::ContextMapSecondNibble(3, ContextMapType::Distance, 0, true)))],
+                   ("PredModeCtxMap", "DistanceContextMapSecondNibble"));
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// `divans bench`: compresses every file of a corpus under every combination of the listed
+// settings and reports size, throughput and whether the stream decodes back to the input.
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use divans::{DivansCompressorOptions, Speed, StrideSelection};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct Grid {
+    pub quality: Vec<u16>,
+    pub mixing: Vec<u8>,
+    pub stride: Vec<String>,
+    pub speed: Vec<String>,
+    pub context_map: Vec<bool>,
+    pub ir_optimizer: Vec<u8>,
+}
+
+impl Default for Grid {
+    // a single point: the settings divans compresses with when given no options
+    fn default() -> Self {
+        Grid {
+            quality: vec![11],
+            mixing: vec![1],
+            stride: vec!["brotli".to_string()],
+            speed: vec!["default".to_string()],
+            context_map: vec![true],
+            ir_optimizer: vec![0],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Setting {
+    pub quality: u16,
+    pub mixing: u8,
+    pub stride: String,
+    pub speed: String,
+    pub context_map: bool,
+    pub ir_optimizer: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct Measurement {
+    pub file: String,
+    pub setting: Setting,
+    pub original_size: usize,
+    pub compressed_size: usize,
+    pub compress_time: Duration,
+    pub decompress_time: Duration,
+    pub roundtrip: bool,
+}
+
+fn parse_stride(value: &str) -> Result<StrideSelection, String> {
+    Ok(match value {
+        "none" => StrideSelection::PriorDisabled,
+        "brotli" => StrideSelection::UseBrotliRec,
+        "1" => StrideSelection::Stride1,
+        "2" => StrideSelection::Stride2,
+        "3" => StrideSelection::Stride3,
+        "4" => StrideSelection::Stride4,
+        "5" => StrideSelection::Stride5,
+        "6" => StrideSelection::Stride6,
+        "7" => StrideSelection::Stride7,
+        "8" => StrideSelection::Stride8,
+        _ => return Err(format!("stride must be none, brotli or 1 to 8, not {}", value)),
+    })
+}
+
+fn parse_speed(value: &str) -> Result<Option<Speed>, String> {
+    if value == "default" {
+        return Ok(None);
+    }
+    value.parse::<Speed>().map(Some).map_err(|_| format!("unknown speed {}", value))
+}
+
+impl Setting {
+    pub fn options(&self) -> Result<DivansCompressorOptions, String> {
+        let mut opts = DivansCompressorOptions::default();
+        opts.quality = Some(self.quality);
+        opts.dynamic_context_mixing = Some(self.mixing);
+        opts.force_stride_value = parse_stride(&self.stride)?;
+        opts.literal_adaptation = parse_speed(&self.speed)?.map(|speed| [speed, speed, speed, speed]);
+        opts.use_context_map = self.context_map;
+        opts.divans_ir_optimizer = self.ir_optimizer;
+        Ok(opts)
+    }
+}
+
+impl Grid {
+    pub fn settings(&self) -> Vec<Setting> {
+        let mut ret = Vec::new();
+        for &quality in self.quality.iter() {
+            for &mixing in self.mixing.iter() {
+                for stride in self.stride.iter() {
+                    for speed in self.speed.iter() {
+                        for &context_map in self.context_map.iter() {
+                            for &ir_optimizer in self.ir_optimizer.iter() {
+                                ret.push(Setting {
+                                    quality,
+                                    mixing,
+                                    stride: stride.clone(),
+                                    speed: speed.clone(),
+                                    context_map,
+                                    ir_optimizer,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+fn parse_list<T: ::std::str::FromStr>(name: &str, value: &str) -> Result<Vec<T>, String> {
+    value.split(',').map(|item| item.parse::<T>().map_err(|_| format!("bad {} value {}", name, item))).collect()
+}
+
+// directories are walked recursively, in name order so runs line up row by row
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if fs::metadata(path)?.is_dir() {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(path)? {
+            entries.push(entry?.path());
+        }
+        entries.sort();
+        for entry in entries.iter() {
+            collect_files(entry, files)?;
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+pub fn measure(file: &str, data: &[u8], setting: &Setting, repeat: usize, parallel: bool) -> Result<Measurement, String> {
+    let opts = setting.options()?;
+    let mut ret = Measurement {
+        file: file.to_string(),
+        setting: setting.clone(),
+        original_size: data.len(),
+        compressed_size: 0,
+        compress_time: Duration::new(u64::MAX, 0),
+        decompress_time: Duration::new(u64::MAX, 0),
+        roundtrip: true,
+    };
+    // the fastest of the repetitions is the least disturbed by the rest of the machine
+    for _ in 0..repeat {
+        let mut compressed = Vec::<u8>::new();
+        let start = Instant::now();
+        super::compress_raw(&mut &data[..], &mut compressed, opts, 65_536, true, true, parallel)
+            .map_err(|e| format!("{}: compression failed: {}", file, e))?;
+        ret.compress_time = ::core::cmp::min(ret.compress_time, start.elapsed());
+        ret.compressed_size = compressed.len();
+        let mut decompressed = Vec::<u8>::with_capacity(data.len());
+        let start = Instant::now();
+        let res = super::decompress(&mut &compressed[..], &mut decompressed, 65_536, &mut [], false, parallel);
+        ret.decompress_time = ::core::cmp::min(ret.decompress_time, start.elapsed());
+        if res.is_err() || decompressed != data {
+            ret.roundtrip = false;
+        }
+    }
+    Ok(ret)
+}
+
+fn megabytes_per_second(size: usize, time: Duration) -> f64 {
+    let seconds = time.as_secs_f64();
+    if seconds == 0.0 {
+        return 0.0;
+    }
+    size as f64 / 1_000_000.0 / seconds
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+pub const CSV_HEADER: &str = "file,quality,mixing,stride,speed,context_map,ir_optimizer,original_bytes,compressed_bytes,compress_mb_per_s,decompress_mb_per_s,roundtrip";
+
+pub fn write_row<W: Write>(w: &mut W, format: Format, first: bool, m: &Measurement) -> io::Result<()> {
+    let compress_speed = megabytes_per_second(m.original_size, m.compress_time);
+    let decompress_speed = megabytes_per_second(m.original_size, m.decompress_time);
+    match format {
+        Format::Csv => writeln!(w, "{},{},{},{},{},{},{},{},{},{:.3},{:.3},{}",
+                                csv_field(&m.file), m.setting.quality, m.setting.mixing, csv_field(&m.setting.stride),
+                                csv_field(&m.setting.speed), m.setting.context_map as u8, m.setting.ir_optimizer,
+                                m.original_size, m.compressed_size, compress_speed, decompress_speed, m.roundtrip),
+        Format::Json => write!(w, "{}{{\"file\":{},\"quality\":{},\"mixing\":{},\"stride\":{},\"speed\":{},\"context_map\":{},\"ir_optimizer\":{},\"original_bytes\":{},\"compressed_bytes\":{},\"compress_mb_per_s\":{:.3},\"decompress_mb_per_s\":{:.3},\"roundtrip\":{}}}",
+                               if first {"\n"} else {",\n"},
+                               json_string(&m.file), m.setting.quality, m.setting.mixing, json_string(&m.setting.stride),
+                               json_string(&m.setting.speed), m.setting.context_map, m.setting.ir_optimizer,
+                               m.original_size, m.compressed_size, compress_speed, decompress_speed, m.roundtrip),
+    }
+}
+
+pub fn usage() {
+    let _ = writeln!(&mut io::stderr(), "Benchmark: divans bench [grid options] corpus_dir_or_file...
+  -quality=11 -mixing=1 -stride=brotli -cm=1 -O=0 take comma separated lists; the grid is every combination
+  -stride= values are none, brotli or 1 to 8. -speed=MED or -speed=inc,lim adds one literal adaptation speed
+  per flag, since speeds contain commas; -speed=default keeps the per file choice
+  -format=csv|json picks the output, -repeat=N keeps the fastest of N timings, -serial decodes on one thread
+  exits 1 if any file fails to round trip");
+}
+
+/// Runs the benchmark described by the arguments after `bench` and returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut grid = Grid::default();
+    let mut speeds = Vec::<String>::new();
+    let mut format = Format::Csv;
+    let mut repeat = 1usize;
+    let mut parallel = super::PARALLEL_AVAILABLE;
+    let mut paths = Vec::<String>::new();
+    for arg in args.iter() {
+        let parsed = if let Some(value) = arg.strip_prefix("-quality=") {
+            parse_list(arg, value).map(|list| grid.quality = list)
+        } else if let Some(value) = arg.strip_prefix("-mixing=") {
+            parse_list(arg, value).map(|list| grid.mixing = list)
+        } else if let Some(value) = arg.strip_prefix("-stride=") {
+            let list: Vec<String> = value.split(',').map(|item| item.to_string()).collect();
+            list.iter().map(|item| parse_stride(item)).collect::<Result<Vec<_>, _>>().map(|_| grid.stride = list)
+        } else if let Some(value) = arg.strip_prefix("-speed=") {
+            parse_speed(value).map(|_| speeds.push(value.to_string()))
+        } else if let Some(value) = arg.strip_prefix("-cm=") {
+            parse_list::<u8>(arg, value).and_then(|list| {
+                if list.iter().any(|&cm| cm > 1) {
+                    return Err(format!("{}: context map settings are 0 or 1", arg));
+                }
+                grid.context_map = list.iter().map(|&cm| cm != 0).collect();
+                Ok(())
+            })
+        } else if let Some(value) = arg.strip_prefix("-O=") {
+            parse_list(arg, value).map(|list| grid.ir_optimizer = list)
+        } else if let Some(value) = arg.strip_prefix("-format=") {
+            match value {
+                "csv" => Ok(format = Format::Csv),
+                "json" => Ok(format = Format::Json),
+                _ => Err(format!("format must be csv or json, not {}", value)),
+            }
+        } else if let Some(value) = arg.strip_prefix("-repeat=") {
+            match value.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(repeat = n),
+                _ => Err(format!("bad repeat count {}", value)),
+            }
+        } else if arg == "-serial" {
+            Ok(parallel = false)
+        } else if arg == "-h" || arg == "-help" || arg == "--help" {
+            usage();
+            return 0;
+        } else if arg.starts_with('-') {
+            Err(format!("unknown bench option {}", arg))
+        } else {
+            Ok(paths.push(arg.clone()))
+        };
+        if let Err(why) = parsed {
+            let _ = writeln!(&mut io::stderr(), "divans bench: {}", why);
+            usage();
+            return 1;
+        }
+    }
+    if !speeds.is_empty() {
+        grid.speed = speeds;
+    }
+    let mut files = Vec::<PathBuf>::new();
+    for path in paths.iter() {
+        if let Err(e) = collect_files(Path::new(path), &mut files) {
+            let _ = writeln!(&mut io::stderr(), "divans bench: {}: {}", path, e);
+            return 1;
+        }
+    }
+    if files.is_empty() {
+        let _ = writeln!(&mut io::stderr(), "divans bench: no files to benchmark");
+        usage();
+        return 1;
+    }
+    let settings = grid.settings();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut exit_code = 0;
+    let mut first = true;
+    let header = match format {
+        Format::Csv => writeln!(out, "{}", CSV_HEADER),
+        Format::Json => write!(out, "["),
+    };
+    if header.is_err() {
+        return 1;
+    }
+    for file in files.iter() {
+        let name = file.display().to_string();
+        let data = match fs::read(file) {
+            Ok(data) => data,
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "divans bench: {}: {}", name, e);
+                exit_code = 1;
+                continue;
+            },
+        };
+        for setting in settings.iter() {
+            match measure(&name, &data, setting, repeat, parallel) {
+                Ok(m) => {
+                    if !m.roundtrip {
+                        let _ = writeln!(&mut io::stderr(), "divans bench: {} does not round trip under {:?}", name, setting);
+                        exit_code = 1;
+                    }
+                    // rows are flushed as they finish so a long run can be watched or cut short
+                    if write_row(&mut out, format, first, &m).and_then(|_| out.flush()).is_err() {
+                        return 1;
+                    }
+                    first = false;
+                },
+                Err(why) => {
+                    let _ = writeln!(&mut io::stderr(), "divans bench: {}", why);
+                    exit_code = 1;
+                },
+            }
+        }
+    }
+    if format == Format::Json && writeln!(out, "\n]").is_err() {
+        return 1;
+    }
+    exit_code
+}
@@ -29,24 +29,19 @@ extern crate brotli as brotli_decompressor;
 
 mod integration_test;
 mod benchmark;
+mod file_mode;
+mod bench;
+mod golden;
 mod util;
 
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 use std::env;
 use std::error;
 
-use core::convert::From;
-use std::vec::Vec;
 use divans::StaticCommand;
-use divans::BlockSwitch;
-use divans::FeatureFlagSliceType;
-use divans::CopyCommand;
 use divans::LiteralBlockSwitch;
-use divans::LiteralCommand;
 use divans::LiteralPredictionModeNibble;
-use divans::PredictionModeContextMap;
 use divans::Command;
-use divans::DictCommand;
 use divans::DivansResult;
 use divans::DivansOutputResult;
 use divans::Compressor;
@@ -63,7 +58,6 @@ use divans::DivansDecompressorFactoryStruct;
 use divans::interface::{ArithmeticEncoderOrDecoder, NewWithAllocator, StrideSelection};
 use divans::Nop;
 use std::fs::File;
-use std::error::Error;
 use std::io::{self,Write, Seek, SeekFrom, BufReader};
 
 macro_rules! println_stderr(
@@ -103,38 +97,7 @@ impl error::Error for DivansErrMsg {
     fn cause(&self) -> Option<&error::Error> {None}
 }
 
-#[derive(Debug)]
-pub struct ItemVec<Item:Sized+Default>(Vec<Item>);
-impl<Item:Sized+Default> Default for ItemVec<Item> {
-    fn default() -> Self {
-        ItemVec(Vec::<Item>::new())
-    }
-}
-impl<Item:Sized+Default> alloc::SliceWrapper<Item> for ItemVec<Item> {
-    fn slice(&self) -> &[Item] {
-        &self.0[..]
-    }
-}
-
-impl<Item:Sized+Default> alloc::SliceWrapperMut<Item> for ItemVec<Item> {
-    fn slice_mut(&mut self) -> &mut [Item] {
-        &mut self.0[..]
-    }
-}
-
-impl<Item:Sized+Default> core::ops::Index<usize> for ItemVec<Item> {
-    type Output = Item;
-    fn index(&self, index:usize) -> &Item {
-        &self.0[index]
-    }
-}
-
-impl<Item:Sized+Default> core::ops::IndexMut<usize> for ItemVec<Item> {
-
-    fn index_mut(&mut self, index:usize) -> &mut Item {
-        &mut self.0[index]
-    }
-}
+pub use divans::ir::ItemVec;
 
 #[derive(Default)]
 struct ItemVecAllocator<Item:Sized+Default> {
@@ -150,336 +113,44 @@ impl<Item:Sized+Default+Clone> alloc::Allocator<Item> for ItemVecAllocator<Item>
         //eprint!("F:{}\n", _bv.slice().len());
     }
 }
-fn window_parse(s : &str) -> Result<i32, io::Error> {
-    let window_vec : Vec<String> = s.split(' ').map(|s| s.to_string()).collect();
-    if window_vec.is_empty() {
-        panic!("Unexpected");    }
-    if window_vec.len() < 2 {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                       "window needs 1 argument"));
+
+// the numeric suffix of delta and transpose, if any
+fn filter_param(param: &str, default: u8) -> Result<u8, io::Error> {
+    if param.is_empty() {
+        return Ok(default);
     }
-    if window_vec[0] != "window" {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                       "first arg must be window followed by log window size"));
+    match param.parse::<u8>() {
+        Ok(p) if p >= 1 && p <= 16 => Ok(p),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                "filter parameter must be between 1 and 16")),
     }
-    let expected_window_size = match window_vec[1].parse::<i32>() {
-        Ok(el) => el,
-        Err(msg) => {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      msg.description()));
-        }
-    };
-    Ok(expected_window_size)
 }
 
-#[cfg(not(feature="external-literal-probability"))]
-fn deserialize_external_probabilities(probs: &std::vec::Vec<u8>) -> Result<FeatureFlagSliceType<ItemVec<u8>>, io::Error> {
-    if !probs.is_empty() {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-            "To parse nonzero external probabiltiy flags, compile with feature flag external-literal-probability"));
+fn filter_parse(s : &str) -> Result<divans::FilterSelection, io::Error> {
+    if s == "auto" {
+        return Ok(divans::FilterSelection::Auto);
     }
-    Ok(FeatureFlagSliceType::<ItemVec<u8>>::default())
-}
-#[cfg(feature="external-literal-probability")]
-fn deserialize_external_probabilities(probs: &std::vec::Vec<u8>) -> Result<FeatureFlagSliceType<ItemVec<u8>>, io::Error> {
-    Ok(FeatureFlagSliceType::<ItemVec<u8>>(ItemVec(probs)))
-}
-
-
-
-
-fn command_parse(s : &str) -> Result<Option<Command<ItemVec<u8>>>, io::Error> {
-    let command_vec : Vec<&str>= s.split(' ').collect();
-    if command_vec.is_empty() {
-        panic!("Unexpected");
+    if s == "none" {
+        return Ok(divans::FilterSelection::Disabled);
     }
-    let cmd = command_vec[0];
-    if cmd == "window" {
-            // FIXME validate
-            return Ok(None);
-    } else if cmd == "prediction" {
-        if command_vec.len() < 2 {
+    let mut filters = [divans::Filter::Identity; 2];
+    for (index, name) in s.split(',').enumerate() {
+        if index >= filters.len() {
             return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      "prediction needs 1 argument"));
-        }
-        let pmode = match command_vec[1] {
-          "utf8" => LiteralPredictionModeNibble::utf8(),
-          "sign" => LiteralPredictionModeNibble::signed(),
-          "lsb6" => LiteralPredictionModeNibble::lsb6(),
-          "msb6" => LiteralPredictionModeNibble::msb6(),
-          _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                         "invalid prediction mode; not {utf8,sign,lsb6,msb6}")),
-        };
-        let mut ret = PredictionModeContextMap::<ItemVec<u8> > {
-            literal_context_map: ItemVec::<u8>::default(),
-            predmode_speed_and_distance_context_map: ItemVec::<u8>::default(),
-        };
-        if let Some((index, _)) = command_vec.iter().enumerate().find(|r| *r.1 == "lcontextmap") {
-            for literal_context_map_val in command_vec.split_at(index + 1).1.iter() {
-                match literal_context_map_val.parse::<i64>() {
-                    Ok(el) => {
-                        if el <= 255 && el >= 0 {
-                            ret.literal_context_map.0.push(el as u8);
-                        } else {
-                            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                      literal_context_map_val.to_string() +
-                                                      " literal context mp val must be u8"));
-                        }
-                    },
-                    Err(_) => {
-                        break;
-                    },
-                }
-            }
-        }
-        ret.predmode_speed_and_distance_context_map.0.resize(PredictionModeContextMap::<ItemVec<u8> >::size_of_combined_array(0), 0u8);
-        ret.set_literal_prediction_mode(pmode);
-        if let Some((index, _)) = command_vec.iter().enumerate().find(|r| *r.1 == "dcontextmap") {
-            for distance_context_map_val in command_vec.split_at(index + 1).1.iter() {
-                match distance_context_map_val.parse::<i64>() {
-                    Ok(el) => {
-                        if el <= 255 && el >= 0 {
-                            ret.predmode_speed_and_distance_context_map.0.push(el as u8);
-                        } else {
-                            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                      distance_context_map_val.to_string() +
-                                                      " distance context map val must be u8"));
-                        }
-                    },
-                    Err(_) => {
-                        break;
-                    },
-                }
-            }
-        }
-        let mut mixing_values = [0;8192];
-        if let Some((index, _)) = command_vec.iter().enumerate().find(|r| *r.1 == "mixingvalues") {
-            let mut offset = 0usize;
-            for mixing_val in command_vec.split_at(index + 1).1.iter() {
-                match mixing_val.parse::<i64>() {
-                    Ok(el) => {
-                        if offset >= 8192 {
-                            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                      mixing_val.to_string() +
-                                                      "Must have no more than 512 mixing values"));
-                        }
-                        if el <= 8 && el >= 0 {
-                            mixing_values[offset] = el as u8;
-                            offset += 1;
-                        } else {
-                            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                      mixing_val.to_string() +
-                                                      "Prior Strategy Mixing val must be 0 or 1"));
-                        }
-                    },
-                    Err(_) => {
-                        break;
-                    },
-                }
-            }
-        }
-        ret.set_mixing_values(&mixing_values);
-        
-        let mut cm_stride_mix_speed = [[(0u16,0u16);2];3];
-        let keys = [["cmspeedinc", "cmspeedmax"],
-                    ["stspeedinc", "stspeedmax"],
-                    ["mxspeedinc", "mxspeedmax"]];
-        for (which_type, keypair) in keys.iter().enumerate() {
-            for (incmx, key) in keypair.iter().enumerate() {
-                if let Some((index, _)) = command_vec.iter().enumerate().find(|r| *r.1 == *key) {
-                    for (index, speed_inc_val) in command_vec.split_at(index + 1).1.iter().enumerate() {
-                        if index >= 2 {
-                            break;
-                        }
-                        match speed_inc_val.parse::<u16>() {
-                            Ok(el) => {
-                                if el <= 16384 {
-                                    if incmx == 0 {
-                                        cm_stride_mix_speed[which_type][index].0 = el;
-                                    } else {
-                                        cm_stride_mix_speed[which_type][index].1 = el;
-                                    }
-                                } else {
-                                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                              speed_inc_val.to_string() +
-                                                              " speed inc val must be u16 <= 16384"));
-                                }
-                            },
-                            Err(_) => {
-                                break;
-                            },
-                        }
-                    }
-                }
-            }
-        }
-        ret.set_context_map_speed(cm_stride_mix_speed[0]);
-        ret.set_stride_context_speed(cm_stride_mix_speed[1]);
-        ret.set_combined_stride_context_speed(cm_stride_mix_speed[2]);
-        return Ok(Some(Command::PredictionMode(ret)));
-    } else if cmd == "ctype" || cmd == "ltype" || cmd == "dtype" {
-        if command_vec.len() != 2 && (command_vec.len() != 3 || cmd != "ltype") {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      "*type needs 1 argument"));
-        }
-        let block_type = match command_vec[1].parse::<u32>() {
-            Ok(el) => el as u8,
-            Err(msg) => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          msg.description()));
-            }
-        };
-        return Ok(Some(match cmd.chars().next().unwrap() {
-            'c' => Command::BlockSwitchCommand(BlockSwitch::new(block_type)),
-            'd' => Command::BlockSwitchDistance(BlockSwitch::new(block_type)),
-            'l' => Command::BlockSwitchLiteral(LiteralBlockSwitch::new(block_type,
-                  if command_vec.len() < 2 {
-                     0
-                  } else {
-                     match command_vec[2].parse::<u32>() {
-                         Ok(stride) => {
-                             if stride > 8 {
-                                 return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                           "Strude must be <= 8"));
-                                 
-                             }
-                             stride as u8
-                         },
-                         Err(msg) => {
-                             return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                       msg.description()));
-                         }
-                     }
-                  }
-            )),
-            _ => panic!("Logic error: already checked for valid command"),
-        }));
-    } else if cmd == "copy" {
-        if command_vec.len() < 4 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      "copy needs 4 arguments"));
-        }
-        let expected_len = match command_vec[1].parse::<u32>() {
-            Ok(el) => el,
-            Err(msg) => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          msg.description()));
-            }
-        };
-        if command_vec[2] != "from" {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      s.to_string() + "needs a from statement in the 2nd arg"));
-        }
-        let distance = match command_vec[3].parse::<u32>() {
-            Ok(el) => el,
-            Err(msg) => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          msg.description()));
-            }
-        };
-        if expected_len == 0 {
-           return Ok(None);
+                                      "at most 2 filters may be chained"));
         }
-        return Ok(Some(Command::Copy(CopyCommand{distance:distance, num_bytes:expected_len})));
-    } else if cmd == "dict" {
-        if command_vec.len() < 6 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      "dict needs 6+ arguments"));
-        }
-        let expected_len = match command_vec[1].parse::<u32>() {
-            Ok(el) => el,
-            Err(msg) => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          msg.description()));
-            }
-        } as u8;
-        if command_vec[2] != "word" {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      s.to_string() + " needs a word after the expected len"));
-        }
-        let word_id : Vec<String> = command_vec[3].split(',').map(|s| s.to_string()).collect();
-        if word_id.len() != 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      s.to_string() + " needs a comma separated word value"));
-        }
-        let word_len = match word_id[0].parse::<u32>() {
-            Ok(el) => el,
-            Err(msg) => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          msg.description()));
-            }
-        } as u8;
-        let word_index = match word_id[1].parse::<u32>() {
-            Ok(el) => el,
-            Err(msg) => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          msg.description()));
-            }
-        };
-        for index in 5..command_vec.len() {
-            if command_vec[index - 1] == "func" {
-                let transform = match command_vec[index].parse::<u32>() {
-                    Ok(el) => el,
-                    Err(msg) => {
-                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                                  msg.description()));
-                    }
-                } as u8;
-                return Ok(Some(Command::Dict(DictCommand{
-                    word_size:word_len,
-                    word_id:word_index,
-                    empty:0,
-                    final_size:expected_len,
-                    transform:transform
-                })));
-            }
-        }
-    } else if cmd == "insert" || cmd == "rndins" {
-        if command_vec.len() < 3 {
-            if command_vec.len() == 2 && command_vec[1] == "0" {
-                return Ok(None);
-            }
-                return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                          String::from("insert needs 3 arguments, not (") + s + ")"));
-        }
-        let expected_len = match command_vec[1].parse::<usize>() {
-            Ok(el) => el,
-            Err(msg) => {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                              msg.description()));
-            }
-        };
-        if expected_len ==  0 {
-            return Ok(None);
-        }
-        let data = try!(util::literal_slice_to_vec(&s.as_bytes()[command_vec[0].len() + command_vec[1].len() + 2..]));
-        let probs = if command_vec.len() > 3 && command_vec[2].len() != 0 && command_vec[2].bytes().next().unwrap() != b'\"' {
-            let prob = try!(util::hex_slice_to_vec(command_vec[3].as_bytes()));
-            assert!(prob.len() == expected_len * 8);
-            prob
+        filters[index] = if name == "x86" {
+            divans::Filter::X86
+        } else if name.starts_with("delta") {
+            divans::Filter::Delta(filter_param(&name["delta".len()..], 1)?)
+        } else if name.starts_with("transpose") {
+            divans::Filter::Transpose(filter_param(&name["transpose".len()..], 4)?)
         } else {
-            Vec::<u8>::new()
-        };
-
-        if data.len() != expected_len {
             return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                      String::from("Length does not match ") + s))
-        }
-        match deserialize_external_probabilities(&probs) {
-            Ok(external_probs) => {
-                return Ok(Some(Command::Literal(LiteralCommand{
-                    data:ItemVec(data),
-                    high_entropy:cmd == "rndins",
-                    prob:external_probs,
-                         })));
-            },
-            Err(external_probs_err) => {
-                return Err(external_probs_err);
-            },
-        }
+                                      "filter must be auto, none, x86, delta[N] or transpose[N]"));
+        };
     }
-    Err(io::Error::new(io::ErrorKind::InvalidInput,
-                       String::from("Unknown ") + s))
+    Ok(divans::FilterSelection::Fixed(divans::FilterChain::new(filters[0], filters[1])))
 }
 
 fn recode_cmd_buffer<RState:divans::interface::Compressor,
@@ -527,9 +198,8 @@ fn recode_cmd_buffer<RState:divans::interface::Compressor,
 fn recode_inner<Reader:std::io::BufRead,
                 Writer:std::io::Write,
                 RingBuffer:core::default::Default+SliceWrapper<u8>+SliceWrapperMut<u8>>(
-    r:&mut Reader,
+    r:&mut divans::ir::Reader<Reader>,
     w:&mut Writer) -> io::Result<()> {
-    let mut buffer = String::new();
     let mut obuffer = vec![0u8; 65_536];
     let mut ibuffer:[Command<ItemVec<u8>>; CMD_BUFFER_SIZE] = [Command::<ItemVec<u8>>::nop(),
                                                            Command::<ItemVec<u8>>::nop(),
@@ -551,31 +221,17 @@ fn recode_inner<Reader:std::io::BufRead,
     let mut i_read_index = 0usize;
     let mut state = divans::DivansRecodeState::<RingBuffer>::default();
     loop {
-        buffer.clear();
-        match r.read_line(&mut buffer) {
-            Err(e) => {
-                if e.kind() == io::ErrorKind::Interrupted {
-                    continue;
-                }
-                return Err(e)
-            },
-            Ok(count) => {
-                if i_read_index == ibuffer.len() || count == 0 {
-                    recode_cmd_buffer(&mut state, ibuffer.split_at(i_read_index).0, w,
-                                      &mut obuffer[..]).unwrap();
-                    i_read_index = 0
-                }
-                if count == 0 {
-                    break;
-                }
-                let line = buffer.trim().to_string();
-                match command_parse(&line).unwrap() {
-                    None => {},
-                    Some(c) => {
-                        ibuffer[i_read_index] = c;
-                        i_read_index += 1;
-                    }
-                }
+        let cmd = r.next_command().unwrap();
+        if i_read_index == ibuffer.len() || cmd.is_none() {
+            recode_cmd_buffer(&mut state, ibuffer.split_at(i_read_index).0, w,
+                              &mut obuffer[..]).unwrap();
+            i_read_index = 0
+        }
+        match cmd {
+            None => break,
+            Some(c) => {
+                ibuffer[i_read_index] = c;
+                i_read_index += 1;
             }
         }
     }
@@ -621,9 +277,8 @@ fn compress_inner<Reader:std::io::BufRead,
                                 AllocU8,
                                 AllocU32,
                                 AllocCDF16>,
-    r:&mut Reader,
+    r:&mut divans::ir::Reader<Reader>,
     w:&mut Writer) -> io::Result<()> {
-    let mut buffer = String::new();
     let mut obuffer = vec![0u8; 65_536];
     let mut ibuffer:[Command<ItemVec<u8>>; CMD_BUFFER_SIZE] = [Command::<ItemVec<u8>>::nop(),
                                                            Command::<ItemVec<u8>>::nop(),
@@ -646,37 +301,23 @@ fn compress_inner<Reader:std::io::BufRead,
     let mut last_literal_switch = LiteralBlockSwitch::new(0, 0);
     let mut m8 = ItemVecAllocator::<u8>::default();
     loop {
-        buffer.clear();
-        match r.read_line(&mut buffer) {
-            Err(e) => {
-                if e.kind() == io::ErrorKind::Interrupted {
-                    continue;
-                }
-                return Err(e)
-            },
-            Ok(count) => {
-                if i_read_index == ibuffer.len() || count == 0 {
-                    try!(recode_cmd_buffer(&mut state, ibuffer.split_at(i_read_index).0, w,
-                                               &mut obuffer[..]));
+        let cmd = try!(r.next_command());
+        if i_read_index == ibuffer.len() || cmd.is_none() {
+            try!(recode_cmd_buffer(&mut state, ibuffer.split_at(i_read_index).0, w,
+                                   &mut obuffer[..]));
 
-                    for item in &mut ibuffer {
-                       free_cmd(item, &mut m8);
-                    }
-                    i_read_index = 0
-                }
-                if count == 0 {
-                    break;
-                }
-                let line = buffer.trim().to_string();
-                match try!(command_parse(&line)) {
-                    None => {},
-                    Some(c) => {
-                        if allowed_command(&c,
-                                           &mut last_literal_switch) {
-                            ibuffer[i_read_index] = c;
-                            i_read_index += 1;
-                        }
-                    }
+            for item in &mut ibuffer {
+               free_cmd(item, &mut m8);
+            }
+            i_read_index = 0
+        }
+        match cmd {
+            None => break,
+            Some(c) => {
+                if allowed_command(&c,
+                                   &mut last_literal_switch) {
+                    ibuffer[i_read_index] = c;
+                    i_read_index += 1;
                 }
             }
         }
@@ -852,7 +493,7 @@ type BrotliFactory = divans::BrotliDivansHybridCompressorFactory<ItemVecAllocato
 fn compress_raw<Reader:std::io::Read,
                 Writer:std::io::Write>(r:&mut Reader,
                                        w:&mut Writer,
-                                       opts: divans::DivansCompressorOptions,
+                                       mut opts: divans::DivansCompressorOptions,
                                        mut buffer_size: usize,
                                        use_brotli: bool,
                                        force_compress: bool,
@@ -890,7 +531,11 @@ fn compress_raw<Reader:std::io::Read,
     }
     let ibuffer = m8.alloc_cell(buffer_size);
     let obuffer = m8.alloc_cell(buffer_size);
-    if use_brotli {
+    // the hybrid compressor also runs the IR optimizer and block splitter over the internal matcher's commands
+    if use_brotli || opts.divans_ir_optimizer != 0 || opts.block_split {
+        if !use_brotli {
+            opts.use_brotli = divans::BrotliCompressionSetting::UseInternalCommandSelection;
+        }
         let combined_alloc = brotli::enc::CombiningAllocator::new(
             ItemVecAllocator::<u8>::default(),
             ItemVecAllocator::<u16>::default(),
@@ -948,24 +593,27 @@ fn compress_ir<Reader:std::io::BufRead,
     w:&mut Writer,
     mut opts: divans::DivansCompressorOptions,
 ) -> io::Result<()> {
-    let window_size : i32;
-    let mut buffer = String::new();
-    loop {
-        match r.read_line(&mut buffer) {
-            Err(e) => {
-                if e.kind() == io::ErrorKind::Interrupted {
-                    continue;
-                }
-                return Err(e);
-            },
-            Ok(_) => {
-                let line = buffer.trim().to_string();
-                window_size = try!(window_parse(&line));
-                break;
-            }
-        }
-    }
-    opts.window_size = Some(window_size);
+    let mut parser = try!(divans::ir::Reader::new(r));
+    compress_ir_reader(&mut parser, w, opts)
+}
+
+fn compress_deflate<Reader:std::io::BufRead,
+            Writer:std::io::Write>(
+    r:&mut Reader,
+    w:&mut Writer,
+    opts: divans::DivansCompressorOptions,
+) -> io::Result<()> {
+    let mut parser = try!(divans::ir::Reader::from_deflate(r));
+    compress_ir_reader(&mut parser, w, opts)
+}
+
+fn compress_ir_reader<Reader:std::io::BufRead,
+            Writer:std::io::Write>(
+    parser:&mut divans::ir::Reader<Reader>,
+    w:&mut Writer,
+    mut opts: divans::DivansCompressorOptions,
+) -> io::Result<()> {
+    opts.window_size = Some(try!(parser.header()).window_size);
     let state =DivansCompressorFactoryStruct::<ItemVecAllocator<u8>,
                                   ItemVecAllocator<divans::DefaultCDF16>>::new(
         ItemVecAllocator::<u8>::default(),
@@ -974,7 +622,50 @@ fn compress_ir<Reader:std::io::BufRead,
         opts,
         (),
     );
-    compress_inner(state, r, w)
+    compress_inner(state, parser, w)
+}
+
+// compresses input that has to be held in memory as a whole: for -exhaustive or -billing-report=
+fn compress_buffered<Reader:std::io::Read,
+                     Writer:std::io::Write>(r:&mut Reader,
+                                            w:&mut Writer,
+                                            mut opts: divans::DivansCompressorOptions,
+                                            buffer_size: usize,
+                                            use_brotli: bool,
+                                            exhaustive: bool,
+                                            billing_report: Option<(&str, usize)>,
+                                            multithread: bool) -> io::Result<()> {
+    let mut input = Vec::<u8>::new();
+    r.read_to_end(&mut input)?;
+    if exhaustive {
+        let num_threads = if multithread {
+            std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+        } else {
+            1
+        };
+        let mut output = Vec::<u8>::new();
+        let best = match divans::exhaustive::compress_exhaustive(&input[..], opts, num_threads, &mut output) {
+            Ok(best) => best,
+            Err(m) => return Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m))),
+        };
+        println_stderr!("Exhaustive: {} candidates, chose stride {:?} context map {} mixing {:?} speeds {:?} prior depth {:?}",
+                        best.candidates, best.options.force_stride_value, best.options.use_context_map,
+                        best.options.dynamic_context_mixing, best.options.literal_adaptation, best.options.prior_depth);
+        w.write_all(&output[..])?;
+        opts = best.options;
+    } else {
+        compress_raw(&mut &input[..], w, opts, buffer_size, use_brotli, true, multithread)?;
+    }
+    if let Some((filename, region_size)) = billing_report {
+        let report = match divans::billing_report_for_input(&input[..], opts, region_size) {
+            Ok(report) => report,
+            Err(m) => return Err(io::Error::new(io::ErrorKind::InvalidInput, DivansErrMsg(m))),
+        };
+        let mut output = io::BufWriter::new(File::create(&Path::new(filename))?);
+        report.write_json(&mut output)?;
+        output.flush()?;
+    }
+    Ok(())
 }
 
 fn decompress<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
@@ -984,7 +675,41 @@ fn decompress<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
                                                            skip_crc: bool,
                                                            multithread:bool,) -> io::Result<()>
 {
-    let ret;
+    decompress_with_trace(r, w, buffer_size, additional_input, skip_crc, multithread, None)
+}
+
+fn write_thread_trace(trace: Option<divans::ThreadTrace>, filename: &str) -> io::Result<()> {
+    let trace = match trace {
+        Some(trace) => trace,
+        None => {
+            println_stderr!("No thread trace written: the stream was decoded on a single thread");
+            return Ok(());
+        },
+    };
+    if !cfg!(feature="threadlog") {
+        println_stderr!("Built without the threadlog feature: the thread trace has no events");
+    }
+    let mut output = io::BufWriter::new(File::create(&Path::new(filename))?);
+    trace.write_chrome_trace(&mut output)?;
+    output.flush()?;
+    if trace.dropped != 0 {
+        println_stderr!("Thread log full: {} events dropped", trace.dropped);
+    }
+    for total in trace.wait_summary().iter() {
+        println_stderr!("{:?}: {} waits, {:?} total", total.event, total.count, total.total);
+    }
+    Ok(())
+}
+
+fn decompress_with_trace<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
+                                                                      w:&mut Writer,
+                                                                      buffer_size: usize,
+                                                                      additional_input: &mut[u8],
+                                                                      skip_crc: bool,
+                                                                      multithread:bool,
+                                                                      thread_trace: Option<&str>) -> io::Result<()>
+{
+    let mut ret;
     let mut state = DivansDecompressorFactoryStruct::<ItemVecAllocator<u8>, ItemVecAllocator<divans::DefaultCDF16>, ItemVecAllocator<StaticCommand>>::new(
         ItemVecAllocator::<u8>::default(),
         ItemVecAllocator::<divans::DefaultCDF16>::default(),
@@ -999,6 +724,9 @@ fn decompress<Reader:std::io::Read, Writer:std::io::Write>(r:&mut Reader,
         &mut state,
         additional_input,
         buffer_size);
+    if let (true, Some(filename)) = (ret.is_ok(), thread_trace) {
+        ret = write_thread_trace(state.thread_trace(), filename);
+    }
     state.free();
     ret
 }
@@ -1105,87 +833,98 @@ fn decompress_generic<Reader:std::io::Read,
     Ok(())
 }
 
+// lists every semantic problem in the IR and exits with status 1 if there were any
+fn validate<Reader:std::io::BufRead,
+            Writer:std::io::Write>(r:&mut Reader,
+                                   w:&mut Writer) -> io::Result<()> {
+    let violations = try!(divans::ir::validate(r));
+    for violation in violations.iter() {
+        try!(writeln!(w, "{}", violation));
+    }
+    try!(w.flush());
+    if !violations.is_empty() {
+        println_stderr!("{} IR violations found", violations.len());
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// the IR tools end with a message and status 1 on malformed input instead of panicking
+fn exit_on_error<T>(input_name: &str, ret: io::Result<T>) -> T {
+    match ret {
+        Ok(val) => val,
+        Err(e) => {
+            println_stderr!("divans: {}: {}", input_name, e);
+            std::process::exit(1);
+        },
+    }
+}
+
 fn recode<Reader:std::io::BufRead,
           Writer:std::io::Write>(
-    mut r:&mut Reader,
+    r:&mut Reader,
     mut w:&mut Writer) -> io::Result<()> {
-    let window_size : i32;
-    let mut buffer = String::new();
-    loop {
-        match r.read_line(&mut buffer) {
-            Err(e) => {
-                if e.kind() == io::ErrorKind::Interrupted {
-                    continue;
-                }
-                return Err(e);
-            },
-            Ok(_) => {
-                let line = buffer.trim().to_string();
-                window_size = window_parse(&line).unwrap();
-                break;
-            }
-        }
-    }
-    match window_size {
-        10 => recode_inner::<Reader,
+    let mut parser = try!(divans::ir::Reader::new(r));
+    match try!(parser.header()).window_size {
+        10 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer10>(&mut r,
+                     util::StaticHeapBuffer10>(&mut parser,
                                                &mut w),
-        11 => recode_inner::<Reader,
+        11 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer11>(&mut r,
+                     util::StaticHeapBuffer11>(&mut parser,
                                                &mut w),
-        12 => recode_inner::<Reader,
+        12 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer12>(&mut r,
+                     util::StaticHeapBuffer12>(&mut parser,
                                          &mut w),
-        13 => recode_inner::<Reader,
+        13 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer13>(&mut r,
+                     util::StaticHeapBuffer13>(&mut parser,
                                          &mut w),
-        14 => recode_inner::<Reader,
+        14 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer14>(&mut r,
+                     util::StaticHeapBuffer14>(&mut parser,
                                          &mut w),
-        15 => recode_inner::<Reader,
+        15 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer15>(&mut r,
+                     util::StaticHeapBuffer15>(&mut parser,
                                          &mut w),
-        16 => recode_inner::<Reader,
+        16 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer16>(&mut r,
+                     util::StaticHeapBuffer16>(&mut parser,
                                          &mut w),
-        17 => recode_inner::<Reader,
+        17 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer17>(&mut r,
+                     util::StaticHeapBuffer17>(&mut parser,
                                          &mut w),
-        18 => recode_inner::<Reader,
+        18 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer18>(&mut r,
+                     util::StaticHeapBuffer18>(&mut parser,
                                          &mut w),
-        19 => recode_inner::<Reader,
+        19 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer19>(&mut r,
+                     util::StaticHeapBuffer19>(&mut parser,
                                          &mut w),
-        20 => recode_inner::<Reader,
+        20 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer20>(&mut r,
+                     util::StaticHeapBuffer20>(&mut parser,
                                          &mut w),
-        21 => recode_inner::<Reader,
+        21 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer21>(&mut r,
+                     util::StaticHeapBuffer21>(&mut parser,
                                          &mut w),
-        22 => recode_inner::<Reader,
+        22 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer22>(&mut r,
+                     util::StaticHeapBuffer22>(&mut parser,
                                          &mut w),
-        23 => recode_inner::<Reader,
+        23 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer23>(&mut r,
+                     util::StaticHeapBuffer23>(&mut parser,
                                          &mut w),
-        24 => recode_inner::<Reader,
+        24 => recode_inner::<&mut Reader,
                      Writer,
-                     util::StaticHeapBuffer24>(&mut r,
+                     util::StaticHeapBuffer24>(&mut parser,
                                          &mut w),
         _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Window size must be <=24 >= 10")),
     }
@@ -1196,13 +935,39 @@ const PARALLEL_AVAILABLE: bool = true;
 const PARALLEL_AVAILABLE: bool = false;
 
 fn main() {
+    {
+        let args: Vec<String> = env::args().collect();
+        if args.len() > 1 && args[1] == "bench" {
+            std::process::exit(bench::run(&args[2..]));
+        }
+        if args.len() > 1 && args[1] == "golden-regenerate" {
+            std::process::exit(golden::regenerate(&args[2..]));
+        }
+    }
     let mut force_compress = false;
     let mut do_compress = true;
     let mut raw_compress = true;
+    let mut deflate_input = false;
     let mut q9_5 = false;
-    let mut divans_ir_optimizer = false;
+    let mut divans_ir_optimizer = 0u8;
+    let mut block_split = false;
+    let mut exhaustive = false;
+    let mut billing_report: Option<String> = None;
+    let mut billing_region = 65_536usize;
+    let mut entropy_coder = divans::EntropyCoderSelection::default();
+    let mut cdf_family = divans::CDFFamily::default();
+    let mut mux_policy = divans::MuxPolicy::default();
+    let mut stream_count = divans::StreamCount::default();
+    let mut thread_trace: Option<String> = None;
     let mut do_recode = false;
+    let mut convert_ir: Option<divans::ir::Format> = None;
+    let mut dump_ir: Option<divans::ir::Format> = None;
+    let mut validate_ir = false;
     let mut filenames = [std::string::String::new(), std::string::String::new()];
+    let mut file_list = Vec::<String>::new();
+    let mut file_mode = false;
+    let mut decompress_files = false;
+    let mut file_opts = file_mode::FileModeOptions::default();
     let mut num_benchmarks = 1;
     let mut use_context_map = true;
     let mut use_brotli = true;
@@ -1223,11 +988,37 @@ fn main() {
     let mut force_literal_context_mode:Option<LiteralPredictionModeNibble> = None;
     let mut skip_crc = false;
     let mut parallel = PARALLEL_AVAILABLE;
+    let mut filters = divans::FilterSelection::Disabled;
     {
         for argument in env::args().skip(1) {
             if !doubledash {
                 if argument == "-d" {
                     do_compress = false;
+                    decompress_files = true;
+                    continue;
+                }
+                if argument == "-files" {
+                    file_mode = true;
+                    continue;
+                }
+                if argument == "-k" {
+                    file_mode = true;
+                    file_opts.keep = true;
+                    continue;
+                }
+                if argument == "-f" {
+                    file_mode = true;
+                    file_opts.force = true;
+                    continue;
+                }
+                if argument == "-r" {
+                    file_mode = true;
+                    file_opts.recursive = true;
+                    continue;
+                }
+                if argument.starts_with("-suffix=") {
+                    file_mode = true;
+                    file_opts.suffix = argument.split_at("-suffix=".len()).1.to_string();
                     continue;
                 }
                 if argument == "-serial" {
@@ -1303,6 +1094,29 @@ fn main() {
                     do_recode = true;
                     continue;
                 }
+                if argument.starts_with("-convert-ir=") {
+                    do_compress = false;
+                    convert_ir = Some(match argument.split_at("-convert-ir=".len()).1 {
+                        "text" => divans::ir::Format::Text,
+                        "binary" => divans::ir::Format::Binary,
+                        _ => panic!("IR format must be text or binary, not {}", argument),
+                    });
+                    continue;
+                }
+                if argument == "--validate-ir" {
+                    do_compress = false;
+                    validate_ir = true;
+                    continue;
+                }
+                if argument.starts_with("-dump-ir=") {
+                    do_compress = false;
+                    dump_ir = Some(match argument.split_at("-dump-ir=".len()).1 {
+                        "text" => divans::ir::Format::Text,
+                        "binary" => divans::ir::Format::Binary,
+                        _ => panic!("IR format must be text or binary, not {}", argument),
+                    });
+                    continue;
+                }
                 if argument.starts_with("-lgwin") {
                     let fs = argument.trim_matches(
                         '-').trim_matches(
@@ -1368,6 +1182,16 @@ fn main() {
                     window_size=Some(fs);
                     continue;
                 }
+                if argument.starts_with("-filter=") {
+                    filters = match filter_parse(argument.split_at("-filter=".len()).1) {
+                        Ok(filters) => filters,
+                        Err(e) => {
+                            println_stderr!("divans: {}: {}", argument, e);
+                            std::process::exit(1);
+                        },
+                    };
+                    continue;
+                }
                 if argument == "-brotlistride" {
                     force_stride_value = StrideSelection::UseBrotliRec;
                     stride_detection_quality = Some(1);
@@ -1431,10 +1255,73 @@ fn main() {
                     raw_compress = false;
                     continue;
                 }
+                if argument == "-gzip" {
+                    do_compress = true;
+                    raw_compress = false;
+                    deflate_input = true;
+                    continue;
+                }
+                if argument == "-blocksplit" {
+                    block_split = true;
+                    continue;
+                }
+                if argument == "-exhaustive" {
+                    exhaustive = true;
+                    continue;
+                }
+                if argument.starts_with("-billing-report=") {
+                    billing_report = Some(argument.split_at("-billing-report=".len()).1.to_string());
+                    continue;
+                }
+                if argument.starts_with("-billing-region=") {
+                    billing_region = argument.split_at("-billing-region=".len()).1.parse::<usize>().unwrap();
+                    continue;
+                }
+                if argument.starts_with("-coder=") {
+                    entropy_coder = match argument.split_at("-coder=".len()).1 {
+                        "ans" => divans::EntropyCoderSelection::Ans,
+                        "range" => divans::EntropyCoderSelection::Range,
+                        "binary" => divans::EntropyCoderSelection::Binary,
+                        _ => panic!("Entropy coder must be ans, range or binary, not {}", argument),
+                    };
+                    continue;
+                }
+                if argument.starts_with("-cdf=") {
+                    cdf_family = match argument.split_at("-cdf=".len()).1 {
+                        "frequentist" => divans::CDFFamily::Frequentist,
+                        "blend" => divans::CDFFamily::Blend,
+                        _ => panic!("CDF family must be frequentist or blend, not {}", argument),
+                    };
+                    continue;
+                }
+                if argument.starts_with("-threadtrace=") {
+                    do_compress = false;
+                    thread_trace = Some(argument.split_at("-threadtrace=".len()).1.to_string());
+                    continue;
+                }
+                if argument == "-lowlatency" {
+                    mux_policy = divans::MuxPolicy::LowLatency;
+                    continue;
+                }
+                if argument.starts_with("-streams=") {
+                    stream_count = match argument.split_at("-streams=".len()).1.parse::<usize>().map(divans::StreamCount::new) {
+                        Ok(Ok(count)) => count,
+                        _ => {
+                            println_stderr!("divans: {}: the stream count must be between {} and {}",
+                                            argument, divans::interface::NUM_STREAMS, divans::interface::MAX_NUM_STREAMS);
+                            std::process::exit(1);
+                        },
+                    };
+                    continue;
+                }
                 if argument.starts_with("-O") {
-                    if argument != "-O0" {
-                        divans_ir_optimizer = true
-                    }
+                    divans_ir_optimizer = if argument == "-O" {
+                        1
+                    } else {
+                        argument.trim_matches(
+                            '-').trim_matches(
+                            'O').parse::<u8>().unwrap()
+                    };
                     continue;
                 }
                 if argument == "-c" {
@@ -1568,6 +1455,25 @@ fn main() {
                 if argument == "-h" || argument == "-help" || argument == "--help" {
                     println_stderr!("Compression: divans {{-c [raw_input_file] | -i [ir_file]}} [output_file]");
                     println_stderr!("Decompression: divans [input_file] [output_file]");
+                    println_stderr!("IR conversion: divans -convert-ir={{text|binary}} [ir_file] [output_file]");
+                    println_stderr!("IR of a compressed file: divans -dump-ir={{text|binary}} [divans_file] [output_file]");
+                    println_stderr!("IR validation: divans --validate-ir [ir_file] [report_file]");
+                    println_stderr!("Recompressing a deflate parse: divans -gzip [gzip_zlib_or_deflate_file] [output_file]");
+                    println_stderr!("IR files given to -i and --recode may be text or binary; -i also takes gzip files");
+                    println_stderr!("Filters: -filter={{auto|none|x86|deltaN|transposeN}}[,filter] preprocesses raw input");
+                    println_stderr!("Block splitting: -blocksplit re-picks literal block types and context clusters where adaptive coding prices them lower; command and distance blocks are left as they are");
+                    println_stderr!("Exhaustive: -exhaustive runs brotli once, then tries strides, mixing, speeds, context maps and prior depths on the IR and keeps the smallest");
+                    println_stderr!("Billing: -billing-report=<file> writes the bits spent per billing designation as JSON, per -billing-region=65536 input bytes");
+                    println_stderr!("Entropy coder: -coder={{ans|range|binary}} is recorded in the header for the decoder");
+                    println_stderr!("CDF model family: -cdf={{frequentist|blend}} is recorded in the header for the decoder");
+                    println_stderr!("Low latency: -lowlatency serializes every encoded byte as soon as it is produced, in smaller chunks");
+                    println_stderr!("Streams: -streams=N records N muxed streams (2 to 16) in the header; the decoder allocates that many");
+                    println_stderr!("Thread trace: -threadtrace=<file> writes a Chrome trace of a parallel decode and prints wait totals (needs the threadlog feature)");
+                    println_stderr!("Files: divans {{-files|-k|-f|-r|-suffix=.dv}} file... compresses each file to file.dv; divans -d file.dv... decompresses it back");
+                    println_stderr!("  -k keeps the input, -f overwrites existing outputs, -r descends into directories, -suffix= replaces .dv");
+                    println_stderr!("  outputs keep the input's mode and times; exits 0 on success, 1 on any error, 2 if files were skipped with a warning");
+                    println_stderr!("Option grid benchmark: divans bench [grid options] corpus... prints CSV or JSON; see divans bench -help");
+                    println_stderr!("Rewrite the format compatibility streams after a deliberate bitstream change: divans golden-regenerate [testdata]");
                     return;
                 }
                 if argument == "-v" || argument == "-version" || argument == "--version" {
@@ -1575,6 +1481,7 @@ fn main() {
                     return;
                 }
             }
+            file_list.push(argument.clone());
             if filenames[0] == "" {
                 filenames[0] = argument.clone();
                 continue;
@@ -1583,7 +1490,13 @@ fn main() {
                 filenames[1] = argument.clone();
                 continue;
             }
-            panic!("Unknown Argument {:}", argument);
+        }
+        // like gunzip, -d decodes named files next to themselves rather than to stdout
+        if decompress_files && !file_list.is_empty() {
+            file_mode = true;
+        }
+        if !file_mode && file_list.len() > 2 {
+            panic!("Unknown Argument {:}", file_list[2]);
         }
         let brotli_setting = if use_brotli  {
             divans::BrotliCompressionSetting::UseBrotliCommandSelection
@@ -1606,8 +1519,43 @@ fn main() {
             speed_detection_quality: speed_detection_quality,
             prior_bitmask_detection: if prior_bitmask_detection {1} else {0},
             force_literal_context_mode: force_literal_context_mode,
-            divans_ir_optimizer: if divans_ir_optimizer {1} else {0},
+            divans_ir_optimizer: divans_ir_optimizer,
+            block_split: block_split,
+            entropy_coder: entropy_coder,
+            cdf_family: cdf_family,
+            mux_policy: mux_policy,
+            stream_count: stream_count,
+            filters: filters,
         };
+        if (exhaustive || billing_report.is_some())
+            && (!do_compress || !raw_compress || !use_brotli || filters != divans::FilterSelection::Disabled) {
+            println_stderr!("divans: -exhaustive and -billing-report= only compress raw input with brotli command selection, without filters");
+            std::process::exit(1);
+        }
+        let billing_report = billing_report.as_ref().map(|filename| (&filename[..], billing_region));
+        if file_mode {
+            if !raw_compress || convert_ir.is_some() || dump_ir.is_some() || validate_ir || do_recode
+                || thread_trace.is_some() || billing_report.is_some() || num_benchmarks != 1 {
+                println_stderr!("divans: -d with files, -files, -k, -f, -r and -suffix= only compress and decompress raw files");
+                std::process::exit(file_mode::EXIT_ERROR);
+            }
+            file_opts.decompress = !do_compress;
+            let exit_code = if do_compress {
+                // no sniffing: a file that happens to hold a divans stream is compressed again, not decoded
+                file_mode::process_files(&file_list, &file_opts, &mut |input, output| {
+                    if exhaustive {
+                        compress_buffered(input, output, opts, buffer_size, use_brotli, true, None, parallel)
+                    } else {
+                        compress_raw(input, output, opts, buffer_size, use_brotli, true, parallel)
+                    }
+                })
+            } else {
+                file_mode::process_files(&file_list, &file_opts, &mut |input, output| {
+                    decompress(input, output, buffer_size, &mut [], skip_crc, parallel)
+                })
+            };
+            std::process::exit(exit_code);
+        }
         if filenames[0] != "" {
             let mut input = match File::open(&Path::new(&filenames[0])) {
                 Err(why) => panic!("couldn't open {:}\n{:}", filenames[0], why),
@@ -1621,11 +1569,21 @@ fn main() {
                 for i in 0..num_benchmarks {
                     if do_compress && !raw_compress {
                         let mut buffered_input = BufReader::new(input);
-                        match compress_ir(&mut buffered_input, &mut output, opts) {
+                        let ret = if deflate_input {
+                            compress_deflate(&mut buffered_input, &mut output, opts)
+                        } else {
+                            compress_ir(&mut buffered_input, &mut output, opts)
+                        };
+                        match ret {
                             Ok(_) => {}
                             Err(e) => panic!("Error {:?}", e),
                         }
                         input = buffered_input.into_inner();
+                    } else if do_compress && (exhaustive || billing_report.is_some()) {
+                        match compress_buffered(&mut input, &mut output, opts, buffer_size, use_brotli, exhaustive, billing_report, parallel) {
+                            Ok(_) => {}
+                            Err(e) => panic!("Error {:?}", e),
+                        }
                     } else if do_compress {
                         match compress_raw(&mut input,
                                            &mut output,
@@ -1634,13 +1592,23 @@ fn main() {
                             Ok(_) => {}
                             Err(e) => panic!("Error {:?}", e),
                         }
+                    } else if validate_ir {
+                        let mut buffered_input = BufReader::new(input);
+                        exit_on_error(&filenames[0], validate(&mut buffered_input, &mut output));
+                        input = buffered_input.into_inner();
+                    } else if let Some(format) = dump_ir {
+                        exit_on_error(&filenames[0], divans::ir::decompress_to_ir(&mut input, &mut output, format, buffer_size, skip_crc));
+                    } else if let Some(format) = convert_ir {
+                        let mut buffered_input = BufReader::new(input);
+                        exit_on_error(&filenames[0], divans::ir::convert(&mut buffered_input, &mut output, format));
+                        input = buffered_input.into_inner();
                     } else if do_recode {
                         let mut buffered_input = BufReader::new(input);
                         recode(&mut buffered_input,
                                &mut output).unwrap();
                         input = buffered_input.into_inner();
                     } else {
-                        match decompress(&mut input, &mut output, buffer_size, &mut [], skip_crc, parallel) {
+                        match decompress_with_trace(&mut input, &mut output, buffer_size, &mut [], skip_crc, parallel, thread_trace.as_ref().map(|s| &s[..])) {
                             Ok(_) => {}
                             Err(e) => panic!("Error {:?}", e),
                         }
@@ -1655,7 +1623,17 @@ fn main() {
                 assert_eq!(num_benchmarks, 1);
                 if do_compress && !raw_compress {
                     let mut buffered_input = BufReader::new(input);
-                    match compress_ir (&mut buffered_input, &mut io::stdout(), opts) {
+                    let ret = if deflate_input {
+                        compress_deflate(&mut buffered_input, &mut io::stdout(), opts)
+                    } else {
+                        compress_ir(&mut buffered_input, &mut io::stdout(), opts)
+                    };
+                    match ret {
+                        Ok(_) => {}
+                        Err(e) => panic!("Error {:?}", e),
+                    }
+                } else if do_compress && (exhaustive || billing_report.is_some()) {
+                    match compress_buffered(&mut input, &mut io::stdout(), opts, buffer_size, use_brotli, exhaustive, billing_report, parallel) {
                         Ok(_) => {}
                         Err(e) => panic!("Error {:?}", e),
                     }
@@ -1668,12 +1646,19 @@ fn main() {
                         Ok(_) => {}
                         Err(e) => panic!("Error {:?}", e),
                     }
+                } else if validate_ir {
+                    exit_on_error(&filenames[0], validate(&mut BufReader::new(input), &mut io::stdout()));
+                } else if let Some(format) = dump_ir {
+                    exit_on_error(&filenames[0], divans::ir::decompress_to_ir(&mut input, &mut io::stdout(), format, buffer_size, skip_crc));
+                } else if let Some(format) = convert_ir {
+                    let mut buffered_input = BufReader::new(input);
+                    exit_on_error(&filenames[0], divans::ir::convert(&mut buffered_input, &mut io::stdout(), format));
                 } else if do_recode {
                     let mut buffered_input = BufReader::new(input);
                     recode(&mut buffered_input,
                            &mut io::stdout()).unwrap()
                 } else {
-                    match decompress(&mut input, &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel) {
+                    match decompress_with_trace(&mut input, &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel, thread_trace.as_ref().map(|s| &s[..])) {
                         Ok(_) => {}
                         Err(e) => panic!("Error {:?}", e),
                     }
@@ -1684,7 +1669,17 @@ fn main() {
             if do_compress && !raw_compress {
                 let stdin = std::io::stdin();
                 let mut stdin = stdin.lock();
-                match compress_ir(&mut stdin, &mut io::stdout(), opts) {
+                let ret = if deflate_input {
+                    compress_deflate(&mut stdin, &mut io::stdout(), opts)
+                } else {
+                    compress_ir(&mut stdin, &mut io::stdout(), opts)
+                };
+                match ret {
+                    Ok(_) => return,
+                    Err(e) => panic!("Error {:?}", e),
+                }
+            } else if do_compress && (exhaustive || billing_report.is_some()) {
+                match compress_buffered(&mut std::io::stdin(), &mut io::stdout(), opts, buffer_size, use_brotli, exhaustive, billing_report, parallel) {
                     Ok(_) => return,
                     Err(e) => panic!("Error {:?}", e),
                 }
@@ -1697,13 +1692,23 @@ fn main() {
                     Ok(_) => return,
                     Err(e) => panic!("Error {:?}", e),
                 }
+            } else if validate_ir {
+                let stdin = std::io::stdin();
+                let mut stdin = stdin.lock();
+                exit_on_error("stdin", validate(&mut stdin, &mut io::stdout()));
+            } else if let Some(format) = dump_ir {
+                exit_on_error("stdin", divans::ir::decompress_to_ir(&mut io::stdin(), &mut io::stdout(), format, buffer_size, skip_crc));
+            } else if let Some(format) = convert_ir {
+                let stdin = std::io::stdin();
+                let mut stdin = stdin.lock();
+                exit_on_error("stdin", divans::ir::convert(&mut stdin, &mut io::stdout(), format));
             } else if do_recode {
                 let stdin = std::io::stdin();
                 let mut stdin = stdin.lock();
                 recode(&mut stdin,
                        &mut io::stdout()).unwrap()
             } else {
-                match decompress(&mut io::stdin(), &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel) {
+                match decompress_with_trace(&mut io::stdin(), &mut io::stdout(), buffer_size, &mut [], skip_crc, parallel, thread_trace.as_ref().map(|s| &s[..])) {
                     Ok(_) => return,
                     Err(e) => panic!("Error {:?}", e),
                 }
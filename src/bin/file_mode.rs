@@ -0,0 +1,199 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// gzip style handling of named files: foo compresses to foo.dv next to it and foo.dv decompresses back to foo.
+use std::fs::{self, File, FileTimes, Metadata, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::vec::Vec;
+
+pub const DEFAULT_SUFFIX: &str = ".dv";
+
+// the exit codes gzip uses: warnings only matter to callers that check for them
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_WARNING: i32 = 2;
+
+#[derive(Clone, Debug)]
+pub struct FileModeOptions {
+    pub decompress: bool,
+    pub keep: bool,
+    pub force: bool,
+    pub recursive: bool,
+    pub suffix: String,
+}
+
+impl Default for FileModeOptions {
+    fn default() -> Self {
+        FileModeOptions {
+            decompress: false,
+            keep: false,
+            force: false,
+            recursive: false,
+            suffix: DEFAULT_SUFFIX.to_string(),
+        }
+    }
+}
+
+struct Status {
+    exit_code: i32,
+}
+
+impl Status {
+    fn warn(&mut self, message: &str) {
+        let _ = writeln!(&mut io::stderr(), "divans: {}", message);
+        if self.exit_code == EXIT_OK {
+            self.exit_code = EXIT_WARNING;
+        }
+    }
+    fn error(&mut self, message: &str) {
+        let _ = writeln!(&mut io::stderr(), "divans: {}", message);
+        self.exit_code = EXIT_ERROR;
+    }
+}
+
+// Err holds why the file is skipped
+fn output_path(path: &Path, opts: &FileModeOptions) -> Result<PathBuf, String> {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        None => return Err("file name is not valid UTF-8 -- ignored".to_string()),
+    };
+    let has_suffix = name.len() > opts.suffix.len() && name.ends_with(&opts.suffix[..]);
+    if opts.decompress {
+        if !has_suffix {
+            return Err(format!("unknown suffix, expected {} -- ignored", opts.suffix));
+        }
+        Ok(path.with_file_name(&name[..name.len() - opts.suffix.len()]))
+    } else {
+        if has_suffix {
+            return Err(format!("already has {} suffix -- unchanged", opts.suffix));
+        }
+        Ok(path.with_file_name(format!("{}{}", name, opts.suffix)))
+    }
+}
+
+fn copy_metadata(metadata: &Metadata, output: &File) -> io::Result<()> {
+    output.set_permissions(metadata.permissions())?;
+    output.set_times(FileTimes::new()
+                     .set_accessed(metadata.accessed()?)
+                     .set_modified(metadata.modified()?))
+}
+
+fn process_file<Codec: FnMut(&mut File, &mut File) -> io::Result<()>>(path: &Path,
+                                                                      metadata: &Metadata,
+                                                                      named: bool,
+                                                                      opts: &FileModeOptions,
+                                                                      codec: &mut Codec,
+                                                                      status: &mut Status) {
+    let output_path = match output_path(path, opts) {
+        Ok(output_path) => output_path,
+        Err(why) => {
+            // files met while recursing are expected to be a mix of both kinds
+            if named {
+                status.warn(&format!("{}: {}", path.display(), why));
+            }
+            return;
+        },
+    };
+    let mut input = match File::open(path) {
+        Ok(input) => input,
+        Err(e) => return status.error(&format!("{}: {}", path.display(), e)),
+    };
+    if opts.force {
+        // replace the old output instead of writing through it, in case it is a symlink
+        match fs::remove_file(&output_path) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                return status.error(&format!("{}: {}", output_path.display(), e));
+            },
+            _ => {},
+        }
+    }
+    let mut output = match OpenOptions::new().write(true).create_new(true).open(&output_path) {
+        Ok(output) => output,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            return status.error(&format!("{} already exists; use -f to overwrite it", output_path.display()));
+        },
+        Err(e) => return status.error(&format!("{}: {}", output_path.display(), e)),
+    };
+    if let Err(e) = codec(&mut input, &mut output).and_then(|_| output.flush()) {
+        drop(output);
+        let _ = fs::remove_file(&output_path);
+        return status.error(&format!("{}: {}", path.display(), e));
+    }
+    if let Err(e) = copy_metadata(metadata, &output) {
+        status.warn(&format!("{}: could not copy mode and times: {}", output_path.display(), e));
+    }
+    drop(output);
+    if !opts.keep {
+        if let Err(e) = fs::remove_file(path) {
+            status.error(&format!("{}: {}", path.display(), e));
+        }
+    }
+}
+
+fn process_path<Codec: FnMut(&mut File, &mut File) -> io::Result<()>>(path: &Path,
+                                                                      named: bool,
+                                                                      opts: &FileModeOptions,
+                                                                      codec: &mut Codec,
+                                                                      status: &mut Status) {
+    // symlinks are not followed, so a recursive run never leaves the tree or visits a file twice
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return status.error(&format!("{}: {}", path.display(), e)),
+    };
+    if metadata.is_dir() {
+        if !opts.recursive {
+            return status.warn(&format!("{}: is a directory -- ignored", path.display()));
+        }
+        let mut entries = Vec::<PathBuf>::new();
+        match fs::read_dir(path) {
+            Ok(dir) => for entry in dir {
+                match entry {
+                    Ok(entry) => entries.push(entry.path()),
+                    Err(e) => status.error(&format!("{}: {}", path.display(), e)),
+                }
+            },
+            Err(e) => return status.error(&format!("{}: {}", path.display(), e)),
+        }
+        entries.sort();
+        for entry in entries.iter() {
+            process_path(entry, false, opts, codec, status);
+        }
+    } else if metadata.is_file() {
+        process_file(path, &metadata, named, opts, codec, status);
+    } else {
+        status.warn(&format!("{}: not a directory or a regular file -- ignored", path.display()));
+    }
+}
+
+/// Compresses or decompresses each named file or directory tree with `codec`, which gets
+/// the opened input and a new output file, and returns the exit code.
+pub fn process_files<Codec: FnMut(&mut File, &mut File) -> io::Result<()>>(paths: &[String],
+                                                                           opts: &FileModeOptions,
+                                                                           codec: &mut Codec) -> i32 {
+    let mut status = Status{exit_code: EXIT_OK};
+    if opts.suffix.is_empty() || opts.suffix.contains('/') {
+        status.error(&format!("invalid suffix '{}'", opts.suffix));
+        return status.exit_code;
+    }
+    if paths.is_empty() {
+        status.error("no files given");
+        return status.exit_code;
+    }
+    for path in paths.iter() {
+        process_path(Path::new(path), true, opts, codec, &mut status);
+    }
+    status.exit_code
+}
@@ -0,0 +1,196 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Frozen streams in testdata/golden: every case is one input compressed with one set of options.
+// The integration tests decode them and re-encode the inputs, so any change to the bitstream shows up
+// as a test failure. `divans golden-regenerate` rewrites them when the format is meant to change.
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::string::String;
+use std::vec::Vec;
+use divans::{CDFFamily, DivansCompressorOptions, EntropyCoderSelection, Filter,
+             FilterChain, FilterSelection, MuxPolicy, Speed, StreamCount, StrideSelection};
+
+pub const GOLDEN_DIR: &str = "golden";
+pub const GOLDEN_SUFFIX: &str = ".dv";
+
+pub struct GoldenCase {
+    pub name: &'static str,
+    pub input: &'static str,
+    // only the first this many bytes of the input are compressed, to keep the checked in streams small
+    pub limit: usize,
+    pub use_brotli: bool,
+    pub options: fn(&mut DivansCompressorOptions),
+}
+
+// the defaults depend on cargo features, so each case pins everything that is written to the header
+fn base_options() -> DivansCompressorOptions {
+    let mut opts = DivansCompressorOptions::default();
+    opts.entropy_coder = EntropyCoderSelection::Ans;
+    opts.cdf_family = CDFFamily::Frequentist;
+    opts.mux_policy = MuxPolicy::Throughput;
+    opts.filters = FilterSelection::Disabled;
+    opts
+}
+
+fn defaults(_opts: &mut DivansCompressorOptions) {}
+
+fn q9_no_context_map(opts: &mut DivansCompressorOptions) {
+    opts.quality = Some(9);
+    opts.use_context_map = false;
+    opts.force_stride_value = StrideSelection::PriorDisabled;
+}
+
+fn range_coder(opts: &mut DivansCompressorOptions) {
+    opts.entropy_coder = EntropyCoderSelection::Range;
+}
+
+fn binary_coder(opts: &mut DivansCompressorOptions) {
+    opts.entropy_coder = EntropyCoderSelection::Binary;
+}
+
+fn blend_cdf(opts: &mut DivansCompressorOptions) {
+    opts.cdf_family = CDFFamily::Blend;
+}
+
+fn low_latency(opts: &mut DivansCompressorOptions) {
+    opts.mux_policy = MuxPolicy::LowLatency;
+}
+
+fn sixteen_streams(opts: &mut DivansCompressorOptions) {
+    opts.stream_count = StreamCount::new(16).unwrap();
+}
+
+fn mixing2_speeds(opts: &mut DivansCompressorOptions) {
+    opts.dynamic_context_mixing = Some(2);
+    opts.prior_depth = Some(2);
+    opts.literal_adaptation = Some([Speed::MUD, Speed::SLOW, Speed::FAST, Speed::ROCKET]);
+    opts.force_stride_value = StrideSelection::Stride4;
+}
+
+fn small_window(opts: &mut DivansCompressorOptions) {
+    opts.window_size = Some(16);
+    opts.lgblock = Some(16);
+    opts.quality = Some(10);
+}
+
+fn ir_optimizer(opts: &mut DivansCompressorOptions) {
+    opts.divans_ir_optimizer = 1;
+    opts.q9_5 = true;
+}
+
+fn detection(opts: &mut DivansCompressorOptions) {
+    opts.stride_detection_quality = Some(2);
+    opts.speed_detection_quality = Some(1);
+    opts.prior_bitmask_detection = 0;
+}
+
+fn delta_filter(opts: &mut DivansCompressorOptions) {
+    opts.filters = FilterSelection::Fixed(FilterChain::new(Filter::Delta(1), Filter::Identity));
+}
+
+fn x86_filter(opts: &mut DivansCompressorOptions) {
+    opts.filters = FilterSelection::Fixed(FilterChain::new(Filter::X86, Filter::Identity));
+}
+
+fn transpose_filter(opts: &mut DivansCompressorOptions) {
+    opts.filters = FilterSelection::Fixed(FilterChain::new(Filter::Transpose(4), Filter::Identity));
+}
+
+fn auto_filter(opts: &mut DivansCompressorOptions) {
+    opts.filters = FilterSelection::Auto;
+}
+
+pub const GOLDEN_CASES: [GoldenCase; 18] = [
+    GoldenCase{name: "alice29-default", input: "alice29", limit: usize::MAX, use_brotli: true, options: defaults},
+    GoldenCase{name: "alice29-q9-no-context-map", input: "alice29", limit: 32768, use_brotli: true, options: q9_no_context_map},
+    GoldenCase{name: "alice29-range", input: "alice29", limit: 32768, use_brotli: true, options: range_coder},
+    GoldenCase{name: "alice29-binary", input: "alice29", limit: 32768, use_brotli: true, options: binary_coder},
+    GoldenCase{name: "alice29-blend", input: "alice29", limit: 32768, use_brotli: true, options: blend_cdf},
+    GoldenCase{name: "alice29-low-latency", input: "alice29", limit: 32768, use_brotli: true, options: low_latency},
+    GoldenCase{name: "alice29-16-streams", input: "alice29", limit: 32768, use_brotli: true, options: sixteen_streams},
+    GoldenCase{name: "asyoulik-mixing2-speeds", input: "asyoulik", limit: 32768, use_brotli: true, options: mixing2_speeds},
+    GoldenCase{name: "asyoulik-small-window", input: "asyoulik", limit: 32768, use_brotli: true, options: small_window},
+    GoldenCase{name: "asyoulik-ir-optimizer", input: "asyoulik", limit: 32768, use_brotli: true, options: ir_optimizer},
+    GoldenCase{name: "asyoulik-detection", input: "asyoulik", limit: 32768, use_brotli: true, options: detection},
+    GoldenCase{name: "asyoulik-nobrotli", input: "asyoulik", limit: 16384, use_brotli: false, options: defaults},
+    GoldenCase{name: "asyoulik-delta", input: "asyoulik", limit: 32768, use_brotli: true, options: delta_filter},
+    GoldenCase{name: "asyoulik-transpose", input: "asyoulik", limit: 32768, use_brotli: true, options: transpose_filter},
+    GoldenCase{name: "u32_samples-auto-filter", input: "u32_samples", limit: usize::MAX, use_brotli: true, options: auto_filter},
+    GoldenCase{name: "random_then_unicode-x86", input: "random_then_unicode", limit: 32768, use_brotli: true, options: x86_filter},
+    GoldenCase{name: "random_then_unicode", input: "random_then_unicode", limit: usize::MAX, use_brotli: true, options: defaults},
+    GoldenCase{name: "ends_with_truncated_dictionary", input: "ends_with_truncated_dictionary", limit: usize::MAX, use_brotli: true, options: defaults},
+];
+
+impl GoldenCase {
+    pub fn options(&self) -> DivansCompressorOptions {
+        let mut opts = base_options();
+        (self.options)(&mut opts);
+        opts
+    }
+    pub fn stream_path(&self, testdata: &Path) -> PathBuf {
+        testdata.join(GOLDEN_DIR).join(format!("{}{}", self.name, GOLDEN_SUFFIX))
+    }
+    pub fn read_input(&self, testdata: &Path) -> io::Result<Vec<u8>> {
+        let mut data = fs::read(testdata.join(self.input))?;
+        data.truncate(self.limit);
+        Ok(data)
+    }
+    // single threaded, so the stream does not depend on how the work was scheduled
+    pub fn compress(&self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::<u8>::new();
+        super::compress_raw(&mut &input[..], &mut output, self.options(), 65_536, self.use_brotli, true, false)?;
+        Ok(output)
+    }
+}
+
+fn regenerate_case(case: &GoldenCase, testdata: &Path) -> io::Result<usize> {
+    let input = case.read_input(testdata)?;
+    let stream = case.compress(&input)?;
+    let mut decompressed = Vec::<u8>::with_capacity(input.len());
+    super::decompress(&mut &stream[..], &mut decompressed, 65_536, &mut [], false, false)?;
+    if decompressed != input {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "stream does not decode back to the input"));
+    }
+    fs::write(case.stream_path(testdata), &stream)?;
+    Ok(stream.len())
+}
+
+/// Rewrites every golden stream under `args[0]` (testdata by default). Only run this when the
+/// bitstream is deliberately changed: old archives stop decoding once the streams are replaced.
+pub fn regenerate(args: &[String]) -> i32 {
+    if args.len() > 1 || args.iter().any(|arg| arg.starts_with('-')) {
+        let _ = writeln!(&mut io::stderr(), "Usage: divans golden-regenerate [testdata directory]");
+        return 1;
+    }
+    let testdata = Path::new(args.first().map(|arg| &arg[..]).unwrap_or("testdata"));
+    if let Err(e) = fs::create_dir_all(testdata.join(GOLDEN_DIR)) {
+        let _ = writeln!(&mut io::stderr(), "{}: {}", testdata.join(GOLDEN_DIR).display(), e);
+        return 1;
+    }
+    let mut exit_code = 0;
+    for case in GOLDEN_CASES.iter() {
+        match regenerate_case(case, testdata) {
+            Ok(size) => {
+                let _ = writeln!(&mut io::stderr(), "{}: {} bytes", case.stream_path(testdata).display(), size);
+            },
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "{}: {}", case.name, e);
+                exit_code = 1;
+            },
+        }
+    }
+    exit_code
+}
@@ -18,7 +18,7 @@ use std::io;
 
 use std::io::BufReader;
 use core::cmp;
-use divans::{Speed, StrideSelection, DivansCompressorOptions, BrotliCompressionSetting};
+use divans::{Speed, StrideSelection, DivansCompressorOptions, BrotliCompressionSetting, Filter, FilterChain, FilterSelection};
 #[cfg(feature="no-stdlib")]
 pub static MULTI: bool = false;
 #[cfg(not(feature="no-stdlib"))]
@@ -132,6 +132,12 @@ fn e2e_no_ir(buffer_size: usize, use_serialized_priors: bool, use_brotli: bool,
                             prior_bitmask_detection: 1,
                             stride_detection_quality: None,
                             divans_ir_optimizer:1,
+                            block_split:false,
+                            entropy_coder:divans::EntropyCoderSelection::default(),
+                            cdf_family:divans::CDFFamily::default(),
+                            mux_policy:divans::MuxPolicy::default(),
+                            stream_count:divans::StreamCount::default(),
+                            filters: FilterSelection::Disabled,
                         },
                         buffer_size,
                         use_brotli,
@@ -164,6 +170,19 @@ fn test_e2e_ones_tinybuf() {
     e2e_no_ir(1, false, false, &data[..], 0.99);
 }
 #[test]
+fn test_e2e_internal_matcher_far_repeats() {
+    // a noisy 40000 byte block repeated with small edits: every copy reaches back most of
+    // the 64k window and the input wraps the matcher's ring buffer several times
+    let mut block = e2e_noise(40000, 0x1234_5678);
+    let mut data = Vec::new();
+    for round in 0..12usize {
+        block[(round * 7919) % 40000] ^= 0x5a;
+        data.extend_from_slice(&block[..]);
+    }
+    e2e_no_ir(4096, false, false, &data[..], 0.12);
+    e2e_no_ir(15, false, false, &data[..], 0.12);
+}
+#[test]
 fn test_e2e_empty_tinybuf() {
     let data = [];
     e2e_no_ir(1, false, false, &data[..], 0.0);
@@ -233,7 +252,7 @@ fn e2e_alice(buffer_size: usize, use_serialized_priors: bool) {
    let raw_text_slice = include_bytes!("../../testdata/alice29");
    let raw_text_buffer = UnlimitedBuffer::new(&raw_text_slice[..]);
    e2e_no_ir(buffer_size, use_serialized_priors, true, &raw_text_buffer.data[..], 0.34);
-   e2e_no_ir(buffer_size, use_serialized_priors, false, &raw_text_buffer.data[..], 0.46);
+   e2e_no_ir(buffer_size, use_serialized_priors, false, &raw_text_buffer.data[..], 0.36);
    let ir_buffer = if use_serialized_priors {
        UnlimitedBuffer::new(include_bytes!("../../testdata/alice29-priors.ir"))
    } else {
@@ -273,6 +292,83 @@ fn test_e2e_tinybuf() {
 }
 
 
+#[test]
+fn test_e2e_binary_ir() {
+   let text_ir = include_bytes!("../../testdata/alice29.ir");
+   let binary_ir = divans::ir::convert(&text_ir[..], Vec::<u8>::new(), divans::ir::Format::Binary).unwrap();
+   let mut text_dv = UnlimitedBuffer::new(&[]);
+   let mut binary_dv = UnlimitedBuffer::new(&[]);
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&text_ir[..])), &mut text_dv, DivansCompressorOptions::default()).unwrap();
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&binary_ir[..])), &mut binary_dv, DivansCompressorOptions::default()).unwrap();
+   assert_eq!(text_dv.data, binary_dv.data);
+   let mut rt_buffer = UnlimitedBuffer::new(&[]);
+   super::decompress(&mut binary_dv, &mut rt_buffer, 65536, &mut[], false, MULTI).unwrap();
+   assert_eq!(&rt_buffer.data[..], &include_bytes!("../../testdata/alice29")[..]);
+}
+
+#[test]
+fn test_e2e_gzip() {
+   let gz = include_bytes!("../../testdata/alice29.gz");
+   let mut from_gzip = UnlimitedBuffer::new(&[]);
+   super::compress_deflate(&mut BufReader::new(UnlimitedBuffer::new(&gz[..])), &mut from_gzip, DivansCompressorOptions::default()).unwrap();
+   let mut autodetected = UnlimitedBuffer::new(&[]);
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&gz[..])), &mut autodetected, DivansCompressorOptions::default()).unwrap();
+   assert_eq!(from_gzip.data, autodetected.data);
+   let mut rt_buffer = UnlimitedBuffer::new(&[]);
+   super::decompress(&mut from_gzip, &mut rt_buffer, 65536, &mut[], false, MULTI).unwrap();
+   assert_eq!(&rt_buffer.data[..], &include_bytes!("../../testdata/alice29")[..]);
+}
+
+#[test]
+fn test_e2e_decompress_to_ir() {
+   let raw = include_bytes!("../../testdata/alice29");
+   let dv = e2e_filtered(65536, true, &raw[..], FilterSelection::Disabled);
+   let text_ir = divans::ir::decompress_to_ir(&mut &dv[..], Vec::<u8>::new(), divans::ir::Format::Text, 1021, false).unwrap();
+   assert!(text_ir.starts_with(b"window 22\n"));
+   assert!(text_ir.windows(b"\nprediction ".len()).any(|w| w == b"\nprediction "));
+   let binary_ir = divans::ir::decompress_to_ir(&mut &dv[..], Vec::<u8>::new(), divans::ir::Format::Binary, 65536, false).unwrap();
+   let converted = divans::ir::convert(&binary_ir[..], Vec::<u8>::new(), divans::ir::Format::Text).unwrap();
+   assert_eq!(converted, text_ir);
+   assert_eq!(&divans_decompress_internal(&text_ir[..]).unwrap()[..], &raw[..]);
+   let mut dv_again = UnlimitedBuffer::new(&[]);
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&binary_ir[..])), &mut dv_again, DivansCompressorOptions::default()).unwrap();
+   let mut rt_buffer = UnlimitedBuffer::new(&[]);
+   super::decompress(&mut dv_again, &mut rt_buffer, 65536, &mut[], false, MULTI).unwrap();
+   assert_eq!(&rt_buffer.data[..], &raw[..]);
+
+   let filtered = e2e_filtered(65536, true, &synthetic_pcm(20000)[..],
+                               FilterSelection::Fixed(FilterChain::new(Filter::Delta(2), Filter::Identity)));
+   assert!(divans::ir::decompress_to_ir(&mut &filtered[..], Vec::<u8>::new(), divans::ir::Format::Text, 65536, false).is_err());
+   assert!(divans::ir::decompress_to_ir(&mut &dv[..dv.len() - 9], Vec::<u8>::new(), divans::ir::Format::Text, 65536, false).is_err());
+}
+
+#[test]
+fn test_e2e_decompress_to_ir_reencodes() {
+   let ir = include_bytes!("../../testdata/alice29-q11.ir");
+   let mut dv = UnlimitedBuffer::new(&[]);
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&ir[..])), &mut dv, DivansCompressorOptions::default()).unwrap();
+   let text_ir = divans::ir::decompress_to_ir(&mut &dv.data[..], Vec::<u8>::new(), divans::ir::Format::Text, 65536, false).unwrap();
+   // the q11 parse switches command and distance block types as well as literal ones
+   for block_switch in [&b"\nctype 1\n"[..], &b"\ndtype 2\n"[..]].iter() {
+       assert!(text_ir.windows(block_switch.len()).any(|w| w == *block_switch));
+   }
+   let mut dv_again = UnlimitedBuffer::new(&[]);
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&text_ir[..])), &mut dv_again, DivansCompressorOptions::default()).unwrap();
+   assert_eq!(dv_again.data.len(), dv.data.len());
+   assert!(dv_again.data == dv.data);
+}
+
+#[test]
+fn test_e2e_high_entropy_ir() {
+   let ir = b"window 22 len 20\ninsert 4 4d617279\nrndins 6 11992ffcfeef\ncopy 10 from 10\n";
+   let mut dv_buffer = UnlimitedBuffer::new(&[]);
+   super::compress_ir(&mut BufReader::new(UnlimitedBuffer::new(&ir[..])), &mut dv_buffer, DivansCompressorOptions::default()).unwrap();
+   let text_ir = divans::ir::decompress_to_ir(&mut &dv_buffer.data[..], Vec::<u8>::new(), divans::ir::Format::Text, 65536, false).unwrap();
+   // the decoder keeps the flag the literal was coded with
+   assert!(text_ir.windows(b"\nrndins 6 11992ffcfeef\n".len()).any(|w| w == b"\nrndins 6 11992ffcfeef\n"));
+   assert!(text_ir.windows(b"\ninsert 4 4d617279\n".len()).any(|w| w == b"\ninsert 4 4d617279\n"));
+}
+
 #[test]
 fn test_e2e_32xx() {
    let raw_text_buffer = UnlimitedBuffer::new(b"XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX");
@@ -336,3 +432,833 @@ fn test_e2e_64xp() {
 }
 
 
+
+// default options at the given quality, on brotli's or the internal matcher's commands
+fn e2e_opts(quality: u16, use_brotli: bool) -> DivansCompressorOptions {
+    let mut opts = DivansCompressorOptions::default();
+    opts.quality = Some(quality);
+    opts.use_brotli = if use_brotli {
+        BrotliCompressionSetting::UseBrotliCommandSelection
+    } else {
+        BrotliCompressionSetting::UseInternalCommandSelection
+    };
+    opts
+}
+
+fn e2e_decompress(compressed: &[u8], buffer_size: usize, multithread: bool) -> Vec<u8> {
+    let mut dv_buffer = UnlimitedBuffer::new(compressed);
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut dv_buffer, &mut rt_buffer, buffer_size, &mut[], false, multithread).unwrap();
+    rt_buffer.data
+}
+
+// compresses data with the given options, checks that it decodes back and returns the compressed bytes
+fn e2e_roundtrip(data: &[u8], buffer_size: usize, opts: DivansCompressorOptions) -> Vec<u8> {
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    let use_brotli = !matches!(opts.use_brotli, BrotliCompressionSetting::UseInternalCommandSelection);
+    super::compress_raw(&mut UnlimitedBuffer::new(data),
+                        &mut dv_buffer,
+                        opts,
+                        buffer_size,
+                        use_brotli,
+                        true,
+                        MULTI).unwrap();
+    assert_eq!(e2e_decompress(&dv_buffer.data[..], buffer_size, MULTI), data);
+    dv_buffer.data
+}
+
+// bytes of a 31 bit linear congruential generator, which brotli finds no matches in
+fn e2e_noise(len: usize, mut seed: u32) -> Vec<u8> {
+    let mut noise = Vec::new();
+    for _ in 0..len {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fffffff;
+        noise.push((seed >> 16) as u8);
+    }
+    noise
+}
+
+fn e2e_filtered(buffer_size: usize, use_brotli: bool, data: &[u8], filters: FilterSelection) -> Vec<u8> {
+    let mut opts = e2e_opts(5, use_brotli);
+    opts.filters = filters;
+    let compressed = e2e_roundtrip(data, buffer_size, opts);
+    assert_eq!(e2e_decompress(&compressed[..], buffer_size, false), data);
+    compressed
+}
+
+fn synthetic_pcm(len: usize) -> Vec<u8> {
+    let mut pcm = Vec::new();
+    for i in 0..(len / 2) as i32 {
+        let sample = ((i % 300) - 150) * 97 + (i % 7) * 3;
+        pcm.push(sample as u8);
+        pcm.push((sample >> 8) as u8);
+    }
+    pcm
+}
+
+#[test]
+fn test_e2e_filters_fixed() {
+    let pcm = synthetic_pcm(150000);
+    for chain in [FilterChain::new(Filter::Delta(2), Filter::Identity),
+                  FilterChain::new(Filter::Transpose(2), Filter::Delta(1)),
+                  FilterChain::new(Filter::X86, Filter::Identity)].iter() {
+        let compressed = e2e_filtered(65536, true, &pcm[..], FilterSelection::Fixed(*chain));
+        assert!(compressed[6] != 0);
+        e2e_filtered(4093, false, &pcm[..], FilterSelection::Fixed(*chain));
+    }
+}
+
+#[test]
+fn test_e2e_filters_auto() {
+    let pcm = synthetic_pcm(150000);
+    let compressed = e2e_filtered(65536, true, &pcm[..], FilterSelection::Auto);
+    assert!(compressed[6] != 0);
+    let unfiltered = e2e_filtered(65536, true, &pcm[..], FilterSelection::Disabled);
+    assert_eq!(unfiltered[6], 0);
+    let alice = e2e_filtered(65536, true, &include_bytes!("../../testdata/alice29")[..], FilterSelection::Auto);
+    assert_eq!(alice[6], 0);
+    e2e_filtered(15, true, &[], FilterSelection::Auto);
+    e2e_filtered(15, false, &pcm[..100], FilterSelection::Fixed(FilterChain::new(Filter::Delta(2), Filter::Identity)));
+}
+
+#[test]
+fn test_filter_parse() {
+    use super::filter_parse;
+    assert_eq!(filter_parse("auto").unwrap(), FilterSelection::Auto);
+    assert_eq!(filter_parse("none").unwrap(), FilterSelection::Disabled);
+    assert_eq!(filter_parse("x86").unwrap(), FilterSelection::Fixed(FilterChain::new(Filter::X86, Filter::Identity)));
+    assert_eq!(filter_parse("delta4").unwrap(), FilterSelection::Fixed(FilterChain::new(Filter::Delta(4), Filter::Identity)));
+    assert_eq!(filter_parse("delta").unwrap(), FilterSelection::Fixed(FilterChain::new(Filter::Delta(1), Filter::Identity)));
+    assert_eq!(filter_parse("transpose2").unwrap(), FilterSelection::Fixed(FilterChain::new(Filter::Transpose(2), Filter::Identity)));
+    assert_eq!(filter_parse("transpose2,delta1").unwrap(),
+               FilterSelection::Fixed(FilterChain::new(Filter::Transpose(2), Filter::Delta(1))));
+    assert_eq!(filter_parse("x86,delta16").unwrap(), FilterSelection::Fixed(FilterChain::new(Filter::X86, Filter::Delta(16))));
+    for bad in ["x", "x860", "delta0", "delta17", "transposeX", "delta1,delta1,delta1", "auto,x86", ""].iter() {
+        assert!(filter_parse(bad).is_err(), "{} should not parse", bad);
+    }
+}
+
+fn e2e_ir_optimizer(buffer_size: usize, data: &[u8], quality: u16, use_brotli: bool, divans_ir_optimizer: u8) -> usize {
+    let mut opts = e2e_opts(quality, use_brotli);
+    opts.divans_ir_optimizer = divans_ir_optimizer;
+    e2e_roundtrip(data, buffer_size, opts).len()
+}
+
+#[test]
+fn test_e2e_optimal_parse() {
+    let alice = include_bytes!("../../testdata/alice29");
+    let greedy = e2e_ir_optimizer(65536, &alice[..], 9, true, 0);
+    let parsed = e2e_ir_optimizer(65536, &alice[..], 9, true, 2);
+    assert!(parsed < greedy);
+    e2e_ir_optimizer(15, &alice[..4096], 5, true, 2);
+    e2e_ir_optimizer(15, &[], 5, true, 2);
+    e2e_ir_optimizer(1, &[0u8; 70000], 5, true, 2);
+}
+
+#[test]
+fn test_e2e_optimal_parse_falls_back_to_merge() {
+    let asyoulik = include_bytes!("../../testdata/asyoulik");
+    let merged = e2e_ir_optimizer(65536, &asyoulik[..], 11, true, 1);
+    assert!(e2e_ir_optimizer(65536, &asyoulik[..], 11, true, 2) <= merged);
+}
+
+#[test]
+fn test_e2e_optimal_parse_internal_matcher() {
+    let alice = include_bytes!("../../testdata/alice29");
+    let unoptimized = e2e_ir_optimizer(65536, &alice[..], 11, false, 0);
+    let merged = e2e_ir_optimizer(65536, &alice[..], 11, false, 1);
+    let parsed = e2e_ir_optimizer(65536, &alice[..], 11, false, 2);
+    assert!(merged <= unoptimized);
+    assert!(parsed < merged);
+    e2e_ir_optimizer(15, &alice[..4096], 5, false, 2);
+    e2e_ir_optimizer(15, &[], 5, false, 2);
+}
+
+fn e2e_block_split(data: &[u8], quality: u16, use_brotli: bool, block_split: bool) -> usize {
+    let mut opts = e2e_opts(quality, use_brotli);
+    opts.block_split = block_split;
+    e2e_roundtrip(data, 65536, opts).len()
+}
+
+#[test]
+fn test_e2e_block_split() {
+    let alice = include_bytes!("../../testdata/alice29");
+    // text interleaved with skewed noise that brotli's matcher leaves as literals
+    let noise: Vec<u8> = e2e_noise(65536, 1).iter().zip(e2e_noise(65536, 2).iter()).map(|(a, b)| a & b & 0x9f).collect();
+    let mut mixed = Vec::new();
+    for (text, binary) in alice.chunks(8192).zip(noise.chunks(8192)) {
+        mixed.extend(text);
+        mixed.extend(binary);
+    }
+    let unsplit = e2e_block_split(&mixed[..], 9, true, false);
+    let split = e2e_block_split(&mixed[..], 9, true, true);
+    assert!(split < unsplit);
+    // corpus text around corpus binary, split over the internal matcher's commands
+    let mut corpus = Vec::new();
+    corpus.extend(&alice[..40000]);
+    corpus.extend(&include_bytes!("../../testdata/random_then_unicode")[..]);
+    corpus.extend(&include_bytes!("../../testdata/asyoulik")[..40000]);
+    let unsplit = e2e_block_split(&corpus[..], 9, false, false);
+    let split = e2e_block_split(&corpus[..], 9, false, true);
+    assert!(split < unsplit);
+    assert_eq!(e2e_block_split(&alice[..], 9, false, true), e2e_block_split(&alice[..], 9, false, false));
+    e2e_block_split(&alice[..], 11, true, true);
+    e2e_block_split(&[], 9, false, true);
+}
+
+fn e2e_entropy_coder(buffer_size: usize, data: &[u8], use_brotli: bool, entropy_coder: divans::EntropyCoderSelection) {
+    let mut opts = e2e_opts(9, use_brotli);
+    opts.entropy_coder = entropy_coder;
+    assert_eq!(e2e_roundtrip(data, buffer_size, opts)[8], entropy_coder as u8);
+}
+
+#[test]
+fn test_e2e_entropy_coders() {
+    let alice = include_bytes!("../../testdata/alice29");
+    let noise = e2e_noise(65536, 1);
+    for entropy_coder in [divans::EntropyCoderSelection::Ans,
+                          divans::EntropyCoderSelection::Range,
+                          divans::EntropyCoderSelection::Binary].iter() {
+        for use_brotli in [false, true].iter() {
+            e2e_entropy_coder(65536, &alice[..], *use_brotli, *entropy_coder);
+            e2e_entropy_coder(1, &alice[..8192], *use_brotli, *entropy_coder);
+            e2e_entropy_coder(65536, &noise[..], *use_brotli, *entropy_coder);
+            e2e_entropy_coder(65536, &[0u8; 70000], *use_brotli, *entropy_coder);
+            e2e_entropy_coder(15, &[], *use_brotli, *entropy_coder);
+        }
+    }
+}
+
+// codecs pick their kernels from a process wide setting, so tests that change it take turns
+static KERNEL_SELECTION: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn e2e_compress_with_kernels(data: &[u8], use_brotli: bool, dynamic_context_mixing: u8, scalar_only: bool) -> Vec<u8> {
+    divans::cpu_features::set_scalar_only(scalar_only);
+    let mut opts = e2e_opts(11, use_brotli);
+    opts.dynamic_context_mixing = Some(dynamic_context_mixing);
+    e2e_roundtrip(data, 65536, opts)
+}
+
+fn e2e_decompress_with_kernels(compressed: &[u8], scalar_only: bool) -> Vec<u8> {
+    divans::cpu_features::set_scalar_only(scalar_only);
+    e2e_decompress(compressed, 65536, MULTI)
+}
+
+#[test]
+fn test_e2e_simd_kernels_bit_identical() {
+    let alice = include_bytes!("../../testdata/alice29");
+    let asyoulik = include_bytes!("../../testdata/asyoulik");
+    let _selection = KERNEL_SELECTION.lock().unwrap();
+    for data in [&alice[..], &asyoulik[..32768]].iter() {
+        for use_brotli in [false, true].iter() {
+            for mixing in [0u8, 2u8].iter() {
+                let scalar = e2e_compress_with_kernels(data, *use_brotli, *mixing, true);
+                let detected = e2e_compress_with_kernels(data, *use_brotli, *mixing, false);
+                assert_eq!(scalar, detected);
+                assert_eq!(e2e_decompress_with_kernels(&detected, true), *data);
+                assert_eq!(e2e_decompress_with_kernels(&scalar, false), *data);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_e2e_kernels_per_codec() {
+    use divans::{Compressor, DivansCompressorFactory, cpu_features};
+    let alice = include_bytes!("../../testdata/alice29");
+    let mut compressors = Vec::new();
+    {
+        let _selection = KERNEL_SELECTION.lock().unwrap();
+        for scalar_only in [true, false].iter() {
+            cpu_features::set_scalar_only(*scalar_only);
+            let mut opts = e2e_opts(9, false);
+            opts.dynamic_context_mixing = Some(2);
+            compressors.push(super::DivansCompressorFactoryStruct::<super::ItemVecAllocator<u8>,
+                                                                   super::ItemVecAllocator<divans::DefaultCDF16>>::new(
+                super::ItemVecAllocator::<u8>::default(),
+                super::ItemVecAllocator::<u32>::default(),
+                super::ItemVecAllocator::<divans::DefaultCDF16>::default(),
+                opts,
+                ()));
+        }
+    }
+    assert_eq!(compressors[0].get_codec().kernels(), cpu_features::CpuFeatures::default());
+    assert_eq!(compressors[1].get_codec().kernels(), cpu_features::detected());
+    // both codecs run at once, each on the kernels it was constructed with
+    let mut outputs = [Vec::new(), Vec::new()];
+    let mut buffer = [0u8; 4096];
+    for chunk in alice.chunks(1000) {
+        for (compressor, output) in compressors.iter_mut().zip(outputs.iter_mut()) {
+            let mut input_offset = 0;
+            while input_offset < chunk.len() {
+                let mut output_offset = 0;
+                match compressor.encode(chunk, &mut input_offset, &mut buffer[..], &mut output_offset) {
+                    divans::DivansResult::Success | divans::DivansResult::NeedsMoreInput |
+                    divans::DivansResult::NeedsMoreOutput => {},
+                    res => panic!("{:?}", res),
+                }
+                output.extend(&buffer[..output_offset]);
+            }
+        }
+    }
+    for (compressor, output) in compressors.iter_mut().zip(outputs.iter_mut()) {
+        loop {
+            let mut output_offset = 0;
+            let res = compressor.flush(&mut buffer[..], &mut output_offset);
+            output.extend(&buffer[..output_offset]);
+            match res {
+                divans::DivansOutputResult::Success => break,
+                divans::DivansOutputResult::NeedsMoreOutput => {},
+                res => panic!("{:?}", res),
+            }
+        }
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(e2e_decompress(&outputs[0][..], 65536, MULTI), &alice[..]);
+}
+
+#[test]
+fn test_e2e_unknown_entropy_coder() {
+    let mut in_buffer = UnlimitedBuffer::new(&include_bytes!("../../testdata/alice29")[..4096]);
+    let mut dv_buffer = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut in_buffer,
+                        &mut dv_buffer,
+                        DivansCompressorOptions::default(),
+                        65536,
+                        false,
+                        true,
+                        MULTI).unwrap();
+    dv_buffer.data[8] = 3;
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    assert!(super::decompress(&mut dv_buffer, &mut rt_buffer, 65536, &mut[], false, MULTI).is_err());
+}
+
+fn e2e_cdf_family(buffer_size: usize, data: &[u8], use_brotli: bool, mixing: u8, cdf_family: divans::CDFFamily) -> Vec<u8> {
+    let mut opts = e2e_opts(11, use_brotli);
+    opts.dynamic_context_mixing = Some(mixing);
+    opts.cdf_family = cdf_family;
+    let compressed = e2e_roundtrip(data, buffer_size, opts);
+    assert_eq!(compressed[9], cdf_family as u8);
+    compressed
+}
+
+#[test]
+fn test_e2e_cdf_families() {
+    let alice = include_bytes!("../../testdata/alice29");
+    for use_brotli in [false, true].iter() {
+        for mixing in [0u8, 1u8, 2u8].iter() {
+            let frequentist = e2e_cdf_family(65536, &alice[..], *use_brotli, *mixing, divans::CDFFamily::Frequentist);
+            let blend = e2e_cdf_family(65536, &alice[..], *use_brotli, *mixing, divans::CDFFamily::Blend);
+            assert!(frequentist[divans::interface::HEADER_LENGTH..] != blend[divans::interface::HEADER_LENGTH..]);
+        }
+        e2e_cdf_family(1, &alice[..8192], *use_brotli, 2, divans::CDFFamily::Blend);
+        e2e_cdf_family(65536, &[0u8; 70000], *use_brotli, 0, divans::CDFFamily::Blend);
+        e2e_cdf_family(15, &[], *use_brotli, 0, divans::CDFFamily::Blend);
+    }
+}
+
+#[test]
+fn test_e2e_unknown_cdf_family() {
+    let alice = include_bytes!("../../testdata/alice29");
+    let mut dv_buffer = UnlimitedBuffer::new(&e2e_cdf_family(65536, &alice[..4096], false, 0, divans::CDFFamily::Blend));
+    dv_buffer.data[9] = 2;
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    let err = super::decompress(&mut dv_buffer, &mut rt_buffer, 65536, &mut[], false, MULTI).unwrap_err();
+    assert_eq!(err.to_string(), "UnknownCDFFamily(2)");
+}
+
+fn e2e_stream_count(data: &[u8], use_brotli: bool, stream_count: divans::StreamCount) -> Vec<u8> {
+    let mut opts = e2e_opts(11, use_brotli);
+    opts.stream_count = stream_count;
+    let compressed = e2e_roundtrip(data, 65536, opts);
+    assert_eq!(compressed[10], if stream_count.get() == divans::interface::NUM_STREAMS {0} else {stream_count.get() as u8});
+    compressed
+}
+
+fn e2e_decode_stream_count(dv: &[u8], multithread: bool) -> io::Result<Vec<u8>> {
+    use divans::DivansDecompressorFactory;
+    let mut state = super::DivansDecompressorFactoryStruct::<super::ItemVecAllocator<u8>,
+                                                            super::ItemVecAllocator<divans::DefaultCDF16>,
+                                                            super::ItemVecAllocator<divans::StaticCommand>>::new(
+        super::ItemVecAllocator::<u8>::default(),
+        super::ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        super::ItemVecAllocator::<divans::StaticCommand>::default(),
+        false,
+        multithread);
+    let mut rt_buffer = UnlimitedBuffer::new(&[]);
+    let ret = super::decompress_generic(&mut UnlimitedBuffer::new(dv), &mut rt_buffer, &mut state, &mut[], 65536);
+    // only the parallel decompressor records a thread trace
+    if ret.is_ok() && !dv.is_empty() {
+        assert_eq!(state.thread_trace().is_some(), multithread);
+    }
+    state.free();
+    ret.map(|_| rt_buffer.data)
+}
+
+#[test]
+fn test_e2e_stream_count() {
+    let alice = include_bytes!("../../testdata/alice29");
+    for use_brotli in [false, true].iter() {
+        for count in [2usize, 3, 4, 16].iter() {
+            let stream_count = divans::StreamCount::new(*count).unwrap();
+            let dv = e2e_stream_count(&alice[..], *use_brotli, stream_count);
+            let empty = e2e_stream_count(&[], *use_brotli, stream_count);
+            for multithread in [false, MULTI].iter() {
+                assert_eq!(e2e_decode_stream_count(&dv[..], *multithread).unwrap(), &alice[..]);
+                assert_eq!(e2e_decode_stream_count(&empty[..], *multithread).unwrap(), &[]);
+            }
+        }
+    }
+    // the encoder cannot write an out of range count, so those headers are patched in
+    let mut dv = e2e_stream_count(&alice[..4096], false, divans::StreamCount::default());
+    for count in [1u8, 17, 255].iter() {
+        dv[10] = *count;
+        for multithread in [false, MULTI].iter() {
+            let err = e2e_decode_stream_count(&dv[..], *multithread).unwrap_err();
+            assert_eq!(err.to_string(), format!("UnsupportedStreamCount({})", count));
+        }
+    }
+}
+
+fn e2e_estimate_cost(data: &[u8], use_brotli: bool, divans_ir_optimizer: u8) -> (divans::CostEstimate, usize) {
+    let mut opts = e2e_opts(11, use_brotli);
+    opts.divans_ir_optimizer = divans_ir_optimizer;
+    (divans::estimate_cost(data, opts).unwrap(), e2e_roundtrip(data, 65536, opts).len())
+}
+
+#[test]
+fn test_e2e_estimate_cost() {
+    let alice = include_bytes!("../../testdata/alice29");
+    let asyoulik = include_bytes!("../../testdata/asyoulik");
+    for use_brotli in [false, true].iter() {
+        for data in [&alice[..], &asyoulik[..]].iter() {
+            let (estimate, compressed_len) = e2e_estimate_cost(data, *use_brotli, if *use_brotli {1} else {0});
+            assert!(estimate.literal > 0.0 && estimate.copy > 0.0 && estimate.cross_command > 0.0);
+            assert_eq!(estimate.framing, ((divans::interface::HEADER_LENGTH + 8) * 8) as f64);
+            assert!((estimate.total_bytes() - compressed_len as f64).abs() < compressed_len as f64 * 0.02,
+                    "estimated {} bytes for {} compressed bytes", estimate.total_bytes(), compressed_len);
+        }
+        let (empty, compressed_len) = e2e_estimate_cost(&[], *use_brotli, 0);
+        assert!(empty.total_bytes() <= compressed_len as f64);
+    }
+}
+
+#[test]
+fn test_e2e_shared_thread_pool() {
+    use std::io::Read;
+    let alice = include_bytes!("../../testdata/alice29");
+    let asyoulik = include_bytes!("../../testdata/asyoulik");
+    let mut jobs = Vec::new();
+    for (index, data) in [&alice[..], &asyoulik[..], &alice[..65536], &asyoulik[..4096], &[][..], &alice[1..]].iter().enumerate() {
+        let mut in_buffer = UnlimitedBuffer::new(data);
+        let mut dv_buffer = UnlimitedBuffer::new(&[]);
+        super::compress_raw(&mut in_buffer,
+                            &mut dv_buffer,
+                            DivansCompressorOptions::default(),
+                            65536,
+                            index % 2 == 0,
+                            true,
+                            false).unwrap();
+        let mut serial = UnlimitedBuffer::new(&[]);
+        super::decompress(&mut UnlimitedBuffer::new(&dv_buffer.data), &mut serial, 65536, &mut[], false, false).unwrap();
+        assert_eq!(&serial.data[..], *data);
+        jobs.push((dv_buffer.data, serial.data));
+    }
+    let executor: divans::ExecutorHandle = std::sync::Arc::new(divans::ThreadPool::new(2));
+    let threads: Vec<_> = jobs.into_iter().map(|(compressed, serial)| {
+        let executor = executor.clone();
+        std::thread::spawn(move || {
+            let mut reader = divans::DivansDecompressorReader::new_with_executor(UnlimitedBuffer::new(&compressed), 4096, false, executor);
+            let mut pooled = Vec::new();
+            reader.read_to_end(&mut pooled).unwrap();
+            assert_eq!(pooled, serial);
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+// feeds the compressor in partial writes and, after each one, decodes everything it has emitted so far;
+// returns how many of the written bytes could not be decoded yet
+fn e2e_decode_progress(data: &[u8], mux_policy: divans::MuxPolicy, multithread: bool) -> (Vec<usize>, usize) {
+    use divans::{Compressor, Decompressor, DivansCompressorFactory, DivansDecompressorFactory, DivansResult};
+    const WRITE_SIZE: usize = 1024;
+    let mut opts = DivansCompressorOptions::default();
+    opts.entropy_coder = divans::EntropyCoderSelection::Range;
+    opts.mux_policy = mux_policy;
+    let mut compressor = super::DivansCompressorFactoryStruct::<super::ItemVecAllocator<u8>,
+                                                               super::ItemVecAllocator<divans::DefaultCDF16>>::new(
+        super::ItemVecAllocator::<u8>::default(),
+        super::ItemVecAllocator::<u32>::default(),
+        super::ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        opts,
+        ());
+    let mut decompressor = super::DivansDecompressorFactoryStruct::<super::ItemVecAllocator<u8>,
+                                                                   super::ItemVecAllocator<divans::DefaultCDF16>,
+                                                                   super::ItemVecAllocator<divans::StaticCommand>>::new(
+        super::ItemVecAllocator::<u8>::default(),
+        super::ItemVecAllocator::<divans::DefaultCDF16>::default(),
+        super::ItemVecAllocator::<divans::StaticCommand>::default(),
+        false,
+        multithread);
+    let mut compressed = vec![0u8; data.len() * 2 + 65536];
+    let mut compressed_len = 0usize;
+    let mut compressed_offset = 0usize;
+    let mut decoded = vec![0u8; data.len()];
+    let mut decoded_len = 0usize;
+    let mut lag = Vec::new();
+    let mut input_offset = 0usize;
+    while input_offset < data.len() {
+        let write_end = core::cmp::min(input_offset + WRITE_SIZE, data.len());
+        match compressor.encode(&data[..write_end], &mut input_offset, &mut compressed[..], &mut compressed_len) {
+            DivansResult::NeedsMoreInput => {},
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(input_offset, write_end);
+        match decompressor.decode(&compressed[..compressed_len], &mut compressed_offset, &mut decoded[..], &mut decoded_len) {
+            DivansResult::NeedsMoreInput => {},
+            res => panic!("{:?}", res),
+        }
+        lag.push(write_end - decoded_len);
+    }
+    loop {
+        match compressor.flush(&mut compressed[..], &mut compressed_len) {
+            divans::DivansOutputResult::Success => break,
+            res => panic!("{:?}", res),
+        }
+    }
+    match decompressor.decode(&compressed[..compressed_len], &mut compressed_offset, &mut decoded[..], &mut decoded_len) {
+        DivansResult::Success => {},
+        res => panic!("{:?}", res),
+    }
+    assert_eq!(&decoded[..decoded_len], data);
+    compressor.free();
+    decompressor.free();
+    (lag, compressed_len)
+}
+
+#[test]
+fn test_e2e_low_latency_decode_progress() {
+    let alice = include_bytes!("../../testdata/alice29");
+    for multithread in [false, true].iter() {
+        let (throughput_lag, throughput_len) = e2e_decode_progress(&alice[..65536], divans::MuxPolicy::Throughput, *multithread);
+        let (low_latency_lag, low_latency_len) = e2e_decode_progress(&alice[..65536], divans::MuxPolicy::LowLatency, *multithread);
+        // the command assembler holds back 4096 bytes of lookahead, so only what it has not parsed may be undecodable
+        assert!(low_latency_lag.iter().all(|lag| *lag <= 4096 + 2048), "{:?}", low_latency_lag);
+        assert!(throughput_lag.iter().any(|lag| *lag > 16384), "{:?}", throughput_lag);
+        assert!(low_latency_len < throughput_len + throughput_len / 20);
+    }
+}
+
+#[test]
+fn test_e2e_low_latency_round_trip() {
+    let alice = include_bytes!("../../testdata/alice29");
+    for use_brotli in [false, true].iter() {
+        for data in [&alice[..], &alice[..1], &[][..]].iter() {
+            let mut in_buffer = UnlimitedBuffer::new(data);
+            let mut dv_buffer = UnlimitedBuffer::new(&[]);
+            let mut opts = DivansCompressorOptions::default();
+            opts.mux_policy = divans::MuxPolicy::LowLatency;
+            super::compress_raw(&mut in_buffer,
+                                &mut dv_buffer,
+                                opts,
+                                1024,
+                                *use_brotli,
+                                true,
+                                MULTI).unwrap();
+            assert_eq!(dv_buffer.data[11], divans::MuxPolicy::LowLatency as u8);
+            let compressed = dv_buffer.data.clone();
+            let mut rt_buffer = UnlimitedBuffer::new(&[]);
+            super::decompress(&mut dv_buffer, &mut rt_buffer, 1024, &mut[], false, MULTI).unwrap();
+            assert_eq!(rt_buffer.data, *data);
+            let mut unknown = UnlimitedBuffer::new(&compressed);
+            unknown.data[11] = 2;
+            let err = super::decompress(&mut unknown, &mut UnlimitedBuffer::new(&[]), 1024, &mut[], true, MULTI).unwrap_err();
+            assert_eq!(err.to_string(), "UnknownMuxPolicy(2)");
+        }
+    }
+}
+
+#[test]
+fn test_e2e_thread_trace() {
+    use divans::DivansDecompressorFactory;
+    let raw = include_bytes!("../../testdata/alice29");
+    let dv = e2e_filtered(65536, true, &raw[..], FilterSelection::Disabled);
+    for &multithread in [false, true].iter() {
+        let mut state = super::DivansDecompressorFactoryStruct::<super::ItemVecAllocator<u8>,
+                                                                super::ItemVecAllocator<divans::DefaultCDF16>,
+                                                                super::ItemVecAllocator<divans::StaticCommand>>::new(
+            super::ItemVecAllocator::<u8>::default(),
+            super::ItemVecAllocator::<divans::DefaultCDF16>::default(),
+            super::ItemVecAllocator::<divans::StaticCommand>::default(),
+            false,
+            multithread);
+        let mut rt_buffer = UnlimitedBuffer::new(&[]);
+        super::decompress_generic(&mut UnlimitedBuffer::new(&dv[..]), &mut rt_buffer, &mut state, &mut[], 4096).unwrap();
+        assert_eq!(&rt_buffer.data[..], &raw[..]);
+        let trace = state.thread_trace();
+        state.free();
+        let trace = match trace {
+            Some(trace) => trace,
+            None => {
+                assert!(!multithread);
+                continue;
+            },
+        };
+        assert!(multithread);
+        let mut json = Vec::<u8>::new();
+        trace.write_chrome_trace(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert_eq!(json.matches("\"ph\":").count(), 2 + trace.main.len() + trace.worker.len());
+        if cfg!(feature="threadlog") {
+            assert!(trace.main.len() != 0 && trace.worker.len() != 0);
+            assert!(trace.wait_summary().iter().all(|total| total.event.is_wait() && total.count != 0));
+        } else {
+            assert_eq!(trace.wait_summary().len(), 0);
+        }
+    }
+}
+
+#[test]
+fn test_e2e_decompressor_options() {
+    use divans::{Decompressor, DivansDecompressorFactory, DivansResult, ErrMsg};
+    let raw = include_bytes!("../../testdata/alice29");
+    let mut opts = DivansCompressorOptions::default();
+    opts.window_size = Some(20);
+    let mut dv = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut UnlimitedBuffer::new(&raw[..]), &mut dv, opts, 65536, true, true, MULTI).unwrap();
+    let crc_start = dv.data.len() - 8; // the checksum precedes the 4 byte trailer
+    dv.data[crc_start] ^= 1;
+    for &(memory_limit, skip_crc) in [(None, false), (Some(1usize << 19), true), (Some(1usize << 20), true)].iter() {
+        let mut state = super::DivansDecompressorFactoryStruct::<super::ItemVecAllocator<u8>,
+                                                                super::ItemVecAllocator<divans::DefaultCDF16>,
+                                                                super::ItemVecAllocator<divans::StaticCommand>>::new(
+            super::ItemVecAllocator::<u8>::default(),
+            super::ItemVecAllocator::<divans::DefaultCDF16>::default(),
+            super::ItemVecAllocator::<divans::StaticCommand>::default(),
+            false,
+            MULTI);
+        assert!(state.set_memory_limit(memory_limit));
+        assert!(state.set_skip_crc(skip_crc));
+        let mut decoded = vec![0u8; raw.len()];
+        let mut input_offset = 0usize;
+        let mut output_offset = 0usize;
+        let res = state.decode(&dv.data[..], &mut input_offset, &mut decoded[..], &mut output_offset);
+        match (memory_limit, skip_crc) {
+            (None, false) => match res {
+                DivansResult::Failure(ErrMsg::BadChecksum(_, _)) => {},
+                res => panic!("{:?}", res),
+            },
+            (Some(limit), true) if limit < 1 << 20 => match res {
+                DivansResult::Failure(ErrMsg::WindowExceedsMemoryLimit(20)) => assert_eq!(output_offset, 0),
+                res => panic!("{:?}", res),
+            },
+            _ => {
+                match res {
+                    DivansResult::Success => assert_eq!(&decoded[..output_offset], &raw[..]),
+                    res => panic!("{:?}", res),
+                }
+                // options are fixed once the header is decoded
+                assert!(!state.set_skip_crc(false));
+            },
+        }
+        state.free();
+    }
+}
+
+#[test]
+fn test_e2e_file_mode() {
+    use std::fs;
+    use super::file_mode::{process_files, FileModeOptions, EXIT_OK, EXIT_ERROR, EXIT_WARNING};
+    let raw = include_bytes!("../../testdata/alice29");
+    let dir = ::std::env::temp_dir().join(format!("divans-file-mode-{}", ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let a = dir.join("a");
+    let b = dir.join("sub").join("b");
+    fs::write(&a, &raw[..]).unwrap();
+    fs::write(&b, b"hello\n").unwrap();
+    let mtime = ::std::time::UNIX_EPOCH + ::std::time::Duration::new(981173106, 0);
+    fs::File::options().write(true).open(&a).unwrap().set_modified(mtime).unwrap();
+    let names = [dir.to_str().unwrap().to_string()];
+    let mut compress = |input: &mut fs::File, output: &mut fs::File| {
+        super::compress_raw(input, output, DivansCompressorOptions::default(), 65536, true, true, MULTI)
+    };
+    let mut decompress = |input: &mut fs::File, output: &mut fs::File| {
+        super::decompress(input, output, 65536, &mut [], false, MULTI)
+    };
+    let mut opts = FileModeOptions::default();
+    // directories need -r
+    assert_eq!(process_files(&names, &opts, &mut compress), EXIT_WARNING);
+    opts.recursive = true;
+    assert_eq!(process_files(&names, &opts, &mut compress), EXIT_OK);
+    assert!(!a.exists() && !b.exists());
+    assert_eq!(fs::metadata(dir.join("a.dv")).unwrap().modified().unwrap(), mtime);
+    // files that already carry the suffix are skipped quietly when recursing
+    assert_eq!(process_files(&names, &opts, &mut compress), EXIT_OK);
+
+    let a_dv = [dir.join("a.dv").to_str().unwrap().to_string()];
+    opts.recursive = false;
+    opts.decompress = true;
+    opts.keep = true;
+    assert_eq!(process_files(&a_dv, &opts, &mut decompress), EXIT_OK);
+    assert_eq!(&fs::read(&a).unwrap()[..], &raw[..]);
+    assert_eq!(fs::metadata(&a).unwrap().modified().unwrap(), mtime);
+    // the output exists now
+    assert_eq!(process_files(&a_dv, &opts, &mut decompress), EXIT_ERROR);
+    // -f replaces an existing output instead of writing through it
+    let victim = dir.join("victim");
+    fs::write(&victim, b"untouched").unwrap();
+    #[cfg(unix)]
+    {
+        fs::remove_file(&a).unwrap();
+        ::std::os::unix::fs::symlink(&victim, &a).unwrap();
+    }
+    opts.force = true;
+    opts.keep = false;
+    assert_eq!(process_files(&a_dv, &opts, &mut decompress), EXIT_OK);
+    assert!(!dir.join("a.dv").exists());
+    assert!(fs::symlink_metadata(&a).unwrap().is_file());
+    assert_eq!(&fs::read(&a).unwrap()[..], &raw[..]);
+    assert_eq!(&fs::read(&victim).unwrap()[..], b"untouched");
+    assert_eq!(process_files(&[a.to_str().unwrap().to_string()], &opts, &mut decompress), EXIT_WARNING);
+
+    // a failed decode removes its partial output and keeps the input
+    let bad = dir.join("bad.dv");
+    fs::write(&bad, b"not a divans stream").unwrap();
+    assert_eq!(process_files(&[bad.to_str().unwrap().to_string()], &opts, &mut decompress), EXIT_ERROR);
+    assert!(bad.exists() && !dir.join("bad").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_e2e_bench() {
+    use super::bench::{Grid, Format, measure, write_row, CSV_HEADER};
+    let raw = include_bytes!("../../testdata/asyoulik");
+    let mut grid = Grid::default();
+    grid.quality = vec![9, 11];
+    grid.stride = vec!["none".to_string(), "4".to_string()];
+    grid.speed = vec!["default".to_string(), "16,8192".to_string()];
+    let settings = grid.settings();
+    assert_eq!(settings.len(), 8);
+    assert_eq!(settings[3].quality, 9);
+    assert_eq!(settings[3].stride, "4");
+    assert_eq!(settings[3].speed, "16,8192");
+    let mut out = Vec::<u8>::new();
+    for (index, setting) in settings[..4].iter().enumerate() {
+        let m = measure("as you,like", &raw[..], setting, 1, MULTI).unwrap();
+        assert!(m.roundtrip);
+        assert_eq!(m.original_size, raw.len());
+        assert!(m.compressed_size < raw.len());
+        write_row(&mut out, Format::Csv, index == 0, &m).unwrap();
+    }
+    let csv = String::from_utf8(out).unwrap();
+    assert_eq!(csv.lines().count(), 4);
+    assert_eq!(csv.lines().next().unwrap().split(',').count(), 1 + CSV_HEADER.split(',').count());
+    assert!(csv.lines().all(|line| line.starts_with("\"as you,like\",9,1,") && line.ends_with(",true")));
+    assert!(csv.contains(",\"16,8192\","));
+    let mut bad = settings[0].clone();
+    bad.stride = "9".to_string();
+    assert!(measure("x", &raw[..], &bad, 1, MULTI).is_err());
+    let mut json = Vec::<u8>::new();
+    let m = measure("q\"\\", &raw[..1000], &settings[0], 2, MULTI).unwrap();
+    write_row(&mut json, Format::Json, true, &m).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with("\n{\"file\":\"q\\\"\\\\\",\"quality\":9,"));
+    assert!(json.ends_with(",\"roundtrip\":true}"));
+}
+
+#[test]
+fn test_e2e_exhaustive() {
+    let raw = &include_bytes!("../../testdata/alice29")[..32768];
+    let opts = DivansCompressorOptions::default();
+    let commands = divans::exhaustive::capture_brotli_ir(raw, opts).unwrap();
+    let start = divans::estimate_ir_cost(&commands[..], opts).unwrap();
+    let mut compressed = Vec::<u8>::new();
+    let best = divans::exhaustive::compress_exhaustive(raw, opts, 2, &mut compressed).unwrap();
+    assert!(best.candidates > 20);
+    assert!(best.estimate.total_bits() <= start.total_bits());
+    assert!((best.estimate.total_bytes() - compressed.len() as f64).abs() < compressed.len() as f64 * 0.02);
+    let mut default_compressed = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut &raw[..], &mut default_compressed, opts, 65536, true, true, MULTI).unwrap();
+    assert!(compressed.len() <= default_compressed.data.len() + 8);
+    let mut rt = UnlimitedBuffer::new(&[]);
+    super::decompress(&mut &compressed[..], &mut rt, 65536, &mut [], false, MULTI).unwrap();
+    assert_eq!(&rt.data[..], raw);
+}
+
+#[test]
+fn test_e2e_billing_report() {
+    let raw = &include_bytes!("../../testdata/alice29")[..40000];
+    let opts = DivansCompressorOptions::default();
+    let report = divans::billing_report_for_input(raw, opts, 16384).unwrap();
+    assert_eq!(report.original_bytes, raw.len());
+    assert_eq!(report.framing_bytes, divans::interface::HEADER_LENGTH + 8);
+    let bounds: Vec<(usize, usize)> = report.regions.iter().map(|r| (r.start, r.end)).collect();
+    assert_eq!(bounds, [(0, 16384), (16384, 32768), (32768, 40000)]);
+    let mut region_bits = 0.0;
+    for region in report.regions.iter() {
+        assert!(region.cost.iter().all(|c| c.bits >= -0.001 && c.virtual_bits >= 0.0));
+        region_bits += region.cost.iter().map(|c| c.bits).sum::<f64>();
+    }
+    assert!((region_bits - report.total().bits).abs() < 0.01);
+    let mut compressed = UnlimitedBuffer::new(&[]);
+    super::compress_raw(&mut &raw[..], &mut compressed, opts, 65536, true, true, MULTI).unwrap();
+    assert!((report.total_bytes() - compressed.data.len() as f64).abs() < compressed.data.len() as f64 * 0.02,
+            "billed {} bytes for {} compressed bytes", report.total_bytes(), compressed.data.len());
+    let mut json = Vec::<u8>::new();
+    report.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with("{\"original_bytes\":40000,\"region_size\":16384,\"framing_bits\":"));
+    assert!(json.contains("{\"designation\":\"LiteralCommand\",\"state\":\"LiteralNibbleIndex\",\"bits\":"));
+    assert_eq!(json.matches("\"start\":").count(), 3);
+    let whole = divans::billing_report_for_input(raw, opts, 0).unwrap();
+    assert_eq!(whole.regions.len(), 1);
+    assert_eq!(whole.regions[0].end, raw.len());
+}
+
+fn golden_testdata() -> std::path::PathBuf {
+    std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata")
+}
+
+#[test]
+fn test_golden_streams_decode() {
+    use super::golden::{GOLDEN_CASES, GOLDEN_DIR};
+    let testdata = golden_testdata();
+    for case in GOLDEN_CASES.iter() {
+        let input = case.read_input(&testdata).unwrap();
+        let stream = std::fs::read(case.stream_path(&testdata)).unwrap();
+        for &multithread in [false, MULTI].iter() {
+            let mut output = UnlimitedBuffer::new(&[]);
+            super::decompress(&mut &stream[..], &mut output, 65_536, &mut [], false, multithread).unwrap();
+            assert!(output.data == input, "{}: golden stream no longer decodes to its input", case.name);
+        }
+    }
+    // a stream without a case would never be checked
+    let mut files = 0;
+    for entry in std::fs::read_dir(testdata.join(GOLDEN_DIR)).unwrap() {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        assert!(GOLDEN_CASES.iter().any(|case| case.stream_path(std::path::Path::new("")).file_name().unwrap() == &name[..]),
+                "{} has no golden case", name);
+        files += 1;
+    }
+    assert_eq!(files, GOLDEN_CASES.len());
+}
+
+#[test]
+fn test_golden_streams_reencode() {
+    use super::golden::GOLDEN_CASES;
+    let testdata = golden_testdata();
+    let mut changed = Vec::<&str>::new();
+    for case in GOLDEN_CASES.iter() {
+        let input = case.read_input(&testdata).unwrap();
+        let stream = std::fs::read(case.stream_path(&testdata)).unwrap();
+        if case.compress(&input).unwrap() != stream {
+            changed.push(case.name);
+        }
+    }
+    assert!(changed.is_empty(),
+            "compressing now produces different bytes for {:?}; if the format change is deliberate, run `divans golden-regenerate`",
+            changed);
+}
@@ -0,0 +1,242 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// A binary arithmetic coder in the style of LZMA's range coder: every nibble is coded as
+// four binary decisions and carries are resolved through a cached byte and a run of 0xff.
+use core;
+use probability::CDF16;
+use super::interface::ArithmeticEncoderOrDecoder;
+use super::DivansResult;
+use super::arithmetic_coder::{
+    EntropyEncoder,
+    EntropyDecoder,
+    ByteQueue,
+    RegisterQueue,
+};
+
+const TOP: u32 = 1 << 24;
+// a bit shifts out at most one byte, so the decoder keeps a nibble's worth buffered
+const LOOKAHEAD: usize = 4;
+const INIT_BYTES: u8 = 5;
+const MAX_RUNS: usize = 16;
+
+// settled output as (byte, repeat count) runs, since a carry can settle a long run of 0xff at once
+pub struct RunQueue {
+    runs: [(u8, u32); MAX_RUNS],
+    start: usize,
+    end: usize,
+}
+
+impl Default for RunQueue {
+    fn default() -> Self {
+        RunQueue {
+            runs: [(0, 0); MAX_RUNS],
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl RunQueue {
+    fn push_run(&mut self, byte: u8, count: u32) {
+        if count == 0 {
+            return;
+        }
+        if self.end != self.start && self.runs[self.end - 1].0 == byte {
+            self.runs[self.end - 1].1 += count;
+            return;
+        }
+        assert!(self.end < MAX_RUNS);
+        self.runs[self.end] = (byte, count);
+        self.end += 1;
+    }
+}
+
+impl ByteQueue for RunQueue {
+    fn num_push_bytes_avail(&self) -> usize {
+        0
+    }
+    fn num_pop_bytes_avail(&self) -> usize {
+        self.runs[self.start..self.end].iter().map(|run| run.1 as usize).sum()
+    }
+    fn push_data(&mut self, data: &[u8]) -> usize {
+        for byte in data.iter() {
+            self.push_run(*byte, 1);
+        }
+        data.len()
+    }
+    fn pop_data(&mut self, data: &mut [u8]) -> usize {
+        let mut count = 0usize;
+        while count < data.len() && self.start != self.end {
+            let run = &mut self.runs[self.start];
+            let to_copy = core::cmp::min(run.1 as usize, data.len() - count);
+            for item in data[count..count + to_copy].iter_mut() {
+                *item = run.0;
+            }
+            count += to_copy;
+            run.1 -= to_copy as u32;
+            if run.1 == 0 {
+                self.start += 1;
+            }
+        }
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        count
+    }
+}
+
+pub struct BinaryEncoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: u32,
+    queue: RunQueue,
+}
+
+impl Default for BinaryEncoder {
+    fn default() -> Self {
+        BinaryEncoder {
+            low: 0,
+            range: 0xffff_ffff,
+            cache: 0,
+            cache_size: 1,
+            queue: RunQueue::default(),
+        }
+    }
+}
+
+impl BinaryEncoder {
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xff00_0000 || (self.low >> 32) != 0 {
+            let carry = (self.low >> 32) as u8;
+            self.queue.push_run(self.cache.wrapping_add(carry), 1);
+            self.queue.push_run(0xffu8.wrapping_add(carry), self.cache_size - 1);
+            self.cache = (self.low >> 24) as u8;
+            self.cache_size = 0;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+    fn mov_internal(&mut self) -> Self {
+        core::mem::take(self)
+    }
+}
+
+impl EntropyEncoder for BinaryEncoder {
+    type Queue = RunQueue;
+    fn get_internal_buffer_mut(&mut self) -> &mut RunQueue {
+        &mut self.queue
+    }
+    fn get_internal_buffer(&self) -> &RunQueue {
+        &self.queue
+    }
+    fn put_bit(&mut self, bit: bool, prob_of_false: u8) {
+        let bound = (self.range >> 8) * u32::from(core::cmp::max(prob_of_false, 1));
+        if bit {
+            self.low += u64::from(bound);
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        if self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+    fn flush(&mut self) {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        // padding for the decoder's lookahead
+        self.queue.push_run(0, LOOKAHEAD as u32);
+    }
+}
+
+impl ArithmeticEncoderOrDecoder for BinaryEncoder {
+    arithmetic_encoder_or_decoder_methods!();
+}
+
+#[derive(Clone)]
+pub struct BinaryDecoder {
+    range: u32,
+    code: u32,
+    init_bytes: u8,
+    lookahead: RegisterQueue,
+}
+
+impl Default for BinaryDecoder {
+    fn default() -> Self {
+        BinaryDecoder {
+            range: 0xffff_ffff,
+            code: 0,
+            init_bytes: INIT_BYTES,
+            lookahead: RegisterQueue::default(),
+        }
+    }
+}
+
+impl ByteQueue for BinaryDecoder {
+    fn num_push_bytes_avail(&self) -> usize {
+        usize::from(self.init_bytes) + LOOKAHEAD - self.lookahead.num_pop_bytes_avail()
+    }
+    fn num_pop_bytes_avail(&self) -> usize {
+        0
+    }
+    fn push_data(&mut self, data: &[u8]) -> usize {
+        let init_count = core::cmp::min(data.len(), usize::from(self.init_bytes));
+        for byte in data[..init_count].iter() {
+            self.code = (self.code << 8) | u32::from(*byte);
+        }
+        self.init_bytes -= init_count as u8;
+        let rest = &data[init_count..];
+        let lookahead_count = core::cmp::min(rest.len(), LOOKAHEAD - self.lookahead.num_pop_bytes_avail());
+        init_count + self.lookahead.push_data(&rest[..lookahead_count])
+    }
+    fn pop_data(&mut self, _data: &mut [u8]) -> usize {
+        unreachable!()
+    }
+}
+
+impl EntropyDecoder for BinaryDecoder {
+    type Queue = Self;
+    fn get_internal_buffer_mut(&mut self) -> &mut Self {
+        self
+    }
+    fn get_internal_buffer(&self) -> &Self {
+        self
+    }
+    fn get_bit(&mut self, prob_of_false: u8) -> bool {
+        debug_assert_eq!(self.init_bytes, 0);
+        let bound = (self.range >> 8) * u32::from(core::cmp::max(prob_of_false, 1));
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        if self.range < TOP {
+            let mut byte = [0u8];
+            self.lookahead.pop_data(&mut byte[..]);
+            self.range <<= 8;
+            self.code = (self.code << 8) | u32::from(byte[0]);
+        }
+        bit
+    }
+    fn flush(&mut self) -> DivansResult {
+        DivansResult::Success
+    }
+}
@@ -11,13 +11,17 @@
 //   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 //   See the License for the specific language governing permissions and
 //   limitations under the License.
+use core;
 use core::marker::PhantomData;
-use core::cmp::{min, max};
+use core::cmp::min;
 use super::probability::CDF16;
 use super::brotli;
 use super::mux::{Mux,DevNull};
 use codec::io::DemuxerAndRingBuffer;
 use codec::{CommandArray, CommandSliceArray};
+use codec::decoder::{CommandLog, log_command};
+#[cfg(feature="std")]
+use std::vec::Vec;
 pub use super::alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use super::interface::{BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
     LITERAL_PREDICTION_MODE_SIGN,
@@ -29,25 +33,36 @@ pub use super::interface::{BlockSwitch, LiteralBlockSwitch, Command, Compressor,
 pub use super::cmd_to_divans::EncoderSpecialization;
 pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection};
 use super::resizable_buffer::ResizableByteBuffer;
+use super::raw_to_cmd;
+use super::brotli::enc::StaticCommand;
+use super::interface::StreamMuxer;
+use super::cmd_to_divans::EncoderCodec;
 use super::interface;
-use super::interface::{DivansOutputResult, DivansResult, ErrMsg};
+use super::interface::{CDFFamily, DivansOutputResult, DivansResult, EntropyCoderSelection, ErrMsg, MuxPolicy, StreamCount};
 use super::brotli::enc::encode::{BrotliEncoderStateStruct, BrotliEncoderCompressStream, BrotliEncoderOperation, BrotliEncoderIsFinished};
 use super::brotli::enc::backward_references::BrotliEncoderMode;
 use super::divans_compressor::write_header;
+use super::filter::{FilterChain, FilterEncoder};
 pub struct BrotliDivansHybridCompressor<SelectedCDF:CDF16,
                             ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
                             AllocCDF16:Allocator<SelectedCDF>,
-                            AllocBrotli: brotli::enc::BrotliAlloc>
+                            AllocBrotli: brotli::enc::BrotliAlloc,
+                            LinearOutputBytes:StreamMuxer<AllocU8>+Default=Mux<AllocU8>>
 {
     brotli_encoder: BrotliEncoderStateStruct<AllocBrotli>,
-    codec: DivansCodec<ChosenEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, SelectedCDF, AllocU8, AllocCDF16>,
+    codec: DivansCodec<ChosenEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, LinearOutputBytes, SelectedCDF, AllocU8, AllocCDF16>,
     header_progress: usize,
     window_size: u8,
     brotli_data: ResizableByteBuffer<u8, AllocBrotli>,
     divans_data: ResizableByteBuffer<u8, AllocU8>,
     encoded_byte_offset: usize,
     opt: super::interface::DivansCompressorOptions,
+    filter: FilterEncoder<AllocU8>,
+    // the commands handed to the codec, kept only when the caller asked for the IR
+    ir_log: CommandLog,
+    // replaces brotli when the options select the internal command selection
+    internal_matcher: Option<InternalMatcher<AllocBrotli>>,
 }
 
 
@@ -57,14 +72,144 @@ impl<SelectedCDF:CDF16,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<SelectedCDF>,
      AllocBrotli: brotli::enc::BrotliAlloc,
+     LinearOutputBytes:StreamMuxer<AllocU8>+Default,
      > BrotliDivansHybridCompressor<SelectedCDF,
                                     ChosenEncoder,
                                     AllocU8,
                                     AllocCDF16,
-                                    AllocBrotli> {
+                                    AllocBrotli,
+                                    LinearOutputBytes> {
+    pub fn new(mut m8: AllocU8, mcdf16:AllocCDF16,
+               opt: super::interface::DivansCompressorOptions,
+               mut alloc_brotli: AllocBrotli) -> Self {
+        let window_size = opt.window_size.unwrap_or(22).clamp(10, 24);
+        let internal_matcher = match opt.use_brotli {
+            interface::BrotliCompressionSetting::UseInternalCommandSelection => Some(
+                InternalMatcher::new(&mut alloc_brotli, window_size as u8, &opt)),
+            _ => None,
+        };
+        let cmd_enc = ChosenEncoder::new_with_entropy_coder(&mut m8, opt.entropy_coder);
+        let lit_enc = ChosenEncoder::new_with_entropy_coder(&mut m8, opt.entropy_coder);
+        let filter = FilterEncoder::new(&mut m8, opt.filters);
+        let mut ret = BrotliDivansHybridCompressor {
+             brotli_data: ResizableByteBuffer::<u8, AllocBrotli>::new(),
+             divans_data: ResizableByteBuffer::<u8, AllocU8>::new(),
+             encoded_byte_offset:0, 
+             brotli_encoder: brotli::enc::encode::BrotliEncoderCreateInstance(alloc_brotli),
+            codec:DivansCodec::<ChosenEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, LinearOutputBytes, SelectedCDF, AllocU8, AllocCDF16>::new(
+                m8,
+                mcdf16,
+                cmd_enc,
+                lit_enc,
+                EncoderSpecialization::new(),
+                DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
+                window_size as usize,
+                opt.dynamic_context_mixing.unwrap_or(0),
+                opt.prior_depth,
+                opt.literal_adaptation,
+                opt.use_context_map,
+                opt.force_stride_value,
+                false,
+                opt.cdf_family,
+            ),
+            opt,
+            filter,
+            header_progress: 0,
+            window_size: window_size as u8,
+            ir_log: CommandLog::default(),
+            internal_matcher,
+        };
+        ret.codec.muxer().set_policy(opt.mux_policy);
+        ret.codec.muxer().set_stream_count(opt.stream_count);
+        if let Some(prediction_mode) = opt.force_literal_context_mode {
+            brotli::enc::encode::BrotliEncoderSetParameter(
+                &mut ret.brotli_encoder,
+                brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_MODE,
+                match prediction_mode.0 {
+                    LITERAL_PREDICTION_MODE_LSB6 => BrotliEncoderMode::BROTLI_FORCE_LSB_PRIOR as u32,
+                    LITERAL_PREDICTION_MODE_MSB6 => BrotliEncoderMode::BROTLI_FORCE_MSB_PRIOR as u32,
+                    LITERAL_PREDICTION_MODE_UTF8 => BrotliEncoderMode::BROTLI_FORCE_UTF8_PRIOR as u32,
+                    LITERAL_PREDICTION_MODE_SIGN => BrotliEncoderMode::BROTLI_FORCE_SIGNED_PRIOR as u32,
+                    _ => BrotliEncoderMode::BROTLI_FORCE_SIGNED_PRIOR as u32,
+                });
+        }
+        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_LGWIN,
+                                                       window_size as u32);
+        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_LGBLOCK,
+                                                       opt.lgblock.unwrap_or(18));
+        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
+                                                       u32::from(opt.quality.unwrap_or(10)));
+        if opt.q9_5 {
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_Q9_5,
+                                                       1);
+        }
+        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_METABLOCK_CALLBACK,
+                                                       1);
+        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CDF_ADAPTATION_DETECTION,
+                                                       u32::from(opt.speed_detection_quality.unwrap_or(0)));
+        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_STRIDE_DETECTION_QUALITY,
+                                                       u32::from(opt.stride_detection_quality.unwrap_or(0)));
+        if let Some(literal_byte_score) = opt.brotli_literal_byte_score {
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_LITERAL_BYTE_SCORE,
+                                                           literal_byte_score);
+        }
+        
+        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_PRIOR_BITMASK_DETECTION,
+                                                       u32::from(opt.prior_bitmask_detection));
+        if let Some(speed) = opt.literal_adaptation {
+
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED,
+                                                           speed[3].inc() as u32);
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED_MAX,
+                                                           speed[3].lim() as u32);
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED_LOW,
+                                                           speed[2].inc() as u32);
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED_LOW_MAX,
+                                                           speed[2].lim() as u32);
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED,
+                                                           speed[1].inc() as u32);
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED_MAX,
+                                                           speed[1].lim() as u32);
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED_LOW,
+                                                           speed[0].inc() as u32);
+            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
+                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED_LOW_MAX,
+                                                           speed[0].lim() as u32);
+        }
+        ret
+    }
     pub fn get_m8(&mut self) -> &mut AllocU8 {
        self.codec.get_m8().unwrap().get_base_alloc()
     }
+    pub fn get_codec(&self) -> &EncoderCodec<ChosenEncoder, LinearOutputBytes, SelectedCDF, AllocU8, AllocCDF16> {
+        &self.codec
+    }
+    #[cfg(feature="std")]
+    pub fn capture_ir(&mut self) {
+        self.ir_log = Some(Vec::new());
+    }
+    #[cfg(feature="std")]
+    pub fn take_ir_commands(&mut self, commands: &mut Vec<::ir::IrCommand>) {
+        if let Some(ref mut log) = self.ir_log {
+            commands.append(log);
+        }
+    }
     #[cfg(not(feature="std"))]
     fn do_panic(_m:ErrMsg) {
         panic!("Internal Error With Compression Stage")
@@ -79,11 +224,16 @@ impl<SelectedCDF:CDF16,
                                                           codec: &mut DivansCodec<ChosenEncoder,
                                                                                   EncoderSpecialization,
                                                                                   DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
-                                                                                  Mux<AllocU8>,
+                                                                                  LinearOutputBytes,
                                                                                   SelectedCDF,
                                                                                   AllocU8,
                                                                                   AllocCDF16>,
-                                                          window_size: u8) {
+                                                          window_size: u8,
+                                                          filters: FilterChain,
+                                                          entropy_coder: EntropyCoderSelection,
+                                                          cdf_family: CDFFamily,
+                                                          mux_policy: MuxPolicy,
+                                                          stream_count: StreamCount) {
         let mut cmd_offset = 0usize;
         loop {
             let ret: DivansResult;
@@ -92,7 +242,7 @@ impl<SelectedCDF:CDF16,
                 let output = data.checkout_next_buffer(codec.get_m8().as_mut().unwrap().get_base_alloc(),
                                                            Some(interface::HEADER_LENGTH + 256));
                 if *header_progress != interface::HEADER_LENGTH {
-                    match write_header(header_progress, window_size, output, &mut output_offset, codec.get_crc()) {
+                    match write_header(header_progress, window_size, filters, entropy_coder, cdf_family, mux_policy, stream_count, output, &mut output_offset, codec.get_crc()) {
                         DivansOutputResult::Success => {},
                         _ => panic!("Unexpected failure writing header"),
                     }
@@ -109,7 +259,7 @@ impl<SelectedCDF:CDF16,
                 DivansResult::Success | DivansResult::NeedsMoreInput => {
                     assert_eq!(cmd_offset, cmd.len());
                     data.commit_next_buffer(output_offset);
-                    return;
+                    break;
                 },
                 DivansResult::Failure(m) => Self::do_panic(m),
                 DivansResult::NeedsMoreOutput => {
@@ -117,17 +267,32 @@ impl<SelectedCDF:CDF16,
                 }
             }
         }
+        loop {
+            let mut output_offset = 0usize;
+            let ret = {
+                let output = data.checkout_next_buffer(codec.get_m8().as_mut().unwrap().get_base_alloc(), None);
+                codec.linearize_pending(output, &mut output_offset)
+            };
+            data.commit_next_buffer(output_offset);
+            match ret {
+                DivansOutputResult::Success => return,
+                DivansOutputResult::Failure(m) => Self::do_panic(m),
+                DivansOutputResult::NeedsMoreOutput => {},
+            }
+        }
     }
     fn internal_encode_stream(&mut self,
                               op: BrotliEncoderOperation,
                               input:&[u8], input_offset: &mut usize,
-                              is_end: bool) -> interface::DivansResult {
+                              is_end: bool,
+                              filters: FilterChain) -> interface::DivansResult {
         let mut nothing : Option<usize> = None;
         let mut cb_err:Result<(),ErrMsg> = Ok(());
         {
             let divans_data_ref = &mut self.divans_data;
             let divans_codec_ref = &mut self.codec;
             let header_progress_ref = &mut self.header_progress;
+            let ir_log_ref = &mut self.ir_log;
             let window_size = self.window_size;
             let opt = self.opt;
             let mut cb = |pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
@@ -135,8 +300,10 @@ impl<SelectedCDF:CDF16,
                           mb:brotli::InputPair,
                           mc:&mut AllocBrotli| {
                               let mut expanded_buffer  = <AllocBrotli as Allocator<brotli::enc::StaticCommand>>::AllocatedMemory::default();
+                              let mut split_buffer = <AllocBrotli as Allocator<brotli::enc::StaticCommand>>::AllocatedMemory::default();
+                              let mut split_context_map = <AllocBrotli as Allocator<u8>>::AllocatedMemory::default();
                               {
-                                  let final_cmd = if opt.divans_ir_optimizer != 0 {
+                                  let mut final_cmd = if opt.divans_ir_optimizer != 0 {
                                       match super::ir_optimize::ir_optimize(pm, a, mb, divans_codec_ref, window_size, opt, mc, &mut  expanded_buffer) {
                                           Ok(buf) => buf,
                                           Err(e) => {cb_err = Err(e); return;},
@@ -144,28 +311,67 @@ impl<SelectedCDF:CDF16,
                                   } else {
                                       a
                                   };
+                                  if opt.block_split && opt.use_context_map {
+                                      if let Some(len) = super::ir_optimize::block_split::split_metablock::<SelectedCDF, AllocBrotli>(
+                                          final_cmd, mb, pm.literal_prediction_mode(), pm.literal_context_map.slice(),
+                                          mc, &mut split_buffer, &mut split_context_map) {
+                                          final_cmd = &split_buffer.slice()[..len];
+                                      }
+                                  }
+                                  let literal_context_map = if split_context_map.len() != 0 {
+                                      brotli::InputReference{data: split_context_map.slice(), orig_offset: 0}
+                                  } else {
+                                      brotli::InputReference::from(&pm.literal_context_map)
+                                  };
                                   let tmp = Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
-                                      literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
+                                      literal_context_map,
                                       predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
                                   });
+                                  log_command(ir_log_ref, &tmp);
+                                  for cmd in final_cmd.iter() {
+                                      log_command(ir_log_ref, &brotli::thaw_pair(cmd, &mb));
+                                  }
                                   Self::divans_encode_commands(&CommandSliceArray(&[tmp]),
                                                                header_progress_ref,
                                                                divans_data_ref,
                                                                divans_codec_ref,
-                                                               window_size);
+                                                               window_size,
+                                                               filters,
+                                                               opt.entropy_coder,
+                                                               opt.cdf_family,
+                                                               opt.mux_policy,
+                                                               opt.stream_count);
                                   if final_cmd.len() != 0 {
                                       Self::divans_encode_commands(&ThawingSliceArray(final_cmd, mb),
                                                                    header_progress_ref,
                                                                    divans_data_ref,
                                                                    divans_codec_ref,
-                                                                   window_size);
+                                                                   window_size,
+                                                                   filters,
+                                                                   opt.entropy_coder,
+                                                               opt.cdf_family,
+                                                               opt.mux_policy,
+                                                               opt.stream_count);
                                   }
                               }
                               if expanded_buffer.len() != 0 {
                                   <AllocBrotli as Allocator<brotli::enc::StaticCommand>>::free_cell(mc, expanded_buffer);
                               }
+                              if split_buffer.len() != 0 {
+                                  <AllocBrotli as Allocator<brotli::enc::StaticCommand>>::free_cell(mc, split_buffer);
+                              }
+                              if split_context_map.len() != 0 {
+                                  <AllocBrotli as Allocator<u8>>::free_cell(mc, split_context_map);
+                              }
             };
-            {
+            if let Some(ref mut matcher) = self.internal_matcher {
+                if is_end && matcher.finished {
+                    return DivansResult::Success;
+                }
+                if let Err(e) = matcher.encode(&mut self.brotli_encoder.m8, input, input_offset, is_end, &mut cb) {
+                    return DivansResult::Failure(e);
+                }
+            } else {
                 let mut available_in = input.len() - *input_offset;
                 if available_in == 0 && BrotliEncoderIsFinished(&mut self.brotli_encoder) != 0 {
                     return DivansResult::Success;
@@ -198,7 +404,7 @@ impl<SelectedCDF:CDF16,
         if let Err(e) = cb_err {
             return DivansResult::Failure(e);
         }
-        if is_end && BrotliEncoderIsFinished(&mut self.brotli_encoder) == 0 {
+        if is_end && self.internal_matcher.is_none() && BrotliEncoderIsFinished(&self.brotli_encoder) == 0 {
             return DivansResult::NeedsMoreOutput;
         }
         if is_end {
@@ -220,7 +426,25 @@ impl<SelectedCDF:CDF16,
             return DivansResult::NeedsMoreInput
         }
     }
+    fn encode_filtered(&mut self,
+                       filters: FilterChain,
+                       input: &[u8],
+                       input_offset: &mut usize) -> DivansResult {
+        match self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
+                                          input,
+                                          input_offset,
+                                          false,
+                                          filters) {
+            DivansResult::NeedsMoreOutput => DivansResult::Failure(ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush),
+            DivansResult::Failure(m) => DivansResult::Failure(m),
+            DivansResult::Success | DivansResult::NeedsMoreInput => DivansResult::NeedsMoreInput,
+        }
+    }
     fn free_internal(&mut self) {
+        if let Some(matcher) = self.internal_matcher.take() {
+            matcher.free(&mut self.brotli_encoder.m8);
+        }
+        self.filter.free(self.codec.get_m8().as_mut().unwrap().get_base_alloc());
         self.brotli_data.free(&mut self.brotli_encoder.m8);
         self.divans_data.free(&mut self.codec.get_m8().as_mut().unwrap().get_base_alloc());
         brotli::enc::encode::BrotliEncoderDestroyInstance(&mut self.brotli_encoder);
@@ -240,32 +464,42 @@ impl<SelectedCDF:CDF16,
      ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
      AllocU8:Allocator<u8>,
      AllocCDF16:Allocator<SelectedCDF>,
-     AllocBrotli: brotli::enc::BrotliAlloc>
+     AllocBrotli: brotli::enc::BrotliAlloc,
+     LinearOutputBytes:StreamMuxer<AllocU8>+Default>
      Compressor for BrotliDivansHybridCompressor<SelectedCDF,
                                                  ChosenEncoder,
                                                  AllocU8,
                                                  AllocCDF16,
-                                                 AllocBrotli> {
+                                                 AllocBrotli,
+                                                 LinearOutputBytes> {
     fn encode(&mut self,
               input: &[u8],
               input_offset: &mut usize,
               _output: &mut [u8],
               _output_offset: &mut usize) -> DivansResult {
-        match self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_PROCESS,
-                                          input,
-                                          input_offset,
-                                          false) {
-            DivansResult::NeedsMoreOutput => DivansResult::Failure(ErrMsg::BrotliInternalEncodeStreamNeedsOutputWithoutFlush),
-            DivansResult::Failure(m) => DivansResult::Failure(m),
-            DivansResult::Success | DivansResult::NeedsMoreInput => DivansResult::NeedsMoreInput,
-        }
+        let mut filter = core::mem::take(&mut self.filter);
+        let ret = filter.encode(input, input_offset, |filters, filtered, filtered_offset| {
+            self.encode_filtered(filters, filtered, filtered_offset)
+        });
+        self.filter = filter;
+        ret
     }
     fn flush(&mut self,
              output: &mut [u8],
              output_offset: &mut usize) -> DivansOutputResult {
         let mut zero = 0usize;
+        let mut filter = core::mem::take(&mut self.filter);
+        let filter_ret = filter.flush(|filters, filtered, filtered_offset| {
+            self.encode_filtered(filters, filtered, filtered_offset)
+        });
+        self.filter = filter;
+        match filter_ret {
+            DivansOutputResult::Success => {},
+            res => return res,
+        }
+        let filters = self.filter.chain();
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset, self.codec.get_crc()) {
+            match write_header(&mut self.header_progress, self.window_size, filters, self.opt.entropy_coder, self.opt.cdf_family, self.opt.mux_policy, self.opt.stream_count, output, output_offset, self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 need => return need,
             }
@@ -275,7 +509,8 @@ impl<SelectedCDF:CDF16,
             match self.internal_encode_stream(BrotliEncoderOperation::BROTLI_OPERATION_FINISH,
                                               &[],
                                               &mut zero,
-                                              true) {
+                                              true,
+                                              filters) {
                 DivansResult::Failure(m) => return DivansOutputResult::Failure(m),
                 DivansResult::Success => break,
                 DivansResult::NeedsMoreOutput => {},
@@ -300,7 +535,8 @@ impl<SelectedCDF:CDF16,
                                                            output :&mut[u8],
                                                            output_offset: &mut usize) -> DivansOutputResult {
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset, self.codec.get_crc()) {
+            // commands describe the unfiltered bytes, so no filters are signalled
+            match write_header(&mut self.header_progress, self.window_size, FilterChain::default(), self.opt.entropy_coder, self.opt.cdf_family, self.opt.mux_policy, self.opt.stream_count, output, output_offset, self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return res,
             }
@@ -341,112 +577,171 @@ impl<AllocU8:Allocator<u8>,
                                                                AllocCDF16,
                                                                AllocBrotli>;
       type AdditionalArgs = AllocBrotli;
-        fn new(mut m8: AllocU8, _m32: AllocU32, mcdf16:AllocCDF16,
+        fn new(m8: AllocU8, _m32: AllocU32, mcdf16:AllocCDF16,
                opt: super::interface::DivansCompressorOptions,
                additional_args: Self::AdditionalArgs) -> Self::ConstructedCompressor {
-        let window_size = min(24, max(10, opt.window_size.unwrap_or(22)));
-        let cmd_enc = Self::DefaultEncoder::new(&mut m8);
-        let lit_enc = Self::DefaultEncoder::new(&mut m8);
-        let mut ret = Self::ConstructedCompressor {
-             brotli_data: ResizableByteBuffer::<u8, AllocBrotli>::new(),
-             divans_data: ResizableByteBuffer::<u8, AllocU8>::new(),
-             encoded_byte_offset:0, 
-             brotli_encoder: brotli::enc::encode::BrotliEncoderCreateInstance(additional_args),
-            codec:DivansCodec::<Self::DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16>::new(
-                m8,
-                mcdf16,
-                cmd_enc,
-                lit_enc,
-                EncoderSpecialization::new(),
-                DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
-                window_size as usize,
-                opt.dynamic_context_mixing.unwrap_or(0),
-                opt.prior_depth,
-                opt.literal_adaptation,
-                opt.use_context_map,
-                opt.force_stride_value,
-                false,
-            ),
-            opt:opt,
-            header_progress: 0,
-            window_size: window_size as u8,
-        };
-        if let Some(prediction_mode) = opt.force_literal_context_mode {
-            brotli::enc::encode::BrotliEncoderSetParameter(
-                &mut ret.brotli_encoder,
-                brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_MODE,
-                match prediction_mode.0 {
-                    LITERAL_PREDICTION_MODE_LSB6 => BrotliEncoderMode::BROTLI_FORCE_LSB_PRIOR as u32,
-                    LITERAL_PREDICTION_MODE_MSB6 => BrotliEncoderMode::BROTLI_FORCE_MSB_PRIOR as u32,
-                    LITERAL_PREDICTION_MODE_UTF8 => BrotliEncoderMode::BROTLI_FORCE_UTF8_PRIOR as u32,
-                    LITERAL_PREDICTION_MODE_SIGN => BrotliEncoderMode::BROTLI_FORCE_SIGNED_PRIOR as u32,
-                    _ => BrotliEncoderMode::BROTLI_FORCE_SIGNED_PRIOR as u32,
-                });
-        }
-        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_LGWIN,
-                                                       window_size as u32);
-        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_LGBLOCK,
-                                                       opt.lgblock.unwrap_or(18));
-        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_QUALITY,
-                                                       u32::from(opt.quality.unwrap_or(10)));
-        if opt.q9_5 {
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_Q9_5,
-                                                       1);
+        Self::ConstructedCompressor::new(m8, mcdf16, opt, additional_args)
+    }
+}
+
+
+// the raw_to_cmd matcher, run a metablock at a time so that its commands reach the same
+// IR optimizer and block splitter as brotli's
+struct InternalMatcher<AllocBrotli: brotli::enc::BrotliAlloc> {
+    assembler: raw_to_cmd::RawToCmdState<<AllocBrotli as Allocator<u8>>::AllocatedMemory, AllocBrotli>,
+    literal_context_map: <AllocBrotli as Allocator<u8>>::AllocatedMemory,
+    prediction_mode: <AllocBrotli as Allocator<u8>>::AllocatedMemory,
+    // how much of each map the assembler's prediction mode command uses
+    context_map_len: (usize, usize),
+    metablock: <AllocBrotli as Allocator<u8>>::AllocatedMemory,
+    metablock_len: usize,
+    // bytes of the metablock given to the assembler and covered by its commands
+    streamed_len: usize,
+    parsed_len: usize,
+    commands: <AllocBrotli as Allocator<StaticCommand>>::AllocatedMemory,
+    num_commands: usize,
+    finished: bool,
+}
+
+impl<AllocBrotli: brotli::enc::BrotliAlloc> InternalMatcher<AllocBrotli> {
+    fn new(m: &mut AllocBrotli, window_size: u8, opt: &interface::DivansCompressorOptions) -> Self {
+        let ring_buffer = <AllocBrotli as Allocator<u8>>::alloc_cell(m, 2 << window_size);
+        let metablock_size = 1usize << min(u32::from(window_size), opt.lgblock.unwrap_or(18).clamp(16, 24));
+        InternalMatcher {
+            assembler: raw_to_cmd::RawToCmdState::new(m, ring_buffer, window_size, opt.quality.unwrap_or(11)),
+            literal_context_map: <AllocBrotli as Allocator<u8>>::alloc_cell(m, interface::MAX_LITERAL_CONTEXT_MAP_SIZE),
+            prediction_mode: <AllocBrotli as Allocator<u8>>::alloc_cell(m, interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE),
+            context_map_len: (0, 0),
+            metablock: <AllocBrotli as Allocator<u8>>::alloc_cell(m, metablock_size),
+            metablock_len: 0,
+            streamed_len: 0,
+            parsed_len: 0,
+            commands: <AllocBrotli as Allocator<StaticCommand>>::AllocatedMemory::default(),
+            num_commands: 0,
+            finished: false,
         }
-        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_METABLOCK_CALLBACK,
-                                                       1);
-        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CDF_ADAPTATION_DETECTION,
-                                                       u32::from(opt.speed_detection_quality.unwrap_or(0)));
-        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_STRIDE_DETECTION_QUALITY,
-                                                       u32::from(opt.stride_detection_quality.unwrap_or(0)));
-        if let Some(literal_byte_score) = opt.brotli_literal_byte_score {
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_LITERAL_BYTE_SCORE,
-                                                           literal_byte_score);
+    }
+    // runs the assembler over the metablock bytes it has not seen; is_final parses up to the last byte
+    fn parse(&mut self, m: &mut AllocBrotli, is_final: bool) -> Result<(), ErrMsg> {
+        loop {
+            let mut batch = [Command::<brotli::InputReference>::default(); 16];
+            let mut batch_len = 0usize;
+            let done = if is_final && self.streamed_len == self.metablock_len {
+                match self.assembler.flush(&mut batch[..], &mut batch_len,
+                                           self.literal_context_map.slice_mut(), self.prediction_mode.slice_mut()) {
+                    DivansOutputResult::Success => true,
+                    DivansOutputResult::NeedsMoreOutput => false,
+                    DivansOutputResult::Failure(e) => return Err(e),
+                }
+            } else {
+                match self.assembler.stream(&self.metablock.slice()[..self.metablock_len], &mut self.streamed_len,
+                                            &mut batch[..], &mut batch_len,
+                                            self.literal_context_map.slice_mut(), self.prediction_mode.slice_mut()) {
+                    DivansResult::NeedsMoreInput => !is_final,
+                    DivansResult::NeedsMoreOutput => false,
+                    DivansResult::Success => return Err(ErrMsg::AssemblerStreamReportsDone),
+                    DivansResult::Failure(e) => return Err(e),
+                }
+            };
+            for cmd in batch[..batch_len].iter() {
+                let cmd = match *cmd {
+                    Command::PredictionMode(ref pm) => {
+                        self.context_map_len = (pm.literal_context_map.len(), pm.predmode_speed_and_distance_context_map.len());
+                        continue;
+                    },
+                    Command::Literal(ref lit) => {
+                        self.parsed_len += lit.data.len();
+                        Command::Literal(LiteralCommand{
+                            data: brotli::SliceOffset(self.parsed_len - lit.data.len(), lit.data.len() as u32),
+                            prob: FeatureFlagSliceType::default(),
+                            high_entropy: lit.high_entropy,
+                        })
+                    },
+                    Command::Copy(ref copy) => {
+                        self.parsed_len += copy.num_bytes as usize;
+                        Command::Copy(*copy)
+                    },
+                    Command::Dict(ref dict) => {
+                        self.parsed_len += dict.final_size as usize;
+                        Command::Dict(*dict)
+                    },
+                    Command::BlockSwitchLiteral(ref switch) => Command::BlockSwitchLiteral(*switch),
+                    Command::BlockSwitchCommand(ref switch) => Command::BlockSwitchCommand(*switch),
+                    Command::BlockSwitchDistance(ref switch) => Command::BlockSwitchDistance(*switch),
+                };
+                if self.num_commands == self.commands.len() {
+                    let mut grown = <AllocBrotli as Allocator<StaticCommand>>::alloc_cell(m, core::cmp::max(1024, self.num_commands * 2));
+                    grown.slice_mut()[..self.num_commands].clone_from_slice(&self.commands.slice()[..self.num_commands]);
+                    <AllocBrotli as Allocator<StaticCommand>>::free_cell(m, core::mem::replace(&mut self.commands, grown));
+                }
+                self.commands.slice_mut()[self.num_commands] = cmd;
+                self.num_commands += 1;
+            }
+            if done {
+                return Ok(());
+            }
         }
-        
-        brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                       brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_PRIOR_BITMASK_DETECTION,
-                                                       u32::from(opt.prior_bitmask_detection));
-        if let Some(speed) = opt.literal_adaptation {
-
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED,
-                                                           speed[3].inc() as u32);
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED_MAX,
-                                                           speed[3].lim() as u32);
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED_LOW,
-                                                           speed[2].inc() as u32);
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_CM_SPEED_LOW_MAX,
-                                                           speed[2].lim() as u32);
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED,
-                                                           speed[1].inc() as u32);
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED_MAX,
-                                                           speed[1].lim() as u32);
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED_LOW,
-                                                           speed[0].inc() as u32);
-            brotli::enc::encode::BrotliEncoderSetParameter(&mut ret.brotli_encoder,
-                                                           brotli::enc::encode::BrotliEncoderParameter::BROTLI_PARAM_SPEED_LOW_MAX,
-                                                           speed[0].lim() as u32);
+    }
+    // fills the metablock from input and hands every completed metablock to cb the way brotli would
+    fn encode<Cb>(&mut self,
+                  m: &mut AllocBrotli,
+                  input: &[u8],
+                  input_offset: &mut usize,
+                  is_end: bool,
+                  cb: &mut Cb) -> Result<(), ErrMsg>
+        where Cb: FnMut(&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                        &mut [StaticCommand],
+                        brotli::InputPair,
+                        &mut AllocBrotli) {
+        loop {
+            let to_copy = min(input.len() - *input_offset, self.metablock.len() - self.metablock_len);
+            self.metablock.slice_mut()[self.metablock_len..(self.metablock_len + to_copy)].clone_from_slice(
+                &input[*input_offset..(*input_offset + to_copy)]);
+            *input_offset += to_copy;
+            self.metablock_len += to_copy;
+            let is_final = self.metablock_len == self.metablock.len() || (is_end && *input_offset == input.len());
+            self.parse(m, is_final)?;
+            if !is_final {
+                return Ok(());
+            }
+            if self.metablock_len != 0 {
+                if self.parsed_len != self.metablock_len {
+                    return Err(ErrMsg::AssemblerStreamReportsDone);
+                }
+                let mut pm = PredictionModeContextMap::<brotli::InputReferenceMut>{
+                    literal_context_map: brotli::InputReferenceMut{
+                        data: &mut self.literal_context_map.slice_mut()[..self.context_map_len.0],
+                        orig_offset: 0,
+                    },
+                    predmode_speed_and_distance_context_map: brotli::InputReferenceMut{
+                        data: &mut self.prediction_mode.slice_mut()[..self.context_map_len.1],
+                        orig_offset: 0,
+                    },
+                };
+                let mb = brotli::InputPair(brotli::InputReference{data: &self.metablock.slice()[..self.metablock_len], orig_offset: 0},
+                                           brotli::InputReference{data: &[], orig_offset: self.metablock_len});
+                cb(&mut pm, &mut self.commands.slice_mut()[..self.num_commands], mb, m);
+            }
+            self.metablock_len = 0;
+            self.streamed_len = 0;
+            self.parsed_len = 0;
+            self.num_commands = 0;
+            if *input_offset == input.len() {
+                self.finished = is_end;
+                return Ok(());
+            }
         }
-        ret
+    }
+    fn free(mut self, m: &mut AllocBrotli) {
+        self.assembler.free(m);
+        <AllocBrotli as Allocator<u8>>::free_cell(m, core::mem::take(&mut self.assembler.ring_buffer));
+        <AllocBrotli as Allocator<u8>>::free_cell(m, self.literal_context_map);
+        <AllocBrotli as Allocator<u8>>::free_cell(m, self.prediction_mode);
+        <AllocBrotli as Allocator<u8>>::free_cell(m, self.metablock);
+        <AllocBrotli as Allocator<StaticCommand>>::free_cell(m, self.commands);
     }
 }
 
-
 struct ThawingSliceArray<'a>(&'a [brotli::interface::Command<brotli::SliceOffset>],
                              brotli::InputPair<'a>);
 
@@ -14,11 +14,18 @@
 
 use alloc::{SliceWrapper, Allocator};
 
-use codec::EncoderOrDecoderSpecialization;
+use codec::{DivansCodec, EncoderOrDecoderSpecialization};
+use codec::io::DemuxerAndRingBuffer;
+use mux::DevNull;
 
 use super::interface::{CopyCommand,DictCommand,LiteralCommand,Command};
 
 use slice_util::AllocatedMemoryPrefix;
+
+// the codec both compressors drive; only the entropy coder and the output muxer vary
+pub type EncoderCodec<ChosenEncoder, LinearOutputBytes, Cdf16, AllocU8, AllocCDF16> =
+    DivansCodec<ChosenEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, LinearOutputBytes, Cdf16, AllocU8, AllocCDF16>;
+
 pub struct EncoderSpecialization {
     backing: [u8; 128],
     max_size: usize,
@@ -16,8 +16,19 @@ use ::interface::{
     u8_to_speed,
     MAX_LITERAL_CONTEXT_MAP_SIZE,
     MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE,
+    DISTANCE_CONTEXT_MAP_OFFSET,
     NUM_MIXING_VALUES,
 };
+
+// undoes PredictionModeState::truncate_to_coded_len before the maps are looked up by context
+pub fn restore_max_len<AllocU8:Allocator<u8>>(pm: &mut PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>) {
+    pm.literal_context_map.1 = core::cmp::min(pm.literal_context_map.max_len(),
+                                              MAX_LITERAL_CONTEXT_MAP_SIZE) as u32;
+    if pm.has_context_speeds() {
+        pm.predmode_speed_and_distance_context_map.1 = core::cmp::min(pm.predmode_speed_and_distance_context_map.max_len(),
+                                                                      MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE) as u32;
+    }
+}
 use ::priors::PriorCollection;
 use ::probability::{Speed, CDF16, SpeedPalette};
 
@@ -26,6 +37,8 @@ use ::probability::{Speed, CDF16, SpeedPalette};
 pub struct PredictionModeState<AllocU8:Allocator<u8>> {
     pub pm:PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>,
     pub state: PredictionModeSubstate,
+    // entries the stream carried for the literal and distance maps, which are allocated at their maximum size
+    pub coded_len: [u32; 2],
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -91,6 +104,19 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
             };
         }
         self.state = PredictionModeSubstate::Begin;
+        self.coded_len = [0, 0];
+    }
+    // shortens the maps to the entries that were coded, so the command describes the stream exactly
+    pub fn truncate_to_coded_len(&mut self) {
+        if self.pm.literal_context_map.1 > self.coded_len[ContextMapType::Literal as usize] {
+            self.pm.literal_context_map.1 = self.coded_len[ContextMapType::Literal as usize];
+        }
+        if self.pm.has_context_speeds() {
+            let distance_len = DISTANCE_CONTEXT_MAP_OFFSET as u32 + self.coded_len[ContextMapType::Distance as usize];
+            if self.pm.predmode_speed_and_distance_context_map.1 > distance_len {
+                self.pm.predmode_speed_and_distance_context_map.1 = distance_len;
+            }
+        }
     }
     pub fn nop() -> Self {
         PredictionModeState::<AllocU8> {
@@ -99,6 +125,7 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                 predmode_speed_and_distance_context_map:AllocatedMemoryPrefix::<u8, AllocU8>::default(),
             },
             state:PredictionModeSubstate::Begin,
+            coded_len: [0, 0],
         }
     }
     #[cfg_attr(not(feature="no-inline"), inline(always))]
@@ -295,6 +322,7 @@ impl <AllocU8:Allocator<u8>> PredictionModeState<AllocU8> {
                        }
                    }
                    if mnemonic_nibble == 14 {
+                       self.coded_len[context_map_type as usize] = index;
                        match context_map_type {
                            ContextMapType::Literal => { // switch to distance context map
                                superstate.bk.reset_context_map_lru(); // distance context map should start with 0..14 as lru
@@ -14,19 +14,20 @@ Redistribution and use in source and binary forms, with or without modification,
 #[allow(unused_imports)]
 use core;
 use super::crc32_table::TABLE16;
+use cpu_features::CpuFeatures;
 pub fn crc32c_init() -> u32 {
     0
 }
-#[cfg(not(all(feature="simd", not(feature="portable-simd"), not(feature="safe"), any(target_arch="x86", target_arch="x86_64"))))]
+#[cfg(any(feature="safe", not(target_arch="x86_64")))]
 #[inline(always)]
-pub fn crc32c_update(crc:u32, buf: &[u8]) -> u32 {
+pub fn crc32c_update(_kernels: CpuFeatures, crc:u32, buf: &[u8]) -> u32 {
     fallback_crc32c_update(crc, buf)
 }
 
-#[cfg(all(feature="simd", not(feature="portable-simd"), not(feature="safe"), any(target_arch="x86", target_arch="x86_64")))]
+#[cfg(all(not(feature="safe"), target_arch="x86_64"))]
 #[inline(always)]
-pub fn crc32c_update(crc:u32, buf: &[u8]) -> u32 {
-    if is_x86_feature_detected!("sse4.2") {
+pub fn crc32c_update(kernels: CpuFeatures, crc:u32, buf: &[u8]) -> u32 {
+    if kernels.sse42 {
         return unsafe {
             sse_crc32c_update(crc, buf)
         };
@@ -62,15 +63,8 @@ pub fn fallback_crc32c_update(mut crc:u32, mut buf: &[u8]) -> u32 {
     }
     !crc
 }
-#[cfg(feature="simd")]
-#[cfg(not(target_arch = "x86_64"))]
-fn sse_crc32c_update(_crc:u32, _buf: &[u8]) -> u32 {
-  unimplemented!();
-}
-#[cfg(feature="simd")]
-#[cfg(target_arch = "x86_64")]
-#[inline(always)]
-//#[target_feature(enable = "sse4.2")]
+#[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+#[target_feature(enable = "sse4.2")]
 unsafe fn sse_crc32c_update(mut crc:u32, mut buf: &[u8]) -> u32 {
     crc = !crc;
     while buf.len() >= 8 {
@@ -87,30 +81,52 @@ unsafe fn sse_crc32c_update(mut crc:u32, mut buf: &[u8]) -> u32 {
 mod test {
     #[cfg(test)]
     use super::{crc32c_init, crc32c_update};
+    #[cfg(test)]
+    use cpu_features::detected;
     #[test]
     fn test_crc32c_empty() {
-        assert_eq!(crc32c_update(crc32c_init(), &[]), 0x0);
+        assert_eq!(crc32c_update(detected(), crc32c_init(), &[]), 0x0);
     }
     #[test]
     fn test_crc32c_numeric() {
         let slice = b"123456789";
-        assert_eq!(crc32c_update(crc32c_init(), slice), 0xe3069283);
+        assert_eq!(crc32c_update(detected(), crc32c_init(), slice), 0xe3069283);
     }
     #[test]
     fn test_crc32c_numeric_half() {
         let slice = b"123456789";
         let (firsthalf, secondhalf) = slice.split_at(5);
-        assert_eq!(crc32c_update(crc32c_update(crc32c_init(), firsthalf), secondhalf), 0xe3069283);
+        assert_eq!(crc32c_update(detected(), crc32c_update(detected(), crc32c_init(), firsthalf), secondhalf), 0xe3069283);
     }
     #[test]
     fn test_crc32c_qbf() {
         let slice = b"The quick brown fox jumps over the lazy dog";
-        assert_eq!(crc32c_update(crc32c_init(), slice), 0x22620404);
+        assert_eq!(crc32c_update(detected(), crc32c_init(), slice), 0x22620404);
     }
     #[test]
     fn test_crc32c_qbf_half() {
         let slice = b"The quick brown fox jumps over the lazy dog";
         let (firsthalf, secondhalf) = slice.split_at(18);
-        assert_eq!(crc32c_update(crc32c_update(crc32c_init(), firsthalf), secondhalf), 0x22620404);
+        assert_eq!(crc32c_update(detected(), crc32c_update(detected(), crc32c_init(), firsthalf), secondhalf), 0x22620404);
+    }
+    #[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+    #[test]
+    fn test_crc32c_sse_matches_fallback() {
+        if !detected().sse42 {
+            return;
+        }
+        let mut data = [0u8; 1031];
+        let mut state = 0x1234_5678u32;
+        for item in data.iter_mut() {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            *item = (state >> 16) as u8;
+        }
+        let mut crc = crc32c_init();
+        for start in 0..data.len() {
+            let chunk = &data[start..core::cmp::min(start + start % 37, data.len())];
+            let expected = super::fallback_crc32c_update(crc, chunk);
+            assert_eq!(unsafe { super::sse_crc32c_update(crc, chunk) }, expected);
+            crc = expected;
+        }
     }
 }
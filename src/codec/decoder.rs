@@ -8,8 +8,10 @@ use ::slice_util::{AllocatedMemoryPrefix, AllocatedMemoryRange};
 use ::alloc_util::UninitializedOnAlloc;
 use ::divans_to_raw::DecoderSpecialization;
 use super::literal::{LiteralState, LiteralSubstate};
+use super::context_map;
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
 use super::crc32::{crc32c_init,crc32c_update};
+use cpu_features::CpuFeatures;
 use super::interface::{
     MainThreadContext,
     CMD_CODER,
@@ -32,7 +34,37 @@ use ::interface::{
     free_cmd,
 };
 
+use filter::{FilterChain, FilterDecoder};
 use threading::{MainToThread, PullAllocatedCommand, CommandResult, NUM_SERIAL_COMMANDS_BUFFERED, StaticCommand};
+#[cfg(feature="std")]
+use std::vec::Vec;
+
+// copies of the decoded commands, kept only when the caller asked for the IR
+#[cfg(feature="std")]
+pub type CommandLog = Option<Vec<::ir::IrCommand>>;
+#[cfg(not(feature="std"))]
+pub type CommandLog = ();
+
+#[cfg(feature="std")]
+pub fn log_command<SliceType:SliceWrapper<u8>>(log: &mut CommandLog, cmd: &Command<SliceType>) {
+    if let Some(ref mut log) = *log {
+        log.push(::ir::to_ir_command(cmd));
+    }
+}
+#[cfg(feature="std")]
+fn log_prediction_mode<SliceType:SliceWrapper<u8>>(log: &mut CommandLog, pm: &PredictionModeContextMap<SliceType>) {
+    if let Some(ref mut log) = *log {
+        log.push(Command::PredictionMode(::ir::to_ir_context_map(pm)));
+    }
+}
+#[cfg(not(feature="std"))]
+#[cfg_attr(not(feature="no-inline"), inline(always))]
+pub fn log_command<SliceType:SliceWrapper<u8>>(_log: &mut CommandLog, _cmd: &Command<SliceType>) {
+}
+#[cfg(not(feature="std"))]
+#[cfg_attr(not(feature="no-inline"), inline(always))]
+fn log_prediction_mode<SliceType:SliceWrapper<u8>>(_log: &mut CommandLog, _pm: &PredictionModeContextMap<SliceType>) {
+}
 
 pub struct DivansDecoderCodec<Cdf16:CDF16,
                           AllocU8:Allocator<u8>,
@@ -60,6 +92,8 @@ pub struct DivansDecoderCodec<Cdf16:CDF16,
     pub cmd_buffer_offset: usize,
     pub cmd_buffer_contains_eof: bool,
     pub pred_buffer: [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>;2],
+    pub filter: FilterDecoder<AllocU8>,
+    pub ir_log: CommandLog,
 }
 
 impl<Cdf16:CDF16,
@@ -98,6 +132,25 @@ impl<Cdf16:CDF16,
             cmd_buffer_contains_eof:false,
             pred_buffer: [empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>(),
                           empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>()],
+            filter: FilterDecoder::default(),
+            ir_log: CommandLog::default(),
+        }
+    }
+    pub fn set_filters(&mut self, filters: FilterChain) {
+        self.filter.free(self.ctx.m8.get_base_alloc());
+        self.filter = FilterDecoder::new(self.ctx.m8.get_base_alloc(), filters);
+    }
+    #[cfg(feature="std")]
+    pub fn set_command_log(&mut self, enable: bool) {
+        self.ir_log = if enable { Some(Vec::new()) } else { None };
+    }
+    #[cfg(not(feature="std"))]
+    pub fn set_command_log(&mut self, _enable: bool) {
+    }
+    #[cfg(feature="std")]
+    pub fn take_logged_commands(&mut self, commands: &mut Vec<::ir::IrCommand>) {
+        if let Some(ref mut log) = self.ir_log {
+            commands.append(log);
         }
     }
     pub fn free(&mut self, mcommand: &mut AllocCommand) {
@@ -106,6 +159,7 @@ impl<Cdf16:CDF16,
         self.ctx.m8.get_base_alloc().free_cell(core::mem::replace(&mut self.state_lit.lc.data.0,
                                                                   AllocU8::AllocatedMemory::default()));
         self.demuxer.free_demux(self.ctx.m8.get_base_alloc());
+        self.filter.free(self.ctx.m8.get_base_alloc());
         for item in self.pred_buffer.iter_mut() {
             free_cmd(&mut Command::PredictionMode(core::mem::replace(item,
                                                                      empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>())),
@@ -273,6 +327,7 @@ impl<Cdf16:CDF16,
                                     self.state_populate_ring_buffer = Command::Literal(
                                         core::mem::replace(&mut self.state_lit.lc,
                                                            LiteralCommand::<AllocatedMemoryPrefix<u8, AllocU8>>::nop()));
+                                    log_command(&mut self.ir_log, &self.state_populate_ring_buffer);
                                 },
                                 retval => {
                                     return DecoderResult::Processed(retval);
@@ -358,9 +413,11 @@ impl<Cdf16:CDF16,
             if let &mut Command::Copy(cp) = cur_cmd {
                 self.is_populating_ring_buffer = true;
                 self.state_populate_ring_buffer=Command::Copy(cp);
+                log_command(&mut self.ir_log, &self.state_populate_ring_buffer);
             } else if let &mut Command::Literal(ref lit) = cur_cmd {
                 let num_bytes = lit.data.len();
                 self.state_lit.lc.data = self.ctx.m8.use_cached_allocation::<UninitializedOnAlloc>().alloc_cell(num_bytes);
+                self.state_lit.lc.high_entropy = lit.high_entropy;
                 let last_8 = self.ctx.recoder.last_8_literals();
                 self.ctx.lbk.last_8_literals = //FIXME(threading) only should be run in the main thread
                     u64::from(last_8[0])
@@ -371,7 +428,7 @@ impl<Cdf16:CDF16,
                     | (u64::from(last_8[5])<<0x28)
                     | (u64::from(last_8[6])<<0x30)
                     | (u64::from(last_8[7])<<0x38);
-                let new_state = self.state_lit.get_nibble_code_state(0, &self.state_lit.lc, self.demuxer.read_buffer()[LIT_CODER].bytes_avail());
+                let new_state = self.state_lit.get_nibble_code_state(0, &self.state_lit.lc, self.demuxer.read_buffer(LIT_CODER as StreamID).bytes_avail());
                 self.state_lit.state = new_state;
                 if Worker::COOPERATIVE_MAIN {
                     return DecoderResult::Yield;
@@ -382,7 +439,8 @@ impl<Cdf16:CDF16,
                     let mut pred_mode = empty_prediction_mode_context_map::<AllocatedMemoryPrefix<u8, AllocU8>>();
                         core::mem::swap(&mut pred_mode, &mut self.pred_buffer[1]);
                         core::mem::swap(&mut pred_mode, &mut self.pred_buffer[0]); // shift pred_buffer[1] to pred_buffer[0] and extract [0]
-                        
+                        log_prediction_mode(&mut self.ir_log, &pred_mode);
+                        context_map::restore_max_len(&mut pred_mode);
                         let ret = self.ctx.lbk.obs_prediction_mode_context_map(
                             &pred_mode,
                             &mut self.ctx.mcdf16);
@@ -399,24 +457,59 @@ impl<Cdf16:CDF16,
                     &mut Command::BlockSwitchLiteral(new_block_type) => {
                         self.ctx.lbk.obs_literal_block_switch(new_block_type.clone());
                         self.codec_traits = construct_codec_trait_from_bookkeeping(&self.ctx.lbk);
+                        log_command(&mut self.ir_log, &Command::<AllocatedMemoryPrefix<u8, AllocU8>>::BlockSwitchLiteral(new_block_type));
                     },
                     &mut Command::BlockSwitchCommand(mcc) => {
                         self.is_populating_ring_buffer = true;
                         self.state_populate_ring_buffer=Command::BlockSwitchCommand(mcc);
+                        log_command(&mut self.ir_log, &self.state_populate_ring_buffer);
                     },
                     &mut Command::BlockSwitchDistance(mcc) =>{
                         self.is_populating_ring_buffer = true;
                         self.state_populate_ring_buffer=Command::BlockSwitchDistance(mcc);
+                        log_command(&mut self.ir_log, &self.state_populate_ring_buffer);
                     },
                     &mut Command::Dict(dc) => {
                         self.is_populating_ring_buffer = true;
                         self.state_populate_ring_buffer=Command::Dict(dc);
+                        log_command(&mut self.ir_log, &self.state_populate_ring_buffer);
                     },  
                     &mut Command::Literal(_) | &mut Command::Copy(_) => unreachable!(),
                 }
             }
         }
     }
+    // decodes into the filter's block buffer and hands back bytes once the inverse filters have run
+    pub fn decode_process_filtered_output<Worker: MainToThread<AllocU8>+PullAllocatedCommand<AllocU8, AllocCommand>>(&mut self,
+                                                                worker:&mut Worker,
+                                                                output: &mut [u8],
+                                                                output_offset: &mut usize) -> DecoderResult{
+        if self.filter.is_identity() {
+            return self.decode_process_output(worker, output, output_offset);
+        }
+        let mut filter = core::mem::take(&mut self.filter);
+        let ret = loop {
+            if !filter.drain(output, output_offset) {
+                break DecoderResult::Processed(DivansResult::NeedsMoreOutput);
+            }
+            if filter.is_done() {
+                break DecoderResult::Processed(DivansResult::Success);
+            }
+            match filter.fill(|block, block_offset| self.decode_process_output(worker, block, block_offset)) {
+                DecoderResult::Processed(DivansResult::Success) => {
+                    filter.seal(true);
+                },
+                DecoderResult::Processed(DivansResult::NeedsMoreOutput) => {
+                    if !filter.seal(false) {
+                        break DecoderResult::Processed(DivansResult::NeedsMoreOutput);
+                    }
+                },
+                other => break other,
+            }
+        };
+        self.filter = filter;
+        ret
+    }
     pub fn decode<Worker: MainToThread<AllocU8> + PullAllocatedCommand<AllocU8, AllocCommand> >(&mut self,
                                                  worker:&mut Worker,
                                                  input: &[u8],
@@ -427,28 +520,34 @@ impl<Cdf16:CDF16,
             DivansInputResult::Success => {},
             need_something => return DivansResult::from(need_something),
         }
-        match self.decode_process_output(worker, output, output_offset) {
+        match self.decode_process_filtered_output(worker, output, output_offset) {
             DecoderResult::Processed(retval) => retval,
             DecoderResult::Yield => unreachable!(),
         }
     }
 }
 
+// a running crc32c and the kernels it is computed with
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub struct SubDigest(u32);
+pub struct SubDigest(u32, CpuFeatures);
 impl core::hash::Hasher for SubDigest {
     #[inline(always)]
     fn write(&mut self, data:&[u8]) {
-        self.0 = crc32c_update(self.0, data)
+        self.0 = crc32c_update(self.1, self.0, data)
     }
     #[inline(always)]
     fn finish(&self) -> u64 {
         u64::from(self.0)
     }
 }
+impl SubDigest {
+    pub fn new(kernels: CpuFeatures) -> Self {
+        SubDigest(crc32c_init(), kernels)
+    }
+}
 #[inline(always)]
 pub fn default_crc() -> SubDigest {
-    SubDigest(crc32c_init())
+    SubDigest::new(::cpu_features::detected())
 }
 
 impl Default for SubDigest {
@@ -1,7 +1,7 @@
 use core;
 use brotli;
 use brotli::interface::Nop;
-use interface::{DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, DivansResult, WritableBytes};
+use interface::{DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, StreamID, DivansResult, WritableBytes};
 use ::cmd_to_raw::{DivansRecodeState, RingBufferSnapshot};
 use ::probability::{CDF16, Speed};
 use alloc::{SliceWrapper, Allocator, SliceWrapperMut};
@@ -26,6 +26,7 @@ use ::interface::{
     NewWithAllocator,
     MAX_LITERAL_CONTEXT_MAP_SIZE,
     EncoderOrDecoderRecoderSpecialization,
+    CDFFamily,
 };
 use super::priors::{
     LiteralNibblePriors,
@@ -137,6 +138,23 @@ pub struct LiteralBookKeeping<Cdf16:CDF16,
     pub mixing_mask:[u8; 8192],
     pub model_weights: [super::weights::Weights;2],
     pub lit_cm_priors: LiteralCommandPriorsCM<Cdf16, AllocCDF16>,
+    // a fresh CDF of the stream's model family, for priors allocated mid-stream
+    pub untrained_cdf: Cdf16,
+}
+
+// allocates priors that start out untrained in the stream's model family
+fn alloc_untrained_priors<Cdf16:CDF16,
+                          AllocCDF16:Allocator<Cdf16>>(mcdf16: &mut AllocCDF16,
+                                                       len: usize,
+                                                       untrained_cdf: Cdf16) -> AllocCDF16::AllocatedMemory {
+    let mut priors = mcdf16.alloc_cell(len);
+    let default_cdf = Cdf16::default();
+    if untrained_cdf.family() != default_cdf.family() || untrained_cdf.kernels() != default_cdf.kernels() {
+        for prior in priors.slice_mut().iter_mut() {
+            *prior = untrained_cdf;
+        }
+    }
+    priors
 }
 
 pub struct CrossCommandBookKeeping<Cdf16:CDF16,
@@ -196,7 +214,7 @@ pub struct ByteContext {
   pub prev_byte: u8,
 }
 
-fn get_lut0(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
+pub fn get_lut0(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
     let mut ret = [0u8; 256];
     match lpn.0 {
         LITERAL_PREDICTION_MODE_SIGN =>
@@ -219,7 +237,7 @@ fn get_lut0(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
     }
     ret
 }
-fn get_lut1(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
+pub fn get_lut1(lpn: LiteralPredictionModeNibble) -> [u8; 256] {
     let mut ret = [0u8; 256];
     match lpn.0 {
         LITERAL_PREDICTION_MODE_SIGN =>
@@ -243,7 +261,7 @@ impl<
      AllocU8:Allocator<u8>> LiteralBookKeeping<Cdf16,
                                                AllocU8,
                                                AllocCDF16> {
-    fn new(literal_context_map:AllocU8::AllocatedMemory) -> Self {
+    fn new(literal_context_map:AllocU8::AllocatedMemory, untrained_cdf: Cdf16) -> Self {
         LiteralBookKeeping::<Cdf16, AllocU8, AllocCDF16> {
             combine_literal_predictions: false,
             last_8_literals: 0,
@@ -260,6 +278,7 @@ impl<
             lit_cm_priors: LiteralCommandPriorsCM {
                 priors: AllocCDF16::AllocatedMemory::default()
             },
+            untrained_cdf,
         }
     }
     pub fn get_literal_block_type(&self) -> u8 {
@@ -322,7 +341,9 @@ impl<
     pub fn obs_dynamic_context_mixing(&mut self, context_mixing: u8, mcdf16: &mut AllocCDF16) {
         self.combine_literal_predictions = (context_mixing != 0) as bool;
         if context_mixing >= 2 && self.lit_cm_priors.priors.slice().len() == 0 {
-            self.lit_cm_priors.priors = mcdf16.alloc_cell(LiteralCommandPriorsCM::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+            self.lit_cm_priors.priors = alloc_untrained_priors(mcdf16,
+                                                               LiteralCommandPriorsCM::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS,
+                                                               self.untrained_cdf);
         }
         self.model_weights[0].set_mixing_param(context_mixing);
         self.model_weights[1].set_mixing_param(context_mixing);
@@ -723,16 +744,17 @@ impl <AllocU8:Allocator<u8>,
                prior_depth:u8,
                literal_adaptation_rate: Option<[Speed;4]>,
                do_context_map:bool,
-               force_stride: StrideSelection) -> Self {
+               force_stride: StrideSelection,
+               untrained_cdf: Cdf16) -> Self {
         let ring_buffer = m8.alloc_cell(1 << ring_buffer_size);
-        let lit_low_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let lit_high_priors = mcdf16.alloc_cell(LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let lit_len_priors = mcdf16.alloc_cell(LiteralCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let copy_priors = mcdf16.alloc_cell(CopyCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let dict_priors = mcdf16.alloc_cell(DictCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let cc_priors = mcdf16.alloc_cell(CrossCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let pred_priors = mcdf16.alloc_cell(PredictionModePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
-        let btype_priors = mcdf16.alloc_cell(BlockTypePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS);
+        let lit_low_priors = alloc_untrained_priors(&mut mcdf16, LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
+        let lit_high_priors = alloc_untrained_priors(&mut mcdf16, LiteralNibblePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
+        let lit_len_priors = alloc_untrained_priors(&mut mcdf16, LiteralCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
+        let copy_priors = alloc_untrained_priors(&mut mcdf16, CopyCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
+        let dict_priors = alloc_untrained_priors(&mut mcdf16, DictCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
+        let cc_priors = alloc_untrained_priors(&mut mcdf16, CrossCommandPriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
+        let pred_priors = alloc_untrained_priors(&mut mcdf16, PredictionModePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
+        let btype_priors = alloc_untrained_priors(&mut mcdf16, BlockTypePriors::<Cdf16, AllocCDF16>::NUM_ALL_PRIORS, untrained_cdf);
         let literal_context_map = m8.alloc_cell(MAX_LITERAL_CONTEXT_MAP_SIZE);
         let distance_context_map = m8.alloc_cell(4 * NUM_BLOCK_TYPES);
         CrossCommandState::<ArithmeticCoder,
@@ -751,7 +773,7 @@ impl <AllocU8:Allocator<u8>,
                 ring_buffer),
                 m8: RepurposingAlloc::<u8, AllocU8>::new(m8),
                 mcdf16:mcdf16,
-                lbk: LiteralBookKeeping::new(literal_context_map),
+                lbk: LiteralBookKeeping::new(literal_context_map, untrained_cdf),
                 lit_high_priors: LiteralNibblePriors {
                     priors: lit_high_priors
                 },
@@ -879,9 +901,9 @@ pub fn drain_or_fill_static_buffer<AllocU8:Allocator<u8>,
     if LinearOutputBytes::can_linearize() {
         while local_coder.has_data_to_drain_or_fill() {
             *output_offset += muxer.linearize(output_bytes.split_at_mut(*output_offset).1);
-            let mut cur_input = demuxer.read_buffer();
-            let mut cur_output = muxer.write_buffer(match m8 {Some(ref mut x) => x, None => unreachable!()});
-            match local_coder.drain_or_fill_internal_buffer_unchecked(&mut cur_input[stream_index], &mut cur_output[stream_index]) {
+            let mut cur_input = demuxer.read_buffer(stream_index as StreamID);
+            let mut cur_output = muxer.write_buffer(stream_index as StreamID, match m8 {Some(ref mut x) => x, None => unreachable!()});
+            match local_coder.drain_or_fill_internal_buffer_unchecked(&mut cur_input, &mut cur_output) {
                 DivansResult::NeedsMoreOutput => {
                     assert!(LinearOutputBytes::can_linearize());
                     if *output_offset == output_bytes.len() {
@@ -896,20 +918,13 @@ pub fn drain_or_fill_static_buffer<AllocU8:Allocator<u8>,
         DivansResult::Success
     } else {
         if local_coder.has_data_to_drain_or_fill() {
-            let mut cur_input = demuxer.read_buffer();
+            let mut cur_input = demuxer.read_buffer(stream_index as StreamID);
             let mut a = 0usize;
-            let mut b = 0usize;
-            let mut cur_output = [
-                WritableBytes{
-                    data:&mut [],
-                    write_offset:&mut a,
-                },
-                WritableBytes{
+            let mut cur_output = WritableBytes{
                 data:&mut [],
-                    write_offset:&mut b,
-                },
-            ];
-            local_coder.drain_or_fill_internal_buffer_unchecked(&mut cur_input[stream_index], &mut cur_output[stream_index])
+                write_offset:&mut a,
+            };
+            local_coder.drain_or_fill_internal_buffer_unchecked(&mut cur_input, &mut cur_output)
         } else {
             DivansResult::Success
         }
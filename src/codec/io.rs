@@ -1,5 +1,5 @@
 use core;
-use interface::{Command, PredictionModeContextMap, free_cmd, StreamDemuxer, ReadableBytes, StreamID, NUM_STREAMS};
+use interface::{Command, PredictionModeContextMap, free_cmd, StreamDemuxer, ReadableBytes, StreamID};
 use ::interface::{
     DivansOutputResult,
     MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE,
@@ -45,8 +45,8 @@ impl<AllocU8:Allocator<u8>, LinearInputBytes:StreamDemuxer<AllocU8>> StreamDemux
         self.input.write_linear(data, m8)
     }
     #[inline(always)]
-    fn read_buffer(&mut self) -> [ReadableBytes; NUM_STREAMS] {
-        self.input.read_buffer()
+    fn read_buffer(&mut self, stream_id: StreamID) -> ReadableBytes<'_> {
+        self.input.read_buffer(stream_id)
     }
     #[inline(always)]
     fn data_ready(&self, stream_id:StreamID) -> usize {
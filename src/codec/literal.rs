@@ -211,7 +211,7 @@ impl<AllocU8:Allocator<u8>,
             let immutable_prior: Cdf16;
             let coder_prior: &Cdf16;
             if mm_opts == 2 {
-                immutable_prior = Cdf16::default();
+                immutable_prior = lbk.untrained_cdf;
                 coder_prior = &immutable_prior;
             } else {
                 coder_prior = nibble_prob;
@@ -18,6 +18,7 @@ use core::hash::Hasher;
 use alloc::{SliceWrapper, Allocator};
 use interface::{DivansResult, DivansOutputResult, DivansOpResult, ErrMsg, StreamMuxer, StreamDemuxer, StreamID, ReadableBytes};
 use ::alloc_util::UninitializedOnAlloc;
+use cpu_features::CpuFeatures;
 use mux::Mux;
 pub const CMD_BUFFER_SIZE: usize = 16;
 use ::alloc_util::RepurposingAlloc;
@@ -43,6 +44,7 @@ use threading::{ThreadToMain, StaticCommand};
 use ::slice_util::AllocatedMemoryPrefix;
 pub use self::interface::{
     ThreadContext,
+    ContextMapType,
     StrideSelection,
     EncoderOrDecoderSpecialization,
     CrossCommandState,
@@ -54,6 +56,8 @@ pub use self::interface::{
     CommandSliceArray,
     MainThreadContext,
     get_distance_from_mnemonic_code,
+    get_lut0,
+    get_lut1,
 };
 use super::interface::{
     ArithmeticEncoderOrDecoder,
@@ -181,6 +185,7 @@ pub struct DivansCodec<ArithmeticCoder:ArithmeticEncoderOrDecoder,
     state_populate_ring_buffer: Command<AllocatedMemoryPrefix<u8, AllocU8>>,
     codec_traits: CodecTraitSelector,
     crc: SubDigest,
+    kernels: CpuFeatures,
     frozen_checksum: Option<u64>,
     skip_checksum: bool,
 }
@@ -215,7 +220,11 @@ impl<AllocU8: Allocator<u8>,
                literal_adaptation_rate: Option<[Speed;4]>,
                do_context_map: bool,
                force_stride: interface::StrideSelection,
-               skip_checksum: bool) -> Self {
+               skip_checksum: bool,
+               cdf_family: ::interface::CDFFamily) -> Self {
+        let kernels = ::cpu_features::select();
+        // callers reject families the CDF type cannot model before constructing a codec
+        let untrained_cdf = Cdf16::new_in_family(cdf_family).unwrap_or_default().with_kernels(kernels);
         let mut cross_command_state = CrossCommandState::<ArithmeticCoder,
                                                     Specialization,
                                                     LinearInputBytes,
@@ -234,6 +243,7 @@ impl<AllocU8: Allocator<u8>,
                                                                      literal_adaptation_rate,
                                                                      do_context_map,
                                                                      force_stride,
+                                                                     untrained_cdf,
         );
 
         let pm = context_map::PredictionModeState::begin(cross_command_state.thread_ctx.m8().unwrap());
@@ -252,7 +262,8 @@ impl<AllocU8: Allocator<u8>,
             state_block_switch: block_type::BlockTypeState::begin(),
             state_prediction_mode: pm,
             state_populate_ring_buffer: Command::<AllocatedMemoryPrefix<u8, AllocU8>>::nop(),
-            crc: default_crc(),
+            crc: SubDigest::new(kernels),
+            kernels,
             frozen_checksum: None,
             skip_checksum:skip_checksum,
         };
@@ -316,6 +327,9 @@ impl<AllocU8: Allocator<u8>,
     pub fn demuxer(&mut self) -> &mut LinearInputBytes{
         &mut self.cross_command_state.demuxer
     }
+    pub fn muxer(&mut self) -> &mut LinearOutputBytes{
+        &mut self.cross_command_state.muxer
+    }
     pub fn free(mut self) -> (AllocU8, AllocCDF16) {
         self.free_ref();
         self.cross_command_state.free()
@@ -406,6 +420,10 @@ impl<AllocU8: Allocator<u8>,
     pub fn get_crc(&mut self) -> &mut SubDigest {
         &mut self.crc
     }
+    // the SIMD kernels picked when this codec was constructed
+    pub fn kernels(&self) -> CpuFeatures {
+        self.kernels
+    }
     pub fn flush(&mut self,
              output_bytes: &mut [u8],
              output_bytes_offset: &mut usize) -> DivansOutputResult{
@@ -421,6 +439,21 @@ impl<AllocU8: Allocator<u8>,
         }
         ret
     }
+    // serializes the encoded bytes that the mux policy does not allow to wait for further commands
+    pub fn linearize_pending(&mut self,
+                             output_bytes: &mut [u8],
+                             output_bytes_offset: &mut usize) -> DivansOutputResult {
+        let adjusted_output_bytes = output_bytes.split_at_mut(*output_bytes_offset).1;
+        let amt = self.cross_command_state.muxer.linearize_pending(adjusted_output_bytes);
+        *output_bytes_offset += amt;
+        if self.frozen_checksum.is_none() && !Specialization::IS_DECODING_FILE {
+            self.crc.write(adjusted_output_bytes.split_at(amt).0);
+        }
+        if amt == adjusted_output_bytes.len() {
+            return DivansOutputResult::NeedsMoreOutput;
+        }
+        DivansOutputResult::Success
+    }
     fn internal_flush(&mut self,
                  output_bytes: &mut [u8],
                  output_bytes_offset: &mut usize) -> DivansOutputResult{
@@ -847,8 +880,9 @@ impl<AllocU8: Allocator<u8>,
                                  return CodecTraitResult::UpdateCodecTraitAndAdvance(
                                      construct_codec_trait_from_bookkeeping(&mut ctx.lbk));
                              } else {
-                                 let pm = core::mem::replace(&mut self.state_prediction_mode,
-                                                             context_map::PredictionModeState::<AllocU8>::nop());
+                                 let mut pm = core::mem::replace(&mut self.state_prediction_mode,
+                                                                 context_map::PredictionModeState::<AllocU8>::nop());
+                                 pm.truncate_to_coded_len();
                                  self.state_populate_ring_buffer = Command::PredictionMode(pm.pm);
                                  self.state = EncodeOrDecodeState::PopulateRingBuffer;
                              }
@@ -901,13 +935,22 @@ impl<AllocU8: Allocator<u8>,
                                                             output_bytes,
                                                             output_bytes_offset) {
                         DivansResult::Success => {
-                            self.cross_command_state.bk.obs_btypec(match self.state_block_switch {
+                            let new_block_type = match self.state_block_switch {
                                 block_type::BlockTypeState::FullyDecoded(btype) => btype,
                                 _ => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(
                                     DivansResult::Failure(ErrMsg::UnintendedCodecState(1)))),
-                            });
-                            self.state = EncodeOrDecodeState::Begin;
-                            return CodecTraitResult::Res(OneCommandReturn::Advance);
+                            };
+                            self.cross_command_state.bk.obs_btypec(new_block_type);
+                            match self.cross_command_state.thread_ctx {
+                                ThreadContext::MainThread(_) => {
+                                    self.state = EncodeOrDecodeState::Begin;
+                                    return CodecTraitResult::Res(OneCommandReturn::Advance);
+                                },
+                                ThreadContext::Worker => {
+                                    self.state_populate_ring_buffer = Command::BlockSwitchCommand(BlockSwitch::new(new_block_type));
+                                    self.state = EncodeOrDecodeState::PopulateRingBuffer;
+                                },
+                            }
                         },
                         retval => {
                             return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(retval));
@@ -926,13 +969,22 @@ impl<AllocU8: Allocator<u8>,
                                                             output_bytes,
                                                             output_bytes_offset) {
                         DivansResult::Success => {
-                            self.cross_command_state.bk.obs_btyped(match self.state_block_switch {
+                            let new_block_type = match self.state_block_switch {
                                 block_type::BlockTypeState::FullyDecoded(btype) => btype,
                                 _ => return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(
                                     DivansResult::Failure(ErrMsg::UnintendedCodecState(2)))),
-                            });
-                            self.state = EncodeOrDecodeState::Begin;
-                            return CodecTraitResult::Res(OneCommandReturn::Advance);
+                            };
+                            self.cross_command_state.bk.obs_btyped(new_block_type);
+                            match self.cross_command_state.thread_ctx {
+                                ThreadContext::MainThread(_) => {
+                                    self.state = EncodeOrDecodeState::Begin;
+                                    return CodecTraitResult::Res(OneCommandReturn::Advance);
+                                },
+                                ThreadContext::Worker => {
+                                    self.state_populate_ring_buffer = Command::BlockSwitchDistance(BlockSwitch::new(new_block_type));
+                                    self.state = EncodeOrDecodeState::PopulateRingBuffer;
+                                },
+                            }
                         },
                         retval => {
                             return CodecTraitResult::Res(OneCommandReturn::BufferExhausted(retval));
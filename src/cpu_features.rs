@@ -0,0 +1,81 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Runtime selection between the SIMD and scalar kernels for CDF16 updates and crc32c.
+// Every codec picks its kernels when it is constructed and keeps them in its priors and
+// checksum, so codecs with different kernels can run side by side. The kernels produce
+// bit-identical results, so the choice never shows up in the stream.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const SSE42: u8 = 1;
+const AVX2: u8 = 2;
+
+static SCALAR_ONLY: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub sse42: bool,
+    pub avx2: bool,
+}
+
+impl CpuFeatures {
+    fn from_bits(bits: u8) -> Self {
+        CpuFeatures {
+            sse42: bits & SSE42 != 0,
+            avx2: bits & AVX2 != 0,
+        }
+    }
+}
+
+#[cfg(all(feature="std", not(feature="safe"), target_arch="x86_64"))]
+fn detect_bits() -> u8 {
+    let mut bits = 0;
+    if is_x86_feature_detected!("sse4.2") {
+        bits |= SSE42;
+    }
+    if is_x86_feature_detected!("avx2") {
+        bits |= AVX2;
+    }
+    bits
+}
+
+#[cfg(all(not(feature="std"), not(feature="safe"), target_arch="x86_64"))]
+fn detect_bits() -> u8 {
+    (if cfg!(target_feature="sse4.2") { SSE42 } else { 0 })
+        | (if cfg!(target_feature="avx2") { AVX2 } else { 0 })
+}
+
+#[cfg(any(feature="safe", not(target_arch="x86_64")))]
+fn detect_bits() -> u8 {
+    0
+}
+
+// the SIMD kernels this build could use on this CPU
+pub fn detected() -> CpuFeatures {
+    CpuFeatures::from_bits(detect_bits())
+}
+
+// restricts codecs constructed from now on to the scalar kernels; running codecs keep theirs
+pub fn set_scalar_only(scalar_only: bool) {
+    SCALAR_ONLY.store(scalar_only, Ordering::Relaxed);
+}
+
+// the kernels a codec constructed now runs with
+pub fn select() -> CpuFeatures {
+    if SCALAR_ONLY.load(Ordering::Relaxed) {
+        CpuFeatures::default()
+    } else {
+        detected()
+    }
+}
@@ -21,6 +21,7 @@ use super::mux::{Mux,DevNull};
 
 
 use super::raw_to_cmd;
+use super::filter::{FilterChain, FilterEncoder};
 use super::slice_util;
 use super::alloc_util::RepurposingAlloc;
 pub use super::alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
@@ -29,6 +30,7 @@ use brotli;
 use brotli::InputReference;
 use brotli::interface::Freezable;
 pub use super::interface::{
+    StreamMuxer,
     BlockSwitch,
     LiteralBlockSwitch,
     Command,
@@ -47,24 +49,31 @@ pub use super::interface::{
     };
 
 pub use super::cmd_to_divans::EncoderSpecialization;
+use super::cmd_to_divans::EncoderCodec;
 pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection, default_crc, CommandArray, CommandSliceArray};
 use super::interface;
-use super::interface::{DivansOutputResult, DivansResult, ErrMsg};
+use super::interface::{CDFFamily, DivansOutputResult, DivansResult, EntropyCoderSelection, ErrMsg, MuxPolicy, StreamCount};
 const COMPRESSOR_CMD_BUFFER_SIZE : usize = 16;
 pub struct DivansCompressor<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                             AllocU8:Allocator<u8>,
                             AllocU32:Allocator<u32>,
-                            AllocCDF16:Allocator<interface::DefaultCDF16>> {
+                            AllocCDF16:Allocator<interface::DefaultCDF16>,
+                            LinearOutputBytes:StreamMuxer<AllocU8>+Default=Mux<AllocU8>> {
     m32: AllocU32,
-    codec: DivansCodec<DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16>,
+    codec: DivansCodec<DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, LinearOutputBytes, interface::DefaultCDF16, AllocU8, AllocCDF16>,
     header_progress: usize,
     window_size: u8,
+    entropy_coder: EntropyCoderSelection,
+    cdf_family: CDFFamily,
+    mux_policy: MuxPolicy,
+    stream_count: StreamCount,
     literal_context_map_backing: AllocU8::AllocatedMemory,
     prediction_mode_backing: AllocU8::AllocatedMemory,
     cmd_assembler: raw_to_cmd::RawToCmdState<AllocU8::AllocatedMemory, AllocU32>,
     freeze_dried_cmd_array: [Command<slice_util::SliceReference<'static,u8>>; COMPRESSOR_CMD_BUFFER_SIZE],
     freeze_dried_cmd_start: usize,
     freeze_dried_cmd_end: usize,
+    filter: FilterEncoder<AllocU8>,
 }
 
 
@@ -84,49 +93,22 @@ impl<AllocU8:Allocator<u8>,
      type DefaultEncoder = DefaultEncoderType!();
      type ConstructedCompressor = DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16>;
      type AdditionalArgs = ();
-     fn new(mut m8: AllocU8, mut m32: AllocU32, mcdf16:AllocCDF16,
+     fn new(m8: AllocU8, m32: AllocU32, mcdf16:AllocCDF16,
             opts: super::interface::DivansCompressorOptions,
             _additional_args: ()) -> DivansCompressor<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
-         let window_size = core::cmp::min(24, core::cmp::max(10, opts.window_size.unwrap_or(22)));
-         let ring_buffer = m8.alloc_cell(1<<window_size);
-         let prediction_mode_backing = m8.alloc_cell(interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE);
-         let literal_context_map = m8.alloc_cell(interface::MAX_LITERAL_CONTEXT_MAP_SIZE);
-         let cmd_enc = Self::DefaultEncoder::new(&mut m8);
-         let lit_enc = Self::DefaultEncoder::new(&mut m8);
-         let assembler = raw_to_cmd::RawToCmdState::new(&mut m32, ring_buffer);
-         DivansCompressor::<Self::DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
-            m32 :m32,
-            codec:DivansCodec::<Self::DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, Mux<AllocU8>, interface::DefaultCDF16, AllocU8, AllocCDF16>::new(
-                m8,
-                mcdf16,
-                cmd_enc,
-                lit_enc,
-                EncoderSpecialization::new(),
-                DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
-                window_size as usize,
-                opts.dynamic_context_mixing.unwrap_or(0),
-                opts.prior_depth,
-                opts.literal_adaptation,
-                opts.use_context_map,
-                opts.force_stride_value,
-                false,
-            ),
-            literal_context_map_backing: literal_context_map,
-            prediction_mode_backing: prediction_mode_backing,
-            freeze_dried_cmd_array:[interface::Command::<slice_util::SliceReference<'static, u8>>::default(); COMPRESSOR_CMD_BUFFER_SIZE],
-            freeze_dried_cmd_start:0,
-            freeze_dried_cmd_end:0,
-            cmd_assembler:assembler,
-            header_progress: 0,
-            window_size: window_size as u8,
-        }
+         DivansCompressor::new(m8, m32, mcdf16, opts)
      }
 }
 
-pub fn make_header(window_size: u8) -> [u8; interface::HEADER_LENGTH] {
+pub fn make_header(window_size: u8, filters: FilterChain, entropy_coder: EntropyCoderSelection, cdf_family: CDFFamily, mux_policy: MuxPolicy, stream_count: StreamCount) -> [u8; interface::HEADER_LENGTH] {
     let mut retval = [0u8; interface::HEADER_LENGTH];
     retval[0..interface::MAGIC_NUMBER.len()].clone_from_slice(&interface::MAGIC_NUMBER[..]);
     retval[5] = window_size;
+    filters.write_header(&mut retval);
+    entropy_coder.write_header(&mut retval);
+    cdf_family.write_header(&mut retval);
+    mux_policy.write_header(&mut retval);
+    stream_count.write_header(&mut retval);
     retval
 }
 fn thaw_commands<'a>(input: &[Command<slice_util::SliceReference<'static, u8>>], ring_buffer: &'a[u8], start_index:  usize, end_index: usize) -> [Command<InputReference<'a>>; COMPRESSOR_CMD_BUFFER_SIZE] {
@@ -149,12 +131,17 @@ fn freeze<'a>(item: &FeatureFlagSliceType<InputReference<'a>>) -> FeatureFlagSli
 
 pub fn write_header<CRC:Hasher>(header_progress: &mut usize,
                                 window_size: u8,
+                                filters: FilterChain,
+                                entropy_coder: EntropyCoderSelection,
+                                cdf_family: CDFFamily,
+                                mux_policy: MuxPolicy,
+                                stream_count: StreamCount,
                                 output: &mut[u8],
                                 output_offset:&mut usize,
                                 crc: &mut CRC) -> DivansOutputResult {
     let bytes_avail = output.len() - *output_offset;
     if bytes_avail + *header_progress < interface::HEADER_LENGTH {
-        let to_write = &make_header(window_size)[*header_progress..
+        let to_write = &make_header(window_size, filters, entropy_coder, cdf_family, mux_policy, stream_count)[*header_progress..
                                                  (*header_progress + bytes_avail)];
         crc.write(to_write);
         output.split_at_mut(*output_offset).1.clone_from_slice(
@@ -163,7 +150,7 @@ pub fn write_header<CRC:Hasher>(header_progress: &mut usize,
         *header_progress += bytes_avail;
         return DivansOutputResult::NeedsMoreOutput;
     }
-    let to_write = &make_header(window_size)[*header_progress..];
+    let to_write = &make_header(window_size, filters, entropy_coder, cdf_family, mux_policy, stream_count)[*header_progress..];
     output[*output_offset..(*output_offset + interface::HEADER_LENGTH - *header_progress)].clone_from_slice(
         to_write);
     crc.write(to_write);
@@ -184,8 +171,56 @@ impl<'a> CommandArray for InputReferenceCommandArray<'a> {
     }
 }
 
-impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, AllocU8:Allocator<u8>, AllocU32:Allocator<u32>, AllocCDF16:Allocator<interface::DefaultCDF16>> 
-    DivansCompressor<DefaultEncoder, AllocU8, AllocU32, AllocCDF16> {
+impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, AllocU8:Allocator<u8>, AllocU32:Allocator<u32>, AllocCDF16:Allocator<interface::DefaultCDF16>,
+     LinearOutputBytes:StreamMuxer<AllocU8>+Default>
+    DivansCompressor<DefaultEncoder, AllocU8, AllocU32, AllocCDF16, LinearOutputBytes> {
+    pub fn new(mut m8: AllocU8, mut m32: AllocU32, mcdf16:AllocCDF16,
+               opts: super::interface::DivansCompressorOptions) -> Self {
+        let window_size = opts.window_size.unwrap_or(22).clamp(10, 24);
+        let ring_buffer = m8.alloc_cell(2<<window_size);
+        let prediction_mode_backing = m8.alloc_cell(interface::MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE);
+        let literal_context_map = m8.alloc_cell(interface::MAX_LITERAL_CONTEXT_MAP_SIZE);
+        let cmd_enc = DefaultEncoder::new_with_entropy_coder(&mut m8, opts.entropy_coder);
+        let lit_enc = DefaultEncoder::new_with_entropy_coder(&mut m8, opts.entropy_coder);
+        let assembler = raw_to_cmd::RawToCmdState::new(&mut m32, ring_buffer, window_size as u8,
+                                                         opts.quality.unwrap_or(11));
+        let filter = FilterEncoder::new(&mut m8, opts.filters);
+        let mut ret = DivansCompressor {
+            m32,
+            codec:DivansCodec::<DefaultEncoder, EncoderSpecialization, DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>, LinearOutputBytes, interface::DefaultCDF16, AllocU8, AllocCDF16>::new(
+                m8,
+                mcdf16,
+                cmd_enc,
+                lit_enc,
+                EncoderSpecialization::new(),
+                DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
+                window_size as usize,
+                opts.dynamic_context_mixing.unwrap_or(0),
+                opts.prior_depth,
+                opts.literal_adaptation,
+                opts.use_context_map,
+                opts.force_stride_value,
+                false,
+                opts.cdf_family,
+            ),
+            literal_context_map_backing: literal_context_map,
+            prediction_mode_backing,
+            freeze_dried_cmd_array:[interface::Command::<slice_util::SliceReference<'static, u8>>::default(); COMPRESSOR_CMD_BUFFER_SIZE],
+            freeze_dried_cmd_start:0,
+            freeze_dried_cmd_end:0,
+            cmd_assembler:assembler,
+            header_progress: 0,
+            window_size: window_size as u8,
+            entropy_coder: opts.entropy_coder,
+            cdf_family: opts.cdf_family,
+            mux_policy: opts.mux_policy,
+            stream_count: opts.stream_count,
+            filter,
+        };
+        ret.codec.muxer().set_policy(opts.mux_policy);
+        ret.codec.muxer().set_stream_count(opts.stream_count);
+        ret
+    }
     fn flush_freeze_dried_cmds(&mut self, output: &mut [u8], output_offset: &mut usize) -> interface::DivansOutputResult {
         if self.freeze_dried_cmd_start != self.freeze_dried_cmd_end { // we have some freeze dried items
             let thawed_buffer = thaw_commands(&self.freeze_dried_cmd_array[..], self.cmd_assembler.ring_buffer.slice(),
@@ -244,42 +279,14 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>, All
             };
         }
     }
-    pub fn get_m8(&mut self) -> Option<&mut RepurposingAlloc<u8, AllocU8>> {
-       self.codec.get_m8()
-    }
-    pub fn free_ref(&mut self) {
-        self.cmd_assembler.free(&mut self.m32);
-        self.codec.get_m8().as_mut().unwrap().get_base_alloc().free_cell(core::mem::replace(&mut self.cmd_assembler.ring_buffer, AllocU8::AllocatedMemory::default()));
-        self.codec.get_m8().as_mut().unwrap().free_cell(core::mem::replace(&mut self.literal_context_map_backing, AllocU8::AllocatedMemory::default()));
-        self.codec.get_m8().as_mut().unwrap().free_cell(core::mem::replace(&mut self.prediction_mode_backing, AllocU8::AllocatedMemory::default()));
-        self.codec.free_ref();
-    }
-    pub fn free(mut self) -> (AllocU8, AllocU32, AllocCDF16) {
-        let (mut m8, mcdf16) = self.codec.free();
-        self.cmd_assembler.free(&mut self.m32);
-        m8.free_cell(core::mem::replace(&mut self.cmd_assembler.ring_buffer, AllocU8::AllocatedMemory::default()));
-        m8.free_cell(core::mem::replace(&mut self.literal_context_map_backing, AllocU8::AllocatedMemory::default()));
-        m8.free_cell(core::mem::replace(&mut self.prediction_mode_backing, AllocU8::AllocatedMemory::default()));
-        (m8, self.m32, mcdf16)
-    }
-
-}
-
-
-impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
-     AllocU8:Allocator<u8>,
-     AllocU32:Allocator<u32>,
-     AllocCDF16:Allocator<interface::DefaultCDF16>> Compressor for DivansCompressor<DefaultEncoder,
-                                                                                    AllocU8,
-                                                                                    AllocU32,
-                                                                                    AllocCDF16> {
-    fn encode(&mut self,
+    fn encode_filtered(&mut self,
+              filters: FilterChain,
               input: &[u8],
               input_offset: &mut usize,
               output: &mut [u8],
               output_offset: &mut usize) -> DivansResult {
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
+            match write_header(&mut self.header_progress, self.window_size, filters, self.entropy_coder, self.cdf_family, self.mux_policy, self.stream_count, output, output_offset,
                                self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return DivansResult::from(res),
@@ -335,6 +342,60 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
             }
         }
     }
+    pub fn get_m8(&mut self) -> Option<&mut RepurposingAlloc<u8, AllocU8>> {
+       self.codec.get_m8()
+    }
+    pub fn get_codec(&self) -> &EncoderCodec<DefaultEncoder, LinearOutputBytes, interface::DefaultCDF16, AllocU8, AllocCDF16> {
+        &self.codec
+    }
+    pub fn free_ref(&mut self) {
+        self.cmd_assembler.free(&mut self.m32);
+        self.filter.free(self.codec.get_m8().as_mut().unwrap().get_base_alloc());
+        self.codec.get_m8().as_mut().unwrap().get_base_alloc().free_cell(core::mem::replace(&mut self.cmd_assembler.ring_buffer, AllocU8::AllocatedMemory::default()));
+        self.codec.get_m8().as_mut().unwrap().free_cell(core::mem::replace(&mut self.literal_context_map_backing, AllocU8::AllocatedMemory::default()));
+        self.codec.get_m8().as_mut().unwrap().free_cell(core::mem::replace(&mut self.prediction_mode_backing, AllocU8::AllocatedMemory::default()));
+        self.codec.free_ref();
+    }
+    pub fn free(mut self) -> (AllocU8, AllocU32, AllocCDF16) {
+        let (mut m8, mcdf16) = self.codec.free();
+        self.cmd_assembler.free(&mut self.m32);
+        self.filter.free(&mut m8);
+        m8.free_cell(core::mem::replace(&mut self.cmd_assembler.ring_buffer, AllocU8::AllocatedMemory::default()));
+        m8.free_cell(core::mem::replace(&mut self.literal_context_map_backing, AllocU8::AllocatedMemory::default()));
+        m8.free_cell(core::mem::replace(&mut self.prediction_mode_backing, AllocU8::AllocatedMemory::default()));
+        (m8, self.m32, mcdf16)
+    }
+
+}
+
+
+impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
+     AllocU8:Allocator<u8>,
+     AllocU32:Allocator<u32>,
+     AllocCDF16:Allocator<interface::DefaultCDF16>,
+     LinearOutputBytes:StreamMuxer<AllocU8>+Default> Compressor for DivansCompressor<DefaultEncoder,
+                                                                                    AllocU8,
+                                                                                    AllocU32,
+                                                                                    AllocCDF16,
+                                                                                    LinearOutputBytes> {
+    fn encode(&mut self,
+              input: &[u8],
+              input_offset: &mut usize,
+              output: &mut [u8],
+              output_offset: &mut usize) -> DivansResult {
+        let mut filter = core::mem::take(&mut self.filter);
+        let ret = filter.encode(input, input_offset, |filters, filtered, filtered_offset| {
+            self.encode_filtered(filters, filtered, filtered_offset, output, output_offset)
+        });
+        self.filter = filter;
+        match ret {
+            DivansResult::NeedsMoreInput => match self.codec.linearize_pending(output, output_offset) {
+                DivansOutputResult::Success => DivansResult::NeedsMoreInput,
+                res => DivansResult::from(res),
+            },
+            _ => ret,
+        }
+    }
     fn encode_commands<SliceType:SliceWrapper<u8>+Default>(&mut self,
                                           input:&[Command<SliceType>],
                                           input_offset : &mut usize,
@@ -342,7 +403,8 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                           output_offset: &mut usize) -> DivansOutputResult{
         self.cmd_assembler.raw_input_ir_mode();
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
+            // commands describe the unfiltered bytes, so no filters are signalled
+            match write_header(&mut self.header_progress, self.window_size, FilterChain::default(), self.entropy_coder, self.cdf_family, self.mux_policy, self.stream_count, output, output_offset,
                                self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return res,
@@ -355,7 +417,7 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                     output_offset,
                                     &CommandSliceArray(input),
                                           input_offset) {
-            DivansResult::Success | DivansResult::NeedsMoreInput => DivansOutputResult::Success,
+            DivansResult::Success | DivansResult::NeedsMoreInput => self.codec.linearize_pending(output, output_offset),
             DivansResult::NeedsMoreOutput => DivansOutputResult::NeedsMoreOutput,
             DivansResult::Failure(m) => DivansOutputResult::Failure(m),
         }
@@ -363,8 +425,17 @@ impl<DefaultEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
     fn flush(&mut self,
              output: &mut [u8],
              output_offset: &mut usize) -> DivansOutputResult {
+        let mut filter = core::mem::take(&mut self.filter);
+        let filter_ret = filter.flush(|filters, filtered, filtered_offset| {
+            self.encode_filtered(filters, filtered, filtered_offset, output, output_offset)
+        });
+        self.filter = filter;
+        match filter_ret {
+            DivansOutputResult::Success => {},
+            res => return res,
+        }
         if self.header_progress != interface::HEADER_LENGTH {
-            match write_header(&mut self.header_progress, self.window_size, output, output_offset,
+            match write_header(&mut self.header_progress, self.window_size, self.filter.chain(), self.entropy_coder, self.cdf_family, self.mux_policy, self.stream_count, output, output_offset,
                                self.codec.get_crc()) {
                 DivansOutputResult::Success => {},
                 res => return res,
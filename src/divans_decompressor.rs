@@ -2,7 +2,7 @@ use core;
 use core::marker::PhantomData;
 use core::hash::Hasher;
 use ::interface;
-use ::interface::{NewWithAllocator, Decompressor};
+use ::interface::{NewWithAllocator, Decompressor, EntropyCoderSelection, CDFFamily, StreamCount, MuxPolicy};
 use ::DecoderSpecialization;
 use ::codec;
 use super::mux::{Mux,DevNull};
@@ -13,7 +13,10 @@ use threading::{ThreadToMainDemuxer, SerialWorker};
 use ::interface::{DivansResult, DivansOpResult, DivansInputResult, ErrMsg};
 use ::ArithmeticEncoderOrDecoder;
 use ::alloc::{Allocator};
+use ::filter::FilterChain;
 pub use threading::StaticCommand;
+#[cfg(feature="std")]
+use std::vec::Vec;
 
 #[cfg(feature="std")]
 use parallel_decompressor::{ParallelDivansProcess};
@@ -30,6 +33,11 @@ pub struct HeaderParser<AllocU8:Allocator<u8>,
     pub mcommand: Option<AllocCommand>,
     pub skip_crc: bool,
     pub multithread: bool,
+    pub capture_ir: bool,
+    // largest window buffer, in bytes, the stream may ask for
+    pub memory_limit: Option<usize>,
+    #[cfg(feature="std")]
+    pub executor: Option<::thread_pool::ExecutorHandle>,
 }
 
 impl<AllocU8:Allocator<u8>,
@@ -48,6 +56,26 @@ impl<AllocU8:Allocator<u8>,
         if window_size < 10 || window_size >= 25 {
             return Err(DivansOpResult::Failure(ErrMsg::BadWindowSize(window_size as u8)));
         }
+        if let Some(limit) = self.memory_limit {
+            if (1usize << window_size) > limit {
+                return Err(DivansOpResult::Failure(ErrMsg::WindowExceedsMemoryLimit(window_size as u8)));
+            }
+        }
+        if let Err(m) = FilterChain::from_header(&self.header) {
+            return Err(DivansOpResult::Failure(m));
+        }
+        if let Err(m) = EntropyCoderSelection::from_header(&self.header) {
+            return Err(DivansOpResult::Failure(m));
+        }
+        if let Err(m) = CDFFamily::from_header(&self.header) {
+            return Err(DivansOpResult::Failure(m));
+        }
+        if let Err(m) = StreamCount::from_header(&self.header) {
+            return Err(DivansOpResult::Failure(m));
+        }
+        if let Err(m) = MuxPolicy::from_header(&self.header) {
+            return Err(DivansOpResult::Failure(m));
+        }
         Ok(window_size)
     }
     pub fn decode(&mut self,
@@ -145,7 +173,7 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                 },
                 DivansResult::NeedsMoreOutput => {}, // lets make room for more output
             }
-            let retval = process.literal_decoder.as_mut().unwrap().decode_process_output(
+            let retval = process.literal_decoder.as_mut().unwrap().decode_process_filtered_output(
                 process.codec.as_mut().unwrap().demuxer().get_main_to_thread(),
                 output,
                 output_offset);
@@ -159,6 +187,12 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
             }
         }
     }
+    #[cfg(feature="std")]
+    pub fn take_ir_commands(&mut self, commands: &mut Vec<::ir::IrCommand>) {
+        if let Some(ref mut literal_decoder) = self.literal_decoder {
+            literal_decoder.take_logged_commands(commands);
+        }
+    }
     pub fn free(mut self) -> (AllocU8, AllocCDF16, AllocCommand) {
         use codec::NUM_ARITHMETIC_CODERS;
         if let Some(mut codec) = core::mem::replace(&mut self.codec, None) {
@@ -208,6 +242,25 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
      AllocCDF16:Allocator<interface::DefaultCDF16>,
      AllocCommand:Allocator<StaticCommand>>
     DivansDecompressor<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+    /// Turns checksum verification off or back on.
+    /// Only takes effect before the header has been decoded.
+    pub fn set_skip_crc(&mut self, skip_crc: bool) -> bool {
+        if let DivansDecompressor::Header(ref mut header) = *self {
+            header.skip_crc = skip_crc;
+            return true;
+        }
+        false
+    }
+    /// Fails streams whose window buffer would need more than `limit` bytes, before allocating it.
+    /// The model state, a fixed size independent of the stream, is not counted.
+    /// Only takes effect before the header has been decoded.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) -> bool {
+        if let DivansDecompressor::Header(ref mut header) = *self {
+            header.memory_limit = limit;
+            return true;
+        }
+        false
+    }
 
     fn finish_parsing_header_serial(&mut self, window_size: usize) -> DivansResult {
         if window_size < 10 {
@@ -220,6 +273,7 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
         let mcdf16:AllocCDF16;
         let raw_header:[u8; interface::HEADER_LENGTH];
         let skip_crc:bool;
+        let capture_ir:bool;
         let mut mcommand:AllocCommand;
         match *self {
             DivansDecompressor::Header(ref mut header) => {
@@ -229,6 +283,7 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                 };
                 raw_header = header.header;
                 skip_crc = header.skip_crc;
+                capture_ir = header.capture_ir;
             },
             _ => return DivansResult::Failure(ErrMsg::WrongInternalDecoderState),
         }
@@ -250,9 +305,21 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
             },
             _ => return DivansResult::Failure(ErrMsg::WrongInternalDecoderState),
         }
+        let entropy_coder = match EntropyCoderSelection::from_header(&raw_header) {
+            Ok(coder) => coder,
+            Err(m) => return DivansResult::Failure(m),
+        };
+        let cdf_family = match CDFFamily::from_header(&raw_header) {
+            Ok(family) => family,
+            Err(m) => return DivansResult::Failure(m),
+        };
+        let stream_count = match StreamCount::from_header(&raw_header) {
+            Ok(count) => count,
+            Err(m) => return DivansResult::Failure(m),
+        };
         //update this if you change the SelectedArithmeticDecoder macro
-        let cmd_decoder = DefaultDecoder::new(&mut m8);
-        let lit_decoder = DefaultDecoder::new(&mut m8);
+        let cmd_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, entropy_coder);
+        let lit_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, entropy_coder);
         let linear_input_bytes = ThreadToMainDemuxer::<AllocU8,SerialWorker<AllocU8, AllocCommand>>::new(
             SerialWorker::<AllocU8, AllocCommand>::new(&mut mcommand));
         let mut codec = codec::DivansCodec::<DefaultDecoder,
@@ -273,12 +340,19 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                                               None,
                                                           true,
                                                               codec::StrideSelection::UseBrotliRec,
-                                                              skip_crc);
+                                                              skip_crc,
+                                                              cdf_family);
         if !skip_crc {
             codec.get_crc().write(&raw_header[..]);
         }
-        let main_thread_codec = codec.fork(&mut mcommand);
+        let mut main_thread_codec = codec.fork(&mut mcommand);
         assert_eq!(*codec.get_crc(), main_thread_codec.crc);
+        main_thread_codec.demuxer = Mux::new(stream_count);
+        match FilterChain::from_header(&raw_header) {
+            Ok(filters) => main_thread_codec.set_filters(filters),
+            Err(m) => return DivansResult::Failure(m),
+        }
+        main_thread_codec.set_command_log(capture_ir);
         core::mem::replace(self,
                            DivansDecompressor::Decode(
                                DivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
@@ -339,6 +413,31 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
         AllocU8::AllocatedMemory : Send + 'static,
         {
     free_body!();
+    /// Keeps a copy of every decoded command for `take_ir_commands`.
+    /// Only takes effect before the header has been decoded.
+    pub fn capture_ir(&mut self) -> bool {
+        if let DivansDecompressor::Header(ref mut header) = *self {
+            header.capture_ir = true;
+            return true;
+        }
+        false
+    }
+    /// Moves the commands decoded since the last call onto the end of `commands`.
+    pub fn take_ir_commands(&mut self, commands: &mut Vec<::ir::IrCommand>) {
+        match *self {
+            DivansDecompressor::Header(_) => {},
+            DivansDecompressor::MultiDecode(ref mut process) => process.take_ir_commands(commands),
+            DivansDecompressor::Decode(ref mut process) => process.take_ir_commands(commands),
+        }
+    }
+    /// The threadlog events of both threads of a parallel decode, or None for a serial one.
+    /// Call once decoding has finished: this waits for the worker thread to let go of its codec.
+    pub fn thread_trace(&self) -> Option<::thread_trace::ThreadTrace> {
+        match *self {
+            DivansDecompressor::MultiDecode(ref process) => Some(process.thread_trace()),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(not(feature="std"))]
@@ -444,8 +543,26 @@ pub trait DivansDecompressorFactory<
                                                 m8:Some(m8), mcdf16:Some(mcdf16), mcommand:Some(mc),
                                                 skip_crc:skip_crc,
                                                 multithread:multithread,
+                                                capture_ir:false,
+                                                memory_limit:None,
+                                                #[cfg(feature="std")]
+                                                executor:None,
         })
     }
+    /// Decodes in parallel on `executor`, sharing its threads with every other
+    /// decompressor given the same executor instead of spawning one per stream.
+    #[cfg(feature="std")]
+    fn new_with_executor(m8: AllocU8,
+                         mcdf16:AllocCDF16,
+                         mc: AllocCommand,
+                         skip_crc:bool,
+                         executor: ::thread_pool::ExecutorHandle) -> DivansDecompressor<Self::DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
+        let mut ret = Self::new(m8, mcdf16, mc, skip_crc, true);
+        if let DivansDecompressor::Header(ref mut header) = ret {
+            header.executor = Some(executor);
+        }
+        ret
+    }
 }
 
 #[derive(Default)]
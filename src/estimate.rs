@@ -0,0 +1,211 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Estimates the compressed size of an input without producing the stream: the compressor
+// runs over TallyingArithmeticEncoder and a DevNull muxer, so nothing is entropy coded or muxed.
+use alloc::{Allocator, SliceWrapper};
+use brotli;
+use interface::{BrotliCompressionSetting, Command, Compressor, DefaultCDF16, DivansCompressorOptions, DivansOutputResult, DivansResult, ErrMsg};
+use mux::DevNull;
+use brotli_ir_gen::BrotliDivansHybridCompressor;
+use divans_compressor::DivansCompressor;
+use ir_optimize::statistics_tracking_codec::{TallyingArithmeticEncoder, NUM_BILLING_CATEGORIES, category_billing_cost};
+
+// estimated compressed bits spent on each BillingDesignation category
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CostEstimate {
+    pub unknown: f64,
+    pub copy: f64,
+    pub dict: f64,
+    pub literal: f64,
+    pub cross_command: f64,
+    pub pred_mode_ctx_map: f64,
+    pub block_type: f64,
+    // the header and checksum, which are written verbatim
+    pub framing: f64,
+}
+
+impl CostEstimate {
+    fn new(category_cost: &[f64; NUM_BILLING_CATEGORIES], framing_bytes: usize) -> Self {
+        CostEstimate {
+            unknown: category_cost[0],
+            copy: category_cost[1],
+            dict: category_cost[2],
+            literal: category_cost[3],
+            cross_command: category_cost[4],
+            pred_mode_ctx_map: category_cost[5],
+            block_type: category_cost[6],
+            framing: (framing_bytes * 8) as f64,
+        }
+    }
+    pub fn total_bits(&self) -> f64 {
+        self.unknown + self.copy + self.dict + self.literal + self.cross_command
+            + self.pred_mode_ctx_map + self.block_type + self.framing
+    }
+    pub fn total_bytes(&self) -> f64 {
+        self.total_bits() / 8.0
+    }
+}
+
+// feeds the whole input through the compressor and returns how many bytes it still wrote out
+fn run_to_completion<C:Compressor>(compressor: &mut C, input: &[u8]) -> Result<usize, ErrMsg> {
+    let mut framing = [0u8; 256];
+    let mut framing_bytes = 0usize;
+    let mut input_offset = 0usize;
+    while input_offset < input.len() {
+        let mut output_offset = 0usize;
+        let ret = compressor.encode(input, &mut input_offset, &mut framing[..], &mut output_offset);
+        framing_bytes += output_offset;
+        match ret {
+            DivansResult::Failure(m) => return Err(m),
+            DivansResult::Success | DivansResult::NeedsMoreInput | DivansResult::NeedsMoreOutput => {},
+        }
+    }
+    loop {
+        let mut output_offset = 0usize;
+        let ret = compressor.flush(&mut framing[..], &mut output_offset);
+        framing_bytes += output_offset;
+        match ret {
+            DivansOutputResult::Success => return Ok(framing_bytes),
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => return Err(m),
+        }
+    }
+}
+
+// feeds already selected commands through the compressor and returns how many bytes it still wrote out
+fn run_commands_to_completion<C:Compressor,
+                              SliceType:SliceWrapper<u8>+Default>(compressor: &mut C,
+                                                                  commands: &[Command<SliceType>]) -> Result<usize, ErrMsg> {
+    let mut framing = [0u8; 256];
+    let mut framing_bytes = 0usize;
+    let mut command_offset = 0usize;
+    while command_offset < commands.len() {
+        let mut output_offset = 0usize;
+        let ret = compressor.encode_commands(commands, &mut command_offset, &mut framing[..], &mut output_offset);
+        framing_bytes += output_offset;
+        match ret {
+            DivansOutputResult::Failure(m) => return Err(m),
+            DivansOutputResult::Success | DivansOutputResult::NeedsMoreOutput => {},
+        }
+    }
+    loop {
+        let mut output_offset = 0usize;
+        let ret = compressor.flush(&mut framing[..], &mut output_offset);
+        framing_bytes += output_offset;
+        match ret {
+            DivansOutputResult::Success => return Ok(framing_bytes),
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => return Err(m),
+        }
+    }
+}
+
+pub fn estimate_cost_with_allocators<AllocU8:Allocator<u8>,
+                                     AllocU32:Allocator<u32>,
+                                     AllocCDF16:Allocator<DefaultCDF16>,
+                                     AllocBrotli:brotli::enc::BrotliAlloc>(input: &[u8],
+                                                                           opts: DivansCompressorOptions,
+                                                                           m8: AllocU8,
+                                                                           m32: AllocU32,
+                                                                           mcdf16: AllocCDF16,
+                                                                           alloc_brotli: AllocBrotli) -> Result<CostEstimate, ErrMsg> {
+    match opts.use_brotli {
+        BrotliCompressionSetting::UseInternalCommandSelection if opts.divans_ir_optimizer == 0 && !opts.block_split => {
+            let mut compressor = DivansCompressor::<TallyingArithmeticEncoder,
+                                                    AllocU8,
+                                                    AllocU32,
+                                                    AllocCDF16,
+                                                    DevNull<AllocU8>>::new(m8, m32, mcdf16, opts);
+            let ret = run_to_completion(&mut compressor, input).map(
+                |framing_bytes| CostEstimate::new(&category_billing_cost(compressor.get_codec()), framing_bytes));
+            compressor.free();
+            ret
+        },
+        _ => {
+            let mut compressor = BrotliDivansHybridCompressor::<DefaultCDF16,
+                                                                TallyingArithmeticEncoder,
+                                                                AllocU8,
+                                                                AllocCDF16,
+                                                                AllocBrotli,
+                                                                DevNull<AllocU8>>::new(m8, mcdf16, opts, alloc_brotli);
+            let ret = run_to_completion(&mut compressor, input).map(
+                |framing_bytes| CostEstimate::new(&category_billing_cost(compressor.get_codec()), framing_bytes));
+            compressor.free();
+            ret
+        },
+    }
+}
+
+// the cost of coding commands that were already selected, as an IR file or capture would hold them
+pub fn estimate_ir_cost_with_allocators<AllocU8:Allocator<u8>,
+                                        AllocU32:Allocator<u32>,
+                                        AllocCDF16:Allocator<DefaultCDF16>,
+                                        SliceType:SliceWrapper<u8>+Default>(commands: &[Command<SliceType>],
+                                                                            opts: DivansCompressorOptions,
+                                                                            m8: AllocU8,
+                                                                            m32: AllocU32,
+                                                                            mcdf16: AllocCDF16) -> Result<CostEstimate, ErrMsg> {
+    let mut compressor = DivansCompressor::<TallyingArithmeticEncoder,
+                                            AllocU8,
+                                            AllocU32,
+                                            AllocCDF16,
+                                            DevNull<AllocU8>>::new(m8, m32, mcdf16, opts);
+    let ret = run_commands_to_completion(&mut compressor, commands).map(
+        |framing_bytes| CostEstimate::new(&category_billing_cost(compressor.get_codec()), framing_bytes));
+    compressor.free();
+    ret
+}
+
+#[cfg(feature="std")]
+pub fn estimate_ir_cost<SliceType:SliceWrapper<u8>+Default>(commands: &[Command<SliceType>],
+                                                            opts: DivansCompressorOptions) -> Result<CostEstimate, ErrMsg> {
+    use alloc_stdlib::HeapAlloc;
+    estimate_ir_cost_with_allocators(commands,
+                                     opts,
+                                     HeapAlloc::<u8>::new(0),
+                                     HeapAlloc::<u32>::new(0),
+                                     HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()))
+}
+
+#[cfg(feature="std")]
+pub fn estimate_cost(input: &[u8], opts: DivansCompressorOptions) -> Result<CostEstimate, ErrMsg> {
+    use alloc_stdlib::HeapAlloc;
+    estimate_cost_with_allocators(input,
+                                  opts,
+                                  HeapAlloc::<u8>::new(0),
+                                  HeapAlloc::<u32>::new(0),
+                                  HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                                  brotli::enc::CombiningAllocator::new(
+                                      HeapAlloc::<u8>::new(0),
+                                      HeapAlloc::<u16>::new(0),
+                                      HeapAlloc::<i32>::new(0),
+                                      HeapAlloc::<u32>::new(0),
+                                      HeapAlloc::<u64>::new(0),
+                                      HeapAlloc::<brotli::enc::command::Command>::new(brotli::enc::command::Command::default()),
+                                      HeapAlloc::<brotli::enc::util::floatX>::new(0.0 as brotli::enc::util::floatX),
+                                      HeapAlloc::<brotli::enc::v8>::new(brotli::enc::v8::default()),
+                                      HeapAlloc::<brotli::enc::s16>::new(brotli::enc::s16::default()),
+                                      HeapAlloc::<brotli::enc::PDF>::new(brotli::enc::PDF::default()),
+                                      HeapAlloc::<brotli::enc::StaticCommand>::new(brotli::enc::StaticCommand::default()),
+                                      HeapAlloc::<brotli::enc::histogram::HistogramLiteral>::new(brotli::enc::histogram::HistogramLiteral::default()),
+                                      HeapAlloc::<brotli::enc::histogram::HistogramCommand>::new(brotli::enc::histogram::HistogramCommand::default()),
+                                      HeapAlloc::<brotli::enc::histogram::HistogramDistance>::new(brotli::enc::histogram::HistogramDistance::default()),
+                                      HeapAlloc::<brotli::enc::cluster::HistogramPair>::new(brotli::enc::cluster::HistogramPair::default()),
+                                      HeapAlloc::<brotli::enc::histogram::ContextType>::new(brotli::enc::histogram::ContextType::default()),
+                                      HeapAlloc::<brotli::enc::entropy_encode::HuffmanTree>::new(brotli::enc::entropy_encode::HuffmanTree::default()),
+                                      HeapAlloc::<brotli::enc::ZopfliNode>::new(brotli::enc::ZopfliNode::default()),
+                                  ))
+}
@@ -0,0 +1,251 @@
+#![cfg(feature="std")]
+// Max-effort compression: brotli selects the commands once, the captured IR is tallied under
+// candidate codec options and only the cheapest candidate is entropy coded.
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::vec::Vec;
+use alloc_stdlib::HeapAlloc;
+use brotli;
+use interface::{Compressor, DefaultCDF16, DivansCompressorFactory, DivansCompressorOptions, DivansOutputResult, DivansResult, ErrMsg};
+use mux::DevNull;
+use brotli_ir_gen::BrotliDivansHybridCompressor;
+use divans_compressor::DivansCompressorFactoryStruct;
+use codec::StrideSelection;
+use estimate::{CostEstimate, estimate_ir_cost};
+use filter::FilterSelection;
+use ir::IrCommand;
+use ir_optimize::statistics_tracking_codec::TallyingArithmeticEncoder;
+use probability::Speed;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExhaustiveResult {
+    pub options: DivansCompressorOptions,
+    pub estimate: CostEstimate,
+    // how many option sets were tallied, the starting options included
+    pub candidates: usize,
+}
+
+const STRIDES: [StrideSelection; 10] = [StrideSelection::UseBrotliRec, StrideSelection::PriorDisabled,
+                                        StrideSelection::Stride1, StrideSelection::Stride2,
+                                        StrideSelection::Stride3, StrideSelection::Stride4,
+                                        StrideSelection::Stride5, StrideSelection::Stride6,
+                                        StrideSelection::Stride7, StrideSelection::Stride8];
+const MIXINGS: [u8; 3] = [0, 1, 2];
+const SPEEDS: [Speed; 8] = [Speed::GEOLOGIC, Speed::GLACIAL, Speed::MUD, Speed::SLOW,
+                            Speed::MED, Speed::FAST, Speed::PLANE, Speed::ROCKET];
+const PRIOR_DEPTHS: [u8; 3] = [0, 1, 2];
+const NUM_DIMENSIONS: usize = 5;
+
+// the options that differ from `opts` in exactly one searched setting
+fn variations(opts: &DivansCompressorOptions, dimension: usize) -> Vec<DivansCompressorOptions> {
+    let mut ret = Vec::new();
+    match dimension {
+        0 => for &stride in STRIDES.iter().filter(|&&stride| stride as u8 != opts.force_stride_value as u8) {
+            ret.push(DivansCompressorOptions{force_stride_value: stride, ..*opts});
+        },
+        1 => ret.push(DivansCompressorOptions{use_context_map: !opts.use_context_map, ..*opts}),
+        2 => for &mixing in MIXINGS.iter().filter(|&&mixing| Some(mixing) != opts.dynamic_context_mixing) {
+            ret.push(DivansCompressorOptions{dynamic_context_mixing: Some(mixing), ..*opts});
+        },
+        3 => {
+            // None keeps the speeds brotli's detection put in each prediction mode
+            if opts.literal_adaptation.is_some() {
+                ret.push(DivansCompressorOptions{literal_adaptation: None, ..*opts});
+            }
+            for &speed in SPEEDS.iter().filter(|&&speed| Some([speed; 4]) != opts.literal_adaptation) {
+                ret.push(DivansCompressorOptions{literal_adaptation: Some([speed; 4]), ..*opts});
+            }
+        },
+        _ => for &depth in PRIOR_DEPTHS.iter().filter(|&&depth| depth != opts.prior_depth.unwrap_or(0)) {
+            ret.push(DivansCompressorOptions{prior_depth: Some(depth), ..*opts});
+        },
+    }
+    ret
+}
+
+type HeapBrotliAlloc = brotli::enc::CombiningAllocator<HeapAlloc<u8>,
+                                                       HeapAlloc<u16>,
+                                                       HeapAlloc<i32>,
+                                                       HeapAlloc<u32>,
+                                                       HeapAlloc<u64>,
+                                                       HeapAlloc<brotli::enc::command::Command>,
+                                                       HeapAlloc<brotli::enc::util::floatX>,
+                                                       HeapAlloc<brotli::enc::v8>,
+                                                       HeapAlloc<brotli::enc::s16>,
+                                                       HeapAlloc<brotli::enc::PDF>,
+                                                       HeapAlloc<brotli::enc::StaticCommand>,
+                                                       HeapAlloc<brotli::enc::histogram::HistogramLiteral>,
+                                                       HeapAlloc<brotli::enc::histogram::HistogramCommand>,
+                                                       HeapAlloc<brotli::enc::histogram::HistogramDistance>,
+                                                       HeapAlloc<brotli::enc::cluster::HistogramPair>,
+                                                       HeapAlloc<brotli::enc::histogram::ContextType>,
+                                                       HeapAlloc<brotli::enc::entropy_encode::HuffmanTree>,
+                                                       HeapAlloc<brotli::enc::ZopfliNode>>;
+
+fn heap_brotli_alloc() -> HeapBrotliAlloc {
+    brotli::enc::CombiningAllocator::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<u16>::new(0),
+        HeapAlloc::<i32>::new(0),
+        HeapAlloc::<u32>::new(0),
+        HeapAlloc::<u64>::new(0),
+        HeapAlloc::<brotli::enc::command::Command>::new(brotli::enc::command::Command::default()),
+        HeapAlloc::<brotli::enc::util::floatX>::new(0.0 as brotli::enc::util::floatX),
+        HeapAlloc::<brotli::enc::v8>::new(brotli::enc::v8::default()),
+        HeapAlloc::<brotli::enc::s16>::new(brotli::enc::s16::default()),
+        HeapAlloc::<brotli::enc::PDF>::new(brotli::enc::PDF::default()),
+        HeapAlloc::<brotli::enc::StaticCommand>::new(brotli::enc::StaticCommand::default()),
+        HeapAlloc::<brotli::enc::histogram::HistogramLiteral>::new(brotli::enc::histogram::HistogramLiteral::default()),
+        HeapAlloc::<brotli::enc::histogram::HistogramCommand>::new(brotli::enc::histogram::HistogramCommand::default()),
+        HeapAlloc::<brotli::enc::histogram::HistogramDistance>::new(brotli::enc::histogram::HistogramDistance::default()),
+        HeapAlloc::<brotli::enc::cluster::HistogramPair>::new(brotli::enc::cluster::HistogramPair::default()),
+        HeapAlloc::<brotli::enc::histogram::ContextType>::new(brotli::enc::histogram::ContextType::default()),
+        HeapAlloc::<brotli::enc::entropy_encode::HuffmanTree>::new(brotli::enc::entropy_encode::HuffmanTree::default()),
+        HeapAlloc::<brotli::enc::ZopfliNode>::new(brotli::enc::ZopfliNode::default()),
+    )
+}
+
+/// Runs brotli command selection (and the IR optimizer and block splitter, if enabled) over
+/// `input` once and returns the commands the divans codec would have coded.
+pub fn capture_brotli_ir(input: &[u8], opts: DivansCompressorOptions) -> Result<Vec<IrCommand>, ErrMsg> {
+    let mut compressor = BrotliDivansHybridCompressor::<DefaultCDF16,
+                                                        TallyingArithmeticEncoder,
+                                                        HeapAlloc<u8>,
+                                                        HeapAlloc<DefaultCDF16>,
+                                                        _,
+                                                        DevNull<HeapAlloc<u8>>>::new(HeapAlloc::<u8>::new(0),
+                                                                                     HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+                                                                                     opts,
+                                                                                     heap_brotli_alloc());
+    compressor.capture_ir();
+    let mut ret = Ok(());
+    let mut framing = [0u8; 256];
+    let mut input_offset = 0usize;
+    while ret.is_ok() && input_offset < input.len() {
+        let mut output_offset = 0usize;
+        if let DivansResult::Failure(m) = compressor.encode(input, &mut input_offset, &mut framing[..], &mut output_offset) {
+            ret = Err(m);
+        }
+    }
+    while ret.is_ok() {
+        let mut output_offset = 0usize;
+        match compressor.flush(&mut framing[..], &mut output_offset) {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => ret = Err(m),
+        }
+    }
+    let mut commands = Vec::new();
+    compressor.take_ir_commands(&mut commands);
+    compressor.free();
+    ret.map(|_| commands)
+}
+
+// tallies every candidate on up to num_threads threads; results line up with candidates
+fn estimate_candidates(commands: &[IrCommand],
+                       candidates: &[DivansCompressorOptions],
+                       num_threads: usize) -> Result<Vec<CostEstimate>, ErrMsg> {
+    let results = Mutex::new(vec![Ok(CostEstimate::default()); candidates.len()]);
+    let next = AtomicUsize::new(0);
+    let work = || loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        if index >= candidates.len() {
+            return;
+        }
+        let estimate = estimate_ir_cost(commands, candidates[index]);
+        results.lock().unwrap()[index] = estimate;
+    };
+    thread::scope(|scope| {
+        for _ in 1..core::cmp::min(num_threads, candidates.len()) {
+            scope.spawn(work);
+        }
+        work();
+    });
+    results.into_inner().unwrap().into_iter().collect()
+}
+
+/// Searches stride, context map use, mixing, literal adaptation speed and prior depth one
+/// setting at a time, keeping each change that lowers the tallied size of `commands`.
+pub fn search_options(commands: &[IrCommand],
+                      opts: DivansCompressorOptions,
+                      num_threads: usize) -> Result<ExhaustiveResult, ErrMsg> {
+    let mut best = ExhaustiveResult {
+        options: opts,
+        estimate: estimate_ir_cost(commands, opts)?,
+        candidates: 1,
+    };
+    for dimension in 0..NUM_DIMENSIONS {
+        let candidates = variations(&best.options, dimension);
+        let estimates = estimate_candidates(commands, &candidates, num_threads)?;
+        best.candidates += candidates.len();
+        for (candidate, estimate) in candidates.iter().zip(estimates.iter()) {
+            if estimate.total_bits() < best.estimate.total_bits() {
+                best.options = *candidate;
+                best.estimate = *estimate;
+            }
+        }
+    }
+    Ok(best)
+}
+
+/// Entropy codes already selected commands into a complete divans stream.
+pub fn encode_ir(commands: &[IrCommand], opts: DivansCompressorOptions, output: &mut Vec<u8>) -> Result<(), ErrMsg> {
+    let mut compressor = DivansCompressorFactoryStruct::<HeapAlloc<u8>, HeapAlloc<DefaultCDF16>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<u32>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        opts,
+        ());
+    let mut ret = Ok(());
+    let mut buffer = [0u8; 65_536];
+    let mut command_offset = 0usize;
+    while ret.is_ok() && command_offset < commands.len() {
+        let mut output_offset = 0usize;
+        if let DivansOutputResult::Failure(m) = compressor.encode_commands(commands, &mut command_offset, &mut buffer[..], &mut output_offset) {
+            ret = Err(m);
+        }
+        output.extend_from_slice(&buffer[..output_offset]);
+    }
+    while ret.is_ok() {
+        let mut output_offset = 0usize;
+        let res = compressor.flush(&mut buffer[..], &mut output_offset);
+        output.extend_from_slice(&buffer[..output_offset]);
+        match res {
+            DivansOutputResult::Success => break,
+            DivansOutputResult::NeedsMoreOutput => {},
+            DivansOutputResult::Failure(m) => ret = Err(m),
+        }
+    }
+    compressor.free();
+    ret
+}
+
+/// Compresses `input` with the best options `search_options` finds, starting from `opts`.
+/// Filters are not applied: the IR has to describe the bytes the decoder produces.
+pub fn compress_exhaustive(input: &[u8],
+                           mut opts: DivansCompressorOptions,
+                           num_threads: usize,
+                           output: &mut Vec<u8>) -> Result<ExhaustiveResult, ErrMsg> {
+    opts.filters = FilterSelection::Disabled;
+    let commands = capture_brotli_ir(input, opts)?;
+    let best = search_options(&commands, opts, num_threads)?;
+    encode_ir(&commands, best.options, output)?;
+    Ok(best)
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use interface::DivansCompressorOptions;
+    use codec::StrideSelection;
+    use super::{variations, NUM_DIMENSIONS};
+    #[test]
+    fn test_variations_skip_current_setting() {
+        let opts = DivansCompressorOptions::default();
+        let counts: Vec<usize> = (0..NUM_DIMENSIONS).map(|dimension| variations(&opts, dimension).len()).collect();
+        assert_eq!(counts, [9, 1, 2, 8, 2]);
+        assert!(variations(&opts, 0).iter().all(|v| v.force_stride_value as u8 != StrideSelection::UseBrotliRec as u8));
+        assert!(!variations(&opts, 1)[0].use_context_map);
+    }
+}
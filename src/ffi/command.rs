@@ -0,0 +1,118 @@
+// Conversions between the C command structs and the codec's commands. Commands coming from C
+// borrow the caller's literal and context map buffers; commands handed to C point into ours.
+use alloc::SliceWrapper;
+use ::interface::{Command, CopyCommand, DictCommand, LiteralCommand, BlockSwitch, LiteralBlockSwitch,
+                  PredictionModeContextMap, FeatureFlagSliceType,
+                  DISTANCE_CONTEXT_MAP_OFFSET, MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE,
+                  MAX_ADV_LITERAL_CONTEXT_MAP_SIZE};
+use super::interface::*;
+use super::slice_or_empty;
+
+#[derive(Default)]
+pub struct BorrowedSlice<'a>(&'a [u8]);
+
+impl<'a> SliceWrapper<u8> for BorrowedSlice<'a> {
+    fn slice(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// Returns None for an unknown command type or a prediction mode whose maps the codec cannot index.
+///
+/// # Safety
+///
+/// The pointers of the member selected by `command_type` must be valid for their sizes for `'a`.
+pub unsafe fn from_c_command<'a>(cmd: &'a DivansCommand) -> Option<Command<BorrowedSlice<'a>>> {
+    Some(match cmd.command_type {
+        DIVANS_COMMAND_COPY => Command::Copy(CopyCommand{
+            distance: cmd.data.copy.distance,
+            num_bytes: cmd.data.copy.num_bytes,
+        }),
+        DIVANS_COMMAND_DICT => Command::Dict(DictCommand{
+            word_size: cmd.data.dict.word_size,
+            transform: cmd.data.dict.transform,
+            final_size: cmd.data.dict.final_size,
+            empty: cmd.data.dict.empty,
+            word_id: cmd.data.dict.word_id,
+        }),
+        DIVANS_COMMAND_LITERAL => Command::Literal(LiteralCommand{
+            data: BorrowedSlice(slice_or_empty(cmd.data.literal.data, cmd.data.literal.data_size)),
+            prob: FeatureFlagSliceType::default(),
+            high_entropy: cmd.data.literal.high_entropy != 0,
+        }),
+        DIVANS_COMMAND_BLOCK_SWITCH_COMMAND => Command::BlockSwitchCommand(BlockSwitch::new(cmd.data.block_switch.block_type)),
+        DIVANS_COMMAND_BLOCK_SWITCH_LITERAL => Command::BlockSwitchLiteral(LiteralBlockSwitch::new(cmd.data.block_switch.block_type,
+                                                                                                   cmd.data.block_switch.stride)),
+        DIVANS_COMMAND_BLOCK_SWITCH_DISTANCE => Command::BlockSwitchDistance(BlockSwitch::new(cmd.data.block_switch.block_type)),
+        DIVANS_COMMAND_PREDICTION_MODE => {
+            let pm = cmd.data.prediction_mode;
+            if pm.predmode_speed_and_distance_context_map_size < DISTANCE_CONTEXT_MAP_OFFSET
+                || pm.predmode_speed_and_distance_context_map_size > MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE
+                || pm.literal_context_map_size > MAX_ADV_LITERAL_CONTEXT_MAP_SIZE {
+                return None;
+            }
+            Command::PredictionMode(PredictionModeContextMap{
+                literal_context_map: BorrowedSlice(slice_or_empty(pm.literal_context_map, pm.literal_context_map_size)),
+                predmode_speed_and_distance_context_map: BorrowedSlice(slice_or_empty(pm.predmode_speed_and_distance_context_map,
+                                                                                      pm.predmode_speed_and_distance_context_map_size)),
+            })
+        },
+        _ => return None,
+    })
+}
+
+/// The returned struct points into `cmd`, so it is only valid while `cmd` is.
+pub fn to_c_command<SliceType: SliceWrapper<u8>>(cmd: &Command<SliceType>) -> DivansCommand {
+    match *cmd {
+        Command::Copy(ref cp) => DivansCommand{
+            command_type: DIVANS_COMMAND_COPY,
+            data: DivansCommandData{copy: DivansCopyCommand{
+                distance: cp.distance,
+                num_bytes: cp.num_bytes,
+            }},
+        },
+        Command::Dict(ref dc) => DivansCommand{
+            command_type: DIVANS_COMMAND_DICT,
+            data: DivansCommandData{dict: DivansDictCommand{
+                word_size: dc.word_size,
+                transform: dc.transform,
+                final_size: dc.final_size,
+                empty: dc.empty,
+                word_id: dc.word_id,
+            }},
+        },
+        Command::Literal(ref lit) => DivansCommand{
+            command_type: DIVANS_COMMAND_LITERAL,
+            data: DivansCommandData{literal: DivansLiteralCommand{
+                data: lit.data.slice().as_ptr(),
+                data_size: lit.data.slice().len(),
+                high_entropy: lit.high_entropy as u8,
+            }},
+        },
+        Command::BlockSwitchCommand(bs) => block_switch(DIVANS_COMMAND_BLOCK_SWITCH_COMMAND, bs.block_type(), 0),
+        Command::BlockSwitchLiteral(bs) => block_switch(DIVANS_COMMAND_BLOCK_SWITCH_LITERAL, bs.block_type(), bs.stride()),
+        Command::BlockSwitchDistance(bs) => block_switch(DIVANS_COMMAND_BLOCK_SWITCH_DISTANCE, bs.block_type(), 0),
+        Command::PredictionMode(ref pm) => DivansCommand{
+            command_type: DIVANS_COMMAND_PREDICTION_MODE,
+            data: DivansCommandData{prediction_mode: DivansPredictionModeCommand{
+                literal_context_map: pm.literal_context_map.slice().as_ptr(),
+                literal_context_map_size: pm.literal_context_map.slice().len(),
+                predmode_speed_and_distance_context_map: pm.predmode_speed_and_distance_context_map.slice().as_ptr(),
+                predmode_speed_and_distance_context_map_size: pm.predmode_speed_and_distance_context_map.slice().len(),
+            }},
+        },
+    }
+}
+
+fn block_switch(command_type: DivansCommandType, block_type: u8, stride: u8) -> DivansCommand {
+    DivansCommand{
+        command_type,
+        data: DivansCommandData{block_switch: DivansBlockSwitchCommand{
+            block_type,
+            stride,
+        }},
+    }
+}
+
+// how many C commands are converted per encode_commands call
+pub const COMMAND_BATCH_SIZE: usize = 16;
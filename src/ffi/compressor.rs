@@ -1,38 +1,39 @@
 use ::brotli;
-use ::interface::{DivansResult, DivansOutputResult};
+use ::interface::{DivansResult, DivansOutputResult, ErrMsg};
 use ::brotli::enc::interface::LiteralPredictionModeNibble;
 
 use core;
-use ::interface::{DivansCompressorOptions, BrotliCompressionSetting, StrideSelection, DivansCompressorFactory, Compressor};
+use ::interface::{DivansCompressorOptions, EntropyCoderSelection, CDFFamily, MuxPolicy, StreamCount, BrotliCompressionSetting, StrideSelection, DivansCompressorFactory, Compressor};
 use ::probability::Speed;
 use super::alloc_util::SubclassableAllocator;
 use super::interface::*;
+use super::command::{BorrowedSlice, COMMAND_BATCH_SIZE, from_c_command};
 
 type InternalCompressorFactory = ::DivansCompressorFactoryStruct<SubclassableAllocator<u8>,
                                                          SubclassableAllocator<::DefaultCDF16>>;
 
+type FFIBrotliAllocator = brotli::enc::CombiningAllocator<SubclassableAllocator<u8>,
+                                                         SubclassableAllocator<u16>,
+                                                         SubclassableAllocator<i32>,
+                                                         SubclassableAllocator<u32>,
+                                                         SubclassableAllocator<u64>,
+                                                         SubclassableAllocator<brotli::enc::command::Command>,
+                                                         SubclassableAllocator<brotli::enc::util::floatX>,
+                                                         SubclassableAllocator<brotli::enc::v8>,
+                                                         SubclassableAllocator<brotli::enc::s16>,
+                                                         SubclassableAllocator<brotli::enc::PDF>,
+                                                         SubclassableAllocator<brotli::enc::StaticCommand>,
+                                                         SubclassableAllocator<brotli::enc::histogram::HistogramLiteral>,
+                                                         SubclassableAllocator<brotli::enc::histogram::HistogramCommand>,
+                                                         SubclassableAllocator<brotli::enc::histogram::HistogramDistance>,
+                                                         SubclassableAllocator<brotli::enc::cluster::HistogramPair>,
+                                                         SubclassableAllocator<brotli::enc::histogram::ContextType>,
+                                                         SubclassableAllocator<brotli::enc::entropy_encode::HuffmanTree>,
+                                                         SubclassableAllocator<brotli::enc::ZopfliNode>>;
+
 type FFIHybridBrotliFactory = ::BrotliDivansHybridCompressorFactory<SubclassableAllocator<u8>,
-//                                                                 SubclassableAllocator<u32>,
                                                                  SubclassableAllocator<::DefaultCDF16>,
-                                                                 brotli::enc::CombiningAllocator<
-                                                                         SubclassableAllocator<u8>,
-                                                                     SubclassableAllocator<u16>,
-                                                                     SubclassableAllocator<i32>,
-                                                                     SubclassableAllocator<u32>,
-                                                                     SubclassableAllocator<u64>,
-                                                                     SubclassableAllocator<brotli::enc::command::Command>,
-                                                                     SubclassableAllocator<brotli::enc::util::floatX>,
-                                                                     SubclassableAllocator<brotli::enc::v8>,
-                                                                     SubclassableAllocator<brotli::enc::s16>,
-                                                                     SubclassableAllocator<brotli::enc::PDF>,
-                                                                     SubclassableAllocator<brotli::enc::StaticCommand>,
-                                                                     SubclassableAllocator<brotli::enc::histogram::HistogramLiteral>,
-                                                                     SubclassableAllocator<brotli::enc::histogram::HistogramCommand>,
-                                                                     SubclassableAllocator<brotli::enc::histogram::HistogramDistance>,
-                                                                     SubclassableAllocator<brotli::enc::cluster::HistogramPair>,
-                                                                     SubclassableAllocator<brotli::enc::histogram::ContextType>,
-                                                                     SubclassableAllocator<brotli::enc::entropy_encode::HuffmanTree>,
-                                                                     SubclassableAllocator<brotli::enc::ZopfliNode>>>;
+                                                                 FFIBrotliAllocator>;
 type FFIDivansBrotliConstructedCompressor = <FFIHybridBrotliFactory as ::DivansCompressorFactory<SubclassableAllocator<u8>,
                                                                                                  SubclassableAllocator<u32>,
                                                                                                  SubclassableAllocator<::DefaultCDF16>>>::ConstructedCompressor;
@@ -48,6 +49,29 @@ type FFIInternalCompressor = ::DivansCompressor<FFIInternalDefaultEncoder,
                                                 SubclassableAllocator<::DefaultCDF16>>;
 
 
+fn new_brotli_allocator(allocators: &CAllocator) -> FFIBrotliAllocator {
+    brotli::enc::CombiningAllocator::new(
+        SubclassableAllocator::<u8>::new(allocators.clone()),
+        SubclassableAllocator::<u16>::new(allocators.clone()),
+        SubclassableAllocator::<i32>::new(allocators.clone()),
+        SubclassableAllocator::<u32>::new(allocators.clone()),
+        SubclassableAllocator::<u64>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::command::Command>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::util::floatX>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::v8>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::s16>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::PDF>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::StaticCommand>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::histogram::HistogramLiteral>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::histogram::HistogramCommand>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::histogram::HistogramDistance>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::cluster::HistogramPair>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::histogram::ContextType>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::entropy_encode::HuffmanTree>::new(allocators.clone()),
+        SubclassableAllocator::<brotli::enc::ZopfliNode>::new(allocators.clone()),
+    )
+}
+
 pub enum CompressorState {
     OptionStage(DivansCompressorOptions),
     BrotliCompressor(FFIDivansBrotliConstructedCompressor),
@@ -158,15 +182,55 @@ impl CompressorState {
                 DIVANS_OPTION_IR_OPTIMIZER => {
                     opts.divans_ir_optimizer = value as u8;
                 },
+                DIVANS_OPTION_BLOCK_SPLIT => {
+                    opts.block_split = value != 0;
+                },
+                DIVANS_OPTION_ENTROPY_CODER => {
+                    opts.entropy_coder = match EntropyCoderSelection::new(value as u8) {
+                        Ok(coder) if value <= 0xff => coder,
+                        _ => return DIVANS_FAILURE,
+                    };
+                },
+                DIVANS_OPTION_CDF_FAMILY => {
+                    opts.cdf_family = match CDFFamily::new(value as u8) {
+                        Ok(family) if value <= 0xff => family,
+                        _ => return DIVANS_FAILURE,
+                    };
+                },
+                DIVANS_OPTION_MUX_POLICY => {
+                    opts.mux_policy = match MuxPolicy::new(value as u8) {
+                        Ok(policy) if value <= 0xff => policy,
+                        _ => return DIVANS_FAILURE,
+                    };
+                },
+                DIVANS_OPTION_STREAM_COUNT => {
+                    opts.stream_count = match StreamCount::new(value as usize) {
+                        Ok(count) => count,
+                        _ => return DIVANS_FAILURE,
+                    };
+                },
                 _ => return DIVANS_FAILURE,
             }
             return DIVANS_SUCCESS;
         }
         DIVANS_FAILURE
     }
+    pub fn estimate_cost(&self, input_buf:&[u8], allocators: &CAllocator) -> Result<::CostEstimate, ErrMsg> {
+        match *self {
+            CompressorState::OptionStage(opts) => ::estimate_cost_with_allocators(
+                input_buf,
+                opts,
+                SubclassableAllocator::<u8>::new(allocators.clone()),
+                SubclassableAllocator::<u32>::new(allocators.clone()),
+                SubclassableAllocator::<::DefaultCDF16>::new(allocators.clone()),
+                new_brotli_allocator(allocators)),
+            // the options are only known before compression starts
+            _ => Err(ErrMsg::WrongInternalEncoderState(1)),
+        }
+    }
     fn start(&mut self, allocators: &CAllocator, opts:DivansCompressorOptions) {
         match opts.use_brotli {
-            BrotliCompressionSetting::UseInternalCommandSelection => {
+            BrotliCompressionSetting::UseInternalCommandSelection if opts.divans_ir_optimizer == 0 && !opts.block_split => {
                 core::mem::replace(self,
                                    CompressorState::InternalCompressor(
                                        InternalCompressorFactory::new(
@@ -184,26 +248,7 @@ impl CompressorState {
                                            SubclassableAllocator::<u32>::new(allocators.clone()),
                                            SubclassableAllocator::<::DefaultCDF16>::new(allocators.clone()),
                                            opts,
-                                               brotli::enc::CombiningAllocator::new(
-                                                   SubclassableAllocator::<u8>::new(allocators.clone()),
-                                                   SubclassableAllocator::<u16>::new(allocators.clone()),
-                                                   SubclassableAllocator::<i32>::new(allocators.clone()),
-                                                   SubclassableAllocator::<u32>::new(allocators.clone()),
-                                                   SubclassableAllocator::<u64>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::command::Command>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::util::floatX>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::v8>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::s16>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::PDF>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::StaticCommand>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::histogram::HistogramLiteral>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::histogram::HistogramCommand>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::histogram::HistogramDistance>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::cluster::HistogramPair>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::histogram::ContextType>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::entropy_encode::HuffmanTree>::new(allocators.clone()),
-                                                   SubclassableAllocator::<brotli::enc::ZopfliNode>::new(allocators.clone()),
-                                           ))));
+                                           new_brotli_allocator(allocators))));
             
             }
 
@@ -234,6 +279,49 @@ impl CompressorState {
             DivansResult::NeedsMoreOutput => DIVANS_NEEDS_MORE_OUTPUT,
         }
     }
+    /// Encodes commands from `*command_offset` on, converting them in small batches.
+    ///
+    /// # Safety
+    ///
+    /// The buffers each command points to must be valid for the sizes it gives.
+    pub unsafe fn encode_commands(&mut self,
+                                  commands:&[DivansCommand],
+                                  command_offset: &mut usize,
+                                  output_buf:&mut[u8],
+                                  output_offset: &mut usize,
+                                  allocators: &CAllocator) -> DivansReturnCode {
+        if let CompressorState::OptionStage(opts) = *self {
+            self.start(allocators, opts);
+        }
+        while *command_offset < commands.len() {
+            let mut batch: [::interface::Command<BorrowedSlice>; COMMAND_BATCH_SIZE] = Default::default();
+            let batch_len = core::cmp::min(batch.len(), commands.len() - *command_offset);
+            for (dst, src) in batch.iter_mut().zip(commands[*command_offset..].iter()) {
+                *dst = match from_c_command(src) {
+                    Some(cmd) => cmd,
+                    None => return DIVANS_FAILURE,
+                };
+            }
+            // a command cut short by a full output buffer is resumed when it is passed in again
+            let mut batch_offset = 0usize;
+            let res = match *self {
+                CompressorState::OptionStage(_) => unreachable!(),
+                CompressorState::BrotliCompressor(ref mut compressor) => {
+                    compressor.encode_commands(&batch[..batch_len], &mut batch_offset, output_buf, output_offset)
+                },
+                CompressorState::InternalCompressor(ref mut compressor) => {
+                    compressor.encode_commands(&batch[..batch_len], &mut batch_offset, output_buf, output_offset)
+                },
+            };
+            *command_offset += batch_offset;
+            match res {
+                DivansOutputResult::Success => {},
+                DivansOutputResult::Failure(_) => return DIVANS_FAILURE,
+                DivansOutputResult::NeedsMoreOutput => return DIVANS_NEEDS_MORE_OUTPUT,
+            }
+        }
+        DIVANS_SUCCESS
+    }
     pub fn flush(&mut self,
               output_buf:&mut[u8],
              output_offset: &mut usize,
@@ -263,6 +351,7 @@ impl CompressorState {
 #[no_mangle]
 pub struct DivansCompressorState {
     pub custom_allocator: CAllocator,
+    pub stats: DivansStats,
     pub compressor: CompressorState
 }
 
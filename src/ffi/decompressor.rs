@@ -1,6 +1,10 @@
 use super::alloc_util::SubclassableAllocator;
 use divans_decompressor::StaticCommand;
-use super::interface::CAllocator;
+use super::interface::*;
+#[cfg(feature="std")]
+use super::command::to_c_command;
+#[cfg(feature="std")]
+use std::vec::Vec;
 //use ::interface::DivansDecompressorFactory;
 pub type DecompressorFactory = ::DivansDecompressorFactoryStruct<SubclassableAllocator<u8>,
                                                                  SubclassableAllocator<::DefaultCDF16>,
@@ -9,11 +13,54 @@ pub type DecompressorFactory = ::DivansDecompressorFactoryStruct<SubclassableAll
 #[no_mangle]
 pub struct DivansDecompressorState {
     pub custom_allocator: CAllocator,
+    pub stats: DivansStats,
     pub decompressor: ::DivansDecompressor<<DecompressorFactory as ::DivansDecompressorFactory<SubclassableAllocator<u8>,  SubclassableAllocator<::DefaultCDF16>, SubclassableAllocator<StaticCommand>>>::DefaultDecoder,
                                            SubclassableAllocator<u8>,
                                            SubclassableAllocator<::DefaultCDF16>,
                                            SubclassableAllocator<StaticCommand>>,
 }
+impl DivansDecompressorState {
+    pub fn set_option(&mut self, selector: DivansOptionSelect, value: u32) -> DivansReturnCode {
+        let applied = match selector {
+            DIVANS_DECOMPRESSOR_OPTION_SKIP_CRC => match value {
+                0 | 1 => self.decompressor.set_skip_crc(value != 0),
+                _ => false,
+            },
+            DIVANS_DECOMPRESSOR_OPTION_MEMORY_LIMIT => self.decompressor.set_memory_limit(
+                if value == 0 {None} else {Some(value as usize)}),
+            DIVANS_DECOMPRESSOR_OPTION_CAPTURE_COMMANDS => match value {
+                1 => self.capture_commands(),
+                _ => false,
+            },
+            _ => false,
+        };
+        if applied {DIVANS_SUCCESS} else {DIVANS_FAILURE}
+    }
+    #[cfg(feature="std")]
+    fn capture_commands(&mut self) -> bool {
+        self.decompressor.capture_ir()
+    }
+    // the decoder only keeps copies of its commands in a std build
+    #[cfg(not(feature="std"))]
+    fn capture_commands(&mut self) -> bool {
+        false
+    }
+    #[cfg(feature="std")]
+    pub fn take_commands(&mut self, callback: extern "C" fn(*mut c_void, *const DivansCommand), opaque: *mut c_void) -> DivansReturnCode {
+        let mut commands = Vec::new();
+        self.decompressor.take_ir_commands(&mut commands);
+        for cmd in commands.iter() {
+            let c_command = to_c_command(cmd);
+            callback(opaque, &c_command);
+        }
+        DIVANS_SUCCESS
+    }
+    #[cfg(not(feature="std"))]
+    pub fn take_commands(&mut self, _callback: extern "C" fn(*mut c_void, *const DivansCommand), _opaque: *mut c_void) -> DivansReturnCode {
+        DIVANS_FAILURE
+    }
+}
+
 impl Drop for DivansDecompressorState {
     fn drop(&mut self) {
         self.decompressor.free_ref();
@@ -35,7 +35,96 @@ pub const DIVANS_OPTION_PRIOR_BITMASK_DETECTION:DivansOptionSelect = 17;
 pub const DIVANS_OPTION_Q9_5:DivansOptionSelect = 18;
 pub const DIVANS_OPTION_FORCE_LITERAL_CONTEXT_MODE:DivansOptionSelect = 19;
 pub const DIVANS_OPTION_IR_OPTIMIZER:DivansOptionSelect = 20;
+pub const DIVANS_OPTION_BLOCK_SPLIT:DivansOptionSelect = 21;
+pub const DIVANS_OPTION_ENTROPY_CODER:DivansOptionSelect = 22;
+pub const DIVANS_OPTION_CDF_FAMILY:DivansOptionSelect = 23;
+pub const DIVANS_OPTION_MUX_POLICY:DivansOptionSelect = 24;
+pub const DIVANS_OPTION_STREAM_COUNT:DivansOptionSelect = 25;
 
+// selectors for divans_set_decompressor_option, a separate namespace from the compressor's
+pub const DIVANS_DECOMPRESSOR_OPTION_SKIP_CRC:DivansOptionSelect = 1;
+pub const DIVANS_DECOMPRESSOR_OPTION_MEMORY_LIMIT:DivansOptionSelect = 2;
+pub const DIVANS_DECOMPRESSOR_OPTION_CAPTURE_COMMANDS:DivansOptionSelect = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct DivansStats {
+    pub total_in: u64,
+    pub total_out: u64,
+}
+
+
+pub type DivansCommandType = u8;
+
+pub const DIVANS_COMMAND_COPY:DivansCommandType = 1;
+pub const DIVANS_COMMAND_DICT:DivansCommandType = 2;
+pub const DIVANS_COMMAND_LITERAL:DivansCommandType = 3;
+pub const DIVANS_COMMAND_BLOCK_SWITCH_COMMAND:DivansCommandType = 4;
+pub const DIVANS_COMMAND_BLOCK_SWITCH_LITERAL:DivansCommandType = 5;
+pub const DIVANS_COMMAND_BLOCK_SWITCH_DISTANCE:DivansCommandType = 6;
+pub const DIVANS_COMMAND_PREDICTION_MODE:DivansCommandType = 7;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DivansCopyCommand {
+    pub distance: u32,
+    pub num_bytes: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DivansDictCommand {
+    pub word_size: u8,
+    pub transform: u8,
+    pub final_size: u8,
+    pub empty: u8,
+    pub word_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DivansLiteralCommand {
+    pub data: *const u8,
+    pub data_size: usize,
+    pub high_entropy: u8,
+}
+
+// used by all three block switch types; stride only applies to literal block switches
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DivansBlockSwitchCommand {
+    pub block_type: u8,
+    pub stride: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DivansPredictionModeCommand {
+    pub literal_context_map: *const u8,
+    pub literal_context_map_size: usize,
+    pub predmode_speed_and_distance_context_map: *const u8,
+    pub predmode_speed_and_distance_context_map_size: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union DivansCommandData {
+    pub copy: DivansCopyCommand,
+    pub dict: DivansDictCommand,
+    pub literal: DivansLiteralCommand,
+    pub block_switch: DivansBlockSwitchCommand,
+    pub prediction_mode: DivansPredictionModeCommand,
+}
+
+// command_type selects the member of data that is set
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DivansCommand {
+    pub command_type: DivansCommandType,
+    pub data: DivansCommandData,
+}
+
+pub type DivansCommandCallback = Option<extern "C" fn(opaque: *mut c_void, command: *const DivansCommand) -> ()>;
 
 #[repr(C)]
 #[no_mangle]
@@ -4,16 +4,21 @@
 use core;
 use core::slice;
 use ::StaticCommand;
+use ::interface::{BrotliCompressionSetting, CDFFamily, DivansCompressorOptions, EntropyCoderSelection, DISTANCE_CONTEXT_MAP_OFFSET};
+use ::codec::StrideSelection;
+use ::probability::Speed;
 use super::DivansDecompressorFactory;
 use super::interface::Decompressor;
 pub mod interface;
 pub mod alloc_util;
 use self::alloc_util::SubclassableAllocator;
+mod command;
 mod compressor;
 mod decompressor;
 use self::compressor::DivansCompressorState;
 use self::decompressor::DivansDecompressorState;
-use self::interface::{CAllocator, c_void, DivansOptionSelect, DivansReturnCode, DIVANS_FAILURE, DIVANS_SUCCESS, DIVANS_NEEDS_MORE_INPUT, DIVANS_NEEDS_MORE_OUTPUT};
+use self::interface::{CAllocator, c_void, DivansCommand, DivansCommandData, DivansCommandCallback, DivansLiteralCommand, DivansPredictionModeCommand,
+                      DIVANS_COMMAND_LITERAL, DIVANS_COMMAND_PREDICTION_MODE, DivansOptionSelect, DivansReturnCode, DivansStats, DIVANS_FAILURE, DIVANS_SUCCESS, DIVANS_NEEDS_MORE_INPUT, DIVANS_NEEDS_MORE_OUTPUT};
 #[no_mangle]
 pub extern fn divans_new_compressor() -> *mut compressor::DivansCompressorState{
     unsafe {
@@ -39,6 +44,7 @@ fn divans_new_compressor_without_custom_alloc(to_box: DivansCompressorState) ->
 pub unsafe extern fn divans_new_compressor_with_custom_alloc(allocators:CAllocator) -> *mut DivansCompressorState{
     let to_box = DivansCompressorState{
         custom_allocator:allocators.clone(),
+        stats:DivansStats::default(),
         compressor:compressor::CompressorState::default(),
     };
     if let Some(alloc_fn) = allocators.alloc_func {
@@ -81,7 +87,11 @@ pub unsafe extern fn divans_encode(state_ptr: *mut DivansCompressorState,
                     match state_ptr.as_mut() {
                         None => return DIVANS_FAILURE,
                         Some(state_ref) => {
-                            return state_ref.compressor.encode(input_buf, input_offset, output_buf, output_offset, &state_ref.custom_allocator);
+                            let (old_input_offset, old_output_offset) = (*input_offset, *output_offset);
+                            let ret = state_ref.compressor.encode(input_buf, input_offset, output_buf, output_offset, &state_ref.custom_allocator);
+                            state_ref.stats.total_in += (*input_offset - old_input_offset) as u64;
+                            state_ref.stats.total_out += (*output_offset - old_output_offset) as u64;
+                            ret
                         }
                     }
                 }
@@ -100,13 +110,245 @@ pub unsafe extern fn divans_encode_flush(state_ptr: *mut DivansCompressorState,
             match state_ptr.as_mut() {
                 None => return DIVANS_FAILURE,
                 Some(state_ref) => {
-                    return state_ref.compressor.flush(output_buf, output_offset, &state_ref.custom_allocator);
+                    let old_output_offset = *output_offset;
+                    let ret = state_ref.compressor.flush(output_buf, output_offset, &state_ref.custom_allocator);
+                    state_ref.stats.total_out += (*output_offset - old_output_offset) as u64;
+                    ret
                 }
             }
         }
     }
 }
 
+/// Encodes commands instead of raw bytes; the stream still has to be finished with
+/// `divans_encode_flush`. Returns DIVANS_FAILURE for a command of unknown type.
+///
+/// # Safety
+///
+/// `commands_ptr` must point to `num_commands` commands whose literal and context map
+/// pointers are valid for the sizes they give, and `output_buf_ptr` to `output_size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn divans_encode_commands(state_ptr: *mut DivansCompressorState,
+                                            commands_ptr: *const DivansCommand, num_commands: usize, command_offset_ptr: *mut usize,
+                                            output_buf_ptr: *mut u8, output_size: usize, output_offset_ptr: *mut usize) -> DivansReturnCode {
+    let commands = slice_or_empty(commands_ptr, num_commands);
+    let output_buf = slice_or_empty_mut(output_buf_ptr, output_size);
+    match (state_ptr.as_mut(), command_offset_ptr.as_mut(), output_offset_ptr.as_mut()) {
+        (Some(state_ref), Some(command_offset), Some(output_offset)) => {
+            let old_output_offset = *output_offset;
+            let ret = state_ref.compressor.encode_commands(commands, command_offset, output_buf, output_offset, &state_ref.custom_allocator);
+            state_ref.stats.total_out += (*output_offset - old_output_offset) as u64;
+            ret
+        },
+        _ => DIVANS_FAILURE,
+    }
+}
+
+/// Estimates the compressed size of the input under the options set so far, without compressing it.
+///
+/// # Safety
+///
+/// `input_buf_ptr` must point to `input_size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn divans_estimate_cost(state_ptr: *mut DivansCompressorState,
+                                          input_buf_ptr: *const u8, input_size: usize,
+                                          estimate_ptr: *mut ::CostEstimate) -> DivansReturnCode {
+    let input_buf = slice::from_raw_parts(input_buf_ptr, input_size);
+    match estimate_ptr.as_mut() {
+        None => DIVANS_FAILURE,
+        Some(estimate) => {
+            match state_ptr.as_mut() {
+                None => DIVANS_FAILURE,
+                Some(state_ref) => {
+                    match state_ref.compressor.estimate_cost(input_buf, &state_ref.custom_allocator) {
+                        Ok(cost) => {
+                            *estimate = cost;
+                            DIVANS_SUCCESS
+                        },
+                        Err(_) => DIVANS_FAILURE,
+                    }
+                }
+            }
+        }
+    }
+}
+
+// the one-shot calls accept NULL for empty arrays
+unsafe fn slice_or_empty<'a, T>(ptr: *const T, len: usize) -> &'a [T] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(ptr, len)
+    }
+}
+
+unsafe fn slice_or_empty_mut<'a, T>(ptr: *mut T, len: usize) -> &'a mut [T] {
+    if len == 0 {
+        &mut []
+    } else {
+        slice::from_raw_parts_mut(ptr, len)
+    }
+}
+
+fn rust_allocator() -> CAllocator {
+    CAllocator{
+        alloc_func:None,
+        free_func:None,
+        opaque: core::ptr::null_mut(),
+    }
+}
+
+// Holds by construction: when the compressed stream does not fit, divans_compress_buffer
+// falls back to compress_buffer_as_literals, whose output only depends on the input size.
+// Each input byte is two nibbles at exactly 4 bits. The ANS coder closes every chunk of 65536
+// nibbles, i.e. every 32768 input bytes, with its 16 byte state, and a literal command per
+// FALLBACK_LITERAL_LEN bytes plus a mux slice header per 4096 bytes add under 32 more bytes
+// per 32768, so the size-dependent part stays below input_size >> 10. The fixed part, which is
+// the header, the prediction mode, the partial chunk of each coder, the last slice headers,
+// the checksum and the end marker, comes to under 128 bytes.
+#[no_mangle]
+pub extern fn divans_max_compressed_size(input_size: usize) -> usize {
+    input_size.saturating_add(input_size >> 10).saturating_add(1024)
+}
+
+const FALLBACK_LITERAL_LEN: usize = 1 << 16;
+
+fn compress_buffer_with(state: &mut DivansCompressorState, input_buf: &[u8],
+                        output_buf: &mut [u8], output_size: &mut usize) -> DivansReturnCode {
+    let mut input_offset = 0usize;
+    let mut output_offset = 0usize;
+    while input_offset < input_buf.len() {
+        match state.compressor.encode(input_buf, &mut input_offset, output_buf, &mut output_offset, &state.custom_allocator) {
+            DIVANS_FAILURE => return DIVANS_FAILURE,
+            DIVANS_NEEDS_MORE_OUTPUT if output_offset == output_buf.len() => return DIVANS_NEEDS_MORE_OUTPUT,
+            _ => {},
+        }
+    }
+    flush_buffer(state, output_buf, output_offset, output_size)
+}
+
+fn flush_buffer(state: &mut DivansCompressorState, output_buf: &mut [u8],
+                mut output_offset: usize, output_size: &mut usize) -> DivansReturnCode {
+    loop {
+        match state.compressor.flush(output_buf, &mut output_offset, &state.custom_allocator) {
+            DIVANS_SUCCESS => break,
+            DIVANS_FAILURE => return DIVANS_FAILURE,
+            DIVANS_NEEDS_MORE_OUTPUT if output_offset == output_buf.len() => return DIVANS_NEEDS_MORE_OUTPUT,
+            _ => {},
+        }
+    }
+    *output_size = output_offset;
+    DIVANS_SUCCESS
+}
+
+// codes the input as literal commands whose CDFs adapt with no increment, so every nibble
+// keeps the uniform 1/16 it starts with and the output size does not depend on the bytes
+fn compress_buffer_as_literals(input_buf: &[u8], output_buf: &mut [u8], output_size: &mut usize) -> DivansReturnCode {
+    let opts = DivansCompressorOptions{
+        use_brotli: BrotliCompressionSetting::UseInternalCommandSelection,
+        literal_adaptation: Some([Speed::new(0, 1024); 4]),
+        dynamic_context_mixing: Some(0),
+        use_context_map: false,
+        force_stride_value: StrideSelection::PriorDisabled,
+        entropy_coder: EntropyCoderSelection::Ans,
+        cdf_family: CDFFamily::Frequentist,
+        ..DivansCompressorOptions::default()
+    };
+    let mut state = DivansCompressorState{
+        custom_allocator:rust_allocator(),
+        stats:DivansStats::default(),
+        compressor:compressor::CompressorState::OptionStage(opts),
+    };
+    let literal_context_map = [0u8; 64];
+    let predmode = [0u8; DISTANCE_CONTEXT_MAP_OFFSET + 4];
+    let mut command = DivansCommand{
+        command_type: DIVANS_COMMAND_PREDICTION_MODE,
+        data: DivansCommandData{prediction_mode: DivansPredictionModeCommand{
+            literal_context_map: literal_context_map.as_ptr(),
+            literal_context_map_size: literal_context_map.len(),
+            predmode_speed_and_distance_context_map: predmode.as_ptr(),
+            predmode_speed_and_distance_context_map_size: predmode.len(),
+        }},
+    };
+    let mut chunks = input_buf.chunks(FALLBACK_LITERAL_LEN);
+    let mut output_offset = 0usize;
+    loop {
+        let mut command_offset = 0usize;
+        // the command points into buffers that outlive the call
+        match unsafe { state.compressor.encode_commands(slice::from_ref(&command), &mut command_offset,
+                                                        output_buf, &mut output_offset, &state.custom_allocator) } {
+            DIVANS_SUCCESS => {},
+            res => return res,
+        }
+        match chunks.next() {
+            None => break,
+            Some(chunk) => command = DivansCommand{
+                command_type: DIVANS_COMMAND_LITERAL,
+                data: DivansCommandData{literal: DivansLiteralCommand{
+                    data: chunk.as_ptr(),
+                    data_size: chunk.len(),
+                    high_entropy: 0,
+                }},
+            },
+        }
+    }
+    flush_buffer(&mut state, output_buf, output_offset, output_size)
+}
+
+// applies the option selector and value pairs, then compresses the whole input;
+// an output of divans_max_compressed_size bytes always suffices
+#[no_mangle]
+pub unsafe extern fn divans_compress_buffer(option_selectors: *const DivansOptionSelect,
+                                            option_values: *const u32,
+                                            num_options: usize,
+                                            input_buf_ptr: *const u8, input_size: usize,
+                                            output_buf_ptr: *mut u8, output_size_ptr: *mut usize) -> DivansReturnCode {
+    let output_size = match output_size_ptr.as_mut() {
+        None => return DIVANS_FAILURE,
+        Some(output_size) => output_size,
+    };
+    let input_buf = slice_or_empty(input_buf_ptr, input_size);
+    let output_buf = slice_or_empty_mut(output_buf_ptr, *output_size);
+    let mut state = DivansCompressorState{
+        custom_allocator:rust_allocator(),
+        stats:DivansStats::default(),
+        compressor:compressor::CompressorState::default(),
+    };
+    for (selector, value) in slice_or_empty(option_selectors, num_options).iter().zip(slice_or_empty(option_values, num_options).iter()) {
+        if state.compressor.set_option(*selector, *value) != DIVANS_SUCCESS {
+            return DIVANS_FAILURE;
+        }
+    }
+    match compress_buffer_with(&mut state, input_buf, output_buf, output_size) {
+        DIVANS_NEEDS_MORE_OUTPUT => compress_buffer_as_literals(input_buf, output_buf, output_size),
+        res => res,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern fn divans_get_stats(state_ptr: *const DivansCompressorState, stats_ptr: *mut DivansStats) -> DivansReturnCode {
+    match (state_ptr.as_ref(), stats_ptr.as_mut()) {
+        (Some(state_ref), Some(stats)) => {
+            *stats = state_ref.stats;
+            DIVANS_SUCCESS
+        },
+        _ => DIVANS_FAILURE,
+    }
+}
+
+// a static, NUL terminated description of a result code
+#[no_mangle]
+pub extern fn divans_result_message(result: DivansReturnCode) -> *const u8 {
+    let message: &'static [u8] = match result {
+        DIVANS_SUCCESS => b"success\0",
+        DIVANS_NEEDS_MORE_INPUT => b"needs more input: the stream is incomplete\0",
+        DIVANS_NEEDS_MORE_OUTPUT => b"needs more output: the output buffer is full\0",
+        DIVANS_FAILURE => b"failure: invalid arguments or options, or a corrupt stream\0",
+        _ => b"unknown result code\0",
+    };
+    message.as_ptr()
+}
+
 #[no_mangle]
 pub unsafe extern fn divans_compressor_malloc_u8(state_ptr: *mut DivansCompressorState, size: usize) -> *mut u8 {
     if let Some(alloc_fn) = (*state_ptr).custom_allocator.alloc_func {
@@ -213,6 +455,7 @@ fn divans_new_decompressor_without_custom_alloc(to_box: DivansDecompressorState)
 pub unsafe extern fn divans_new_decompressor_with_custom_alloc(allocators:CAllocator, skip_crc:u8, multithread: u8) -> *mut DivansDecompressorState{
     let to_box = DivansDecompressorState{
         custom_allocator:allocators.clone(),
+        stats:DivansStats::default(),
         decompressor:decompressor::DecompressorFactory::new(
             SubclassableAllocator::<u8>::new(allocators.clone()),
             SubclassableAllocator::<super::DefaultCDF16>::new(allocators.clone()),
@@ -247,7 +490,11 @@ pub unsafe extern fn divans_decode(state_ptr: *mut DivansDecompressorState,
                     match state_ptr.as_mut() {
                         None => return DIVANS_FAILURE,
                         Some(state_ref) => {
-                            match state_ref.decompressor.decode(input_buf, input_offset, output_buf, output_offset) {
+                            let (old_input_offset, old_output_offset) = (*input_offset, *output_offset);
+                            let ret = state_ref.decompressor.decode(input_buf, input_offset, output_buf, output_offset);
+                            state_ref.stats.total_in += (*input_offset - old_input_offset) as u64;
+                            state_ref.stats.total_out += (*output_offset - old_output_offset) as u64;
+                            match ret {
                                 ::interface::DivansResult::Success => return DIVANS_SUCCESS,
                                 ::interface::DivansResult::Failure(_) => return DIVANS_FAILURE,
                                 ::interface::DivansResult::NeedsMoreInput => return DIVANS_NEEDS_MORE_INPUT,
@@ -261,6 +508,95 @@ pub unsafe extern fn divans_decode(state_ptr: *mut DivansDecompressorState,
     }
 }
 
+// fails once decoding has started
+#[no_mangle]
+pub unsafe extern fn divans_set_decompressor_option(state_ptr: *mut DivansDecompressorState,
+                                                    selector: DivansOptionSelect,
+                                                    value: u32) -> DivansReturnCode {
+    match state_ptr.as_mut() {
+        None => DIVANS_FAILURE,
+        Some(state_ref) => state_ref.set_option(selector, value),
+    }
+}
+
+/// Calls `callback` with each command decoded since the last call, in stream order. Needs
+/// DIVANS_DECOMPRESSOR_OPTION_CAPTURE_COMMANDS to be set before decoding starts; without it
+/// there are no commands to pass on. The command and the buffers it points to are only valid
+/// for the duration of the callback.
+///
+/// # Safety
+///
+/// `state_ptr` must be NULL or a live decompressor.
+#[no_mangle]
+pub unsafe extern "C" fn divans_take_decoded_commands(state_ptr: *mut DivansDecompressorState,
+                                                  callback: DivansCommandCallback,
+                                                  opaque: *mut c_void) -> DivansReturnCode {
+    match (state_ptr.as_mut(), callback) {
+        (Some(state_ref), Some(callback)) => state_ref.take_commands(callback, opaque),
+        _ => DIVANS_FAILURE,
+    }
+}
+
+// applies the decompressor option selector and value pairs, then decompresses the whole
+// stream on the calling thread
+#[no_mangle]
+pub unsafe extern fn divans_decompress_buffer(option_selectors: *const DivansOptionSelect,
+                                              option_values: *const u32,
+                                              num_options: usize,
+                                              input_buf_ptr: *const u8, input_size: usize,
+                                              output_buf_ptr: *mut u8, output_size_ptr: *mut usize) -> DivansReturnCode {
+    let output_size = match output_size_ptr.as_mut() {
+        None => return DIVANS_FAILURE,
+        Some(output_size) => output_size,
+    };
+    let input_buf = slice_or_empty(input_buf_ptr, input_size);
+    let output_buf = slice_or_empty_mut(output_buf_ptr, *output_size);
+    let allocators = rust_allocator();
+    let mut state = DivansDecompressorState{
+        custom_allocator:allocators.clone(),
+        stats:DivansStats::default(),
+        decompressor:decompressor::DecompressorFactory::new(
+            SubclassableAllocator::<u8>::new(allocators.clone()),
+            SubclassableAllocator::<super::DefaultCDF16>::new(allocators.clone()),
+            SubclassableAllocator::<StaticCommand>::new(allocators.clone()),
+            false,
+            false,
+        ),
+    };
+    for (selector, value) in slice_or_empty(option_selectors, num_options).iter().zip(slice_or_empty(option_values, num_options).iter()) {
+        if state.set_option(*selector, *value) != DIVANS_SUCCESS {
+            return DIVANS_FAILURE;
+        }
+    }
+    let mut input_offset = 0usize;
+    let mut output_offset = 0usize;
+    loop {
+        match state.decompressor.decode(input_buf, &mut input_offset, output_buf, &mut output_offset) {
+            ::interface::DivansResult::Success => break,
+            ::interface::DivansResult::Failure(_) => return DIVANS_FAILURE,
+            ::interface::DivansResult::NeedsMoreInput => if input_offset == input_buf.len() {
+                return DIVANS_NEEDS_MORE_INPUT;
+            },
+            ::interface::DivansResult::NeedsMoreOutput => if output_offset == output_buf.len() {
+                return DIVANS_NEEDS_MORE_OUTPUT;
+            },
+        }
+    }
+    *output_size = output_offset;
+    DIVANS_SUCCESS
+}
+
+#[no_mangle]
+pub unsafe extern fn divans_get_decompressor_stats(state_ptr: *const DivansDecompressorState, stats_ptr: *mut DivansStats) -> DivansReturnCode {
+    match (state_ptr.as_ref(), stats_ptr.as_mut()) {
+        (Some(state_ref), Some(stats)) => {
+            *stats = state_ref.stats;
+            DIVANS_SUCCESS
+        },
+        _ => DIVANS_FAILURE,
+    }
+}
+
 #[cfg(feature="std")]
 unsafe fn free_decompressor_no_custom_alloc(state_ptr: *mut DivansDecompressorState) {
     let _state = alloc_util::Box::from_raw(state_ptr);
@@ -322,3 +658,72 @@ pub unsafe extern fn divans_free_decompressor(state_ptr: *mut DivansDecompressor
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::vec::Vec;
+    use super::{compress_buffer_as_literals, divans_compress_buffer, divans_decompress_buffer, divans_max_compressed_size};
+    use super::interface::{DivansOptionSelect, DIVANS_SUCCESS, DIVANS_OPTION_QUALITY, DIVANS_OPTION_USE_BROTLI_COMMAND_SELECTION,
+                           DIVANS_OPTION_CDF_FAMILY, DIVANS_OPTION_ENTROPY_CODER};
+
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345) & 0x7fff_ffff;
+            (seed >> 16) as u8
+        }).collect()
+    }
+
+    fn decompress(compressed: &[u8], len: usize) -> Vec<u8> {
+        let mut output = vec![0u8; len];
+        let mut output_size = len;
+        let res = unsafe {
+            divans_decompress_buffer(core::ptr::null(), core::ptr::null(), 0,
+                                     compressed.as_ptr(), compressed.len(),
+                                     output.as_mut_ptr(), &mut output_size)
+        };
+        assert_eq!(res, DIVANS_SUCCESS);
+        output.truncate(output_size);
+        output
+    }
+
+    #[test]
+    fn test_literal_fallback_size() {
+        for &len in [0usize, 1, 4096, 32769, 65537, 200000, 1 << 20].iter() {
+            let data = noise(len, len as u32);
+            let zeros = vec![0u8; len];
+            let bound = divans_max_compressed_size(len);
+            let mut compressed = vec![0u8; bound];
+            let mut compressed_size = 0usize;
+            assert_eq!(compress_buffer_as_literals(&data, &mut compressed, &mut compressed_size), DIVANS_SUCCESS);
+            assert_eq!(decompress(&compressed[..compressed_size], len), data);
+            let mut zeros_size = 0usize;
+            assert_eq!(compress_buffer_as_literals(&zeros, &mut compressed, &mut zeros_size), DIVANS_SUCCESS);
+            // nothing but the input size decides the size of the fallback
+            assert_eq!(zeros_size, compressed_size);
+        }
+    }
+
+    #[test]
+    fn test_compress_buffer_incompressible() {
+        let options: [&[(DivansOptionSelect, u32)]; 3] = [
+            &[],
+            &[(DIVANS_OPTION_QUALITY, 9), (DIVANS_OPTION_USE_BROTLI_COMMAND_SELECTION, 0)],
+            &[(DIVANS_OPTION_USE_BROTLI_COMMAND_SELECTION, 0), (DIVANS_OPTION_CDF_FAMILY, 1), (DIVANS_OPTION_ENTROPY_CODER, 1)],
+        ];
+        for &len in [0usize, 100, 65536, 1 << 20].iter() {
+            let data = noise(len, 7);
+            for opts in options.iter() {
+                let selectors: Vec<DivansOptionSelect> = opts.iter().map(|opt| opt.0).collect();
+                let values: Vec<u32> = opts.iter().map(|opt| opt.1).collect();
+                let mut compressed = vec![0u8; divans_max_compressed_size(len)];
+                let mut compressed_size = compressed.len();
+                let res = unsafe {
+                    divans_compress_buffer(selectors.as_ptr(), values.as_ptr(), opts.len(),
+                                           data.as_ptr(), len,
+                                           compressed.as_mut_ptr(), &mut compressed_size)
+                };
+                assert_eq!(res, DIVANS_SUCCESS);
+                assert_eq!(decompress(&compressed[..compressed_size], len), data);
+            }
+        }
+    }
+}
@@ -0,0 +1,626 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Reversible preprocessing filters. The compressor runs the raw input through the
+// filter chain one block at a time before handing it to the command assembler;
+// the decoder collects the output of the ring buffer into the same blocks and
+// runs the inverse chain before handing bytes back to the caller.
+use core;
+use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
+use interface::{DivansResult, DivansOutputResult, ErrMsg, HEADER_LENGTH};
+
+pub const FILTER_BLOCK_SIZE: usize = 1 << 16;
+pub const MAX_FILTERS: usize = 2;
+pub const MAX_FILTER_PARAM: u8 = 16;
+const FILTER_ID_HEADER_OFFSET: usize = 6;
+const FILTER_PARAM_HEADER_OFFSET: usize = 7;
+const MIN_AUTO_FILTER_SIZE: usize = 1024;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Filter {
+    #[default]
+    Identity,
+    // subtract the byte this many positions back (1...16)
+    Delta(u8),
+    // convert relative E8/E9 call and jump targets into absolute addresses
+    X86,
+    // gather every Nth byte into its own plane (2...16)
+    Transpose(u8),
+}
+
+#[derive(Copy, Clone, Default)]
+struct FilterState {
+    history: [u8; MAX_FILTER_PARAM as usize],
+    position: u32,
+}
+
+impl Filter {
+    fn id(&self) -> u8 {
+        match *self {
+            Filter::Identity => 0,
+            Filter::Delta(_) => 1,
+            Filter::X86 => 2,
+            Filter::Transpose(_) => 3,
+        }
+    }
+    fn param(&self) -> u8 {
+        match *self {
+            Filter::Delta(dist) => dist - 1,
+            Filter::Transpose(stride) => stride - 1,
+            Filter::Identity | Filter::X86 => 0,
+        }
+    }
+    fn from_id_and_param(id: u8, param: u8) -> Result<Filter, ErrMsg> {
+        match id {
+            0 => Ok(Filter::Identity),
+            1 => Ok(Filter::Delta(param + 1)),
+            2 => Ok(Filter::X86),
+            3 => if param == 0 {
+                Err(ErrMsg::BadFilterParam(id, param))
+            } else {
+                Ok(Filter::Transpose(param + 1))
+            },
+            _ => Err(ErrMsg::UnknownFilter(id)),
+        }
+    }
+    // clamp user supplied parameters into the range representable in the header
+    fn sanitize(&self) -> Filter {
+        match *self {
+            Filter::Delta(dist) => Filter::Delta(dist.clamp(1, MAX_FILTER_PARAM)),
+            Filter::Transpose(stride) => Filter::Transpose(stride.clamp(2, MAX_FILTER_PARAM)),
+            other => other,
+        }
+    }
+    fn forward(&self, data: &mut [u8], scratch: &mut [u8], state: &mut FilterState) {
+        match *self {
+            Filter::Identity => {},
+            Filter::Delta(dist) => delta_forward(data, usize::from(dist), &mut state.history),
+            Filter::X86 => x86_convert(data, state.position, true),
+            Filter::Transpose(stride) => transpose_forward(data, scratch, usize::from(stride)),
+        }
+        state.position = state.position.wrapping_add(data.len() as u32);
+    }
+    fn inverse(&self, data: &mut [u8], scratch: &mut [u8], state: &mut FilterState) {
+        match *self {
+            Filter::Identity => {},
+            Filter::Delta(dist) => delta_inverse(data, usize::from(dist), &mut state.history),
+            Filter::X86 => x86_convert(data, state.position, false),
+            Filter::Transpose(stride) => transpose_inverse(data, scratch, usize::from(stride)),
+        }
+        state.position = state.position.wrapping_add(data.len() as u32);
+    }
+}
+
+fn update_history(history: &mut [u8; MAX_FILTER_PARAM as usize], data: &[u8]) {
+    let hlen = history.len();
+    if data.len() >= hlen {
+        history.clone_from_slice(data.split_at(data.len() - hlen).1);
+    } else {
+        for index in 0..(hlen - data.len()) {
+            history[index] = history[index + data.len()];
+        }
+        history.split_at_mut(hlen - data.len()).1.clone_from_slice(data);
+    }
+}
+
+fn delta_forward(data: &mut [u8], dist: usize, history: &mut [u8; MAX_FILTER_PARAM as usize]) {
+    let hlen = history.len();
+    let mut new_history = *history;
+    update_history(&mut new_history, data);
+    // walk backwards so data[index - dist] still holds the unfiltered byte
+    for index in (0..data.len()).rev() {
+        let prev = if index >= dist {
+            data[index - dist]
+        } else {
+            history[hlen + index - dist]
+        };
+        data[index] = data[index].wrapping_sub(prev);
+    }
+    *history = new_history;
+}
+
+fn delta_inverse(data: &mut [u8], dist: usize, history: &mut [u8; MAX_FILTER_PARAM as usize]) {
+    let hlen = history.len();
+    for index in 0..data.len() {
+        let prev = if index >= dist {
+            data[index - dist]
+        } else {
+            history[hlen + index - dist]
+        };
+        data[index] = data[index].wrapping_add(prev);
+    }
+    update_history(history, data);
+}
+
+// Every E8/E9 opcode claims the following four bytes whether or not they get converted,
+// so the encoder and decoder always agree on which bytes are opcodes. Only operands whose
+// top byte is 0x00 or 0xff are rewritten and the result is kept sign extended from bit 24,
+// which makes the conversion a bijection on the operands it touches.
+fn x86_convert(data: &mut [u8], position: u32, encode: bool) {
+    let mut index = 0usize;
+    while index + 5 <= data.len() {
+        if data[index] != 0xe8 && data[index] != 0xe9 {
+            index += 1;
+            continue;
+        }
+        if data[index + 4] == 0 || data[index + 4] == 0xff {
+            let operand = i32::from(data[index + 1])
+                | (i32::from(data[index + 2]) << 8)
+                | (i32::from(data[index + 3]) << 16)
+                | (i32::from(data[index + 4]) << 24);
+            let pc = position.wrapping_add(index as u32 + 5) as i32;
+            let converted = if encode {
+                operand.wrapping_add(pc)
+            } else {
+                operand.wrapping_sub(pc)
+            };
+            let converted = converted.wrapping_shl(7) >> 7;
+            data[index + 1] = converted as u8;
+            data[index + 2] = (converted >> 8) as u8;
+            data[index + 3] = (converted >> 16) as u8;
+            data[index + 4] = (converted >> 24) as u8;
+        }
+        index += 5;
+    }
+}
+
+// the trailing len % stride bytes are left in place
+fn transpose_forward(data: &mut [u8], scratch: &mut [u8], stride: usize) {
+    let rows = data.len() / stride;
+    for lane in 0..stride {
+        for row in 0..rows {
+            scratch[lane * rows + row] = data[row * stride + lane];
+        }
+    }
+    data[..rows * stride].clone_from_slice(&scratch[..rows * stride]);
+}
+
+fn transpose_inverse(data: &mut [u8], scratch: &mut [u8], stride: usize) {
+    let rows = data.len() / stride;
+    for lane in 0..stride {
+        for row in 0..rows {
+            scratch[row * stride + lane] = data[lane * rows + row];
+        }
+    }
+    data[..rows * stride].clone_from_slice(&scratch[..rows * stride]);
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterChain(pub [Filter; MAX_FILTERS]);
+
+impl FilterChain {
+    pub fn new(first: Filter, second: Filter) -> Self {
+        FilterChain([first.sanitize(), second.sanitize()])
+    }
+    pub fn is_identity(&self) -> bool {
+        self.0.iter().all(|f| *f == Filter::Identity)
+    }
+    pub fn write_header(&self, header: &mut [u8; HEADER_LENGTH]) {
+        header[FILTER_ID_HEADER_OFFSET] = self.0[0].id() | (self.0[1].id() << 4);
+        header[FILTER_PARAM_HEADER_OFFSET] = self.0[0].param() | (self.0[1].param() << 4);
+    }
+    pub fn from_header(header: &[u8; HEADER_LENGTH]) -> Result<Self, ErrMsg> {
+        let ids = header[FILTER_ID_HEADER_OFFSET];
+        let params = header[FILTER_PARAM_HEADER_OFFSET];
+        Ok(FilterChain([Filter::from_id_and_param(ids & 0xf, params & 0xf)?,
+                        Filter::from_id_and_param(ids >> 4, params >> 4)?]))
+    }
+    fn forward(&self, data: &mut [u8], scratch: &mut [u8], state: &mut [FilterState; MAX_FILTERS]) {
+        for (filter, filter_state) in self.0.iter().zip(state.iter_mut()) {
+            filter.forward(data, scratch, filter_state);
+        }
+    }
+    fn inverse(&self, data: &mut [u8], scratch: &mut [u8], state: &mut [FilterState; MAX_FILTERS]) {
+        for (filter, filter_state) in self.0.iter().zip(state.iter_mut()).rev() {
+            filter.inverse(data, scratch, filter_state);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FilterSelection {
+    #[default]
+    Disabled,
+    Auto,
+    Fixed(FilterChain),
+}
+
+// log2(x) in 1/256ths of a bit, linearly interpolated between powers of two
+fn log2_fixed(x: u32) -> u64 {
+    let int = 31 - x.leading_zeros();
+    let frac = if int >= 8 {
+        (x >> (int - 8)) & 0xff
+    } else {
+        (x << (8 - int)) & 0xff
+    };
+    u64::from((int << 8) | frac)
+}
+
+fn histogram_cost(histogram: &[u32; 256]) -> u64 {
+    let total: u32 = histogram.iter().sum();
+    if total == 0 {
+        return 0;
+    }
+    let log_total = log2_fixed(total);
+    histogram.iter().filter(|c| **c != 0).map(
+        |c| u64::from(*c) * (log_total - log2_fixed(*c))).sum()
+}
+
+fn delta_cost(data: &[u8], dist: usize) -> u64 {
+    let mut histogram = [0u32; 256];
+    for index in dist..data.len() {
+        histogram[usize::from(data[index].wrapping_sub(data[index - dist]))] += 1;
+    }
+    histogram_cost(&histogram)
+}
+
+fn plane_cost(data: &[u8], stride: usize) -> u64 {
+    let mut cost = 0u64;
+    for lane in 0..stride {
+        let mut histogram = [0u32; 256];
+        for val in data.iter().skip(lane).step_by(stride) {
+            histogram[usize::from(*val)] += 1;
+        }
+        cost += histogram_cost(&histogram);
+    }
+    cost
+}
+
+fn looks_like_x86(data: &[u8]) -> bool {
+    let mut calls = 0usize;
+    let mut plausible = 0usize;
+    let mut index = 0usize;
+    while index + 5 <= data.len() {
+        if data[index] == 0xe8 || data[index] == 0xe9 {
+            calls += 1;
+            if data[index + 4] == 0 || data[index + 4] == 0xff {
+                plausible += 1;
+            }
+            index += 5;
+        } else {
+            index += 1;
+        }
+    }
+    plausible * 256 >= data.len() && plausible * 2 >= calls
+}
+
+// Pick a filter chain from a sample of the input: x86 code is recognized by the density of
+// call and jump opcodes with near targets, otherwise delta and transpose candidates are
+// scored by the order-0 entropy of the filtered sample and must beat the raw bytes by 1/8.
+pub fn select_filters(data: &[u8]) -> FilterChain {
+    let sample = data.split_at(core::cmp::min(data.len(), FILTER_BLOCK_SIZE)).0;
+    if sample.len() < MIN_AUTO_FILTER_SIZE {
+        return FilterChain::default();
+    }
+    if looks_like_x86(sample) {
+        return FilterChain::new(Filter::X86, Filter::Identity);
+    }
+    let raw_cost = plane_cost(sample, 1);
+    let mut best = (raw_cost - raw_cost / 8, Filter::Identity);
+    for dist in [1u8, 2, 3, 4, 8].iter() {
+        let cost = delta_cost(sample, usize::from(*dist));
+        if cost < best.0 {
+            best = (cost, Filter::Delta(*dist));
+        }
+    }
+    for stride in [2u8, 4, 8].iter() {
+        let cost = plane_cost(sample, usize::from(*stride));
+        if cost < best.0 {
+            best = (cost, Filter::Transpose(*stride));
+        }
+    }
+    FilterChain::new(best.1, Filter::Identity)
+}
+
+pub struct FilterEncoder<AllocU8: Allocator<u8>> {
+    selection: FilterSelection,
+    chain: FilterChain,
+    buffer: AllocU8::AllocatedMemory,
+    scratch: AllocU8::AllocatedMemory,
+    filled: usize,
+    consumed: usize,
+    sealed: bool,
+    state: [FilterState; MAX_FILTERS],
+}
+
+impl<AllocU8: Allocator<u8>> Default for FilterEncoder<AllocU8> {
+    fn default() -> Self {
+        FilterEncoder {
+            selection: FilterSelection::Disabled,
+            chain: FilterChain::default(),
+            buffer: AllocU8::AllocatedMemory::default(),
+            scratch: AllocU8::AllocatedMemory::default(),
+            filled: 0,
+            consumed: 0,
+            sealed: false,
+            state: [FilterState::default(); MAX_FILTERS],
+        }
+    }
+}
+
+impl<AllocU8: Allocator<u8>> FilterEncoder<AllocU8> {
+    pub fn new(m8: &mut AllocU8, selection: FilterSelection) -> Self {
+        let selection = match selection {
+            FilterSelection::Fixed(chain) if chain.is_identity() => FilterSelection::Disabled,
+            FilterSelection::Fixed(chain) => FilterSelection::Fixed(FilterChain::new(chain.0[0], chain.0[1])),
+            other => other,
+        };
+        let mut ret = FilterEncoder::<AllocU8> {
+            selection,
+            ..FilterEncoder::default()
+        };
+        if let FilterSelection::Fixed(chain) = selection {
+            ret.chain = chain;
+        }
+        if selection != FilterSelection::Disabled {
+            ret.buffer = m8.alloc_cell(FILTER_BLOCK_SIZE);
+            ret.scratch = m8.alloc_cell(FILTER_BLOCK_SIZE);
+        }
+        ret
+    }
+    // the chain that has been applied so far; an undecided auto selection is reported as the identity
+    pub fn chain(&self) -> FilterChain {
+        self.chain
+    }
+    pub fn free(&mut self, m8: &mut AllocU8) {
+        m8.free_cell(core::mem::take(&mut self.buffer));
+        m8.free_cell(core::mem::take(&mut self.scratch));
+    }
+    fn seal(&mut self) {
+        if self.selection == FilterSelection::Auto {
+            self.chain = select_filters(self.buffer.slice().split_at(self.filled).0);
+            self.selection = FilterSelection::Fixed(self.chain);
+        }
+        self.chain.forward(self.buffer.slice_mut().split_at_mut(self.filled).0,
+                           self.scratch.slice_mut(),
+                           &mut self.state);
+        self.consumed = 0;
+        self.sealed = true;
+    }
+    fn drain<F: FnMut(FilterChain, &[u8], &mut usize) -> DivansResult>(&mut self, inner: &mut F) -> DivansResult {
+        while self.consumed < self.filled {
+            let old_consumed = self.consumed;
+            match inner(self.chain, self.buffer.slice().split_at(self.filled).0, &mut self.consumed) {
+                DivansResult::NeedsMoreInput | DivansResult::Success => {
+                    if self.consumed == old_consumed {
+                        return DivansResult::NeedsMoreInput; // retry on the next call
+                    }
+                },
+                res => return res,
+            }
+        }
+        self.filled = 0;
+        self.consumed = 0;
+        self.sealed = false;
+        DivansResult::NeedsMoreInput
+    }
+    // Feeds the input through the filter chain into inner, which receives the chain the
+    // data was filtered with so it can emit the header before the first filtered byte.
+    pub fn encode<F: FnMut(FilterChain, &[u8], &mut usize) -> DivansResult>(&mut self,
+                                                                          input: &[u8],
+                                                                          input_offset: &mut usize,
+                                                                          mut inner: F) -> DivansResult {
+        if self.selection == FilterSelection::Disabled {
+            return inner(self.chain, input, input_offset);
+        }
+        loop {
+            if self.sealed {
+                match self.drain(&mut inner) {
+                    DivansResult::NeedsMoreInput => if self.sealed {
+                        return DivansResult::NeedsMoreInput;
+                    },
+                    res => return res,
+                }
+            }
+            if *input_offset == input.len() {
+                return DivansResult::NeedsMoreInput;
+            }
+            let to_copy = core::cmp::min(input.len() - *input_offset, FILTER_BLOCK_SIZE - self.filled);
+            self.buffer.slice_mut()[self.filled..self.filled + to_copy].clone_from_slice(
+                &input[*input_offset..*input_offset + to_copy]);
+            self.filled += to_copy;
+            *input_offset += to_copy;
+            if self.filled == FILTER_BLOCK_SIZE {
+                self.seal();
+            }
+        }
+    }
+    // filters and feeds the final partial block; callers flush their own state afterwards
+    pub fn flush<F: FnMut(FilterChain, &[u8], &mut usize) -> DivansResult>(&mut self,
+                                                                         mut inner: F) -> DivansOutputResult {
+        if self.selection == FilterSelection::Disabled {
+            return DivansOutputResult::Success;
+        }
+        if !self.sealed {
+            self.seal();
+        }
+        match self.drain(&mut inner) {
+            DivansResult::NeedsMoreInput | DivansResult::Success => if self.sealed {
+                DivansOutputResult::Failure(ErrMsg::FilterFlushNeedsInput)
+            } else {
+                DivansOutputResult::Success
+            },
+            DivansResult::NeedsMoreOutput => DivansOutputResult::NeedsMoreOutput,
+            DivansResult::Failure(m) => DivansOutputResult::Failure(m),
+        }
+    }
+}
+
+pub struct FilterDecoder<AllocU8: Allocator<u8>> {
+    chain: FilterChain,
+    buffer: AllocU8::AllocatedMemory,
+    scratch: AllocU8::AllocatedMemory,
+    filled: usize,
+    drained: usize,
+    sealed: bool,
+    eof: bool,
+    state: [FilterState; MAX_FILTERS],
+}
+
+impl<AllocU8: Allocator<u8>> Default for FilterDecoder<AllocU8> {
+    fn default() -> Self {
+        FilterDecoder {
+            chain: FilterChain::default(),
+            buffer: AllocU8::AllocatedMemory::default(),
+            scratch: AllocU8::AllocatedMemory::default(),
+            filled: 0,
+            drained: 0,
+            sealed: false,
+            eof: false,
+            state: [FilterState::default(); MAX_FILTERS],
+        }
+    }
+}
+
+impl<AllocU8: Allocator<u8>> FilterDecoder<AllocU8> {
+    pub fn new(m8: &mut AllocU8, chain: FilterChain) -> Self {
+        let mut ret = FilterDecoder::<AllocU8> {
+            chain,
+            ..FilterDecoder::default()
+        };
+        if !chain.is_identity() {
+            ret.buffer = m8.alloc_cell(FILTER_BLOCK_SIZE);
+            ret.scratch = m8.alloc_cell(FILTER_BLOCK_SIZE);
+        }
+        ret
+    }
+    pub fn is_identity(&self) -> bool {
+        self.chain.is_identity()
+    }
+    pub fn free(&mut self, m8: &mut AllocU8) {
+        m8.free_cell(core::mem::take(&mut self.buffer));
+        m8.free_cell(core::mem::take(&mut self.scratch));
+    }
+    // true once every unfiltered byte has been copied out and the stream has ended
+    pub fn is_done(&self) -> bool {
+        self.eof && !self.sealed
+    }
+    // copies unfiltered bytes into output, returning true once none are left to copy
+    pub fn drain(&mut self, output: &mut [u8], output_offset: &mut usize) -> bool {
+        if !self.sealed {
+            return true;
+        }
+        let to_copy = core::cmp::min(output.len() - *output_offset, self.filled - self.drained);
+        output[*output_offset..*output_offset + to_copy].clone_from_slice(
+            &self.buffer.slice()[self.drained..self.drained + to_copy]);
+        *output_offset += to_copy;
+        self.drained += to_copy;
+        if self.drained != self.filled {
+            return false;
+        }
+        self.filled = 0;
+        self.drained = 0;
+        self.sealed = false;
+        true
+    }
+    // lets the decoder write into the remainder of the current block
+    pub fn fill<R, F: FnOnce(&mut [u8], &mut usize) -> R>(&mut self, inner: F) -> R {
+        inner(self.buffer.slice_mut().split_at_mut(FILTER_BLOCK_SIZE).0, &mut self.filled)
+    }
+    // runs the inverse chain on a full block or, at the end of the stream, the final partial block
+    pub fn seal(&mut self, eof: bool) -> bool {
+        if self.sealed || (self.filled != FILTER_BLOCK_SIZE && !eof) {
+            return false;
+        }
+        self.eof = eof;
+        self.chain.inverse(self.buffer.slice_mut().split_at_mut(self.filled).0,
+                           self.scratch.slice_mut(),
+                           &mut self.state);
+        self.drained = 0;
+        self.sealed = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, FilterChain, FilterState, MAX_FILTERS, FILTER_BLOCK_SIZE, select_filters};
+    use interface::HEADER_LENGTH;
+    use std::vec::Vec;
+
+    fn roundtrip(chain: FilterChain, data: &[u8], block_size: usize) {
+        let mut filtered = data.to_vec();
+        let mut scratch = vec![0u8; block_size];
+        let mut state = [FilterState::default(); MAX_FILTERS];
+        for block in filtered.chunks_mut(block_size) {
+            chain.forward(block, &mut scratch[..], &mut state);
+        }
+        if !chain.is_identity() && block_size >= 1000 {
+            assert!(filtered[..] != data[..]);
+        }
+        let mut state = [FilterState::default(); MAX_FILTERS];
+        for block in filtered.chunks_mut(block_size) {
+            chain.inverse(block, &mut scratch[..], &mut state);
+        }
+        assert_eq!(&filtered[..], data);
+    }
+
+    fn sample_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut seed = 0x1234_5678u32;
+        for i in 0..20000u32 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.push((seed >> 24) as u8);
+            if i % 7 == 0 {
+                data.extend(&[0xe8, (seed >> 8) as u8, (seed >> 16) as u8, 0, 0]);
+            }
+            if i % 11 == 0 {
+                data.extend(&[0xe9, 0x10, 0xff, 0xff, 0xff]);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_filters_roundtrip() {
+        let data = sample_data();
+        for filter in [Filter::Delta(1), Filter::Delta(3), Filter::Delta(16),
+                       Filter::X86, Filter::Transpose(2), Filter::Transpose(7)].iter() {
+            for block_size in [1usize, 5, 1000, FILTER_BLOCK_SIZE].iter() {
+                roundtrip(FilterChain::new(*filter, Filter::Identity), &data[..], *block_size);
+            }
+        }
+        roundtrip(FilterChain::new(Filter::Transpose(4), Filter::Delta(1)), &data[..], 4096);
+        roundtrip(FilterChain::new(Filter::X86, Filter::Delta(2)), &data[..], 999);
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let chain = FilterChain::new(Filter::Transpose(4), Filter::Delta(16));
+        let mut header = [0u8; HEADER_LENGTH];
+        chain.write_header(&mut header);
+        assert_eq!(FilterChain::from_header(&header).unwrap(), chain);
+        assert_eq!(FilterChain::from_header(&[0u8; HEADER_LENGTH]).unwrap(), FilterChain::default());
+        header[6] = 0x5;
+        assert!(FilterChain::from_header(&header).is_err());
+    }
+
+    #[test]
+    fn test_select_filters() {
+        let mut pcm = Vec::new();
+        for i in 0..32768i32 {
+            let sample = ((i % 400) - 200) * 60 + (i % 3);
+            pcm.push(sample as u8);
+            pcm.push((sample >> 8) as u8);
+        }
+        assert!(!select_filters(&pcm[..]).is_identity());
+        let text = b"It was the best of times, it was the worst of times. ".repeat(100);
+        assert!(select_filters(&text[..]).is_identity());
+        let mut code = Vec::new();
+        for i in 0..4096u32 {
+            code.extend(&[0x55, 0x48, 0x89, 0xe5, 0xe8, i as u8, (i >> 8) as u8, 0, 0, 0x5d, 0xc3]);
+        }
+        assert_eq!(select_filters(&code[..]), FilterChain::new(Filter::X86, Filter::Identity));
+    }
+}
@@ -22,6 +22,7 @@ use super::codec::literal::LiteralSubstate;
 use super::codec::context_map::PredictionModeSubstate;
 use super::codec::block_type::BlockTypeState;
 pub use super::codec::StrideSelection;
+pub use super::filter::FilterSelection;
 pub use brotli::enc::interface::*;
 
 #[derive(Copy,Clone,Debug)]
@@ -61,6 +62,15 @@ pub enum ErrMsg {
     MainFunctionCalledFromThread(u8),
     DecodingDecoderAlreadyFreed,
     AdvContextMapNotBoolean(u8),
+    UnknownFilter(u8),
+    BadFilterParam(u8, u8),
+    FilterFlushNeedsInput,
+    UnknownEntropyCoder(u8),
+    UnknownCDFFamily(u8),
+    UnsupportedCDFFamily(u8),
+    UnsupportedStreamCount(u8),
+    UnknownMuxPolicy(u8),
+    WindowExceedsMemoryLimit(u8),
 }
 
 
@@ -142,15 +152,8 @@ impl From<DivansOutputResult> for DivansResult {
     }
 }
 
-// The choice of CDF16 struct is controlled by feature flags.
-#[cfg(feature="blend")]
-pub type DefaultInternalCDF16 = probability::BlendCDF16;
-#[cfg(all(not(any(feature="blend")), feature="uncached_frequentist"))]
-pub type DefaultInternalCDF16 = probability::FrequentistCDF16;
-#[cfg(all(not(any(feature="blend", feature="uncached_frequentist")), feature="simd"))]
-pub type DefaultInternalCDF16 = probability::SIMDFrequentistCDF16;
-#[cfg(all(not(any(feature="blend", feature="uncached_frequentist", feature="simd"))))]
-pub type DefaultInternalCDF16 = probability::OptFrequentistCDF16;
+// The model family of DefaultInternalCDF16 is picked per stream; see CDFFamily.
+pub type DefaultInternalCDF16 = probability::SelectableCDF16;
 
 #[cfg(feature="debug_entropy")]
 #[cfg(not(feature="findspeed"))]
@@ -162,6 +165,68 @@ pub type DefaultCDF16 = probability::VariantSpeedCDF<DefaultInternalCDF16>;
 
 pub const HEADER_LENGTH: usize = 16;
 pub const MAGIC_NUMBER:[u8;4] = [0xff, 0xe5,0x8c, 0x9f];
+const ENTROPY_CODER_HEADER_OFFSET: usize = 8;
+const CDF_FAMILY_HEADER_OFFSET: usize = 9;
+const STREAM_COUNT_HEADER_OFFSET: usize = 10;
+const MUX_POLICY_HEADER_OFFSET: usize = 11;
+
+// the backend that turns nibbles and their probabilities into bytes
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EntropyCoderSelection {
+    #[default]
+    Ans = 0,
+    Range = 1,
+    Binary = 2,
+}
+impl EntropyCoderSelection {
+    pub fn new(id: u8) -> Result<Self, ErrMsg> {
+        match id {
+            0 => Ok(EntropyCoderSelection::Ans),
+            1 => Ok(EntropyCoderSelection::Range),
+            2 => Ok(EntropyCoderSelection::Binary),
+            _ => Err(ErrMsg::UnknownEntropyCoder(id)),
+        }
+    }
+    pub fn write_header(&self, header: &mut [u8; HEADER_LENGTH]) {
+        header[ENTROPY_CODER_HEADER_OFFSET] = *self as u8;
+    }
+    pub fn from_header(header: &[u8; HEADER_LENGTH]) -> Result<Self, ErrMsg> {
+        Self::new(header[ENTROPY_CODER_HEADER_OFFSET])
+    }
+}
+
+// how the nibble CDFs adapt to the symbols they see
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CDFFamily {
+    // counts symbols and rescales when the total gets too large
+    #[cfg_attr(not(feature="blend"), default)]
+    Frequentist = 0,
+    // mixes in the one-hot distribution of each symbol, without division
+    #[cfg_attr(feature="blend", default)]
+    Blend = 1,
+}
+impl CDFFamily {
+    pub fn new(id: u8) -> Result<Self, ErrMsg> {
+        match id {
+            0 => Ok(CDFFamily::Frequentist),
+            1 => Ok(CDFFamily::Blend),
+            _ => Err(ErrMsg::UnknownCDFFamily(id)),
+        }
+    }
+    pub fn write_header(&self, header: &mut [u8; HEADER_LENGTH]) {
+        header[CDF_FAMILY_HEADER_OFFSET] = *self as u8;
+    }
+    // also rejects families that this build's DefaultCDF16 cannot model
+    pub fn from_header(header: &[u8; HEADER_LENGTH]) -> Result<Self, ErrMsg> {
+        let family = Self::new(header[CDF_FAMILY_HEADER_OFFSET])?;
+        if DefaultCDF16::new_in_family(family).is_none() {
+            return Err(ErrMsg::UnsupportedCDFFamily(family as u8));
+        }
+        Ok(family)
+    }
+}
 
 // Commands that can instantiate as a no-op should implement this.
 /*
@@ -232,10 +297,67 @@ impl<SliceType:SliceWrapper<u8>+Default+Clone> Clone for Command<SliceType> {
     }
 }
  */
+// the command and literal coders; streams written before the count was recorded always have these two
 pub const NUM_STREAMS: usize = 2;
-pub const STREAM_ID_MASK: StreamID = 0x1;
+pub const MAX_NUM_STREAMS: usize = 16;
+pub const STREAM_ID_MASK: StreamID = 0xf;
 pub type StreamID = u8;
 
+// how many streams the mux interleaves; the two-stream layout is stored as 0 so older streams still parse
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamCount(u8);
+impl Default for StreamCount {
+    fn default() -> Self {
+        StreamCount(NUM_STREAMS as u8)
+    }
+}
+impl StreamCount {
+    pub fn new(count: usize) -> Result<Self, ErrMsg> {
+        if !(NUM_STREAMS..=MAX_NUM_STREAMS).contains(&count) {
+            return Err(ErrMsg::UnsupportedStreamCount(count as u8));
+        }
+        Ok(StreamCount(count as u8))
+    }
+    pub fn get(&self) -> usize {
+        usize::from(self.0)
+    }
+    pub fn write_header(&self, header: &mut [u8; HEADER_LENGTH]) {
+        header[STREAM_COUNT_HEADER_OFFSET] = if self.get() == NUM_STREAMS {0} else {self.0};
+    }
+    pub fn from_header(header: &[u8; HEADER_LENGTH]) -> Result<Self, ErrMsg> {
+        match header[STREAM_COUNT_HEADER_OFFSET] {
+            0 => Ok(StreamCount::default()),
+            count => Self::new(usize::from(count)),
+        }
+    }
+}
+
+// when the mux serializes buffered stream bytes into the output
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MuxPolicy {
+    // waits for 4k-64k chunks, keeping framing overhead low
+    #[default]
+    Throughput = 0,
+    // every byte the entropy coders have produced is serialized before the encoder asks for more input
+    LowLatency = 1,
+}
+impl MuxPolicy {
+    pub fn new(id: u8) -> Result<Self, ErrMsg> {
+        match id {
+            0 => Ok(MuxPolicy::Throughput),
+            1 => Ok(MuxPolicy::LowLatency),
+            _ => Err(ErrMsg::UnknownMuxPolicy(id)),
+        }
+    }
+    pub fn write_header(&self, header: &mut [u8; HEADER_LENGTH]) {
+        header[MUX_POLICY_HEADER_OFFSET] = *self as u8;
+    }
+    pub fn from_header(header: &[u8; HEADER_LENGTH]) -> Result<Self, ErrMsg> {
+        Self::new(header[MUX_POLICY_HEADER_OFFSET])
+    }
+}
+
 pub struct ReadableBytes<'a> {
     pub data: &'a [u8],
     pub read_offset: &'a mut usize,
@@ -256,7 +378,7 @@ pub trait StreamMuxer<AllocU8: Allocator<u8> > {
     #[inline(always)]
     fn write(&mut self, stream_id: StreamID, data:&[u8], m8: &mut AllocU8) -> usize;
     #[inline(always)]
-    fn write_buffer(&mut self, m8: &mut AllocU8) -> [WritableBytes; NUM_STREAMS];
+    fn write_buffer(&mut self, stream_id: StreamID, m8: &mut AllocU8) -> WritableBytes<'_>;
     #[inline(always)]
     fn can_linearize() ->  bool {true}
     #[inline(always)]
@@ -267,12 +389,21 @@ pub trait StreamMuxer<AllocU8: Allocator<u8> > {
     fn wrote_eof(&self) -> bool;
     #[inline(always)]
     fn free_mux(&mut self, m8: &mut AllocU8);
+    #[inline(always)]
+    fn set_policy(&mut self, _policy: MuxPolicy) {}
+    #[inline(always)]
+    fn set_stream_count(&mut self, _count: StreamCount) {}
+    // called when the encoder runs out of input; serializes whatever the policy does not allow to wait
+    #[inline(always)]
+    fn linearize_pending(&mut self, _output:&mut[u8]) -> usize {
+        0
+    }
 }
 pub trait StreamDemuxer<AllocU8: Allocator<u8> > {
     #[inline(always)]
     fn write_linear(&mut self, data:&[u8], m8: &mut AllocU8) -> usize;
     #[inline(always)]
-    fn read_buffer(&mut self) -> [ReadableBytes; NUM_STREAMS];
+    fn read_buffer(&mut self, stream_id: StreamID) -> ReadableBytes<'_>;
     #[inline(always)]
     fn data_ready(&self, stream_id:StreamID) -> usize;
     #[inline(always)]
@@ -348,6 +479,10 @@ pub enum CrossCommandBilling {
 pub trait NewWithAllocator<AllocU8: Allocator<u8>> {
     fn new(m8: &mut AllocU8) -> Self;
     fn free(&mut self, m8: &mut AllocU8);
+    // coders with a single backend always use it
+    fn new_with_entropy_coder(m8: &mut AllocU8, _coder: EntropyCoderSelection) -> Self where Self: Sized {
+        Self::new(m8)
+    }
 }
 
 pub trait BillingCapability { // maybe we should have called it capa-bill-ity
@@ -459,6 +594,12 @@ pub struct DivansCompressorOptions{
     pub prior_bitmask_detection: u8,
     pub brotli_literal_byte_score: Option<u32>,
     pub divans_ir_optimizer: u8,
+    pub block_split: bool,
+    pub entropy_coder: EntropyCoderSelection,
+    pub cdf_family: CDFFamily,
+    pub mux_policy: MuxPolicy,
+    pub stream_count: StreamCount,
+    pub filters: FilterSelection,
 }
 impl Default for DivansCompressorOptions{
     fn default() ->Self {
@@ -479,6 +620,12 @@ impl Default for DivansCompressorOptions{
             prior_bitmask_detection: 1,
             brotli_literal_byte_score: None,
             divans_ir_optimizer: 0,
+            block_split: false,
+            entropy_coder: EntropyCoderSelection::default(),
+            cdf_family: CDFFamily::default(),
+            mux_policy: MuxPolicy::default(),
+            stream_count: StreamCount::default(),
+            filters: FilterSelection::default(),
         }
     }
 }
@@ -0,0 +1,362 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Binary IR layout, all integers are LEB128 varints unless noted:
+//   magic (4 bytes) version (1 byte) log_window_size len+1 (0 when unknown)
+//   then per command a tag byte followed by
+//     copy:       num_bytes distance
+//     dict:       word_size(u8) word_id transform(u8) final_size(u8)
+//     literal:    len flags(u8) data(len bytes) [probabilities(8 * len bytes)]
+//     ctype/dtype: block_type(u8)
+//     ltype:      block_type(u8) stride(u8)
+//     prediction: literal_context_map_len bytes predmode_speed_and_distance_context_map_len bytes
+//   and a final end tag.
+use std::io::{self, Read, Write};
+use std::vec::Vec;
+use alloc::SliceWrapper;
+use interface::{BlockSwitch,
+                Command,
+                CopyCommand,
+                DictCommand,
+                LiteralBlockSwitch,
+                LiteralCommand,
+                PredictionModeContextMap};
+use super::{ItemVec, Header, external_probabilities};
+
+pub const BINARY_MAGIC: [u8; 4] = [0xff, b'D', b'I', b'R'];
+pub const BINARY_VERSION: u8 = 1;
+
+const TAG_END: u8 = 0;
+const TAG_COPY: u8 = 1;
+const TAG_DICT: u8 = 2;
+const TAG_LITERAL: u8 = 3;
+const TAG_BLOCK_SWITCH_COMMAND: u8 = 4;
+const TAG_BLOCK_SWITCH_LITERAL: u8 = 5;
+const TAG_BLOCK_SWITCH_DISTANCE: u8 = 6;
+const TAG_PREDICTION_MODE: u8 = 7;
+
+const LITERAL_HIGH_ENTROPY: u8 = 1;
+const LITERAL_HAS_PROBS: u8 = 2;
+
+const MAX_VARINT_BYTES: usize = 10;
+
+/// Streams commands out of binary IR.
+pub struct BinaryParser<R: Read> {
+    reader: R,
+    offset: u64,
+    header: Option<Header>,
+    done: bool,
+}
+
+impl<R: Read> BinaryParser<R> {
+    pub fn new(reader: R) -> Self {
+        BinaryParser {
+            reader,
+            offset: 0,
+            header: None,
+            done: false,
+        }
+    }
+    /// The number of bytes consumed so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+    /// Returns the header, reading it from the stream if no command has been read yet.
+    pub fn header(&mut self) -> io::Result<Header> {
+        if let Some(header) = self.header {
+            return Ok(header);
+        }
+        let mut magic = [0u8; 4];
+        for byte in magic.iter_mut() {
+            *byte = self.read_u8()?;
+        }
+        if magic != BINARY_MAGIC {
+            return Err(self.error(io::ErrorKind::InvalidData, "not a binary IR stream"));
+        }
+        let version = self.read_u8()?;
+        if version != BINARY_VERSION {
+            return Err(self.error(io::ErrorKind::InvalidData, "unsupported binary IR version"));
+        }
+        let window_size = self.read_varint()?;
+        if window_size > i32::MAX as u64 {
+            return Err(self.error(io::ErrorKind::InvalidData, "window size out of range"));
+        }
+        let len = self.read_varint()?;
+        let header = Header {
+            window_size: window_size as i32,
+            len: if len == 0 { None } else { Some(len - 1) },
+        };
+        self.header = Some(header);
+        Ok(header)
+    }
+    /// Returns the next command, or `None` once the end tag has been read.
+    pub fn next_command(&mut self) -> io::Result<Option<Command<ItemVec<u8>>>> {
+        if self.done {
+            return Ok(None);
+        }
+        self.header()?;
+        let tag = self.read_u8()?;
+        let cmd = match tag {
+            TAG_END => {
+                self.done = true;
+                return Ok(None);
+            },
+            TAG_COPY => {
+                let num_bytes = self.read_u32()?;
+                let distance = self.read_u32()?;
+                Command::Copy(CopyCommand {
+                    distance,
+                    num_bytes,
+                })
+            },
+            TAG_DICT => {
+                let word_size = self.read_u8()?;
+                let word_id = self.read_u32()?;
+                let transform = self.read_u8()?;
+                let final_size = self.read_u8()?;
+                Command::Dict(DictCommand {
+                    word_size,
+                    word_id,
+                    empty: 0,
+                    final_size,
+                    transform,
+                })
+            },
+            TAG_LITERAL => {
+                let len = self.read_varint()?;
+                let flags = self.read_u8()?;
+                if flags & !(LITERAL_HIGH_ENTROPY | LITERAL_HAS_PROBS) != 0 {
+                    return Err(self.error(io::ErrorKind::InvalidData, "unknown literal flags"));
+                }
+                let data = self.read_bytes(len)?;
+                let probs = if flags & LITERAL_HAS_PROBS != 0 {
+                    match len.checked_mul(8) {
+                        Some(prob_len) => self.read_bytes(prob_len)?,
+                        None => return Err(self.error(io::ErrorKind::InvalidData, "literal too long")),
+                    }
+                } else {
+                    Vec::new()
+                };
+                let prob = match external_probabilities(probs) {
+                    Some(prob) => prob,
+                    None => return Err(self.error(io::ErrorKind::InvalidInput,
+                                                  "to parse external probabilities, compile with feature flag external-literal-probability")),
+                };
+                Command::Literal(LiteralCommand {
+                    data: ItemVec(data),
+                    high_entropy: flags & LITERAL_HIGH_ENTROPY != 0,
+                    prob,
+                })
+            },
+            TAG_BLOCK_SWITCH_COMMAND => Command::BlockSwitchCommand(BlockSwitch::new(self.read_u8()?)),
+            TAG_BLOCK_SWITCH_DISTANCE => Command::BlockSwitchDistance(BlockSwitch::new(self.read_u8()?)),
+            TAG_BLOCK_SWITCH_LITERAL => {
+                let block_type = self.read_u8()?;
+                let stride = self.read_u8()?;
+                Command::BlockSwitchLiteral(LiteralBlockSwitch::new(block_type, stride))
+            },
+            TAG_PREDICTION_MODE => {
+                let literal_context_map_len = self.read_varint()?;
+                let literal_context_map = self.read_bytes(literal_context_map_len)?;
+                let predmode_len = self.read_varint()?;
+                let predmode_speed_and_distance_context_map = self.read_bytes(predmode_len)?;
+                Command::PredictionMode(PredictionModeContextMap {
+                    literal_context_map: ItemVec(literal_context_map),
+                    predmode_speed_and_distance_context_map: ItemVec(predmode_speed_and_distance_context_map),
+                })
+            },
+            _ => return Err(self.error(io::ErrorKind::InvalidData, "unknown command tag")),
+        };
+        Ok(Some(cmd))
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+    fn error(&self, kind: io::ErrorKind, message: &str) -> io::Error {
+        io::Error::new(kind, format!("binary IR offset {}: {}", self.offset, message))
+    }
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Err(self.error(io::ErrorKind::UnexpectedEof, "truncated binary IR")),
+                Ok(_) => {
+                    self.offset += 1;
+                    return Ok(byte[0]);
+                },
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                },
+            }
+        }
+    }
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut val = 0u64;
+        for index in 0..MAX_VARINT_BYTES {
+            let byte = self.read_u8()?;
+            val |= u64::from(byte & 0x7f) << (7 * index);
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(self.error(io::ErrorKind::InvalidData, "varint too long"))
+    }
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let val = self.read_varint()?;
+        if val > u64::from(u32::MAX) {
+            return Err(self.error(io::ErrorKind::InvalidData, "value out of range"));
+        }
+        Ok(val as u32)
+    }
+    fn read_bytes(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        // grow with the data actually present rather than trusting the length up front
+        let mut data = Vec::new();
+        let count = (&mut self.reader).take(len).read_to_end(&mut data)?;
+        self.offset += count as u64;
+        if (count as u64) != len {
+            return Err(self.error(io::ErrorKind::UnexpectedEof, "truncated binary IR"));
+        }
+        Ok(data)
+    }
+}
+
+impl<R: Read> Iterator for BinaryParser<R> {
+    type Item = io::Result<Command<ItemVec<u8>>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_command() {
+            Ok(Some(cmd)) => Some(Ok(cmd)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn write_varint<W: Write>(w: &mut W, mut val: u64) -> io::Result<()> {
+    let mut buf = [0u8; MAX_VARINT_BYTES];
+    let mut len = 0;
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+pub fn write_binary_header<W: Write>(w: &mut W, header: &Header) -> io::Result<()> {
+    if header.window_size < 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "window size out of range"));
+    }
+    w.write_all(&BINARY_MAGIC[..])?;
+    w.write_all(&[BINARY_VERSION])?;
+    write_varint(w, header.window_size as u64)?;
+    write_varint(w, header.len.map_or(0, |len| len + 1))
+}
+
+pub fn write_binary_command<W: Write, SliceType: SliceWrapper<u8>>(w: &mut W, cmd: &Command<SliceType>) -> io::Result<()> {
+    match *cmd {
+        Command::Copy(ref copy) => {
+            w.write_all(&[TAG_COPY])?;
+            write_varint(w, u64::from(copy.num_bytes))?;
+            write_varint(w, u64::from(copy.distance))
+        },
+        Command::Dict(ref dict) => {
+            w.write_all(&[TAG_DICT, dict.word_size])?;
+            write_varint(w, u64::from(dict.word_id))?;
+            w.write_all(&[dict.transform, dict.final_size])
+        },
+        Command::Literal(ref lit) => {
+            let probs = lit.prob.slice();
+            let mut flags = 0u8;
+            if lit.high_entropy {
+                flags |= LITERAL_HIGH_ENTROPY;
+            }
+            if !probs.is_empty() {
+                if probs.len() != lit.data.slice().len() * 8 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "literal probabilities must be 8 bytes per literal"));
+                }
+                flags |= LITERAL_HAS_PROBS;
+            }
+            w.write_all(&[TAG_LITERAL])?;
+            write_varint(w, lit.data.slice().len() as u64)?;
+            w.write_all(&[flags])?;
+            w.write_all(lit.data.slice())?;
+            w.write_all(probs)
+        },
+        Command::BlockSwitchCommand(ref bs) => w.write_all(&[TAG_BLOCK_SWITCH_COMMAND, bs.block_type()]),
+        Command::BlockSwitchDistance(ref bs) => w.write_all(&[TAG_BLOCK_SWITCH_DISTANCE, bs.block_type()]),
+        Command::BlockSwitchLiteral(ref bs) => w.write_all(&[TAG_BLOCK_SWITCH_LITERAL, bs.block_type(), bs.stride()]),
+        Command::PredictionMode(ref pm) => {
+            let literal_context_map = pm.literal_context_map.slice();
+            let predmode = pm.predmode_speed_and_distance_context_map.slice();
+            w.write_all(&[TAG_PREDICTION_MODE])?;
+            write_varint(w, literal_context_map.len() as u64)?;
+            w.write_all(literal_context_map)?;
+            write_varint(w, predmode.len() as u64)?;
+            w.write_all(predmode)
+        },
+    }
+}
+
+/// Marks the end of the command stream; readers treat a stream without it as truncated.
+pub fn write_binary_end<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(&[TAG_END])
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::vec::Vec;
+    use super::super::{convert, Format, Reader};
+
+    #[test]
+    fn test_binary_round_trip() {
+        let text = include_bytes!("../../testdata/alice29.ir");
+        let binary = convert(&text[..], Vec::<u8>::new(), Format::Binary).unwrap();
+        assert!(binary.len() * 4 < text.len());
+        let reader = Reader::new(&binary[..]).unwrap();
+        assert_eq!(reader.format(), Some(Format::Binary));
+        let retext = convert(&binary[..], Vec::<u8>::new(), Format::Text).unwrap();
+        assert_eq!(convert(&retext[..], Vec::<u8>::new(), Format::Binary).unwrap(), binary);
+        let prediction = b"window 16\nprediction msb6 lcontextmap 1 2 dcontextmap 3 mixingvalues 0 2 cmspeedinc 8 16 cmspeedmax 1024 2048\nrndins 2 ffee\nltype 1 4\nctype 2\ndtype 3\n";
+        let binary = convert(&prediction[..], Vec::<u8>::new(), Format::Binary).unwrap();
+        let retext = convert(&binary[..], Vec::<u8>::new(), Format::Text).unwrap();
+        assert_eq!(convert(&prediction[..], Vec::<u8>::new(), Format::Text).unwrap(), retext);
+    }
+
+    #[test]
+    fn test_binary_errors() {
+        let text = b"window 22 len 7\ninsert 4 446f776e\ncopy 3 from 4\n";
+        let binary = convert(&text[..], Vec::<u8>::new(), Format::Binary).unwrap();
+        for truncated in 1..binary.len() {
+            let reader = Reader::new(&binary[..truncated]).unwrap();
+            assert!(reader.collect::<io::Result<Vec<_>>>().is_err());
+        }
+        let mut bad_version = binary.clone();
+        bad_version[4] += 1;
+        assert!(Reader::new(&bad_version[..]).unwrap().header().is_err());
+        let mut bad_tag = binary.clone();
+        let end = bad_tag.len() - 1;
+        bad_tag[end] = 0x7f;
+        let err = Reader::new(&bad_tag[..]).unwrap().collect::<io::Result<Vec<_>>>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Decodes a divans stream and writes out the commands it was made of instead of the bytes.
+use core;
+use std::io::{self, Read, Write};
+use std::vec::Vec;
+use alloc_stdlib::HeapAlloc;
+use brotli::interface::Nop;
+use interface::{Decompressor, DivansResult, HEADER_LENGTH};
+use filter::FilterChain;
+use ::{DivansDecompressorFactory, DivansDecompressorFactoryStruct, StaticCommand, DefaultCDF16};
+use super::{Format, Header, IrCommand, Writer};
+
+fn read_stream_header<R: Read>(r: &mut R, header: &mut [u8; HEADER_LENGTH]) -> io::Result<()> {
+    match r.read_exact(&mut header[..]) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "divans stream is shorter than its header"))
+        },
+        other => other,
+    }
+}
+
+fn write_commands<W: Write>(writer: &mut Writer<W>, commands: &mut Vec<IrCommand>) -> io::Result<()> {
+    for cmd in commands.drain(..) {
+        writer.write_command(&cmd)?;
+    }
+    Ok(())
+}
+
+/// Decodes the divans stream in `r` and writes the commands it contains to `w` as IR.
+///
+/// Streams that were preprocessed with filters are rejected: the IR would describe the
+/// filtered bytes and recoding it could not reproduce the original file.
+pub fn decompress_to_ir<R: Read, W: Write>(r: &mut R,
+                                           w: W,
+                                           format: Format,
+                                           buffer_size: usize,
+                                           skip_crc: bool) -> io::Result<W> {
+    let mut header = [0u8; HEADER_LENGTH];
+    read_stream_header(r, &mut header)?;
+    match FilterChain::from_header(&header) {
+        Ok(filters) => if !filters.is_identity() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "divans stream uses preprocessing filters, which the IR cannot express"));
+        },
+        Err(m) => return Err(io::Error::new(io::ErrorKind::InvalidData, m)),
+    }
+    let mut state = DivansDecompressorFactoryStruct::<HeapAlloc<u8>,
+                                                      HeapAlloc<DefaultCDF16>,
+                                                      HeapAlloc<StaticCommand>>::new(
+        HeapAlloc::<u8>::new(0),
+        HeapAlloc::<DefaultCDF16>::new(DefaultCDF16::default()),
+        HeapAlloc::<StaticCommand>::new(StaticCommand::nop()),
+        skip_crc,
+        false);
+    state.capture_ir();
+    let mut writer = Writer::new(w, format, &Header {
+        window_size: i32::from(header[5]),
+        len: None,
+    })?;
+    let buffer_size = if buffer_size == 0 { 4096 } else { buffer_size };
+    let mut ibuffer = vec![0u8; core::cmp::max(buffer_size, HEADER_LENGTH)];
+    ibuffer[..HEADER_LENGTH].clone_from_slice(&header[..]);
+    let mut obuffer = vec![0u8; buffer_size];
+    let mut input_offset = 0usize;
+    let mut input_end = HEADER_LENGTH;
+    let mut commands = Vec::<IrCommand>::new();
+    let ret = loop {
+        let mut output_offset = 0usize;
+        let result = state.decode(&ibuffer[..input_end],
+                                  &mut input_offset,
+                                  &mut obuffer[..],
+                                  &mut output_offset);
+        state.take_ir_commands(&mut commands);
+        if let Err(e) = write_commands(&mut writer, &mut commands) {
+            break Err(e);
+        }
+        match result {
+            DivansResult::Success => break Ok(()),
+            DivansResult::Failure(m) => break Err(io::Error::new(io::ErrorKind::InvalidData, m)),
+            DivansResult::NeedsMoreOutput => {},
+            DivansResult::NeedsMoreInput => {
+                ibuffer.copy_within(input_offset..input_end, 0);
+                input_end -= input_offset;
+                input_offset = 0;
+                if input_end == ibuffer.len() {
+                    break Err(io::Error::new(io::ErrorKind::InvalidData, "divans decoder stopped consuming input"));
+                }
+                match r.read(&mut ibuffer[input_end..]) {
+                    Ok(0) => break Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                      "Divans file invalid: didn't have a terminator marker")),
+                    Ok(size) => input_end += size,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(e) => break Err(e),
+                }
+            },
+        }
+    };
+    state.free();
+    ret?;
+    writer.finish()
+}
@@ -0,0 +1,598 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// An inflate front-end: rather than producing bytes it keeps the LZ77 parse the deflate
+// encoder made and hands it out as literal and copy commands.
+//
+// Deflate (RFC 1951) is read bit by bit, least significant bit first. Huffman codes are
+// decoded canonically one bit at a time, so only the per-length counts and the symbols
+// sorted by code are kept per table. Copies are replayed into the last 32KiB of output so
+// the gzip (RFC 1952) crc32 or the zlib (RFC 1950) adler32 of every member can be checked.
+use core;
+use std::io::{self, BufRead};
+use std::vec::Vec;
+use interface::{Command, CopyCommand, LiteralCommand, FeatureFlagSliceType};
+use super::{ItemVec, Header};
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// deflate distances reach back 32768 bytes, which is past the 2^15 - 16 a 15 bit window allows
+pub const DEFLATE_WINDOW_SIZE: i32 = 16;
+
+const DEFLATE_METHOD: u8 = 8;
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_RUN: usize = 1 << 16;
+const HISTORY_SIZE: usize = 1 << 15;
+const END_OF_BLOCK: u16 = 256;
+
+const GZIP_FHCRC: u8 = 2;
+const GZIP_FEXTRA: u8 = 4;
+const GZIP_FNAME: u8 = 8;
+const GZIP_FCOMMENT: u8 = 16;
+const GZIP_RESERVED: u8 = 0xe0;
+const ZLIB_FDICT: u8 = 0x20;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+                                 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+                                3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                                   257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+                                   8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+                                  7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// the order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// The wrapper around the deflate data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Gzip,
+    Zlib,
+    Raw,
+}
+
+impl Container {
+    /// Guesses the wrapper from the first two bytes of the stream.
+    pub fn detect(prefix: &[u8]) -> Container {
+        if prefix.len() >= 2 && prefix[..2] == GZIP_MAGIC {
+            Container::Gzip
+        } else if prefix.len() >= 2 && prefix[0] & 0xf == DEFLATE_METHOD && prefix[0] >> 4 <= 7
+            && prefix[1] & ZLIB_FDICT == 0 && ((u16::from(prefix[0]) << 8) | u16::from(prefix[1])) % 31 == 0 {
+            Container::Zlib
+        } else {
+            Container::Raw
+        }
+    }
+}
+
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    // incomplete codes are allowed, as zlib emits them for single distance codes
+    fn new(lengths: &[u8]) -> Result<Huffman, &'static str> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for len in lengths.iter() {
+            counts[*len as usize] += 1;
+        }
+        let mut left = 1i32;
+        for count in counts[1..].iter() {
+            left = (left << 1) - i32::from(*count);
+            if left < 0 {
+                return Err("over-subscribed huffman code");
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, len) in lengths.iter().enumerate() {
+            if *len != 0 {
+                symbols[offsets[*len as usize] as usize] = symbol as u16;
+                offsets[*len as usize] += 1;
+            }
+        }
+        counts[0] = 0;
+        Ok(Huffman {
+            counts,
+            symbols,
+        })
+    }
+}
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (index, entry) in table.iter_mut().enumerate() {
+        let mut crc = index as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+// the last 32KiB of output, which copies read from, and the checksums of the current member
+struct History {
+    data: Vec<u8>,
+    len: u64,
+    crc_table: [u32; 256],
+    crc: u32,
+    adler: (u32, u32),
+}
+
+impl History {
+    fn new() -> Self {
+        History {
+            data: vec![0u8; HISTORY_SIZE],
+            len: 0,
+            crc_table: crc32_table(),
+            crc: !0,
+            adler: (1, 0),
+        }
+    }
+    fn reset(&mut self) {
+        self.len = 0;
+        self.crc = !0;
+        self.adler = (1, 0);
+    }
+    fn push(&mut self, byte: u8) {
+        self.data[self.len as usize & (HISTORY_SIZE - 1)] = byte;
+        self.len += 1;
+        self.crc = self.crc_table[((self.crc ^ u32::from(byte)) & 0xff) as usize] ^ (self.crc >> 8);
+        self.adler.0 = (self.adler.0 + u32::from(byte)) % 65521;
+        self.adler.1 = (self.adler.1 + self.adler.0) % 65521;
+    }
+    fn copy(&mut self, distance: u32, num_bytes: u32) {
+        for _ in 0..num_bytes {
+            let byte = self.data[(self.len - u64::from(distance)) as usize & (HISTORY_SIZE - 1)];
+            self.push(byte);
+        }
+    }
+    fn crc32(&self) -> u32 {
+        !self.crc
+    }
+    fn adler32(&self) -> u32 {
+        (self.adler.1 << 16) | self.adler.0
+    }
+}
+
+struct BitReader<R: BufRead> {
+    reader: R,
+    offset: u64,
+    bits: u32,
+    bit_count: u32,
+}
+
+impl<R: BufRead> BitReader<R> {
+    fn error(&self, kind: io::ErrorKind, message: &str) -> io::Error {
+        io::Error::new(kind, format!("deflate offset {}: {}", self.offset, message))
+    }
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let byte = match self.reader.fill_buf()?.first() {
+            Some(byte) => *byte,
+            None => return Err(self.error(io::ErrorKind::UnexpectedEof, "truncated deflate stream")),
+        };
+        self.reader.consume(1);
+        self.offset += 1;
+        Ok(byte)
+    }
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        while self.bit_count < count {
+            self.bits |= u32::from(self.read_byte()?) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let val = self.bits & ((1u32 << count) - 1);
+        self.bits >>= count;
+        self.bit_count -= count;
+        Ok(val)
+    }
+    fn align(&mut self) {
+        let partial = self.bit_count & 7;
+        self.bits >>= partial;
+        self.bit_count -= partial;
+    }
+    // only valid once aligned: whole bytes still held in the bit buffer come out first
+    fn aligned_byte(&mut self) -> io::Result<u8> {
+        self.bits(8).map(|byte| byte as u8)
+    }
+    fn aligned_u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from(self.aligned_byte()?) | (u16::from(self.aligned_byte()?) << 8))
+    }
+    fn aligned_u32(&mut self, big_endian: bool) -> io::Result<u32> {
+        let mut val = 0u32;
+        for index in 0..4 {
+            let byte = u32::from(self.aligned_byte()?);
+            val |= if big_endian { byte << (24 - 8 * index) } else { byte << (8 * index) };
+        }
+        Ok(val)
+    }
+    fn decode(&mut self, table: &Huffman) -> io::Result<u16> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..=MAX_BITS {
+            code |= self.bits(1)? as i32;
+            let count = i32::from(table.counts[len]);
+            if code - count < first {
+                return Ok(table.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(self.error(io::ErrorKind::InvalidData, "invalid huffman code"))
+    }
+}
+
+enum State {
+    MemberHeader,
+    BlockHeader,
+    Stored(u16),
+    Compressed(Huffman, Huffman),
+    MemberTrailer,
+    Done,
+}
+
+/// Streams the literal and copy commands of a gzip, zlib or raw deflate stream.
+pub struct Inflater<R: BufRead> {
+    input: BitReader<R>,
+    container: Container,
+    state: State,
+    last_block: bool,
+    literals: Vec<u8>,
+    pending_copy: Option<CopyCommand>,
+    history: History,
+}
+
+impl<R: BufRead> Inflater<R> {
+    pub fn new(reader: R, container: Container) -> Self {
+        Inflater {
+            input: BitReader {
+                reader,
+                offset: 0,
+                bits: 0,
+                bit_count: 0,
+            },
+            container,
+            state: State::MemberHeader,
+            last_block: false,
+            literals: Vec::new(),
+            pending_copy: None,
+            history: History::new(),
+        }
+    }
+    /// Picks the container by looking at the start of the stream.
+    pub fn detect(mut reader: R) -> io::Result<Self> {
+        let container = Container::detect(reader.fill_buf()?);
+        Ok(Inflater::new(reader, container))
+    }
+    pub fn container(&self) -> Container {
+        self.container
+    }
+    /// The number of compressed bytes consumed so far.
+    pub fn offset(&self) -> u64 {
+        self.input.offset
+    }
+    pub fn header(&mut self) -> io::Result<Header> {
+        Ok(Header {
+            window_size: DEFLATE_WINDOW_SIZE,
+            len: None,
+        })
+    }
+    pub fn next_command(&mut self) -> io::Result<Option<Command<ItemVec<u8>>>> {
+        loop {
+            if self.literals.is_empty() {
+                if let Some(copy) = self.pending_copy.take() {
+                    return Ok(Some(Command::Copy(copy)));
+                }
+            }
+            if self.pending_copy.is_some() || self.literals.len() >= MAX_LITERAL_RUN {
+                return Ok(Some(self.take_literals()));
+            }
+            match self.state {
+                State::MemberHeader => {
+                    self.read_member_header()?;
+                    self.state = State::BlockHeader;
+                },
+                State::BlockHeader => {
+                    if self.last_block {
+                        self.state = State::MemberTrailer;
+                        continue;
+                    }
+                    self.last_block = self.input.bits(1)? != 0;
+                    self.state = match self.input.bits(2)? {
+                        0 => {
+                            self.input.align();
+                            let len = self.input.aligned_u16()?;
+                            let nlen = self.input.aligned_u16()?;
+                            if len != !nlen {
+                                return Err(self.input.error(io::ErrorKind::InvalidData, "stored block length check failed"));
+                            }
+                            State::Stored(len)
+                        },
+                        1 => fixed_tables(),
+                        2 => self.read_dynamic_tables()?,
+                        _ => return Err(self.input.error(io::ErrorKind::InvalidData, "invalid block type")),
+                    };
+                },
+                State::Stored(0) => self.state = State::BlockHeader,
+                State::Stored(remaining) => {
+                    let byte = self.input.aligned_byte()?;
+                    self.literals.push(byte);
+                    self.history.push(byte);
+                    self.state = State::Stored(remaining - 1);
+                },
+                State::Compressed(ref lit, ref dist) => {
+                    let symbol = self.input.decode(lit)?;
+                    if symbol < END_OF_BLOCK {
+                        self.literals.push(symbol as u8);
+                        self.history.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == END_OF_BLOCK {
+                        self.state = State::BlockHeader;
+                        continue;
+                    }
+                    let length_code = usize::from(symbol - END_OF_BLOCK - 1);
+                    if length_code >= LENGTH_BASE.len() {
+                        return Err(self.input.error(io::ErrorKind::InvalidData, "invalid length code"));
+                    }
+                    let num_bytes = u32::from(LENGTH_BASE[length_code]) + self.input.bits(u32::from(LENGTH_EXTRA[length_code]))?;
+                    let distance_code = usize::from(self.input.decode(dist)?);
+                    if distance_code >= DISTANCE_BASE.len() {
+                        return Err(self.input.error(io::ErrorKind::InvalidData, "invalid distance code"));
+                    }
+                    let distance = u32::from(DISTANCE_BASE[distance_code]) + self.input.bits(u32::from(DISTANCE_EXTRA[distance_code]))?;
+                    if u64::from(distance) > self.history.len {
+                        return Err(self.input.error(io::ErrorKind::InvalidData, "distance reaches before the start of the output"));
+                    }
+                    self.history.copy(distance, num_bytes);
+                    self.pending_copy = Some(CopyCommand {
+                        distance,
+                        num_bytes,
+                    });
+                },
+                State::MemberTrailer => {
+                    self.read_member_trailer()?;
+                    self.state = if self.container == Container::Gzip && !self.input.reader.fill_buf()?.is_empty() {
+                        State::MemberHeader
+                    } else {
+                        State::Done
+                    };
+                },
+                State::Done => {
+                    if !self.literals.is_empty() {
+                        return Ok(Some(self.take_literals()));
+                    }
+                    return Ok(None);
+                },
+            }
+        }
+    }
+    pub fn into_inner(self) -> R {
+        self.input.reader
+    }
+    fn take_literals(&mut self) -> Command<ItemVec<u8>> {
+        let data = core::mem::take(&mut self.literals);
+        Command::Literal(LiteralCommand {
+            data: ItemVec(data),
+            prob: FeatureFlagSliceType::<ItemVec<u8>>::default(),
+            high_entropy: false,
+        })
+    }
+    fn read_dynamic_tables(&mut self) -> io::Result<State> {
+        let num_lit = self.input.bits(5)? as usize + 257;
+        let num_dist = self.input.bits(5)? as usize + 1;
+        let num_code_lengths = self.input.bits(4)? as usize + 4;
+        if num_lit > 286 || num_dist > 30 {
+            return Err(self.input.error(io::ErrorKind::InvalidData, "too many length or distance codes"));
+        }
+        let mut code_length_lengths = [0u8; 19];
+        for index in CODE_LENGTH_ORDER[..num_code_lengths].iter() {
+            code_length_lengths[*index] = self.input.bits(3)? as u8;
+        }
+        let code_lengths = match Huffman::new(&code_length_lengths[..]) {
+            Ok(table) => table,
+            Err(message) => return Err(self.input.error(io::ErrorKind::InvalidData, message)),
+        };
+        let mut lengths = [0u8; 286 + 30];
+        let mut index = 0usize;
+        while index < num_lit + num_dist {
+            let symbol = self.input.decode(&code_lengths)?;
+            let (len, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    if index == 0 {
+                        return Err(self.input.error(io::ErrorKind::InvalidData, "repeat with no previous length"));
+                    }
+                    (lengths[index - 1], 3 + self.input.bits(2)? as usize)
+                },
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+            if index + repeat > num_lit + num_dist {
+                return Err(self.input.error(io::ErrorKind::InvalidData, "code lengths overrun the tables"));
+            }
+            for item in lengths[index..index + repeat].iter_mut() {
+                *item = len;
+            }
+            index += repeat;
+        }
+        if lengths[END_OF_BLOCK as usize] == 0 {
+            return Err(self.input.error(io::ErrorKind::InvalidData, "no code for the end of block"));
+        }
+        match (Huffman::new(&lengths[..num_lit]), Huffman::new(&lengths[num_lit..num_lit + num_dist])) {
+            (Ok(lit), Ok(dist)) => Ok(State::Compressed(lit, dist)),
+            (Err(message), _) | (_, Err(message)) => Err(self.input.error(io::ErrorKind::InvalidData, message)),
+        }
+    }
+    fn read_member_header(&mut self) -> io::Result<()> {
+        self.last_block = false;
+        self.history.reset();
+        match self.container {
+            Container::Raw => {},
+            Container::Zlib => {
+                let cmf = self.input.aligned_byte()?;
+                let flg = self.input.aligned_byte()?;
+                if Container::detect(&[cmf, flg]) != Container::Zlib {
+                    return Err(self.input.error(io::ErrorKind::InvalidData, "not a zlib stream or preset dictionary required"));
+                }
+            },
+            Container::Gzip => {
+                let mut fixed = [0u8; 10];
+                for byte in fixed.iter_mut() {
+                    *byte = self.input.aligned_byte()?;
+                }
+                if fixed[..2] != GZIP_MAGIC || fixed[2] != DEFLATE_METHOD {
+                    return Err(self.input.error(io::ErrorKind::InvalidData, "not a gzip member"));
+                }
+                let flags = fixed[3];
+                if flags & GZIP_RESERVED != 0 {
+                    return Err(self.input.error(io::ErrorKind::InvalidData, "reserved gzip flags set"));
+                }
+                if flags & GZIP_FEXTRA != 0 {
+                    let extra_len = self.input.aligned_u16()?;
+                    for _ in 0..extra_len {
+                        self.input.aligned_byte()?;
+                    }
+                }
+                for flag in [GZIP_FNAME, GZIP_FCOMMENT].iter() {
+                    if flags & *flag != 0 {
+                        while self.input.aligned_byte()? != 0 {}
+                    }
+                }
+                if flags & GZIP_FHCRC != 0 {
+                    self.input.aligned_u16()?;
+                }
+            },
+        }
+        Ok(())
+    }
+    fn read_member_trailer(&mut self) -> io::Result<()> {
+        self.input.align();
+        match self.container {
+            Container::Raw => {},
+            Container::Zlib => {
+                if self.input.aligned_u32(true)? != self.history.adler32() {
+                    return Err(self.input.error(io::ErrorKind::InvalidData, "adler32 mismatch"));
+                }
+            },
+            Container::Gzip => {
+                if self.input.aligned_u32(false)? != self.history.crc32() {
+                    return Err(self.input.error(io::ErrorKind::InvalidData, "crc32 mismatch"));
+                }
+                if self.input.aligned_u32(false)? != self.history.len as u32 {
+                    return Err(self.input.error(io::ErrorKind::InvalidData, "length mismatch"));
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+fn fixed_tables() -> State {
+    let mut lengths = [0u8; 288];
+    for (symbol, len) in lengths.iter_mut().enumerate() {
+        *len = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    match (Huffman::new(&lengths[..]), Huffman::new(&[5u8; 30][..])) {
+        (Ok(lit), Ok(dist)) => State::Compressed(lit, dist),
+        _ => unreachable!(),
+    }
+}
+
+impl<R: BufRead> Iterator for Inflater<R> {
+    type Item = io::Result<Command<ItemVec<u8>>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_command() {
+            Ok(Some(cmd)) => Some(Ok(cmd)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::vec::Vec;
+    use alloc::SliceWrapper;
+    use interface::Command;
+    use super::{Container, Inflater};
+
+    fn inflate(data: &[u8]) -> io::Result<(Container, Vec<u8>, usize)> {
+        let mut inflater = Inflater::detect(data)?;
+        let mut output = Vec::<u8>::new();
+        let mut copies = 0usize;
+        while let Some(cmd) = inflater.next_command()? {
+            match cmd {
+                Command::Literal(lit) => output.extend_from_slice(lit.data.slice()),
+                Command::Copy(copy) => {
+                    copies += 1;
+                    for _ in 0..copy.num_bytes {
+                        let byte = output[output.len() - copy.distance as usize];
+                        output.push(byte);
+                    }
+                },
+                _ => panic!("deflate produced a command other than a literal or copy"),
+            }
+        }
+        Ok((inflater.container(), output, copies))
+    }
+
+    #[test]
+    fn test_inflate_small_blocks() {
+        let stored = [0x01u8, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c'];
+        assert_eq!(inflate(&stored[..]).unwrap(), (Container::Raw, b"abc".to_vec(), 0));
+        let fixed = [0x4bu8, 0x4c, 0x4a, 0x4e, 0x44, 0x42, 0x00];
+        let mut inflater = Inflater::new(&fixed[..], Container::Raw);
+        match inflater.next_command().unwrap() {
+            Some(Command::Literal(lit)) => assert_eq!(lit.data.slice(), b"abca"),
+            _ => panic!("expected the leading literals"),
+        }
+        match inflater.next_command().unwrap() {
+            Some(Command::Copy(copy)) => assert_eq!((copy.distance, copy.num_bytes), (3, 11)),
+            _ => panic!("expected a copy"),
+        }
+        assert!(inflater.next_command().unwrap().is_none());
+        let zlib = [0x78u8, 0x9c, 0x4b, 0x4c, 0x4a, 0x4e, 0x44, 0x42, 0x00, 0x2d, 0xf5, 0x05, 0xbf];
+        assert_eq!(inflate(&zlib[..]).unwrap(), (Container::Zlib, b"abcabcabcabcabc".to_vec(), 1));
+        let mut bad_adler = zlib;
+        bad_adler[12] ^= 1;
+        assert_eq!(inflate(&bad_adler[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_inflate_gzip() {
+        let gz = include_bytes!("../../testdata/alice29.gz");
+        let (container, output, copies) = inflate(&gz[..]).unwrap();
+        assert_eq!(container, Container::Gzip);
+        assert_eq!(&output[..], &include_bytes!("../../testdata/alice29")[..]);
+        assert!(copies > 1000);
+        let mut corrupt = gz.to_vec();
+        let crc_offset = corrupt.len() - 8;
+        corrupt[crc_offset] ^= 0x40;
+        assert_eq!(inflate(&corrupt[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(inflate(&gz[..gz.len() - 100]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
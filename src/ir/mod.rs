@@ -0,0 +1,266 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+//! Reading and writing the divans intermediate representation.
+//!
+//! The textual IR is one command per line, preceded by a `window` header:
+//!
+//! ```text
+//! window 22 len 17
+//! insert 4 446f776e
+//! copy 8 from 4 ctx 3
+//! dict 5 word 5,70 626567696e func 0 626567696e ctx 3
+//! ltype 1 0
+//! prediction utf8 lcontextmap 0 1 2 dcontextmap 0 mixingvalues 1 1
+//! ```
+//!
+//! `Parser` streams `Command<ItemVec<u8>>` out of any `BufRead` and reports
+//! syntax errors with their line and column; `write_header` and `write_command`
+//! produce text that parses back into the same commands.
+//!
+//! The binary IR carries the same commands as varints and raw literal bytes behind
+//! a versioned magic number. `Reader` tells the two formats apart from the first
+//! byte of the stream and `Writer` produces either one.
+//!
+//! `decompress_to_ir` recovers the IR from a compressed divans stream, including the
+//! prediction modes and block switches the encoder chose.
+//!
+//! `Inflater` reads gzip, zlib and raw deflate streams and hands out the literals and
+//! copies the deflate encoder chose, so existing LZ77 parses can be recompressed as IR.
+//! `Reader` picks it up on its own when the stream starts with the gzip magic number.
+//!
+//! `validate` checks that IR which parses is also something the codec can reproduce:
+//! copy distances inside the data and window, dictionary references that exist and
+//! context maps of a usable size.
+use core;
+use std::boxed::Box;
+use std::io::{self, BufRead, Write};
+use std::vec::Vec;
+use alloc::{SliceWrapper, SliceWrapperMut};
+use interface::{Command, FeatureFlagSliceType, LiteralCommand, PredictionModeContextMap};
+
+mod binary;
+mod decode;
+mod inflate;
+mod text;
+mod validate;
+pub use self::binary::{BinaryParser, BINARY_MAGIC, BINARY_VERSION, write_binary_command, write_binary_end, write_binary_header};
+pub use self::decode::decompress_to_ir;
+pub use self::inflate::{Container, Inflater, DEFLATE_WINDOW_SIZE, GZIP_MAGIC};
+pub use self::validate::{Validator, Violation, validate};
+pub use self::text::{Header, Parser, SyntaxError, parse_command, parse_header, write_command, write_header};
+
+/// Heap backed slice used for the commands produced by the IR parsers.
+#[derive(Debug, Clone)]
+pub struct ItemVec<Item:Sized+Default>(pub Vec<Item>);
+
+pub type IrCommand = Command<ItemVec<u8>>;
+
+impl<Item:Sized+Default> Default for ItemVec<Item> {
+    fn default() -> Self {
+        ItemVec(Vec::<Item>::new())
+    }
+}
+
+impl<Item:Sized+Default> From<Vec<Item>> for ItemVec<Item> {
+    fn from(data: Vec<Item>) -> Self {
+        ItemVec(data)
+    }
+}
+
+impl<Item:Sized+Default> SliceWrapper<Item> for ItemVec<Item> {
+    fn slice(&self) -> &[Item] {
+        &self.0[..]
+    }
+}
+
+impl<Item:Sized+Default> SliceWrapperMut<Item> for ItemVec<Item> {
+    fn slice_mut(&mut self) -> &mut [Item] {
+        &mut self.0[..]
+    }
+}
+
+impl<Item:Sized+Default> core::ops::Index<usize> for ItemVec<Item> {
+    type Output = Item;
+    fn index(&self, index:usize) -> &Item {
+        &self.0[index]
+    }
+}
+
+impl<Item:Sized+Default> core::ops::IndexMut<usize> for ItemVec<Item> {
+    fn index_mut(&mut self, index:usize) -> &mut Item {
+        &mut self.0[index]
+    }
+}
+
+#[cfg(not(feature="external-literal-probability"))]
+fn external_probabilities(probs: Vec<u8>) -> Option<FeatureFlagSliceType<ItemVec<u8>>> {
+    if !probs.is_empty() {
+        return None;
+    }
+    Some(FeatureFlagSliceType::<ItemVec<u8>>::default())
+}
+
+#[cfg(feature="external-literal-probability")]
+fn external_probabilities(probs: Vec<u8>) -> Option<FeatureFlagSliceType<ItemVec<u8>>> {
+    Some(FeatureFlagSliceType::<ItemVec<u8>>(ItemVec(probs)))
+}
+
+pub fn to_ir_context_map<SliceType: SliceWrapper<u8>>(pm: &PredictionModeContextMap<SliceType>) -> PredictionModeContextMap<ItemVec<u8>> {
+    PredictionModeContextMap::<ItemVec<u8>> {
+        literal_context_map: ItemVec(pm.literal_context_map.slice().to_vec()),
+        predmode_speed_and_distance_context_map: ItemVec(pm.predmode_speed_and_distance_context_map.slice().to_vec()),
+    }
+}
+
+/// Copies a command out of whatever buffers the codec holds it in.
+pub fn to_ir_command<SliceType: SliceWrapper<u8>>(cmd: &Command<SliceType>) -> IrCommand {
+    match *cmd {
+        Command::Copy(cp) => Command::Copy(cp),
+        Command::Dict(dc) => Command::Dict(dc),
+        Command::BlockSwitchCommand(bs) => Command::BlockSwitchCommand(bs),
+        Command::BlockSwitchLiteral(bs) => Command::BlockSwitchLiteral(bs),
+        Command::BlockSwitchDistance(bs) => Command::BlockSwitchDistance(bs),
+        Command::PredictionMode(ref pm) => Command::PredictionMode(to_ir_context_map(pm)),
+        Command::Literal(ref lit) => Command::Literal(LiteralCommand::<ItemVec<u8>> {
+            data: ItemVec(lit.data.slice().to_vec()),
+            prob: external_probabilities(lit.prob.slice().to_vec()).unwrap_or_default(),
+            high_entropy: lit.high_entropy,
+        }),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+/// Peeks at the start of the stream without consuming it. Empty streams count as text.
+pub fn detect_format<R: BufRead>(reader: &mut R) -> io::Result<Format> {
+    loop {
+        match reader.fill_buf() {
+            Ok(buf) => return Ok(if buf.first() == Some(&BINARY_MAGIC[0]) {
+                Format::Binary
+            } else {
+                Format::Text
+            }),
+            Err(e) => {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            },
+        }
+    }
+}
+
+/// Reads either IR format, chosen by `detect_format`, or the LZ77 parse of a gzip stream.
+pub enum Reader<R: BufRead> {
+    Text(Parser<R>),
+    Binary(BinaryParser<R>),
+    // boxed: the inflate state dwarfs the IR parsers
+    Deflate(Box<Inflater<R>>),
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+            return Ok(Reader::Deflate(Box::new(Inflater::new(reader, Container::Gzip))));
+        }
+        Ok(match detect_format(&mut reader)? {
+            Format::Text => Reader::Text(Parser::new(reader)),
+            Format::Binary => Reader::Binary(BinaryParser::new(reader)),
+        })
+    }
+    /// Reads a gzip, zlib or raw deflate stream, whichever its first bytes look like.
+    pub fn from_deflate(reader: R) -> io::Result<Self> {
+        Ok(Reader::Deflate(Box::new(Inflater::detect(reader)?)))
+    }
+    /// The IR format being read, or `None` when the commands come from a deflate stream.
+    pub fn format(&self) -> Option<Format> {
+        match *self {
+            Reader::Text(_) => Some(Format::Text),
+            Reader::Binary(_) => Some(Format::Binary),
+            Reader::Deflate(_) => None,
+        }
+    }
+    pub fn header(&mut self) -> io::Result<Header> {
+        match *self {
+            Reader::Text(ref mut parser) => parser.header(),
+            Reader::Binary(ref mut parser) => parser.header(),
+            Reader::Deflate(ref mut inflater) => inflater.header(),
+        }
+    }
+    pub fn next_command(&mut self) -> io::Result<Option<Command<ItemVec<u8>>>> {
+        match *self {
+            Reader::Text(ref mut parser) => parser.next_command(),
+            Reader::Binary(ref mut parser) => parser.next_command(),
+            Reader::Deflate(ref mut inflater) => inflater.next_command(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<Command<ItemVec<u8>>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_command() {
+            Ok(Some(cmd)) => Some(Ok(cmd)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Writes IR in either format; `finish` must be called to terminate binary streams.
+pub struct Writer<W: Write> {
+    writer: W,
+    format: Format,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut writer: W, format: Format, header: &Header) -> io::Result<Self> {
+        match format {
+            Format::Text => write_header(&mut writer, header)?,
+            Format::Binary => write_binary_header(&mut writer, header)?,
+        }
+        Ok(Writer {
+            writer,
+            format,
+        })
+    }
+    pub fn write_command<SliceType: SliceWrapper<u8>>(&mut self, cmd: &Command<SliceType>) -> io::Result<()> {
+        match self.format {
+            Format::Text => write_command(&mut self.writer, cmd),
+            Format::Binary => write_binary_command(&mut self.writer, cmd),
+        }
+    }
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.format == Format::Binary {
+            write_binary_end(&mut self.writer)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Re-encodes IR of either format into the requested one.
+pub fn convert<R: BufRead, W: Write>(reader: R, writer: W, format: Format) -> io::Result<W> {
+    let mut reader = Reader::new(reader)?;
+    let header = reader.header()?;
+    let mut writer = Writer::new(writer, format, &header)?;
+    while let Some(cmd) = reader.next_command()? {
+        writer.write_command(&cmd)?;
+    }
+    writer.finish()
+}
@@ -0,0 +1,719 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use core;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::string::String;
+use std::vec::Vec;
+use alloc::SliceWrapper;
+use brotli::dictionary::{kBrotliDictionary,
+                         kBrotliDictionaryOffsetsByLength,
+                         kBrotliDictionarySizeBitsByLength,
+                         kBrotliMaxDictionaryWordLength};
+use brotli::transform::TransformDictionaryWord;
+use interface::{BlockSwitch,
+                Command,
+                CopyCommand,
+                DictCommand,
+                LiteralBlockSwitch,
+                LiteralCommand,
+                LiteralPredictionModeNibble,
+                PredictionModeContextMap,
+                LITERAL_PREDICTION_MODE_LSB6,
+                LITERAL_PREDICTION_MODE_MSB6,
+                LITERAL_PREDICTION_MODE_SIGN,
+                LITERAL_PREDICTION_MODE_UTF8,
+                NUM_MIXING_VALUES};
+use super::{ItemVec, external_probabilities};
+
+const NUM_DICT_TRANSFORMS: u8 = 121;
+const MAX_STRIDE: i64 = 8;
+const MAX_MIXING_VALUE: i64 = 8;
+const MAX_SPEED: i64 = 16384;
+// the increment and the max of the context map, stride and combined stride speeds
+const SPEED_KEYS: [[&str; 2]; 3] = [["cmspeedinc", "cmspeedmax"],
+                                    ["stspeedinc", "stspeedmax"],
+                                    ["mxspeedinc", "mxspeedmax"]];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub line: usize, // 1-based
+    pub column: usize, // 1-based byte offset into the line
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl error::Error for SyntaxError {
+    fn description(&self) -> &str {
+        "Divans IR syntax error"
+    }
+}
+
+impl From<SyntaxError> for io::Error {
+    fn from(err: SyntaxError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// The `window` line that starts an IR stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub window_size: i32,
+    pub len: Option<u64>,
+}
+
+enum Line {
+    Skip,
+    Header(Header),
+    Command(Command<ItemVec<u8>>),
+}
+
+fn syntax_error<T>(line: &str, at: &str, message: String) -> Result<T, SyntaxError> {
+    let start = line.as_ptr() as usize;
+    let column = if at.as_ptr() as usize >= start && at.as_ptr() as usize <= start + line.len() {
+        at.as_ptr() as usize - start + 1
+    } else {
+        line.len() + 1
+    };
+    Err(SyntaxError {
+        line: 1,
+        column,
+        message,
+    })
+}
+
+fn end_of(line: &str) -> &str {
+    &line[line.len()..]
+}
+
+fn number<T: core::str::FromStr>(line: &str, token: &str) -> Result<T, SyntaxError> {
+    match token.parse::<T>() {
+        Ok(val) => Ok(val),
+        Err(_) => syntax_error(line, token, format!("expected a number, found `{}`", token)),
+    }
+}
+
+fn bounded(line: &str, token: &str, max: i64, what: &str) -> Result<i64, SyntaxError> {
+    let val = number::<i64>(line, token)?;
+    if val < 0 || val > max {
+        return syntax_error(line, token, format!("{} must be between 0 and {}, not {}", what, max, val));
+    }
+    Ok(val)
+}
+
+// the numeric values following key, up to the next token that is not a number
+fn values_after<'a, 'b>(tokens: &'b [&'a str], key: &str) -> &'b [&'a str] {
+    match tokens.iter().position(|token| *token == key) {
+        Some(index) => {
+            let rest = &tokens[index + 1..];
+            let count = rest.iter().take_while(|token| token.parse::<i64>().is_ok()).count();
+            &rest[..count]
+        },
+        None => &[],
+    }
+}
+
+fn hex_to_nibble(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn hex_to_vec(line: &str, token: &str) -> Result<Vec<u8>, SyntaxError> {
+    if token.len() & 1 != 0 {
+        return syntax_error(line, token, String::from("hex data needs an even number of digits"));
+    }
+    let mut output = Vec::with_capacity(token.len() >> 1);
+    for (index, pair) in token.as_bytes().chunks(2).enumerate() {
+        match (hex_to_nibble(pair[0]), hex_to_nibble(pair[1])) {
+            (Some(upper), Some(lower)) => output.push((upper << 4) | lower),
+            (None, _) => return syntax_error(line, &token[index * 2..], String::from("invalid hex digit")),
+            (_, None) => return syntax_error(line, &token[index * 2 + 1..], String::from("invalid hex digit")),
+        }
+    }
+    Ok(output)
+}
+
+// a double quoted string with C style escapes, including \xHH
+fn quoted_to_vec(s: &[u8]) -> Result<Vec<u8>, usize> {
+    let mut output = Vec::with_capacity(s.len());
+    let mut index = 1;
+    while index < s.len() {
+        let byte = s[index];
+        if byte == b'"' {
+            if index + 1 != s.len() {
+                return Err(index + 1);
+            }
+            return Ok(output);
+        }
+        if byte != b'\\' {
+            output.push(byte);
+            index += 1;
+            continue;
+        }
+        let unescaped = match s.get(index + 1) {
+            Some(&b'n') => b'\n',
+            Some(&b'r') => b'\r',
+            Some(&b't') => b'\t',
+            Some(&escaped) if escaped == b'\\' || escaped == b'\'' || escaped == b'"' || escaped == b'?' => escaped,
+            Some(&b'x') => {
+                match (s.get(index + 2).and_then(|b| hex_to_nibble(*b)),
+                       s.get(index + 3).and_then(|b| hex_to_nibble(*b))) {
+                    (Some(upper), Some(lower)) => {
+                        index += 2;
+                        (upper << 4) | lower
+                    },
+                    _ => return Err(index),
+                }
+            },
+            _ => return Err(index),
+        };
+        output.push(unescaped);
+        index += 2;
+    }
+    Err(s.len())
+}
+
+/// Parses a `window <log window size> [len <bytes>]` line.
+pub fn parse_header(line: &str) -> Result<Header, SyntaxError> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.first() != Some(&"window") {
+        return syntax_error(line, tokens.first().cloned().unwrap_or(end_of(line)),
+                            String::from("expected a window line"));
+    }
+    if tokens.len() < 2 {
+        return syntax_error(line, end_of(line), String::from("window needs 1 argument"));
+    }
+    let window_size = number::<i32>(line, tokens[1])?;
+    let len = match tokens.iter().position(|token| *token == "len") {
+        Some(index) => match tokens.get(index + 1) {
+            Some(token) => Some(number::<u64>(line, token)?),
+            None => return syntax_error(line, end_of(line), String::from("len needs 1 argument")),
+        },
+        None => None,
+    };
+    Ok(Header {
+        window_size,
+        len,
+    })
+}
+
+fn parse_prediction(line: &str, tokens: &[&str]) -> Result<Command<ItemVec<u8>>, SyntaxError> {
+    if tokens.len() < 2 {
+        return syntax_error(line, end_of(line), String::from("prediction needs 1 argument"));
+    }
+    let pmode = match tokens[1] {
+        "utf8" => LiteralPredictionModeNibble::utf8(),
+        "sign" => LiteralPredictionModeNibble::signed(),
+        "lsb6" => LiteralPredictionModeNibble::lsb6(),
+        "msb6" => LiteralPredictionModeNibble::msb6(),
+        _ => return syntax_error(line, tokens[1],
+                                 String::from("invalid prediction mode; not {utf8,sign,lsb6,msb6}")),
+    };
+    let mut ret = PredictionModeContextMap::<ItemVec<u8> > {
+        literal_context_map: ItemVec::<u8>::default(),
+        predmode_speed_and_distance_context_map: ItemVec::<u8>::default(),
+    };
+    for val in values_after(tokens, "lcontextmap") {
+        ret.literal_context_map.0.push(bounded(line, val, 255, "literal context map value")? as u8);
+    }
+    ret.predmode_speed_and_distance_context_map.0.resize(PredictionModeContextMap::<ItemVec<u8> >::size_of_combined_array(0), 0u8);
+    ret.set_literal_prediction_mode(pmode);
+    for val in values_after(tokens, "dcontextmap") {
+        ret.predmode_speed_and_distance_context_map.0.push(bounded(line, val, 255, "distance context map value")? as u8);
+    }
+    let mut mixing_values = [0u8; NUM_MIXING_VALUES];
+    for (offset, val) in values_after(tokens, "mixingvalues").iter().enumerate() {
+        if offset >= NUM_MIXING_VALUES {
+            return syntax_error(line, val, format!("must have no more than {} mixing values", NUM_MIXING_VALUES));
+        }
+        mixing_values[offset] = bounded(line, val, MAX_MIXING_VALUE, "mixing value")? as u8;
+    }
+    ret.set_mixing_values(&mixing_values);
+    if let Some(val) = values_after(tokens, "mixingmath").first() {
+        ret.set_mixing_math(bounded(line, val, 255, "mixing math")? as u8);
+    }
+    if let Some(val) = values_after(tokens, "advcontextmap").first() {
+        ret.set_adv_context_map(bounded(line, val, 1, "advanced context map flag")? as u8);
+    }
+    let mut cm_stride_mix_speed = [[(0u16, 0u16); 2]; 3];
+    for (which_type, keypair) in SPEED_KEYS.iter().enumerate() {
+        for (incmx, key) in keypair.iter().enumerate() {
+            for (index, val) in values_after(tokens, key).iter().take(2).enumerate() {
+                let speed = bounded(line, val, MAX_SPEED, key)? as u16;
+                if incmx == 0 {
+                    cm_stride_mix_speed[which_type][index].0 = speed;
+                } else {
+                    cm_stride_mix_speed[which_type][index].1 = speed;
+                }
+            }
+        }
+    }
+    ret.set_context_map_speed(cm_stride_mix_speed[0]);
+    ret.set_stride_context_speed(cm_stride_mix_speed[1]);
+    ret.set_combined_stride_context_speed(cm_stride_mix_speed[2]);
+    Ok(Command::PredictionMode(ret))
+}
+
+fn parse_block_switch(line: &str, tokens: &[&str]) -> Result<Command<ItemVec<u8>>, SyntaxError> {
+    let cmd = tokens[0];
+    if tokens.len() != 2 && (tokens.len() != 3 || cmd != "ltype") {
+        return syntax_error(line, tokens.get(2).cloned().unwrap_or(end_of(line)),
+                            format!("{} needs 1 argument", cmd));
+    }
+    let block_type = bounded(line, tokens[1], 255, "block type")? as u8;
+    Ok(match cmd {
+        "ctype" => Command::BlockSwitchCommand(BlockSwitch::new(block_type)),
+        "dtype" => Command::BlockSwitchDistance(BlockSwitch::new(block_type)),
+        _ => {
+            let stride = match tokens.get(2) {
+                Some(token) => bounded(line, token, MAX_STRIDE, "stride")? as u8,
+                None => 0,
+            };
+            Command::BlockSwitchLiteral(LiteralBlockSwitch::new(block_type, stride))
+        },
+    })
+}
+
+fn parse_copy(line: &str, tokens: &[&str]) -> Result<Option<Command<ItemVec<u8>>>, SyntaxError> {
+    if tokens.len() < 4 {
+        return syntax_error(line, end_of(line), String::from("copy needs 3 arguments"));
+    }
+    let num_bytes = number::<u32>(line, tokens[1])?;
+    if tokens[2] != "from" {
+        return syntax_error(line, tokens[2], String::from("copy needs a from statement in the 2nd arg"));
+    }
+    let distance = number::<u32>(line, tokens[3])?;
+    if num_bytes == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Command::Copy(CopyCommand {
+        distance,
+        num_bytes,
+    })))
+}
+
+fn parse_dict(line: &str, tokens: &[&str]) -> Result<Command<ItemVec<u8>>, SyntaxError> {
+    if tokens.len() < 6 {
+        return syntax_error(line, end_of(line), String::from("dict needs 5+ arguments"));
+    }
+    let final_size = bounded(line, tokens[1], 255, "dict length")? as u8;
+    if tokens[2] != "word" {
+        return syntax_error(line, tokens[2], String::from("dict needs a word after the expected len"));
+    }
+    let mut word = tokens[3].splitn(2, ',');
+    let (word_size, word_id) = match (word.next(), word.next()) {
+        (Some(size), Some(id)) => (bounded(line, size, 255, "word size")? as u8, number::<u32>(line, id)?),
+        _ => return syntax_error(line, tokens[3], String::from("dict needs a comma separated word value")),
+    };
+    match tokens.iter().skip(4).position(|token| *token == "func") {
+        Some(index) if index + 5 < tokens.len() => Ok(Command::Dict(DictCommand {
+            word_size,
+            word_id,
+            empty: 0,
+            final_size,
+            transform: bounded(line, tokens[index + 5], 255, "transform")? as u8,
+        })),
+        _ => syntax_error(line, end_of(line), String::from("dict needs a func argument")),
+    }
+}
+
+fn parse_literal(line: &str, tokens: &[&str]) -> Result<Option<Command<ItemVec<u8>>>, SyntaxError> {
+    if tokens.len() < 3 {
+        if tokens.len() == 2 && tokens[1] == "0" {
+            return Ok(None);
+        }
+        return syntax_error(line, end_of(line), format!("{} needs 2 arguments", tokens[0]));
+    }
+    let expected_len = number::<usize>(line, tokens[1])?;
+    if expected_len == 0 {
+        return Ok(None);
+    }
+    let mut probs = Vec::<u8>::new();
+    let data = if tokens[2].starts_with('"') {
+        // quoted strings may contain spaces, so they run to the end of the line
+        let start = tokens[2].as_ptr() as usize - line.as_ptr() as usize;
+        let quoted = line[start..].trim_end();
+        match quoted_to_vec(quoted.as_bytes()) {
+            Ok(data) => data,
+            Err(offset) => return syntax_error(line, &quoted[offset..], String::from("invalid quoted string")),
+        }
+    } else {
+        if let Some(prob_token) = tokens.get(3) {
+            probs = hex_to_vec(line, prob_token)?;
+            if probs.len() != expected_len * 8 {
+                return syntax_error(line, prob_token,
+                                    format!("expected {} bytes of probabilities, found {}", expected_len * 8, probs.len()));
+            }
+        }
+        hex_to_vec(line, tokens[2])?
+    };
+    if data.len() != expected_len {
+        return syntax_error(line, tokens[1], format!("length {} does not match the {} bytes given", expected_len, data.len()));
+    }
+    Ok(Some(Command::Literal(LiteralCommand {
+        data: ItemVec(data),
+        high_entropy: tokens[0] == "rndins",
+        prob: match external_probabilities(probs) {
+            Some(prob) => prob,
+            None => return syntax_error(line, tokens[3],
+                                        String::from("to parse external probabilities, compile with feature flag external-literal-probability")),
+        },
+    })))
+}
+
+fn parse_line(line: &str) -> Result<Line, SyntaxError> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.is_empty() {
+        return Ok(Line::Skip);
+    }
+    let cmd = match tokens[0] {
+        "window" => return Ok(Line::Header(parse_header(line)?)),
+        "prediction" => Some(parse_prediction(line, &tokens)?),
+        "ctype" | "ltype" | "dtype" => Some(parse_block_switch(line, &tokens)?),
+        "copy" => parse_copy(line, &tokens)?,
+        "dict" => Some(parse_dict(line, &tokens)?),
+        "insert" | "rndins" => parse_literal(line, &tokens)?,
+        _ => return syntax_error(line, tokens[0], format!("unknown command `{}`", tokens[0])),
+    };
+    Ok(match cmd {
+        Some(cmd) => Line::Command(cmd),
+        None => Line::Skip,
+    })
+}
+
+/// Parses a single IR line. Empty commands and `window` lines yield `None`.
+pub fn parse_command(line: &str) -> Result<Option<Command<ItemVec<u8>>>, SyntaxError> {
+    match parse_line(line)? {
+        Line::Command(cmd) => Ok(Some(cmd)),
+        Line::Skip | Line::Header(_) => Ok(None),
+    }
+}
+
+/// Streams commands out of textual IR, one line at a time.
+pub struct Parser<R: BufRead> {
+    reader: R,
+    buffer: String,
+    line_number: usize,
+    header: Option<Header>,
+}
+
+impl<R: BufRead> Parser<R> {
+    pub fn new(reader: R) -> Self {
+        Parser {
+            reader,
+            buffer: String::new(),
+            line_number: 0,
+            header: None,
+        }
+    }
+    /// The number of lines consumed so far.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+    /// Returns the `window` header, reading it from the stream if no command has been read yet.
+    pub fn header(&mut self) -> io::Result<Header> {
+        while self.header.is_none() {
+            if !self.read_line()? {
+                return Err(SyntaxError {
+                    line: self.line_number + 1,
+                    column: 1,
+                    message: String::from("missing window header"),
+                }.into());
+            }
+            if self.buffer.trim().is_empty() {
+                continue;
+            }
+            match parse_header(&self.buffer) {
+                Ok(header) => self.header = Some(header),
+                Err(err) => return Err(self.locate(err)),
+            }
+        }
+        Ok(self.header.unwrap())
+    }
+    /// Returns the next command, or `None` once the stream is exhausted.
+    pub fn next_command(&mut self) -> io::Result<Option<Command<ItemVec<u8>>>> {
+        loop {
+            if !self.read_line()? {
+                return Ok(None);
+            }
+            match parse_line(&self.buffer) {
+                Ok(Line::Command(cmd)) => return Ok(Some(cmd)),
+                Ok(Line::Header(header)) => if self.header.is_none() {
+                    self.header = Some(header);
+                },
+                Ok(Line::Skip) => {},
+                Err(err) => return Err(self.locate(err)),
+            }
+        }
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+    fn locate(&self, mut err: SyntaxError) -> io::Error {
+        err.line = self.line_number;
+        err.into()
+    }
+    fn read_line(&mut self) -> io::Result<bool> {
+        self.buffer.clear();
+        loop {
+            match self.reader.read_line(&mut self.buffer) {
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                },
+                Ok(count) => {
+                    if count == 0 {
+                        return Ok(false);
+                    }
+                    self.line_number += 1;
+                    return Ok(true);
+                },
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Parser<R> {
+    type Item = io::Result<Command<ItemVec<u8>>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_command() {
+            Ok(Some(cmd)) => Some(Ok(cmd)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+struct HexSlice<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for HexSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_header<W: Write>(w: &mut W, header: &Header) -> io::Result<()> {
+    match header.len {
+        Some(len) => writeln!(w, "window {} len {}", header.window_size, len),
+        None => writeln!(w, "window {}", header.window_size),
+    }
+}
+
+fn write_dict<W: Write>(w: &mut W, dict: &DictCommand) -> io::Result<()> {
+    let word_size = dict.word_size as usize;
+    if word_size >= kBrotliDictionarySizeBitsByLength.len()
+        || kBrotliDictionarySizeBitsByLength[word_size] == 0
+        || dict.word_id >= 1u32 << kBrotliDictionarySizeBitsByLength[word_size]
+        || dict.transform >= NUM_DICT_TRANSFORMS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "dictionary reference out of range"));
+    }
+    let offset = kBrotliDictionaryOffsetsByLength[word_size] as usize + dict.word_id as usize * word_size;
+    let word = &kBrotliDictionary[offset..offset + word_size];
+    let mut transformed_word = [0u8; kBrotliMaxDictionaryWordLength as usize + 13];
+    let transformed_len = TransformDictionaryWord(&mut transformed_word[..],
+                                                  word,
+                                                  word_size as i32,
+                                                  i32::from(dict.transform)) as usize;
+    writeln!(w, "dict {} word {},{} {} func {} {}",
+             dict.final_size,
+             dict.word_size,
+             dict.word_id,
+             HexSlice(word),
+             dict.transform,
+             HexSlice(&transformed_word[..transformed_len]))
+}
+
+fn write_prediction<W: Write, SliceType: SliceWrapper<u8>>(w: &mut W,
+                                                            pm: &PredictionModeContextMap<SliceType>) -> io::Result<()> {
+    let mode = match pm.literal_prediction_mode().prediction_mode() {
+        LITERAL_PREDICTION_MODE_SIGN => "sign",
+        LITERAL_PREDICTION_MODE_UTF8 => "utf8",
+        LITERAL_PREDICTION_MODE_MSB6 => "msb6",
+        LITERAL_PREDICTION_MODE_LSB6 => "lsb6",
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown literal prediction mode")),
+    };
+    write!(w, "prediction {} lcontextmap", mode)?;
+    for val in pm.literal_context_map.slice() {
+        write!(w, " {}", val)?;
+    }
+    if pm.has_context_speeds() {
+        write!(w, " dcontextmap")?;
+        for val in pm.distance_context_map() {
+            write!(w, " {}", val)?;
+        }
+        // the parser fills in zeros for any trailing mixing values left out
+        let mixing_values = pm.get_mixing_values();
+        let used = mixing_values.iter().rposition(|val| *val != 0).map_or(0, |last| last + 1);
+        write!(w, " mixingvalues")?;
+        for val in &mixing_values[..used] {
+            write!(w, " {}", val)?;
+        }
+        if pm.get_mixing_math() != 0 {
+            write!(w, " mixingmath {}", pm.get_mixing_math())?;
+        }
+        if pm.get_is_adv_context_map() != 0 {
+            write!(w, " advcontextmap {}", pm.get_is_adv_context_map())?;
+        }
+        let speeds = [pm.context_map_speed(), pm.stride_context_speed(), pm.combined_stride_context_speed()];
+        for (keypair, speed) in SPEED_KEYS.iter().zip(speeds.iter()) {
+            write!(w, " {} {} {} {} {} {}",
+                   keypair[0], speed[0].0, speed[1].0,
+                   keypair[1], speed[0].1, speed[1].1)?;
+        }
+    }
+    writeln!(w)
+}
+
+/// Writes one command as a line of IR that `parse_command` turns back into the same command.
+pub fn write_command<W: Write, SliceType: SliceWrapper<u8>>(w: &mut W, cmd: &Command<SliceType>) -> io::Result<()> {
+    match *cmd {
+        Command::Copy(ref copy) => writeln!(w, "copy {} from {}", copy.num_bytes, copy.distance),
+        Command::Dict(ref dict) => write_dict(w, dict),
+        Command::Literal(ref lit) => {
+            write!(w, "{} {} {}",
+                   if lit.high_entropy {"rndins"} else {"insert"},
+                   lit.data.slice().len(),
+                   HexSlice(lit.data.slice()))?;
+            if !lit.prob.slice().is_empty() {
+                write!(w, " {}", HexSlice(lit.prob.slice()))?;
+            }
+            writeln!(w)
+        },
+        Command::BlockSwitchCommand(ref bs) => writeln!(w, "ctype {}", bs.block_type()),
+        Command::BlockSwitchLiteral(ref bs) => writeln!(w, "ltype {} {}", bs.block_type(), bs.stride()),
+        Command::BlockSwitchDistance(ref bs) => writeln!(w, "dtype {}", bs.block_type()),
+        Command::PredictionMode(ref pm) => write_prediction(w, pm),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::string::String;
+    use std::vec::Vec;
+    use alloc::SliceWrapper;
+    use interface::Command;
+    use super::{Header, Parser, parse_command, write_command, write_header};
+    use super::super::ItemVec;
+
+    fn serialize(header: &Header, commands: &[Command<ItemVec<u8>>]) -> Vec<u8> {
+        let mut output = Vec::<u8>::new();
+        write_header(&mut output, header).unwrap();
+        for cmd in commands.iter() {
+            write_command(&mut output, cmd).unwrap();
+        }
+        output
+    }
+
+    fn parse(input: &[u8]) -> (Header, Vec<Command<ItemVec<u8>>>) {
+        let mut parser = Parser::new(input);
+        let header = parser.header().unwrap();
+        let commands = parser.collect::<io::Result<Vec<_>>>().unwrap();
+        (header, commands)
+    }
+
+    fn output_len(commands: &[Command<ItemVec<u8>>]) -> usize {
+        commands.iter().map(|cmd| match *cmd {
+            Command::Copy(ref copy) => copy.num_bytes as usize,
+            Command::Dict(ref dict) => dict.final_size as usize,
+            Command::Literal(ref lit) => lit.data.slice().len(),
+            _ => 0,
+        }).sum()
+    }
+
+    #[test]
+    fn test_round_trip_all_commands() {
+        let input = b"window 18 len 23\n\
+                      prediction sign lcontextmap 0 1 2 3 dcontextmap 3 2 mixingvalues 0 1 8 mixingmath 2 cmspeedinc 8 16 cmspeedmax 1024 2048 stspeedinc 4 4 stspeedmax 512 512 mxspeedinc 2 2 mxspeedmax 256 8192\n\
+                      ctype 2\n\
+                      dtype 1\n\
+                      ltype 3 2\n\
+                      insert 4 446f776e\n\
+                      \n\
+                      rndins 3 \"a\\x62\\n\"\n\
+                      copy 8 from 4 ctx 3\n\
+                      insert 0 \n\
+                      dict 5 word 5,70 626567696e func 0 626567696e ctx 3\n\
+                      copy 3 from 1\n";
+        let (header, commands) = parse(&input[..]);
+        assert_eq!(header, Header{window_size: 18, len: Some(23)});
+        assert_eq!(commands.len(), 9);
+        assert_eq!(output_len(&commands), 23);
+        match commands[5] {
+            Command::Literal(ref lit) => {
+                assert!(lit.high_entropy);
+                assert_eq!(lit.data.slice(), b"ab\n");
+            },
+            _ => panic!("expected a literal"),
+        }
+        let serialized = serialize(&header, &commands);
+        let (reheader, recommands) = parse(&serialized[..]);
+        assert_eq!(reheader, header);
+        assert_eq!(serialize(&reheader, &recommands), serialized);
+        let text = String::from_utf8(serialized).unwrap();
+        assert!(text.contains("\nrndins 3 61620a\n"));
+        assert!(text.contains("\ndict 5 word 5,70 626567696e func 0 626567696e\n"));
+        assert!(text.contains(" mixingvalues 0 1 8 mixingmath 2 cmspeedinc 8 16 cmspeedmax 1024 2048"));
+    }
+
+    #[test]
+    fn test_round_trip_alice() {
+        let input = include_bytes!("../../testdata/alice29.ir");
+        let (header, commands) = parse(&input[..]);
+        assert_eq!(header.len, Some(152089));
+        assert_eq!(output_len(&commands), 152089);
+        let serialized = serialize(&header, &commands);
+        let (reheader, recommands) = parse(&serialized[..]);
+        assert_eq!(recommands.len(), commands.len());
+        assert_eq!(serialize(&reheader, &recommands), serialized);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let input = b"window 22\ninsert 2 4142\ncopy 4 form 3\n";
+        let mut parser = Parser::new(&input[..]);
+        assert!(parser.next_command().unwrap().is_some());
+        let err = parser.next_command().unwrap_err();
+        let syntax = err.get_ref().unwrap().downcast_ref::<super::SyntaxError>().unwrap();
+        assert_eq!((syntax.line, syntax.column), (3, 8));
+
+        assert_eq!(parse_command("insert 3 41424").unwrap_err().column, 10);
+        assert_eq!(parse_command("insert 3 41424g").unwrap_err().column, 15);
+        assert_eq!(parse_command("ltype 1 9").unwrap_err().column, 9);
+        assert_eq!(parse_command("bogus 1").unwrap_err().column, 1);
+        assert_eq!(parse_command("dict 4 word 4,464 0d0a0d0a").unwrap_err().column, 27);
+        assert!(parse_command("copy 0 from 1").unwrap().is_none());
+
+        let mut parser = Parser::new(&b"\ncopy 1 from 1\n"[..]);
+        let err = parser.header().unwrap_err();
+        let syntax = err.get_ref().unwrap().downcast_ref::<super::SyntaxError>().unwrap();
+        assert_eq!((syntax.line, syntax.column), (2, 1));
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Semantic checks on IR that parses fine but that the codec would reject or decode wrongly.
+use std::fmt;
+use std::io::{self, BufRead};
+use std::string::String;
+use std::vec::Vec;
+use alloc::SliceWrapper;
+use brotli::dictionary::{kBrotliDictionary,
+                         kBrotliDictionaryOffsetsByLength,
+                         kBrotliDictionarySizeBitsByLength,
+                         kBrotliMaxDictionaryWordLength};
+use brotli::transform::TransformDictionaryWord;
+use brotli::enc::interface::{DISTANCE_CONTEXT_MAP_OFFSET,
+                             MAX_LITERAL_CONTEXT_MAP_SIZE,
+                             MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE,
+                             PREDMODE_OFFSET};
+use interface::{Command, CopyCommand, DictCommand, LiteralCommand, PredictionModeContextMap};
+use super::{Header, Reader};
+
+const NUM_DICT_TRANSFORMS: u8 = 121;
+const MAX_STRIDE: u8 = 8;
+const LITERAL_CONTEXTS_PER_BLOCK_TYPE: usize = 64;
+const DISTANCE_CONTEXTS_PER_BLOCK_TYPE: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub command: Option<usize>, // 0-based index of the offending command, None for the header and stream length
+    pub position: u64, // bytes of output produced before the command
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.command {
+            Some(index) => write!(f, "command {} at byte {}: {}", index, self.position, self.message),
+            None => write!(f, "stream at byte {}: {}", self.position, self.message),
+        }
+    }
+}
+
+/// Walks a command sequence keeping track of the output position and context map sizes
+/// the decoder would see, and records every command it could not reproduce.
+pub struct Validator {
+    header: Header,
+    index: usize,
+    position: u64,
+    literal_types: Option<usize>,
+    distance_types: Option<usize>,
+    violations: Vec<Violation>,
+}
+
+impl Validator {
+    pub fn new(header: &Header) -> Self {
+        let mut ret = Validator {
+            header: *header,
+            index: 0,
+            position: 0,
+            literal_types: None,
+            distance_types: None,
+            violations: Vec::new(),
+        };
+        if header.window_size < 10 || header.window_size > 24 {
+            ret.report(None, format!("window size {} is outside 10..=24", header.window_size));
+        }
+        ret
+    }
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+    pub fn commands_checked(&self) -> usize {
+        self.index
+    }
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations[..]
+    }
+    fn report(&mut self, command: Option<usize>, message: String) {
+        self.violations.push(Violation {
+            command,
+            position: self.position,
+            message,
+        });
+    }
+    fn max_distance(&self) -> u64 {
+        if self.header.window_size < 10 || self.header.window_size > 24 {
+            return u64::MAX;
+        }
+        (1u64 << self.header.window_size) - 16
+    }
+    fn check_copy(&mut self, copy: &CopyCommand) -> Option<String> {
+        let distance = u64::from(copy.distance);
+        if distance == 0 {
+            Some(String::from("copy distance 0 is not allowed"))
+        } else if distance > self.position {
+            Some(format!("copy distance {} reaches before the start of the stream", distance))
+        } else if distance > self.max_distance() {
+            Some(format!("copy distance {} exceeds the window's maximum distance of {}", distance, self.max_distance()))
+        } else {
+            None
+        }
+    }
+    // returns the number of bytes the dictionary word expands to
+    fn check_dict(&mut self, dict: &DictCommand) -> Result<u64, String> {
+        let word_size = dict.word_size as usize;
+        if word_size >= kBrotliDictionarySizeBitsByLength.len() || kBrotliDictionarySizeBitsByLength[word_size] == 0 {
+            return Err(format!("dictionary has no words of length {}", word_size));
+        }
+        let num_words = 1u32 << kBrotliDictionarySizeBitsByLength[word_size];
+        if dict.word_id >= num_words {
+            return Err(format!("dictionary word {} is past the {} words of length {}", dict.word_id, num_words, word_size));
+        }
+        if dict.transform >= NUM_DICT_TRANSFORMS {
+            return Err(format!("dictionary transform {} is undefined", dict.transform));
+        }
+        let offset = kBrotliDictionaryOffsetsByLength[word_size] as usize + dict.word_id as usize * word_size;
+        let mut transformed_word = [0u8; kBrotliMaxDictionaryWordLength as usize + 13];
+        let transformed_len = TransformDictionaryWord(&mut transformed_word[..],
+                                                      &kBrotliDictionary[offset..offset + word_size],
+                                                      word_size as i32,
+                                                      i32::from(dict.transform)) as u64;
+        if dict.final_size != 0 && u64::from(dict.final_size) != transformed_len {
+            return Err(format!("dictionary word expands to {} bytes, not {}", transformed_len, dict.final_size));
+        }
+        Ok(transformed_len)
+    }
+    fn check_literal<SliceType: SliceWrapper<u8>>(&mut self, lit: &LiteralCommand<SliceType>) -> Option<String> {
+        let num_probs = lit.prob.slice().len();
+        if num_probs != 0 && num_probs != lit.data.slice().len() * 8 {
+            return Some(format!("literal has {} probabilities for {} bytes; expected 8 per byte",
+                                num_probs, lit.data.slice().len()));
+        }
+        None
+    }
+    fn check_prediction<SliceType: SliceWrapper<u8>>(&mut self, pm: &PredictionModeContextMap<SliceType>, problems: &mut Vec<String>) {
+        let predmode = pm.predmode_speed_and_distance_context_map.slice();
+        if predmode.len() < DISTANCE_CONTEXT_MAP_OFFSET {
+            problems.push(format!("prediction mode has {} setup bytes; needs at least {}",
+                                  predmode.len(), DISTANCE_CONTEXT_MAP_OFFSET));
+            return;
+        }
+        if predmode[PREDMODE_OFFSET] > 3 {
+            problems.push(format!("literal prediction mode {} is undefined", predmode[PREDMODE_OFFSET]));
+        }
+        if pm.get_is_adv_context_map() > 1 {
+            problems.push(format!("advanced context map flag {} is not 0 or 1", pm.get_is_adv_context_map()));
+        }
+        let literal_len = pm.literal_context_map.slice().len();
+        if literal_len > MAX_LITERAL_CONTEXT_MAP_SIZE {
+            problems.push(format!("literal context map has {} entries; at most {} fit", literal_len, MAX_LITERAL_CONTEXT_MAP_SIZE));
+        } else if literal_len % LITERAL_CONTEXTS_PER_BLOCK_TYPE != 0 {
+            problems.push(format!("literal context map has {} entries, which is not a multiple of {}",
+                                  literal_len, LITERAL_CONTEXTS_PER_BLOCK_TYPE));
+        }
+        let distance_len = pm.distance_context_map().len();
+        if predmode.len() > MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE {
+            problems.push(format!("distance context map has {} entries; at most {} fit", distance_len,
+                                  MAX_PREDMODE_SPEED_AND_DISTANCE_CONTEXT_MAP_SIZE - DISTANCE_CONTEXT_MAP_OFFSET));
+        } else if distance_len % DISTANCE_CONTEXTS_PER_BLOCK_TYPE != 0 {
+            problems.push(format!("distance context map has {} entries, which is not a multiple of {}",
+                                  distance_len, DISTANCE_CONTEXTS_PER_BLOCK_TYPE));
+        }
+        // an empty map leaves every block type on the default contexts
+        self.literal_types = if literal_len == 0 { None } else {
+            Some(literal_len.div_ceil(LITERAL_CONTEXTS_PER_BLOCK_TYPE))
+        };
+        self.distance_types = if distance_len == 0 { None } else {
+            Some(distance_len.div_ceil(DISTANCE_CONTEXTS_PER_BLOCK_TYPE))
+        };
+    }
+    fn check_block_type(name: &str, block_type: u8, num_types: Option<usize>) -> Option<String> {
+        match num_types {
+            Some(num_types) if usize::from(block_type) >= num_types => {
+                Some(format!("{} block type {} has no context map entry; the map covers {} types", name, block_type, num_types))
+            },
+            _ => None,
+        }
+    }
+    /// Checks one command and advances the simulated output position past it.
+    pub fn check_command<SliceType: SliceWrapper<u8>>(&mut self, cmd: &Command<SliceType>) {
+        let mut problems = Vec::<String>::new();
+        let mut advance = 0u64;
+        match *cmd {
+            Command::Copy(ref copy) => {
+                problems.extend(self.check_copy(copy));
+                advance = u64::from(copy.num_bytes);
+            },
+            Command::Dict(ref dict) => match self.check_dict(dict) {
+                Ok(len) => advance = len,
+                Err(problem) => {
+                    advance = u64::from(dict.final_size);
+                    problems.push(problem);
+                },
+            },
+            Command::Literal(ref lit) => {
+                problems.extend(self.check_literal(lit));
+                advance = lit.data.slice().len() as u64;
+            },
+            Command::PredictionMode(ref pm) => self.check_prediction(pm, &mut problems),
+            Command::BlockSwitchLiteral(ref bs) => {
+                problems.extend(Self::check_block_type("literal", bs.block_type(), self.literal_types));
+                if bs.stride() > MAX_STRIDE {
+                    problems.push(format!("stride {} is larger than {}", bs.stride(), MAX_STRIDE));
+                }
+            },
+            Command::BlockSwitchDistance(ref bs) => {
+                problems.extend(Self::check_block_type("distance", bs.block_type(), self.distance_types));
+            },
+            Command::BlockSwitchCommand(_) => {},
+        }
+        let index = self.index;
+        for problem in problems {
+            self.report(Some(index), problem);
+        }
+        self.position += advance;
+        self.index += 1;
+    }
+    /// Checks the stream length against the header and returns everything found.
+    pub fn finish(mut self) -> Vec<Violation> {
+        if let Some(len) = self.header.len {
+            if len != self.position {
+                let message = format!("header declares {} bytes but the commands produce {}", len, self.position);
+                self.report(None, message);
+            }
+        }
+        self.violations
+    }
+}
+
+// the readers describe malformed input with these kinds; anything else is an I/O failure
+fn parse_violation(err: io::Error, command: Option<usize>, position: u64) -> io::Result<Violation> {
+    match err.kind() {
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Ok(Violation {
+            command,
+            position,
+            message: format!("{}", err),
+        }),
+        _ => Err(err),
+    }
+}
+
+/// Validates IR of either format. A syntax error ends the walk and is reported as the last
+/// violation, with its line and column or binary offset; only I/O errors are returned as `Err`.
+pub fn validate<R: BufRead>(reader: R) -> io::Result<Vec<Violation>> {
+    let mut reader = Reader::new(reader)?;
+    let header = match reader.header() {
+        Ok(header) => header,
+        Err(e) => return Ok(vec![parse_violation(e, None, 0)?]),
+    };
+    let mut validator = Validator::new(&header);
+    loop {
+        match reader.next_command() {
+            Ok(Some(cmd)) => validator.check_command(&cmd),
+            Ok(None) => return Ok(validator.finish()),
+            Err(e) => {
+                let violation = parse_violation(e, Some(validator.index), validator.position)?;
+                validator.violations.push(violation);
+                return Ok(validator.violations);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+    use super::{validate, Violation};
+
+    fn violations(ir: &[u8]) -> Vec<(Option<usize>, u64)> {
+        validate(ir).unwrap().iter().map(|v: &Violation| (v.command, v.position)).collect()
+    }
+
+    #[test]
+    fn test_validate_clean() {
+        assert_eq!(validate(&include_bytes!("../../testdata/alice29.ir")[..]).unwrap(), Vec::<Violation>::new());
+        // this checked-in file's header undercounts its length
+        assert_eq!(violations(&include_bytes!("../../testdata/asyoulik.ir")[..]),
+                   [(None, 125179)]);
+    }
+
+    #[test]
+    fn test_validate_reports_every_violation() {
+        let ir = String::from("window 10 len 12\n") +
+            "insert 2 4142\n" +
+            "copy 2 from 0\n" +
+            "copy 2 from 5\n" +
+            "copy 4 from 2\n" +
+            "dict 4 word 4,1024 61626364 func 0 61626364\n" +
+            "dict 6 word 4,1 61626364 func 0 61626364\n" +
+            "prediction utf8 lcontextmap 0 1 2 dcontextmap 0 0 0 0\n" +
+            "ltype 1\n" +
+            "dtype 0\n" +
+            "dtype 1\n";
+        assert_eq!(violations(ir.as_bytes()),
+                   [(Some(1), 2), // distance 0
+                    (Some(2), 4), // before the start
+                    (Some(4), 10), // word index
+                    (Some(5), 14), // final size
+                    (Some(6), 20), // literal map not a multiple of 64
+                    (Some(7), 20), // ltype beyond the literal map
+                    (Some(9), 20), // dtype beyond the distance map
+                    (None, 20)]); // length mismatch
+        let messages: Vec<String> = validate(ir.as_bytes()).unwrap().iter().map(|v| v.to_string()).collect();
+        assert_eq!(messages[0], "command 1 at byte 2: copy distance 0 is not allowed");
+        assert_eq!(messages[7], "stream at byte 20: header declares 12 bytes but the commands produce 20");
+    }
+
+    #[test]
+    fn test_validate_syntax_error() {
+        let messages: Vec<String> = validate(&b"window 16\ninsert 2 4142\nltype 300\ncopy 1 from 1\n"[..]).unwrap()
+            .iter().map(|v| v.to_string()).collect();
+        assert_eq!(messages, ["command 1 at byte 2: line 3, column 7: block type must be between 0 and 255, not 300"]);
+        assert_eq!(violations(b"window x\n"), [(None, 0)]);
+    }
+
+    #[test]
+    fn test_validate_window() {
+        let ir = "window 10\ninsert 2000 ".to_string() + &"61".repeat(2000) + "\ncopy 4 from 1009\ncopy 4 from 1008\n";
+        assert_eq!(violations(ir.as_bytes()), [(Some(1), 2000)]);
+        assert_eq!(violations(b"window 30\n"), [(None, 0)]);
+    }
+}
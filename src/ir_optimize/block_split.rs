@@ -0,0 +1,415 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Literal block splitting and context clustering priced for adaptive coding.
+//
+// Brotli picks literal block types and the literal context map to suit static Huffman codes.
+// Here literals are priced under adaptive nibble models, tallied the way the actuary tallies
+// them: each block of literals takes whichever block type codes it cheapest, and the
+// (block type, context) slots of a metablock are then clustered greedily, each slot joining
+// the cluster it shares a model with most cheaply. A metablock keeps the new split only when
+// it prices lower than the one it came with.
+//
+// Only literals are re-split. Command and distance block switches from brotli pass through
+// unchanged, and the internal matcher emits none.
+use core;
+use brotli;
+use brotli::interface::{Command, LiteralBlockSwitch, StaticCommand};
+use alloc::{SliceWrapper, SliceWrapperMut, Allocator};
+use codec::{get_lut0, get_lut1};
+use probability::{CDF16, Speed};
+use interface::LiteralPredictionModeNibble;
+use super::statistics_tracking_codec::TallyingArithmeticEncoder;
+use super::optimal_parse::CommandBuffer;
+
+pub const MAX_LITERAL_TYPES: usize = 4;
+pub const NUM_LITERAL_CONTEXTS: usize = 64;
+// blocks end at the first literal command boundary past BLOCK_LITERALS and are priced
+// on at most their first BLOCK_SAMPLE literals
+const BLOCK_LITERALS: usize = 1024;
+const BLOCK_SAMPLE: usize = 2 * BLOCK_LITERALS;
+// roughly what coding a literal block switch costs, in bits
+const SWITCH_COST: f32 = 16.0;
+// the estimate leaves out the stride and mixing priors, so small gains are not trusted
+const MIN_GAIN: f32 = 0.01;
+// a slot is priced against a cluster on at most this many literals of each
+const CLUSTER_SAMPLE: usize = 2048;
+// and only tries the clusters seeded by the largest slots
+const CLUSTER_CANDIDATES: usize = 16;
+const MODEL_SPEED: Speed = Speed::MED;
+
+fn code_nibble<Cdf:CDF16>(tally: &mut TallyingArithmeticEncoder, cdf: &mut Cdf, nibble: u8) {
+    tally.tally(cdf.sym_to_start_and_freq(nibble).range);
+    cdf.blend(nibble, MODEL_SPEED);
+}
+
+// a literal with its 6 bit context in the high byte
+fn pack(byte: u8, context: u8) -> u16 {
+    (u16::from(context) << 8) | u16::from(byte)
+}
+
+#[derive(Clone, Copy)]
+struct TypeModel<Cdf:CDF16> {
+    high: [Cdf; NUM_LITERAL_CONTEXTS],
+    low: [Cdf; 16],
+}
+
+impl<Cdf:CDF16> TypeModel<Cdf> {
+    fn new() -> Self {
+        TypeModel {
+            high: [Cdf::default(); NUM_LITERAL_CONTEXTS],
+            low: [Cdf::default(); 16],
+        }
+    }
+    // the cost of literals under the model as it stands
+    fn price(&self, literals: &[u16]) -> f32 {
+        let mut tally = TallyingArithmeticEncoder::default();
+        for literal in literals.iter() {
+            let high = (*literal >> 4) as u8 & 0xf;
+            tally.tally(self.high[usize::from(*literal >> 8)].sym_to_start_and_freq(high).range);
+            tally.tally(self.low[usize::from(high)].sym_to_start_and_freq(*literal as u8 & 0xf).range);
+        }
+        tally.total_cost()
+    }
+    fn code(&mut self, literals: &[u16]) -> f32 {
+        let mut tally = TallyingArithmeticEncoder::default();
+        for literal in literals.iter() {
+            let high = (*literal >> 4) as u8 & 0xf;
+            code_nibble(&mut tally, &mut self.high[usize::from(*literal >> 8)], high);
+            code_nibble(&mut tally, &mut self.low[usize::from(high)], *literal as u8 & 0xf);
+        }
+        tally.total_cost()
+    }
+}
+
+/// Assigns blocks of literals to block types by their adaptive coding cost.
+pub struct LiteralSplitter<Cdf:CDF16> {
+    models: [TypeModel<Cdf>; MAX_LITERAL_TYPES],
+    num_types: usize,
+    current: u8,
+    lut0: [u8; 256],
+    lut1: [u8; 256],
+    block: [u16; BLOCK_SAMPLE],
+    sampled: usize,
+    block_literals: usize,
+}
+
+impl<Cdf:CDF16> LiteralSplitter<Cdf> {
+    pub fn new(mode: LiteralPredictionModeNibble) -> Self {
+        LiteralSplitter {
+            models: [TypeModel::new(); MAX_LITERAL_TYPES],
+            num_types: 0,
+            current: 0,
+            lut0: get_lut0(mode),
+            lut1: get_lut1(mode),
+            block: [0; BLOCK_SAMPLE],
+            sampled: 0,
+            block_literals: 0,
+        }
+    }
+    pub fn context(&self, prev_byte: u8, prev_prev_byte: u8) -> u8 {
+        self.lut0[usize::from(prev_byte)] | self.lut1[usize::from(prev_prev_byte)]
+    }
+    pub fn num_types(&self) -> usize {
+        self.num_types
+    }
+    pub fn observe(&mut self, byte: u8, context: u8) {
+        if self.sampled < BLOCK_SAMPLE {
+            self.block[self.sampled] = pack(byte, context);
+            self.sampled += 1;
+        }
+        self.block_literals += 1;
+    }
+    pub fn block_full(&self) -> bool {
+        self.block_literals >= BLOCK_LITERALS
+    }
+    /// Picks the block type for the literals observed since the last call and trains it on them.
+    pub fn decide(&mut self) -> u8 {
+        let block = &self.block[..self.sampled];
+        let mut best = (f32::MAX, self.current as usize);
+        for (index, model) in self.models[..self.num_types].iter().enumerate() {
+            let switch = if index == self.current as usize { 0.0 } else { SWITCH_COST };
+            let cost = model.price(block) + switch;
+            if cost < best.0 {
+                best = (cost, index);
+            }
+        }
+        if self.num_types < MAX_LITERAL_TYPES {
+            let mut fresh = TypeModel::<Cdf>::new();
+            let cost = fresh.code(block) + if self.num_types == 0 { 0.0 } else { SWITCH_COST };
+            if cost < best.0 {
+                self.models[self.num_types] = fresh;
+                self.num_types += 1;
+                best = (cost, self.num_types - 1);
+            } else {
+                self.models[best.1].code(block);
+            }
+        } else {
+            self.models[best.1].code(block);
+        }
+        self.sampled = 0;
+        self.block_literals = 0;
+        self.current = best.1 as u8;
+        self.current
+    }
+}
+
+fn replay<Cdf:CDF16>(bytes: &[u8], indices: &[u32]) -> f32 {
+    let mut high = Cdf::default();
+    let mut low = [Cdf::default(); 16];
+    let mut tally = TallyingArithmeticEncoder::default();
+    for index in indices.iter() {
+        let byte = bytes[*index as usize];
+        code_nibble(&mut tally, &mut high, byte >> 4);
+        code_nibble(&mut tally, &mut low[usize::from(byte >> 4)], byte & 0xf);
+    }
+    tally.total_cost()
+}
+
+// the cost of two clusters sharing one model, coded in stream order
+fn replay_merged<Cdf:CDF16>(bytes: &[u8], a: &[u32], b: &[u32]) -> f32 {
+    let mut high = Cdf::default();
+    let mut low = [Cdf::default(); 16];
+    let mut tally = TallyingArithmeticEncoder::default();
+    let (mut ia, mut ib) = (0usize, 0usize);
+    while ia < a.len() || ib < b.len() {
+        let index = if ib == b.len() || (ia < a.len() && a[ia] < b[ib]) {
+            ia += 1;
+            a[ia - 1]
+        } else {
+            ib += 1;
+            b[ib - 1]
+        };
+        let byte = bytes[index as usize];
+        code_nibble(&mut tally, &mut high, byte >> 4);
+        code_nibble(&mut tally, &mut low[usize::from(byte >> 4)], byte & 0xf);
+    }
+    tally.total_cost()
+}
+
+// groups literal indices by label, returning where each label's run ends
+fn sort_by_label(labels: &[u8], sorted: &mut [u32]) -> [usize; 256] {
+    let mut ends = [0usize; 256];
+    for label in labels.iter() {
+        ends[usize::from(*label)] += 1;
+    }
+    let mut total = 0usize;
+    for end in ends.iter_mut() {
+        total += *end;
+        *end = total;
+    }
+    let mut next = [0usize; 256];
+    next[1..].clone_from_slice(&ends[..255]);
+    for (index, label) in labels.iter().enumerate() {
+        sorted[next[usize::from(*label)]] = index as u32;
+        next[usize::from(*label)] += 1;
+    }
+    ends
+}
+
+fn label_range(ends: &[usize; 256], label: usize) -> (usize, usize) {
+    (if label == 0 { 0 } else { ends[label - 1] }, ends[label])
+}
+
+fn plan_cost<Cdf:CDF16>(bytes: &[u8], labels: &[u8], sorted: &mut [u32]) -> f32 {
+    let ends = sort_by_label(labels, sorted);
+    let mut total = 0.0;
+    for label in 0..256 {
+        let (start, end) = label_range(&ends, label);
+        if start != end {
+            total += replay::<Cdf>(bytes, &sorted[start..end]);
+        }
+    }
+    total
+}
+
+// maps every (block type, context) slot to a cluster
+fn cluster_slots<Cdf:CDF16>(bytes: &[u8], slots: &[u8], sorted: &mut [u32], num_types: usize) -> [u8; 256] {
+    let ends = sort_by_label(slots, sorted);
+    let sample = |slot: usize| {
+        let (start, end) = label_range(&ends, slot);
+        &sorted[start..core::cmp::min(end, start + CLUSTER_SAMPLE)]
+    };
+    let mut by_size = [0u8; 256];
+    for (index, item) in by_size.iter_mut().enumerate() {
+        *item = index as u8;
+    }
+    by_size.sort_by_key(|slot| {
+        let (start, end) = label_range(&ends, usize::from(*slot));
+        core::cmp::Reverse(end - start)
+    });
+    let mut cluster_of = [0u8; 256];
+    let mut seeds = [0u8; 256];
+    let mut seed_cost = [0.0f32; 256];
+    let mut num_clusters = 0usize;
+    for slot in by_size.iter() {
+        let slot = usize::from(*slot);
+        if sample(slot).is_empty() {
+            break;
+        }
+        let alone = replay::<Cdf>(bytes, sample(slot));
+        let mut best = (0.0f32, num_clusters);
+        for cluster in 0..core::cmp::min(num_clusters, CLUSTER_CANDIDATES) {
+            let seed = usize::from(seeds[cluster]);
+            let gain = alone + seed_cost[cluster] - replay_merged::<Cdf>(bytes, sample(seed), sample(slot));
+            if gain > best.0 {
+                best = (gain, cluster);
+            }
+        }
+        if best.1 == num_clusters {
+            seeds[num_clusters] = slot as u8;
+            seed_cost[num_clusters] = alone;
+            num_clusters += 1;
+        }
+        cluster_of[slot] = best.1 as u8;
+    }
+    // unused slots follow the same context of the first block type
+    for slot in NUM_LITERAL_CONTEXTS..num_types * NUM_LITERAL_CONTEXTS {
+        if sample(slot).is_empty() {
+            cluster_of[slot] = cluster_of[slot % NUM_LITERAL_CONTEXTS];
+        }
+    }
+    cluster_of
+}
+
+fn byte_at(mb: &brotli::InputPair, pos: usize) -> u8 {
+    if pos < mb.0.len() {
+        mb.0.data[pos]
+    } else {
+        mb.1.data[pos - mb.0.len()]
+    }
+}
+
+/// Re-splits the literals of a metablock into block types and clusters their contexts.
+///
+/// The commands are written to `out` with the brotli literal block switches replaced and
+/// `context_map` is filled with 64 entries per block type. Returns the number of commands
+/// written, or None unless the new split prices clearly lower than brotli's.
+pub fn split_metablock<Cdf:CDF16,
+                       Alloc:Allocator<StaticCommand> + Allocator<u32> + Allocator<u8>>(
+    commands: &[StaticCommand],
+    mb: brotli::InputPair,
+    mode: LiteralPredictionModeNibble,
+    brotli_context_map: &[u8],
+    mc: &mut Alloc,
+    out: &mut <Alloc as Allocator<StaticCommand>>::AllocatedMemory,
+    context_map: &mut <Alloc as Allocator<u8>>::AllocatedMemory) -> Option<usize> {
+    let mut num_literals = 0usize;
+    for cmd in commands.iter() {
+        if let Command::Literal(ref lit) = *cmd {
+            if lit.data.offset() + lit.data.len() > mb.len() {
+                return None;
+            }
+            num_literals += lit.data.len();
+        }
+    }
+    if num_literals == 0 {
+        return None;
+    }
+    let mut bytes = <Alloc as Allocator<u8>>::alloc_cell(mc, num_literals);
+    let mut slots = <Alloc as Allocator<u8>>::alloc_cell(mc, num_literals);
+    let mut brotli_labels = <Alloc as Allocator<u8>>::alloc_cell(mc, num_literals);
+    let mut sorted = <Alloc as Allocator<u32>>::alloc_cell(mc, num_literals);
+    let mut splitter = LiteralSplitter::<Cdf>::new(mode);
+    let mut brotli_type = 0usize;
+    let mut brotli_switches = 0usize;
+    let mut block_start = 0usize;
+    let mut index = 0usize;
+    for cmd in commands.iter() {
+        match *cmd {
+            Command::BlockSwitchLiteral(ref bs) => {
+                brotli_type = usize::from(bs.block_type());
+                brotli_switches += 1;
+            },
+            Command::Literal(ref lit) => {
+                for pos in lit.data.offset()..(lit.data.offset() + lit.data.len()) {
+                    let context = splitter.context(if pos >= 1 { byte_at(&mb, pos - 1) } else { 0 },
+                                                   if pos >= 2 { byte_at(&mb, pos - 2) } else { 0 });
+                    let byte = byte_at(&mb, pos);
+                    splitter.observe(byte, context);
+                    bytes.slice_mut()[index] = byte;
+                    slots.slice_mut()[index] = context;
+                    brotli_labels.slice_mut()[index] = *brotli_context_map.get(brotli_type * NUM_LITERAL_CONTEXTS + usize::from(context))
+                        .unwrap_or(&0);
+                    index += 1;
+                }
+                if splitter.block_full() || index == num_literals {
+                    let btype = splitter.decide();
+                    for slot in slots.slice_mut()[block_start..index].iter_mut() {
+                        *slot |= btype << 6;
+                    }
+                    block_start = index;
+                }
+            },
+            _ => {},
+        }
+    }
+    let num_types = splitter.num_types();
+    let cluster_of = cluster_slots::<Cdf>(bytes.slice(), slots.slice(), sorted.slice_mut(), num_types);
+    let mut num_switches = 0usize;
+    let mut last_type = 0xffu8;
+    // brotli's labels are no longer needed once priced, so they are replaced by the clusters
+    let brotli_cost = plan_cost::<Cdf>(bytes.slice(), brotli_labels.slice(), sorted.slice_mut()) + brotli_switches as f32 * SWITCH_COST;
+    for (label, slot) in brotli_labels.slice_mut().iter_mut().zip(slots.slice().iter()) {
+        if *slot >> 6 != last_type {
+            last_type = *slot >> 6;
+            num_switches += 1;
+        }
+        *label = cluster_of[usize::from(*slot)];
+    }
+    let split_cost = plan_cost::<Cdf>(bytes.slice(), brotli_labels.slice(), sorted.slice_mut()) + num_switches as f32 * SWITCH_COST;
+    let mut ret = None;
+    if split_cost < brotli_cost * (1.0 - MIN_GAIN) {
+        let mut map = <Alloc as Allocator<u8>>::alloc_cell(mc, num_types * NUM_LITERAL_CONTEXTS);
+        map.slice_mut().clone_from_slice(&cluster_of[..num_types * NUM_LITERAL_CONTEXTS]);
+        <Alloc as Allocator<u8>>::free_cell(mc, core::mem::replace(context_map, map));
+        let mut buf = <Alloc as Allocator<StaticCommand>>::AllocatedMemory::default();
+        let len = {
+            let mut out_buf = CommandBuffer::<Alloc> {
+                mc,
+                buf: &mut buf,
+                len: 0,
+            };
+            let mut stride = 0u8;
+            let mut emitted: Option<(u8, u8)> = None;
+            let mut literal_index = 0usize;
+            for cmd in commands.iter() {
+                match *cmd {
+                    Command::BlockSwitchLiteral(ref bs) => {
+                        stride = bs.stride();
+                        continue;
+                    },
+                    Command::Literal(ref lit) if lit.data.len() != 0 => {
+                        let btype = slots.slice()[literal_index] >> 6;
+                        if emitted != Some((btype, stride)) {
+                            out_buf.push(Command::BlockSwitchLiteral(LiteralBlockSwitch::new(btype, stride)));
+                            emitted = Some((btype, stride));
+                        }
+                        literal_index += lit.data.len();
+                    },
+                    _ => {},
+                }
+                out_buf.push(*cmd);
+            }
+            out_buf.len
+        };
+        <Alloc as Allocator<StaticCommand>>::free_cell(mc, core::mem::replace(out, buf));
+        ret = Some(len);
+    }
+    <Alloc as Allocator<u8>>::free_cell(mc, bytes);
+    <Alloc as Allocator<u8>>::free_cell(mc, slots);
+    <Alloc as Allocator<u8>>::free_cell(mc, brotli_labels);
+    <Alloc as Allocator<u32>>::free_cell(mc, sorted);
+    ret
+}
@@ -1,18 +1,22 @@
 use brotli;
 use core;
 use codec;
-use super::mux::{Mux,DevNull};
+use super::mux::DevNull;
 use super::probability::CDF16;
 use codec::io::DemuxerAndRingBuffer;
 pub use super::cmd_to_divans::EncoderSpecialization;
 use brotli::interface::{Command, LiteralCommand, CopyCommand, Nop, PredictionModeContextMap, StaticCommand};
 use alloc_util;
 use alloc::{SliceWrapper, Allocator};
-pub use super::interface::{ArithmeticEncoderOrDecoder, NewWithAllocator, DivansResult, ErrMsg};
-mod statistics_tracking_codec;
+pub use super::interface::{ArithmeticEncoderOrDecoder, NewWithAllocator, DivansResult, ErrMsg, StreamMuxer};
+pub mod statistics_tracking_codec;
 mod cache;
+mod optimal_parse;
+pub mod block_split;
 use self::statistics_tracking_codec::{TallyingArithmeticEncoder, OneCommandThawingArray, TwoCommandThawingArray, ToggleProbabilityBlend,
-                                      take_billing_snapshot, billing_snapshot_delta,reset_billing_snapshot};
+                                      take_billing_snapshot, billing_snapshot_delta,reset_billing_snapshot,
+                                      total_billing_cost};
+use self::optimal_parse::Actuary;
 pub fn should_merge<SelectedCDF:CDF16,
                     AllocU8:Allocator<u8>,
                     AllocCDF16:Allocator<SelectedCDF>>(lit: &LiteralCommand<brotli::SliceOffset>,
@@ -109,84 +113,69 @@ pub fn should_merge<SelectedCDF:CDF16,
               copy_index, full_cost, copy.distance, copy.num_bytes, code, future_hit, cur_cost, combined_cost);*/
     Ok(combined_cost < cur_cost)
 }
-pub fn ir_optimize<'a, SelectedCDF:CDF16,
-                   ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
-                   AllocU8:Allocator<u8>,
-                   AllocCDF16:Allocator<SelectedCDF>,
-                   AllocCommand: Allocator<StaticCommand>
-                   >(pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
-                     orig_buf:&'a mut [brotli::interface::Command<brotli::SliceOffset>],
-                     mb:brotli::InputPair,
-                     codec:&mut codec::DivansCodec<ChosenEncoder,
-                                                   EncoderSpecialization,
-                                                   DemuxerAndRingBuffer<AllocU8,
-                                                                        DevNull<AllocU8>>,
-                                                   Mux<AllocU8>,
-                                                   SelectedCDF,
-                                                   AllocU8,
-                                                   AllocCDF16>,
-                     window_size: u8,
-                     opt: super::interface::DivansCompressorOptions,
-                     _mc: &'a mut AllocCommand,
-                     _buf: &'a mut AllocCommand::AllocatedMemory,
-) -> Result<&'a [brotli::interface::Command<brotli::SliceOffset>], ErrMsg> {
+
+fn new_actuary<SelectedCDF:CDF16,
+               AllocU8:Allocator<u8>,
+               AllocCDF16:Allocator<SelectedCDF>>(m8: AllocU8,
+                                                  mcdf16: AllocCDF16,
+                                                  pm:&brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                                                  window_size: u8,
+                                                  opt: &super::interface::DivansCompressorOptions,
+) -> Result<Actuary<SelectedCDF, AllocU8, AllocCDF16>, ErrMsg> {
     let mut unused = 0usize;
     let mut unused2 = 0usize;
-    if orig_buf.len() == 0 {
-        return Ok(orig_buf);
-    }
-    let (re_m8, mcdf16, remainder) = match core::mem::replace(&mut codec.cross_command_state.thread_ctx, codec::ThreadContext::Worker) {
-        codec::ThreadContext::MainThread(main) => main.dismantle(),
-        codec::ThreadContext::Worker => panic!("Main Thread was none during encode"),
-    };
-    let (mut m8, reallocation_item) = re_m8.disassemble();
-    let mut distance_cache = cache::Cache::<AllocU8>::new(&codec.cross_command_state.bk.distance_lru, orig_buf.len(), &mut m8);
-    let mut actuary = codec::DivansCodec::<TallyingArithmeticEncoder,
-                                           ToggleProbabilityBlend,
-                                           DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
-                                           DevNull<AllocU8>,
-                                           SelectedCDF,
-                                           AllocU8,
-                                           AllocCDF16>::new(m8,
-                                                            mcdf16,
-                                                            TallyingArithmeticEncoder::default(),
-                                                            TallyingArithmeticEncoder::default(),
-                                                            ToggleProbabilityBlend::default(),
-                                                            DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
-                                                            usize::from(window_size),
-                                                            opt.dynamic_context_mixing.unwrap_or(0),
-                                                            opt.prior_depth,
-                                                            opt.literal_adaptation,
-                                                            opt.use_context_map,
-                                                            opt.force_stride_value,
-                                                            false);
-    {
-        let immutable_pm = Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
-            literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
-            predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
-        });
-        let mut cmd_offset = 0usize;
-        match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
-                                            &codec::CommandSliceArray(&[immutable_pm]),&mut cmd_offset) {
-            DivansResult::NeedsMoreOutput => {
-                return Err(ErrMsg::DrainOrFillNeedsInput(2));
-            },
-            DivansResult::Failure(e) => {
-                return Err(e);
-            }
-            DivansResult::NeedsMoreInput | DivansResult::Success => {
-                if cmd_offset != 1 {
-                    return Err(ErrMsg::DrainOrFillNeedsInput(3));                    
-                }
+    let mut actuary = Actuary::<SelectedCDF, AllocU8, AllocCDF16>::new(m8,
+                                                                       mcdf16,
+                                                                       TallyingArithmeticEncoder::default(),
+                                                                       TallyingArithmeticEncoder::default(),
+                                                                       ToggleProbabilityBlend::default(),
+                                                                       DemuxerAndRingBuffer::<AllocU8, DevNull<AllocU8>>::default(),
+                                                                       usize::from(window_size),
+                                                                       opt.dynamic_context_mixing.unwrap_or(0),
+                                                                       opt.prior_depth,
+                                                                       opt.literal_adaptation,
+                                                                       opt.use_context_map,
+                                                                       opt.force_stride_value,
+                                                                       false,
+                                                                       opt.cdf_family);
+    let immutable_pm = Command::PredictionMode(PredictionModeContextMap::<brotli::InputReference>{
+        literal_context_map:brotli::InputReference::from(&pm.literal_context_map),
+        predmode_speed_and_distance_context_map:brotli::InputReference::from(&pm.predmode_speed_and_distance_context_map),
+    });
+    let mut cmd_offset = 0usize;
+    match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
+                                   &codec::CommandSliceArray(&[immutable_pm]),&mut cmd_offset) {
+        DivansResult::NeedsMoreOutput => {
+            return Err(ErrMsg::DrainOrFillNeedsInput(2));
+        },
+        DivansResult::Failure(e) => {
+            return Err(e);
+        }
+        DivansResult::NeedsMoreInput | DivansResult::Success => {
+            if cmd_offset != 1 {
+                return Err(ErrMsg::DrainOrFillNeedsInput(3));
             }
         }
     }
+    Ok(actuary)
+}
+
+// level 1: folds copies into the literals before them when the actuary prices that cheaper
+// and returns how many commands are left at the front of orig_buf
+fn merge_greedily<SelectedCDF:CDF16,
+                  AllocU8:Allocator<u8>,
+                  AllocCDF16:Allocator<SelectedCDF>>(orig_buf: &mut [brotli::interface::Command<brotli::SliceOffset>],
+                                                     mb: brotli::InputPair,
+                                                     actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                     distance_cache: &mut cache::Cache<AllocU8>) -> Result<usize, ErrMsg> {
+    let mut unused = 0usize;
+    let mut unused2 = 0usize;
+    let mut eligible_index = 0usize;
     for (index, cmd) in orig_buf.iter().enumerate() {
         if let Command::Copy(ref copy) = *cmd {
             distance_cache.populate(copy.distance, copy.num_bytes, index);
         }
     }
-    let mut eligible_index = 0usize;
     for index in 1..orig_buf.len() {
         let (eligible_a, item_a) = orig_buf.split_at_mut(index);
         let mut step_command = false;
@@ -195,14 +184,11 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
             if let Command::Copy(ref mut copy) = item_a[0] {
                 let start = lit.data.offset();
                 let fin = start + lit.data.len() + copy.num_bytes as usize;
-                let mut should_merge = match should_merge(lit, copy, index, mb, &mut actuary, &mut distance_cache) {
-                    Ok(should) => should,
-                    Err(msg) => return Err(msg),
-                };
+                let should_merge = should_merge(lit, copy, index, mb, actuary, distance_cache)?;
                 if should_merge && !(start < mb.0.len() && fin > mb.0.len()) {
                     //eprintln!("Merging {},{} into {},{} with mb.0 {} and mb.1 {}", lit.data.0, lit.data.1, lit.data.0, lit.data.1 + copy.num_bytes,mb.0.len(), mb.1.len());
                     lit.data.1 += copy.num_bytes;
-                    core::mem::replace(copy, CopyCommand::nop());
+                    *copy = CopyCommand::nop();
                 } else {
                     step_command = true;
                 }
@@ -216,7 +202,7 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
                     //eprintln!("Merging {},{} into {},{} with mb.0 {} and mb.1 {}", lit.data.0, lit.data.1, lit.data.0, lit.data.1 + cont_lit.data.1,mb.0.len(), mb.1.len());
 
                     lit.data.1 += cont_lit.data.1;
-                    core::mem::replace(&mut item_a[0], Command::Copy(CopyCommand::nop())); // replace with a copy
+                    item_a[0] = Command::Copy(CopyCommand::nop()); // replace with a copy
                 }
             } else {
                 step_command = true;
@@ -228,7 +214,7 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
             eligible_index = index;
             let mut cmd_offset = 0usize;
             match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
-                                           &OneCommandThawingArray(&eligible, &mb),&mut cmd_offset) {
+                                           &OneCommandThawingArray(eligible, &mb),&mut cmd_offset) {
                 DivansResult::NeedsMoreOutput => {
                     return Err(ErrMsg::DrainOrFillNeedsInput(4));
                 },
@@ -243,11 +229,10 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
             }
         }
     }
-    for index in eligible_index..orig_buf.len() {
+    for cmd in orig_buf[eligible_index..].iter() {
         let mut cmd_offset = 0usize;
-        let cmd = &orig_buf[index];
         match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
-                                       &OneCommandThawingArray(&cmd, &mb),&mut cmd_offset) {
+                                       &OneCommandThawingArray(cmd, &mb),&mut cmd_offset) {
             DivansResult::NeedsMoreOutput => {
                 return Err(ErrMsg::DrainOrFillNeedsInput(4));
             },
@@ -263,7 +248,7 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
     }
     eligible_index = 0;
     for index in 0..orig_buf.len() {
-        let cmd = orig_buf[index].clone();
+        let cmd = orig_buf[index];
         if let Command::Copy(ref copy) = cmd {
             if copy.num_bytes == 0 {
                 continue;
@@ -272,7 +257,53 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
         orig_buf[eligible_index] = cmd;
         eligible_index += 1;
     }
-    //eprintln!("Actuary estimate: total cost {} bits; {} bytes\n", total_billing_cost(&actuary), total_billing_cost(&actuary)/ 8.0);
+    Ok(eligible_index)
+}
+
+pub fn ir_optimize<'a, SelectedCDF:CDF16,
+                   ChosenEncoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
+                   AllocU8:Allocator<u8>,
+                   AllocCDF16:Allocator<SelectedCDF>,
+                   AllocCommand: Allocator<StaticCommand> + Allocator<u32> + Allocator<u8>,
+                   LinearOutputBytes:StreamMuxer<AllocU8>+Default,
+                   >(pm:&mut brotli::interface::PredictionModeContextMap<brotli::InputReferenceMut>,
+                     orig_buf:&'a mut [brotli::interface::Command<brotli::SliceOffset>],
+                     mb:brotli::InputPair,
+                     codec:&mut codec::DivansCodec<ChosenEncoder,
+                                                   EncoderSpecialization,
+                                                   DemuxerAndRingBuffer<AllocU8,
+                                                                        DevNull<AllocU8>>,
+                                                   LinearOutputBytes,
+                                                   SelectedCDF,
+                                                   AllocU8,
+                                                   AllocCDF16>,
+                     window_size: u8,
+                     opt: super::interface::DivansCompressorOptions,
+                     mc: &mut AllocCommand,
+                     buf: &'a mut <AllocCommand as Allocator<StaticCommand>>::AllocatedMemory,
+) -> Result<&'a [brotli::interface::Command<brotli::SliceOffset>], ErrMsg> {
+    if orig_buf.len() == 0 {
+        return Ok(orig_buf);
+    }
+    let (re_m8, mcdf16, remainder) = match core::mem::replace(&mut codec.cross_command_state.thread_ctx, codec::ThreadContext::Worker) {
+        codec::ThreadContext::MainThread(main) => main.dismantle(),
+        codec::ThreadContext::Worker => panic!("Main Thread was none during encode"),
+    };
+    let (mut m8, reallocation_item) = re_m8.disassemble();
+    let mut distance_cache = cache::Cache::<AllocU8>::new(&codec.cross_command_state.bk.distance_lru, orig_buf.len(), &mut m8);
+    let mut actuary = new_actuary(m8, mcdf16, pm, window_size, &opt)?;
+    // level 2 and up reparse the metablock, but the parse is only kept if it prices below the
+    // level 1 merge; the parser gives up on metablocks whose commands do not line up with mb
+    let mut parsed = None;
+    if opt.divans_ir_optimizer >= 2 {
+        if let Some(len) = optimal_parse::optimal_parse(orig_buf, mb, &mut actuary, window_size, opt.quality.unwrap_or(11), mc, buf)? {
+            parsed = Some((len, total_billing_cost(&actuary)));
+            let (m8, mcdf16) = actuary.free();
+            actuary = new_actuary(m8, mcdf16, pm, window_size, &opt)?;
+        }
+    }
+    let merged_len = merge_greedily(orig_buf, mb, &mut actuary, &mut distance_cache)?;
+    let merged_cost = total_billing_cost(&actuary);
     let (mut retrieved_m8, retrieved_mcdf16) = actuary.free();
     distance_cache.free(&mut retrieved_m8);
     codec.cross_command_state.thread_ctx = codec::ThreadContext::MainThread(
@@ -282,5 +313,11 @@ pub fn ir_optimize<'a, SelectedCDF:CDF16,
                                    ChosenEncoder>::reassemble((alloc_util::RepurposingAlloc::reassemble((retrieved_m8, reallocation_item)),
                                                                retrieved_mcdf16,
                                                                remainder)));
-    Ok(&orig_buf[..eligible_index])
+    match parsed {
+        Some((len, parsed_cost)) if parsed_cost < merged_cost => {
+            let parsed_buf: &'a <AllocCommand as Allocator<StaticCommand>>::AllocatedMemory = buf;
+            Ok(&parsed_buf.slice()[..len])
+        },
+        _ => Ok(&orig_buf[..merged_len]),
+    }
 }
@@ -0,0 +1,797 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Forward dynamic programming parse of a metablock, priced by the divans cost model.
+// Runs of literal and copy commands are reparsed; every other command is kept in place.
+use core;
+use brotli;
+use brotli::interface::{Command, LiteralCommand, CopyCommand, StaticCommand, FeatureFlagSliceType};
+use alloc::{SliceWrapper, SliceWrapperMut, Allocator};
+use codec;
+use codec::get_distance_from_mnemonic_code;
+use codec::io::DemuxerAndRingBuffer;
+use mux::DevNull;
+use probability::CDF16;
+use interface::{DivansResult, ErrMsg};
+use raw_to_cmd::hash_match::HashMatch;
+use super::statistics_tracking_codec::{TallyingArithmeticEncoder, OneCommandThawingArray, ToggleProbabilityBlend,
+                                      take_billing_snapshot, billing_snapshot_delta, reset_billing_snapshot,
+                                      literal_billing_snapshot_delta};
+
+pub type Actuary<SelectedCDF, AllocU8, AllocCDF16> = codec::DivansCodec<TallyingArithmeticEncoder,
+                                                                    ToggleProbabilityBlend,
+                                                                    DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
+                                                                    DevNull<AllocU8>,
+                                                                    SelectedCDF,
+                                                                    AllocU8,
+                                                                    AllocCDF16>;
+
+// bounds the node memory; literal prices are probed for every chunk
+const PARSE_CHUNK: usize = 1 << 16;
+// copy and literal command prices are re-probed once this many bytes have been parsed
+const REPROBE_INTERVAL: usize = 4096;
+// every copy length up to this is tried; longer candidates are only tried at full length
+const MAX_SHORT_LEN: u32 = 32;
+const MIN_COPY_LEN: u32 = 2;
+const MAX_LEN_BITS: usize = 18;
+const MAX_DIST_BITS: usize = 25;
+const NUM_DISTANCE_CODES: usize = 15;
+
+const NODE_COST: usize = 0;
+const NODE_LEN: usize = 1; // 0 for a literal step
+const NODE_DIST: usize = 2;
+const NODE_LRU: usize = 3; // 4 words; reused as the forward link once the chunk is solved
+const NODE_RUN: usize = 7; // literal bytes since the last copy
+const NODE_WORDS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Node {
+    cost: f32,
+    len: u32,
+    dist: u32,
+    lru: [u32; 4],
+    run: u32,
+}
+
+fn get_node(nodes: &[u32], index: usize) -> Node {
+    let n = &nodes[index * NODE_WORDS..(index + 1) * NODE_WORDS];
+    Node {
+        cost: f32::from_bits(n[NODE_COST]),
+        len: n[NODE_LEN],
+        dist: n[NODE_DIST],
+        lru: [n[NODE_LRU], n[NODE_LRU + 1], n[NODE_LRU + 2], n[NODE_LRU + 3]],
+        run: n[NODE_RUN],
+    }
+}
+
+fn set_node(nodes: &mut [u32], index: usize, node: &Node) {
+    let n = &mut nodes[index * NODE_WORDS..(index + 1) * NODE_WORDS];
+    n[NODE_COST] = node.cost.to_bits();
+    n[NODE_LEN] = node.len;
+    n[NODE_DIST] = node.dist;
+    n[NODE_LRU..NODE_LRU + 4].clone_from_slice(&node.lru[..]);
+    n[NODE_RUN] = node.run;
+}
+
+fn bit_length(val: u32) -> usize {
+    (32 - val.leading_zeros()) as usize
+}
+
+// mirrors CrossCommandBookKeeping::distance_mnemonic_code
+fn mnemonic_code(lru: &[u32; 4], distance: u32, len: u32) -> usize {
+    for code in 0..NUM_DISTANCE_CODES {
+        let (item, ok, _) = get_distance_from_mnemonic_code(lru, code as u8, len);
+        if ok && item == distance {
+            return code;
+        }
+    }
+    NUM_DISTANCE_CODES
+}
+
+// mirrors CrossCommandBookKeeping::obs_distance
+fn observe_distance(lru: &[u32; 4], distance: u32) -> [u32; 4] {
+    if distance == lru[1] {
+        [distance, lru[0], lru[2], lru[3]]
+    } else if distance == lru[2] {
+        [distance, lru[0], lru[1], lru[3]]
+    } else if distance != lru[0] {
+        [distance, lru[0], lru[1], lru[2]]
+    } else {
+        *lru
+    }
+}
+
+fn representative(bits: usize) -> u32 {
+    if bits <= 1 {
+        return bits as u32;
+    }
+    (1u32 << (bits - 1)) | (1u32 << (bits - 2))
+}
+
+fn encode_command<SelectedCDF:CDF16,
+                  AllocU8:Allocator<u8>,
+                  AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                     cmd: &StaticCommand,
+                                                     mb: &brotli::InputPair) -> Result<(), ErrMsg> {
+    let mut unused = 0usize;
+    let mut unused2 = 0usize;
+    let mut cmd_offset = 0usize;
+    match actuary.encode_or_decode(&[], &mut unused, &mut[], &mut unused2,
+                                   &OneCommandThawingArray(cmd, mb), &mut cmd_offset) {
+        DivansResult::NeedsMoreOutput => Err(ErrMsg::DrainOrFillNeedsInput(8)),
+        DivansResult::Failure(e) => Err(e),
+        DivansResult::NeedsMoreInput | DivansResult::Success => {
+            if cmd_offset != 1 {
+                return Err(ErrMsg::DrainOrFillNeedsInput(9));
+            }
+            Ok(())
+        }
+    }
+}
+
+// cost of cmd in the current state, leaving the actuary where it was
+fn price<SelectedCDF:CDF16,
+         AllocU8:Allocator<u8>,
+         AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                            cmd: &StaticCommand,
+                                            mb: &brotli::InputPair) -> Result<f32, ErrMsg> {
+    let snapshot = actuary.cross_command_state.snapshot_literal_or_copy_state();
+    let blend = actuary.cross_command_state.specialization.will_it_blend;
+    actuary.cross_command_state.specialization.will_it_blend = false;
+    take_billing_snapshot(actuary);
+    let ret = encode_command(actuary, cmd, mb);
+    let cost = billing_snapshot_delta(actuary);
+    reset_billing_snapshot(actuary);
+    actuary.cross_command_state.specialization.will_it_blend = blend;
+    actuary.cross_command_state.restore_literal_or_copy_snapshot(snapshot);
+    ret.map(|_| cost)
+}
+
+// cost of encoding cmds in order from the current state, leaving the actuary where it was
+fn price_commands<SelectedCDF:CDF16,
+                  AllocU8:Allocator<u8>,
+                  AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                     cmds: &[StaticCommand],
+                                                     mb: &brotli::InputPair) -> Result<f32, ErrMsg> {
+    let snapshot = actuary.cross_command_state.snapshot_literal_or_copy_state();
+    let blend = actuary.cross_command_state.specialization.will_it_blend;
+    actuary.cross_command_state.specialization.will_it_blend = false;
+    take_billing_snapshot(actuary);
+    let mut ret = Ok(());
+    for cmd in cmds.iter() {
+        ret = encode_command(actuary, cmd, mb);
+        if ret.is_err() {
+            break;
+        }
+    }
+    let cost = billing_snapshot_delta(actuary);
+    reset_billing_snapshot(actuary);
+    actuary.cross_command_state.specialization.will_it_blend = blend;
+    actuary.cross_command_state.restore_literal_or_copy_snapshot(snapshot);
+    ret.map(|_| cost)
+}
+
+fn command_len(cmd: &StaticCommand) -> usize {
+    match *cmd {
+        Command::Literal(ref lit) => lit.data.len(),
+        Command::Copy(ref copy) => copy.num_bytes as usize,
+        Command::Dict(ref dict) => dict.final_size as usize,
+        _ => 0,
+    }
+}
+
+// the part of a literal command of len bytes that does not depend on its bytes
+fn literal_overhead<SelectedCDF:CDF16,
+                    AllocU8:Allocator<u8>,
+                    AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                       mb: &brotli::InputPair,
+                                                       len: usize) -> Result<f32, ErrMsg> {
+    let snapshot = actuary.cross_command_state.snapshot_literal_or_copy_state();
+    take_billing_snapshot(actuary);
+    let ret = encode_command(actuary, &scratch_literal(mb, len), mb);
+    let cost = billing_snapshot_delta(actuary) - literal_billing_snapshot_delta(actuary);
+    reset_billing_snapshot(actuary);
+    actuary.cross_command_state.restore_literal_or_copy_snapshot(snapshot);
+    ret.map(|_| cost)
+}
+
+fn copy_cmd(distance: u32, num_bytes: u32) -> StaticCommand {
+    Command::Copy(CopyCommand{distance, num_bytes})
+}
+
+fn literal_cmd(offset: usize, len: usize) -> StaticCommand {
+    Command::Literal(LiteralCommand{
+        data: brotli::SliceOffset(offset, len as u32),
+        prob: FeatureFlagSliceType::<brotli::SliceOffset>::default(),
+        high_entropy: false,
+    })
+}
+
+// The command type and copy length priors are conditioned on the preceding commands, so
+// copies are priced separately after a copy and after literal runs of each length bucket.
+const NUM_CONTEXTS: usize = 6;
+const CONTEXT_RUN: [usize; NUM_CONTEXTS] = [4, 1, 2, 3, 4, 8]; // context 0 adds a copy after its run
+const DISTANCE_CONTEXT: usize = 4;
+// the distance mnemonic priors are also conditioned on copy lengths of 2, 3, 4 and 5+
+const NUM_LEN_BUCKETS: usize = 4;
+const DISTANCE_SAMPLES: u32 = 2;
+// the distance length priors are conditioned on the copy length as well
+const NUM_DISTANCE_CLASSES: usize = 6;
+const DISTANCE_CLASS_LEN: [u32; NUM_DISTANCE_CLASSES] = [2, 3, 4, 5, 8, 128];
+
+fn context(run: u32) -> usize {
+    if run >= 8 {
+        5
+    } else if run >= 4 {
+        4
+    } else {
+        run as usize
+    }
+}
+
+fn distance_class(len: u32) -> usize {
+    if len >= 128 {
+        5
+    } else if len >= 8 {
+        4
+    } else if len >= 5 {
+        3
+    } else {
+        len as usize - MIN_COPY_LEN as usize
+    }
+}
+
+fn len_bucket(len: u32) -> usize {
+    (core::cmp::min(len, MIN_COPY_LEN + NUM_LEN_BUCKETS as u32 - 1) - MIN_COPY_LEN) as usize
+}
+
+// a literal of about len bytes that does not straddle the two halves of mb
+fn scratch_literal(mb: &brotli::InputPair, len: usize) -> StaticCommand {
+    if mb.0.len() >= len || mb.0.len() >= mb.1.len() {
+        literal_cmd(0, core::cmp::min(len, mb.0.len()))
+    } else {
+        literal_cmd(mb.0.len(), core::cmp::min(len, mb.1.len()))
+    }
+}
+
+fn miss_reference(lru: &[u32; 4], max_distance: u32) -> u32 {
+    let mut reference_distance = representative(core::cmp::min(12, bit_length(max_distance)));
+    while reference_distance < max_distance && mnemonic_code(lru, reference_distance, 4) != NUM_DISTANCE_CODES {
+        reference_distance += 1;
+    }
+    core::cmp::min(reference_distance, max_distance)
+}
+
+// Separable approximation of the copy cost: the price of a copy of len at a reference
+// distance plus the price difference between its distance and the reference distance.
+#[derive(Clone, Copy)]
+struct CostModel {
+    short_literal: [f32; MAX_SHORT_LEN as usize + 1],
+    long_literal: [f32; MAX_LEN_BITS + 1],
+    short_len: [[f32; MAX_SHORT_LEN as usize + 1]; NUM_CONTEXTS],
+    long_len: [[f32; MAX_LEN_BITS + 1]; NUM_CONTEXTS],
+    miss_distance: [[f32; MAX_DIST_BITS + 1]; NUM_DISTANCE_CLASSES],
+    hit_distance: [[[f32; NUM_DISTANCE_CODES]; NUM_LEN_BUCKETS]; NUM_CONTEXTS],
+}
+
+impl CostModel {
+    fn probe<SelectedCDF:CDF16,
+             AllocU8:Allocator<u8>,
+             AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                mb: &brotli::InputPair,
+                                                max_distance: u32) -> Result<Self, ErrMsg> {
+        let mut model = CostModel {
+            short_literal: [0.0; MAX_SHORT_LEN as usize + 1],
+            long_literal: [0.0; MAX_LEN_BITS + 1],
+            short_len: [[f32::MAX; MAX_SHORT_LEN as usize + 1]; NUM_CONTEXTS],
+            long_len: [[f32::MAX; MAX_LEN_BITS + 1]; NUM_CONTEXTS],
+            miss_distance: [[f32::MAX; MAX_DIST_BITS + 1]; NUM_DISTANCE_CLASSES],
+            hit_distance: [[[f32::MAX; NUM_DISTANCE_CODES]; NUM_LEN_BUCKETS]; NUM_CONTEXTS],
+        };
+        let snapshot = actuary.cross_command_state.snapshot_literal_or_copy_state();
+        actuary.cross_command_state.specialization.will_it_blend = false;
+        let mut ret = Ok(());
+        for ctx in 0..NUM_CONTEXTS {
+            ret = model.probe_context(actuary, mb, ctx, max_distance);
+            actuary.cross_command_state.restore_literal_or_copy_snapshot(snapshot.clone());
+            if ret.is_err() {
+                break;
+            }
+        }
+        actuary.cross_command_state.specialization.will_it_blend = true;
+        ret.map(|_| model)
+    }
+    fn probe_context<SelectedCDF:CDF16,
+                     AllocU8:Allocator<u8>,
+                     AllocCDF16:Allocator<SelectedCDF>>(&mut self,
+                                                        actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                        mb: &brotli::InputPair,
+                                                        ctx: usize,
+                                                        max_distance: u32) -> Result<(), ErrMsg> {
+        // the scratch commands only set up the context, so they are left off the bill
+        take_billing_snapshot(actuary);
+        let ret = encode_command(actuary, &scratch_literal(mb, CONTEXT_RUN[ctx]), mb).and_then(|_| if ctx == 0 {
+            let lru = actuary.cross_command_state.bk.distance_lru;
+            let distance = if lru[1] != 0 { lru[1] } else { core::cmp::max(lru[0], 1) };
+            encode_command(actuary, &copy_cmd(distance, 4), mb)
+        } else {
+            Ok(())
+        });
+        reset_billing_snapshot(actuary);
+        ret?;
+        if ctx == 0 {
+            for len in 1..(MAX_SHORT_LEN + 1) {
+                self.short_literal[len as usize] = literal_overhead(actuary, mb, len as usize)?;
+            }
+            for bits in (bit_length(MAX_SHORT_LEN) + 1)..(MAX_LEN_BITS + 1) {
+                self.long_literal[bits] = literal_overhead(actuary, mb, representative(bits) as usize)?;
+            }
+        }
+        let lru = actuary.cross_command_state.bk.distance_lru;
+        let reference_distance = miss_reference(&lru, max_distance);
+        for len in 1..(MAX_SHORT_LEN + 1) {
+            self.short_len[ctx][len as usize] = price(actuary, &copy_cmd(reference_distance, len), mb)?;
+        }
+        for bits in (bit_length(MAX_SHORT_LEN) + 1)..(MAX_LEN_BITS + 1) {
+            self.long_len[ctx][bits] = price(actuary, &copy_cmd(reference_distance, representative(bits)), mb)?;
+        }
+        if ctx == DISTANCE_CONTEXT {
+            for (class, &len) in DISTANCE_CLASS_LEN.iter().enumerate() {
+                let reference_cost = self.len_cost(ctx, len);
+                for bits in 1..(core::cmp::min(bit_length(max_distance), MAX_DIST_BITS) + 1) {
+                    // averaged over a few distances, since the mantissa nibbles are modelled too
+                    let mut total = 0.0;
+                    let mut count = 0;
+                    for step in 0..DISTANCE_SAMPLES {
+                        let distance = (1u32 << (bits - 1)) + (((1u32 << (bits - 1)) - 1) * (2 * step + 1) / (2 * DISTANCE_SAMPLES));
+                        if distance <= max_distance && mnemonic_code(&lru, distance, len) == NUM_DISTANCE_CODES {
+                            total += price(actuary, &copy_cmd(distance, len), mb)?;
+                            count += 1;
+                        }
+                    }
+                    if count != 0 {
+                        self.miss_distance[class][bits] = total / count as f32 - reference_cost;
+                    }
+                }
+            }
+        }
+        for bucket in 0..NUM_LEN_BUCKETS {
+            let len = bucket as u32 + MIN_COPY_LEN;
+            for code in 0..NUM_DISTANCE_CODES {
+                let (distance, ok, _) = get_distance_from_mnemonic_code(&lru, code as u8, len);
+                if ok && distance != 0 && distance <= max_distance && mnemonic_code(&lru, distance, len) == code {
+                    self.hit_distance[ctx][bucket][code] = price(actuary, &copy_cmd(distance, len), mb)? - self.short_len[ctx][len as usize];
+                }
+            }
+        }
+        Ok(())
+    }
+    // charged when a run of literals ends, since the command carries the run length
+    fn literal_command_cost(&self, run: u32) -> f32 {
+        if run <= MAX_SHORT_LEN {
+            return self.short_literal[run as usize];
+        }
+        self.long_literal[core::cmp::min(bit_length(run), MAX_LEN_BITS)]
+    }
+    fn len_cost(&self, ctx: usize, len: u32) -> f32 {
+        if len <= MAX_SHORT_LEN {
+            return self.short_len[ctx][len as usize];
+        }
+        self.long_len[ctx][core::cmp::min(bit_length(len), MAX_LEN_BITS)]
+    }
+    fn copy_cost(&self, run: u32, len: u32, distance: u32, lru: &[u32; 4]) -> f32 {
+        let code = mnemonic_code(lru, distance, len);
+        let hit = if code < NUM_DISTANCE_CODES {
+            self.hit_distance[context(run)][len_bucket(len)][code]
+        } else {
+            f32::MAX
+        };
+        let distance_cost = if hit != f32::MAX {
+            hit
+        } else {
+            self.miss_distance[distance_class(len)][core::cmp::min(bit_length(distance), MAX_DIST_BITS)]
+        };
+        self.literal_command_cost(run) + self.len_cost(context(run), len) + distance_cost
+    }
+}
+
+// per byte literal prices for [chunk_start, chunk_end), probed one byte at a time in order
+fn probe_literals<SelectedCDF:CDF16,
+                  AllocU8:Allocator<u8>,
+                  AllocCDF16:Allocator<SelectedCDF>>(actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                     mb: &brotli::InputPair,
+                                                     chunk_start: usize,
+                                                     literal_cost: &mut [u32]) -> Result<(), ErrMsg> {
+    let snapshot = actuary.cross_command_state.snapshot_literal_or_copy_state();
+    actuary.cross_command_state.specialization.will_it_blend = false;
+    let mut ret = Ok(());
+    for (index, cost) in literal_cost.iter_mut().enumerate() {
+        take_billing_snapshot(actuary);
+        ret = encode_command(actuary, &literal_cmd(chunk_start + index, 1), mb);
+        *cost = literal_billing_snapshot_delta(actuary).to_bits();
+        reset_billing_snapshot(actuary);
+        if ret.is_err() {
+            break;
+        }
+    }
+    actuary.cross_command_state.specialization.will_it_blend = true;
+    actuary.cross_command_state.restore_literal_or_copy_snapshot(snapshot);
+    ret
+}
+
+fn match_len(data: &[u8], pos: usize, distance: u32, max_len: u32) -> u32 {
+    let mut len = 0u32;
+    while len < max_len && data[pos + len as usize] == data[pos + len as usize - distance as usize] {
+        len += 1;
+    }
+    len
+}
+
+fn relax(nodes: &mut [u32], from: usize, from_node: &Node, len: u32, distance: u32, cost: f32) {
+    let to = from + len as usize;
+    let cost = from_node.cost + cost;
+    if cost < f32::from_bits(nodes[to * NODE_WORDS + NODE_COST]) {
+        set_node(nodes, to, &Node{
+            cost,
+            len,
+            dist: distance,
+            lru: observe_distance(&from_node.lru, distance),
+            run: 0,
+        });
+    }
+}
+
+fn try_copy(nodes: &mut [u32], model: &CostModel, from: usize, from_node: &Node, distance: u32, max_len: u32) {
+    if max_len < MIN_COPY_LEN {
+        return;
+    }
+    for len in MIN_COPY_LEN..(core::cmp::min(max_len, MAX_SHORT_LEN) + 1) {
+        relax(nodes, from, from_node, len, distance, model.copy_cost(from_node.run, len, distance, &from_node.lru));
+    }
+    if max_len > MAX_SHORT_LEN {
+        relax(nodes, from, from_node, max_len, distance, model.copy_cost(from_node.run, max_len, distance, &from_node.lru));
+    }
+}
+
+pub struct CommandBuffer<'a, AllocCommand:Allocator<StaticCommand> + 'a> {
+    pub mc: &'a mut AllocCommand,
+    pub buf: &'a mut AllocCommand::AllocatedMemory,
+    pub len: usize,
+}
+
+impl<'a, AllocCommand:Allocator<StaticCommand>> CommandBuffer<'a, AllocCommand> {
+    pub fn push(&mut self, cmd: StaticCommand) {
+        if self.len == self.buf.slice().len() {
+            let mut grown = self.mc.alloc_cell(self.len * 2 + 16);
+            grown.slice_mut()[..self.len].clone_from_slice(&self.buf.slice()[..self.len]);
+            self.mc.free_cell(core::mem::replace(self.buf, grown));
+        }
+        self.buf.slice_mut()[self.len] = cmd;
+        self.len += 1;
+    }
+}
+
+struct Parser<'a, AllocU32:Allocator<u32> + 'a> {
+    data: &'a [u8],
+    ring: &'a [u8], // data padded to a power of two, as HashMatch expects
+    mb_split: usize,
+    hash_match: &'a mut HashMatch<AllocU32>,
+    hashed_upto: usize,
+    nodes: &'a mut [u32],
+    literal_cost: &'a mut [u32],
+    max_distance: u32,
+    model: Option<(CostModel, usize)>, // and the position it was probed at
+}
+
+impl<'a, AllocU32:Allocator<u32>> Parser<'a, AllocU32> {
+    fn hash_upto(&mut self, pos: usize) {
+        while self.hashed_upto < pos {
+            if self.hashed_upto + 4 <= self.data.len() {
+                self.hash_match.insert(self.ring, self.hashed_upto as u64);
+            }
+            self.hashed_upto += 1;
+        }
+    }
+    fn push_literal<AllocCommand:Allocator<StaticCommand>>(&self, out: &mut CommandBuffer<AllocCommand>, start: usize, end: usize) {
+        if start < self.mb_split && end > self.mb_split { // literals may not straddle the two halves of mb
+            out.push(literal_cmd(start, self.mb_split - start));
+            out.push(literal_cmd(self.mb_split, end - self.mb_split));
+        } else {
+            out.push(literal_cmd(start, end - start));
+        }
+    }
+    // pushes orig, extending literals over the copies that follow them when that is cheaper,
+    // and returns the price of what it pushed
+    fn merge_literals<SelectedCDF:CDF16,
+                      AllocU8:Allocator<u8>,
+                      AllocCDF16:Allocator<SelectedCDF>,
+                      AllocCommand:Allocator<StaticCommand>>(&self,
+                                                             actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                             mb: &brotli::InputPair,
+                                                             out: &mut CommandBuffer<AllocCommand>,
+                                                             orig: &[StaticCommand]) -> Result<f32, ErrMsg> {
+        let snapshot = actuary.cross_command_state.snapshot_literal_or_copy_state();
+        actuary.cross_command_state.specialization.will_it_blend = false;
+        let mut total = 0.0;
+        let mut ret = Ok(());
+        let mut index = 0usize;
+        while index < orig.len() && ret.is_ok() {
+            let mut cmd = orig[index];
+            index += 1;
+            if let Command::Literal(ref lit) = orig[index - 1] {
+                let start = lit.data.offset();
+                let mut end = start + lit.data.len();
+                while index < orig.len() {
+                    let next_end = end + command_len(&orig[index]);
+                    if start < self.mb_split && next_end > self.mb_split {
+                        break;
+                    }
+                    match orig[index] {
+                        Command::Literal(_) => {},
+                        Command::Copy(_) => {
+                            let separate = price_commands(actuary, &[literal_cmd(start, end - start), orig[index]], mb);
+                            let merged = price(actuary, &literal_cmd(start, next_end - start), mb);
+                            match (separate, merged) {
+                                (Ok(separate), Ok(merged)) => if merged >= separate {
+                                    break;
+                                },
+                                (Err(e), _) | (_, Err(e)) => {
+                                    ret = Err(e);
+                                    break;
+                                },
+                            }
+                        },
+                        _ => break,
+                    }
+                    end = next_end;
+                    index += 1;
+                }
+                cmd = literal_cmd(start, end - start);
+            }
+            if ret.is_ok() {
+                take_billing_snapshot(actuary);
+                ret = encode_command(actuary, &cmd, mb);
+                total += billing_snapshot_delta(actuary);
+                reset_billing_snapshot(actuary);
+                out.push(cmd);
+            }
+        }
+        actuary.cross_command_state.specialization.will_it_blend = true;
+        actuary.cross_command_state.restore_literal_or_copy_snapshot(snapshot);
+        ret.map(|_| total)
+    }
+    // reparses the bytes the brotli commands in run describe, starting at run_start
+    fn parse_run<SelectedCDF:CDF16,
+                 AllocU8:Allocator<u8>,
+                 AllocCDF16:Allocator<SelectedCDF>,
+                 AllocCommand:Allocator<StaticCommand>>(&mut self,
+                                                        actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                        mb: &brotli::InputPair,
+                                                        out: &mut CommandBuffer<AllocCommand>,
+                                                        run: &[StaticCommand],
+                                                        run_start: usize) -> Result<(), ErrMsg> {
+        let mut first = 0usize;
+        let mut chunk_start = run_start;
+        while first < run.len() {
+            // chunks end on a command boundary so that the brotli commands can compete with the parse
+            let mut last = first;
+            let mut chunk_end = chunk_start;
+            while last < run.len() && chunk_end + command_len(&run[last]) <= chunk_start + PARSE_CHUNK {
+                chunk_end += command_len(&run[last]);
+                last += 1;
+            }
+            self.parse_chunk(actuary, mb, out, &run[first..last], chunk_start, chunk_end)?;
+            first = last;
+            chunk_start = chunk_end;
+        }
+        Ok(())
+    }
+    fn parse_chunk<SelectedCDF:CDF16,
+                   AllocU8:Allocator<u8>,
+                   AllocCDF16:Allocator<SelectedCDF>,
+                   AllocCommand:Allocator<StaticCommand>>(&mut self,
+                                                          actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+                                                          mb: &brotli::InputPair,
+                                                          out: &mut CommandBuffer<AllocCommand>,
+                                                          orig: &[StaticCommand],
+                                                          chunk_start: usize,
+                                                          chunk_end: usize) -> Result<(), ErrMsg> {
+        let chunk_len = chunk_end - chunk_start;
+        self.hash_upto(chunk_start);
+        let model = match self.model {
+            Some((model, probed_at)) if chunk_start < probed_at + REPROBE_INTERVAL => model,
+            _ => {
+                let model = CostModel::probe(actuary, mb, self.max_distance)?;
+                self.model = Some((model, chunk_start));
+                model
+            },
+        };
+        probe_literals(actuary, mb, chunk_start, &mut self.literal_cost[..chunk_len])?;
+        let unreached = Node{cost: f32::MAX, len: 0, dist: 0, lru: [0; 4], run: 0};
+        for index in 0..(chunk_len + 1) {
+            set_node(self.nodes, index, &unreached);
+        }
+        set_node(self.nodes, 0, &Node{cost: 0.0, len: 0, dist: 0,
+                                       lru: actuary.cross_command_state.bk.distance_lru, run: 0});
+        let mut orig_index = 0usize;
+        let mut orig_pos = chunk_start;
+        for index in 0..chunk_len {
+            let pos = chunk_start + index;
+            let node = get_node(self.nodes, index);
+            let remaining = (chunk_end - pos) as u32;
+            // literal steps keep the lru of the node they came from
+            let literal_cost = node.cost + f32::from_bits(self.literal_cost[index]);
+            if literal_cost <= f32::from_bits(self.nodes[(index + 1) * NODE_WORDS + NODE_COST]) {
+                set_node(self.nodes, index + 1, &Node{cost: literal_cost, len: 0, dist: 0, lru: node.lru, run: node.run + 1});
+            }
+            for distance in node.lru.iter() {
+                if *distance != 0 && *distance as usize <= pos && *distance <= self.max_distance {
+                    let len = match_len(self.data, pos, *distance, remaining);
+                    try_copy(self.nodes, &model, index, &node, *distance, len);
+                }
+            }
+            let found = self.hash_match.longest_match(self.ring, pos as u64, remaining, self.max_distance, 0);
+            if found.len != 0 {
+                try_copy(self.nodes, &model, index, &node, found.distance, found.len);
+            }
+            // the copies brotli chose stay valid from any byte they cover, even when
+            // their source lies before this metablock
+            while orig_index < orig.len() {
+                let orig_len = command_len(&orig[orig_index]);
+                if orig_pos + orig_len > pos {
+                    if let Command::Copy(ref copy) = orig[orig_index] {
+                        try_copy(self.nodes, &model, index, &node, copy.distance, (orig_pos + orig_len - pos) as u32);
+                    }
+                    break;
+                }
+                orig_pos += orig_len;
+                orig_index += 1;
+            }
+            self.hash_upto(pos + 1);
+        }
+        // walk back from the end, leaving forward links in the lru slots
+        let mut index = chunk_len;
+        while index != 0 {
+            let node = get_node(self.nodes, index);
+            let prev = index - if node.len == 0 { 1 } else { node.len as usize };
+            self.nodes[prev * NODE_WORDS + NODE_LRU] = index as u32;
+            index = prev;
+        }
+        let parsed_start = out.len;
+        let mut literal_start = chunk_start;
+        while index != chunk_len {
+            let next = self.nodes[index * NODE_WORDS + NODE_LRU] as usize;
+            let node = get_node(self.nodes, next);
+            if node.len != 0 {
+                if literal_start != chunk_start + index {
+                    self.push_literal(out, literal_start, chunk_start + index);
+                }
+                out.push(copy_cmd(node.dist, node.len));
+                literal_start = chunk_start + next;
+            }
+            index = next;
+        }
+        if literal_start != chunk_end {
+            self.push_literal(out, literal_start, chunk_end);
+        }
+        // the model leaves out how each command adapts the priors for the next, so the
+        // parse is only kept when it beats the (greedily merged) brotli commands at their exact price
+        let parsed_cost = price_commands(actuary, &out.buf.slice()[parsed_start..out.len], mb)?;
+        let merged_start = out.len;
+        if self.merge_literals(actuary, mb, out, orig)? <= parsed_cost {
+            out.buf.slice_mut().copy_within(merged_start..out.len, parsed_start);
+            out.len -= merged_start - parsed_start;
+        } else {
+            out.len = merged_start;
+        }
+        for cmd in out.buf.slice()[parsed_start..out.len].iter() {
+            encode_command(actuary, cmd, mb)?;
+        }
+        Ok(())
+    }
+}
+
+// Reparses the metablock into out and returns the number of commands written, or None if the
+// commands do not line up with mb and the caller should fall back to the greedy optimizer.
+pub fn optimal_parse<SelectedCDF:CDF16,
+                     AllocU8:Allocator<u8>,
+                     AllocCDF16:Allocator<SelectedCDF>,
+                     AllocCommand:Allocator<StaticCommand> + Allocator<u32> + Allocator<u8>>(
+    orig_buf: &[StaticCommand],
+    mb: brotli::InputPair,
+    actuary: &mut Actuary<SelectedCDF, AllocU8, AllocCDF16>,
+    window_size: u8,
+    quality: u16,
+    mc: &mut AllocCommand,
+    out: &mut <AllocCommand as Allocator<StaticCommand>>::AllocatedMemory) -> Result<Option<usize>, ErrMsg> {
+    let mut pos = 0usize;
+    for cmd in orig_buf.iter() {
+        if let Command::Literal(ref lit) = *cmd {
+            if lit.data.offset() != pos {
+                return Ok(None);
+            }
+        }
+        pos += command_len(cmd);
+    }
+    if pos != mb.len() {
+        return Ok(None);
+    }
+    let mut data_size = 4usize;
+    while data_size < mb.len() {
+        data_size <<= 1;
+    }
+    let mut data = <AllocCommand as Allocator<u8>>::alloc_cell(mc, data_size);
+    data.slice_mut()[..mb.0.len()].clone_from_slice(mb.0.data);
+    data.slice_mut()[mb.0.len()..mb.len()].clone_from_slice(mb.1.data);
+    let mut hash_match = HashMatch::<AllocCommand>::new(mc, data_size.trailing_zeros() as u8, quality);
+    let mut nodes = <AllocCommand as Allocator<u32>>::alloc_cell(mc, (core::cmp::min(PARSE_CHUNK, mb.len()) + 1) * NODE_WORDS);
+    let mut literal_cost = <AllocCommand as Allocator<u32>>::alloc_cell(mc, core::cmp::min(PARSE_CHUNK, mb.len()));
+    let out_len;
+    let ret = {
+        let mut buf = <AllocCommand as Allocator<StaticCommand>>::AllocatedMemory::default();
+        let ret = {
+            let mut parser = Parser::<AllocCommand> {
+                data: &data.slice()[..mb.len()],
+                ring: data.slice(),
+                mb_split: mb.0.len(),
+                hash_match: &mut hash_match,
+                hashed_upto: 0,
+                nodes: nodes.slice_mut(),
+                literal_cost: literal_cost.slice_mut(),
+                max_distance: (1u32 << window_size) - 16,
+                model: None,
+            };
+            let mut out_buf = CommandBuffer::<AllocCommand> {
+                buf: &mut buf,
+                len: 0,
+                mc,
+            };
+            let mut run_start = 0usize;
+            let mut run_begin_index = 0usize;
+            let mut pos = 0usize;
+            let mut ret = Ok(());
+            for (index, cmd) in orig_buf.iter().enumerate() {
+                let len = command_len(cmd);
+                let in_run = match *cmd {
+                    Command::Literal(ref lit) => !lit.high_entropy,
+                    Command::Copy(_) => true,
+                    _ => false,
+                };
+                if !in_run || len > PARSE_CHUNK {
+                    ret = parser.parse_run(actuary, &mb, &mut out_buf, &orig_buf[run_begin_index..index], run_start)
+                        .and_then(|_| encode_command(actuary, cmd, &mb));
+                    if ret.is_err() {
+                        break;
+                    }
+                    out_buf.push(*cmd);
+                    run_start = pos + len;
+                    run_begin_index = index + 1;
+                }
+                pos += len;
+            }
+            if ret.is_ok() {
+                ret = parser.parse_run(actuary, &mb, &mut out_buf, &orig_buf[run_begin_index..], run_start);
+            }
+            out_len = out_buf.len;
+            ret
+        };
+        <AllocCommand as Allocator<StaticCommand>>::free_cell(mc, core::mem::replace(out, buf));
+        ret
+    };
+    hash_match.free(mc);
+    <AllocCommand as Allocator<u32>>::free_cell(mc, nodes);
+    <AllocCommand as Allocator<u32>>::free_cell(mc, literal_cost);
+    <AllocCommand as Allocator<u8>>::free_cell(mc, data);
+    ret.map(|_| Some(out_len))
+}
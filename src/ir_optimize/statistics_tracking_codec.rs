@@ -1,4 +1,4 @@
-pub use ::interface::{ArithmeticEncoderOrDecoder, NewWithAllocator, DivansResult, ReadableBytes, WritableBytes};
+pub use ::interface::{ArithmeticEncoderOrDecoder, BillingDesignation, NewWithAllocator, DivansResult, ReadableBytes, WritableBytes, StreamMuxer};
 use probability::{ProbRange, CDF16, LOG2_SCALE};
 use alloc::{SliceWrapper, Allocator};
 use brotli;
@@ -8,7 +8,7 @@ use slice_util::AllocatedMemoryPrefix;
 use codec::{EncoderOrDecoderSpecialization};
 use mux::DevNull;
 use codec::io::DemuxerAndRingBuffer;
-use cmd_to_divans::EncoderSpecialization;
+use cmd_to_divans::{EncoderCodec, EncoderSpecialization};
 use brotli::interface::{Command, CopyCommand, LiteralCommand, DictCommand};
 
 
@@ -17,27 +17,73 @@ type floatY = f32;
 
 use brotli::enc::util::FastLog2u16;
 
+// one slot per BillingDesignation variant, in declaration order
+pub const NUM_BILLING_CATEGORIES: usize = 7;
+
+pub fn billing_category(billing: &BillingDesignation) -> usize {
+    match *billing {
+        BillingDesignation::Unknown => 0,
+        BillingDesignation::CopyCommand(_) => 1,
+        BillingDesignation::DictCommand(_) => 2,
+        BillingDesignation::LiteralCommand(_) => 3,
+        BillingDesignation::CrossCommand(_) => 4,
+        BillingDesignation::PredModeCtxMap(_) => 5,
+        BillingDesignation::BlockType(_) => 6,
+    }
+}
+
 #[derive(Default, Copy, Clone)]
 pub struct TallyingArithmeticEncoder {
     snapshot_cost: floatY,
     cost: floatY,
+    // accumulated in f64 so whole-file totals do not drop the cheap symbols
+    snapshot_category_cost: [f64; NUM_BILLING_CATEGORIES],
+    category_cost: [f64; NUM_BILLING_CATEGORIES],
 }
 
 impl TallyingArithmeticEncoder {
     pub fn take_snapshot(&mut self) {
         self.snapshot_cost = self.cost;
+        self.snapshot_category_cost = self.category_cost;
     }
     pub fn snapshot_delta(&self) -> floatY {
         self.cost - self.snapshot_cost
     }
     pub fn reset_cost_to_snapshot(&mut self) {
         self.cost = self.snapshot_cost;
+        self.category_cost = self.snapshot_category_cost;
     }
     pub fn total_cost(&self) ->floatY {
         self.cost
     }
+    pub fn category_cost(&self) -> &[f64; NUM_BILLING_CATEGORIES] {
+        &self.category_cost
+    }
     pub fn tally(&mut self, data: ProbRange) {
-        self.cost += LOG2_SCALE as floatY - FastLog2u16(data.freq as u16) as floatY;
+        self.tally_billed(data, BillingDesignation::Unknown)
+    }
+    fn tally_billed(&mut self, data: ProbRange, billing: BillingDesignation) {
+        let symbol_cost = LOG2_SCALE as floatY - FastLog2u16(data.freq as u16) as floatY;
+        self.cost += symbol_cost;
+        self.category_cost[billing_category(&billing)] += f64::from(symbol_cost);
+    }
+}
+
+fn bit_range(bit: bool, prob_of_false: u8) -> ProbRange {
+    let prob =
+        if bit {
+            i16::from(prob_of_false)
+        } else {
+            255 - i16::from(prob_of_false)
+        };
+    let start = if bit {
+        i16::from(prob_of_false)
+    } else {
+        0
+    };
+    ProbRange{
+        start: (start << 7) -1,
+        freq: (prob << 7) - 1,
     }
 }
 
@@ -67,21 +113,13 @@ impl ArithmeticEncoderOrDecoder for TallyingArithmeticEncoder {
     fn get_or_put_bit_without_billing(&mut self,
                                       bit: &mut bool,
                                       prob_of_false: u8) {
-        let prob = 
-            if *bit {
-                i16::from(prob_of_false)
-            } else {
-                255 - i16::from(prob_of_false)
-            };
-        let start = if *bit {
-            i16::from(prob_of_false)
-        } else {
-            0
-        };
-        self.tally(ProbRange{
-            start: (start << 7) -1,
-            freq: (prob << 7) - 1,
-        });
+        self.tally(bit_range(*bit, prob_of_false));
+    }
+    fn get_or_put_bit(&mut self,
+                      bit: &mut bool,
+                      prob_of_false: u8,
+                      billing: BillingDesignation) {
+        self.tally_billed(bit_range(*bit, prob_of_false), billing);
     }
     #[inline(always)]
     fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
@@ -91,6 +129,15 @@ impl ArithmeticEncoderOrDecoder for TallyingArithmeticEncoder {
         self.tally(ret);
         ret
     }
+    #[inline(always)]
+    fn get_or_put_nibble<C: CDF16>(&mut self,
+                                   nibble: &mut u8,
+                                   prob: &C,
+                                   billing: BillingDesignation) -> ProbRange {
+        let ret = prob.sym_to_start_and_freq(*nibble).range;
+        self.tally_billed(ret, billing);
+        ret
+    }
 
 }
 
@@ -148,7 +195,24 @@ pub fn billing_snapshot_delta<SelectedCDF:CDF16,
     }
 }
 
-#[allow(dead_code)]
+// the part of billing_snapshot_delta spent on literal bytes rather than command fields
+pub fn literal_billing_snapshot_delta<SelectedCDF:CDF16,
+                                      AllocU8:Allocator<u8>,
+                                      AllocCDF16:Allocator<SelectedCDF>,
+                                      Spc: EncoderOrDecoderSpecialization
+                                      >(codec:&codec::DivansCodec<TallyingArithmeticEncoder,
+                                                                  Spc,
+                                                                  DemuxerAndRingBuffer<AllocU8, DevNull<AllocU8>>,
+                                                                  DevNull<AllocU8>,
+                                                                  SelectedCDF,
+                                                                  AllocU8,
+                                                                  AllocCDF16>) -> floatY {
+    match codec.cross_command_state.thread_ctx {
+        codec::ThreadContext::Worker => 0.0,
+        codec::ThreadContext::MainThread(ref ctx) => ctx.lit_coder.snapshot_delta(),
+    }
+}
+
 pub fn total_billing_cost<SelectedCDF:CDF16,
                           AllocU8:Allocator<u8>,
                           AllocCDF16:Allocator<SelectedCDF>,
@@ -167,6 +231,23 @@ pub fn total_billing_cost<SelectedCDF:CDF16,
     }
 }
 
+pub fn category_billing_cost<SelectedCDF:CDF16,
+                             AllocU8:Allocator<u8>,
+                             AllocCDF16:Allocator<SelectedCDF>,
+                             LinearOutputBytes:StreamMuxer<AllocU8>+Default,
+                             >(codec:&EncoderCodec<TallyingArithmeticEncoder,
+                                                   LinearOutputBytes,
+                                                   SelectedCDF,
+                                                   AllocU8,
+                                                   AllocCDF16>) -> [f64; NUM_BILLING_CATEGORIES] {
+    let mut ret = *codec.cross_command_state.coder.category_cost();
+    if let codec::ThreadContext::MainThread(ref ctx) = codec.cross_command_state.thread_ctx {
+        for (total, lit_cost) in ret.iter_mut().zip(ctx.lit_coder.category_cost().iter()) {
+            *total += *lit_cost;
+        }
+    }
+    ret
+}
 
 pub struct OneCommandThawingArray<'a>(pub &'a brotli::interface::Command<brotli::SliceOffset>, pub &'a brotli::InputPair<'a>);
 
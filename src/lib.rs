@@ -46,6 +46,7 @@ extern crate brotli;
 pub mod resizable_buffer;
 pub mod interface;
 pub mod slice_util;
+pub mod cpu_features;
 pub mod alloc_util;
 mod probability;
 #[macro_use]
@@ -64,19 +65,30 @@ pub mod test_helper;
 mod test_ans;
 mod test_mux;
 mod ans;
+mod range_coder;
+mod binary_coder;
+mod selectable_coder;
 mod brotli_ir_gen;
 mod divans_compressor;
 mod divans_decompressor;
+mod estimate;
+pub mod billing_report;
+pub mod exhaustive;
 mod parallel_decompressor;
 mod stub_parallel_decompressor;
 pub mod ir_optimize;
 pub mod mux;
 pub mod constants;
 pub mod threading;
+pub mod filter;
+#[cfg(feature="std")]
+pub mod ir;
 pub mod multithreading;
+pub mod thread_pool;
+pub mod thread_trace;
 pub use self::interface::{DivansInputResult,DivansOpResult,DivansOutputResult, DivansResult, ErrMsg, MAGIC_NUMBER};
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
-pub use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
+pub use interface::{DivansCompressorFactory, EntropyCoderSelection, CDFFamily, StreamCount, MuxPolicy, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType,
                     DefaultCDF16};
 
 pub use brotli_ir_gen::{BrotliDivansHybridCompressor,BrotliDivansHybridCompressorFactory};
@@ -86,6 +98,11 @@ pub use divans_to_raw::DecoderSpecialization;
 pub use cmd_to_divans::EncoderSpecialization;
 pub use codec::{EncoderOrDecoderSpecialization, DivansCodec, StrideSelection};
 pub use divans_compressor::{DivansCompressor, DivansCompressorFactoryStruct};
+pub use estimate::{CostEstimate, estimate_cost_with_allocators, estimate_ir_cost_with_allocators};
+#[cfg(feature="std")]
+pub use estimate::{estimate_cost, estimate_ir_cost};
+#[cfg(feature="std")]
+pub use billing_report::{BillingReport, BillingRegion, BillingCost, billing_report, billing_report_for_input};
 
 #[cfg(not(feature="safe"))]
 mod ffi;
@@ -99,6 +116,10 @@ pub use reader::DivansBrotliHybridCompressorReader;
 pub use reader::DivansExperimentalCompressorReader;
 #[cfg(feature="std")]
 pub use reader::DivansDecompressorReader;
+#[cfg(feature="std")]
+pub use thread_pool::{Executor, ExecutorHandle, ThreadPool};
+#[cfg(feature="std")]
+pub use thread_trace::{ThreadTrace, TraceEvent, WaitTotal};
 
 #[cfg(feature="std")]
 pub use writer::DivansBrotliHybridCompressorWriter;
@@ -117,6 +138,7 @@ pub use probability::BaseCDF;
 
 pub use interface::BrotliCompressionSetting;
 pub use interface::DivansCompressorOptions;
+pub use filter::{Filter, FilterChain, FilterSelection};
 pub use divans_decompressor::{DivansDecompressor,
                               DivansDecompressorFactory,
                               DivansDecompressorFactoryStruct,
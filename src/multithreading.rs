@@ -7,9 +7,12 @@ use slice_util::{AllocatedMemoryRange, AllocatedMemoryPrefix};
 use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
 use alloc_util::RepurposingAlloc;
 use cmd_to_raw::DivansRecodeState;
-use interface::{PredictionModeContextMap, EncoderOrDecoderRecoderSpecialization, Command, DivansOpResult, DivansOutputResult, ErrMsg};
+use interface::{PredictionModeContextMap, EncoderOrDecoderRecoderSpecialization, Command, DivansOpResult, DivansOutputResult, ErrMsg, MuxPolicy};
 use std::time::{SystemTime, Duration};
 use threading::{StaticCommand, PullAllocatedCommand, downcast_command};
+use thread_pool::ResumableJob;
+use thread_trace::TraceEvent;
+use std::vec::Vec;
 #[cfg(feature="threadlog")]
 const MAX_LOG_SIZE: usize = 8192;
 #[cfg(not(feature="threadlog"))]
@@ -19,13 +22,18 @@ const MAX_LOG_SIZE: usize = 0;
 pub struct MultiWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> {
     start: SystemTime,
     queue: Arc<(Mutex<SerialWorker<AllocU8, AllocCommand>>, Condvar)>,
-    log: [ThreadEvent; MAX_LOG_SIZE],
+    // set when the worker side runs as a job on a shared executor rather than on its own thread;
+    // the worker side then returns instead of waiting, and the main side wakes it whenever it may progress
+    job: Option<Arc<ResumableJob>>,
+    // heap allocated: at full size the log would not fit on a test thread's stack
+    log: Vec<ThreadEvent>,
+    // keeps counting once the log is full so that dropped events can be reported
     log_offset: u32,
 }
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, Copy)]
-enum ThreadEventType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadEventType {
     M_PUSH_CONTEXT_MAP,
     M_WAIT_PUSH_CONTEXT_MAP,
     M_PUSH_DATA,
@@ -48,6 +56,21 @@ enum ThreadEventType {
     W_WAIT_PUSH_EOF,
     W_BROADCAST_ERR,
 }
+
+impl ThreadEventType {
+    // wait events are logged just before blocking; the wait lasts until the next event on the same side
+    pub fn is_wait(&self) -> bool {
+        matches!(*self,
+                 ThreadEventType::M_WAIT_PUSH_CONTEXT_MAP |
+                 ThreadEventType::M_WAIT_PULL_COMMAND_RESULT |
+                 ThreadEventType::W_WAIT_PULL_DATA |
+                 ThreadEventType::W_WAIT_PULL_CONTEXT_MAP |
+                 ThreadEventType::W_WAIT_PUSH_CMD |
+                 ThreadEventType::W_WAIT_PUSH_BATCH_CMD |
+                 ThreadEventType::W_WAIT_PUSH_CONSUMED_DATA |
+                 ThreadEventType::W_WAIT_PUSH_EOF)
+    }
+}
 #[derive(Debug, Clone, Copy)]
 struct ThreadEvent(ThreadEventType, u32, Duration);
 #[cfg(feature="threadlog")]
@@ -66,11 +89,10 @@ macro_rules! unguarded_debug_time {
 #[cfg(feature="threadlog")]
 macro_rules! thread_debug {
     ($en: expr, $quant: expr, $proc: expr, $timevar: expr) => {
-        if $proc.log_offset as usize != $proc.log.len() {
-            $proc.log[$proc.log_offset as usize] = ThreadEvent($en, $quant as u32, $timevar);
-            $proc.log_offset += 1;
+        if $proc.log.len() < MAX_LOG_SIZE {
+            $proc.log.push(ThreadEvent($en, $quant as u32, $timevar));
         }
-        eprintln!("{:?} {} {:?}", $en, $quant, $proc.start.elapsed().unwrap_or(Duration::new(0,0)));
+        $proc.log_offset = $proc.log_offset.saturating_add(1);
     };
 }
 
@@ -87,39 +109,52 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Clone for Mul
             log_offset:self.log_offset.clone(),
             start:self.start,
             queue:self.queue.clone(),
+            job:self.job.clone(),
         }
     }
 }
 
-#[cfg(feature="threadlog")]
-impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> Drop for MultiWorker<AllocU8, AllocCommand> {
-    fn drop(&mut self) {
-        let epoch_d = self.start.duration_since(std::time::UNIX_EPOCH).unwrap_or(Duration::new(0,0));
-        let epoch = (epoch_d.as_secs()%100) * 100 + u64::from(epoch_d.subsec_nanos()) / 10000000;
-        let start_log = self.start.elapsed().unwrap_or(Duration::new(0,0));
-        use std;
-        use std::io::Write;
-        let stderr = std::io::stderr();
-        let mut handle = stderr.lock();
-        writeln!(handle, "{:04}:{:02}:{:09}:LOG_START", epoch, start_log.as_secs(), start_log.subsec_nanos()).unwrap();
-        for entry in self.log[..self.log_offset as usize].iter() {
-            writeln!(handle, "{:04}:{:02}:{:09}:{:?}:{}", epoch, entry.2.as_secs(), entry.2.subsec_nanos(), entry.0, entry.1).unwrap();
-        }
-        let fin_log = self.start.elapsed().unwrap_or(Duration::new(0,0));
-        writeln!(handle, "{:04}:{:02}:{:09}:LOG_FLUSH:{:?}ns", epoch, fin_log.as_secs(), fin_log.subsec_nanos(), fin_log - start_log).unwrap();
-    }
-}
-
-
-
 impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MultiWorker<AllocU8, AllocCommand>
 {
     pub fn new(mcommand: &mut AllocCommand) -> Self {
         MultiWorker::<AllocU8, AllocCommand> {
-            log:[ThreadEvent(ThreadEventType::M_PUSH_EMPTY_DATA, 0, Duration::new(0,0)); MAX_LOG_SIZE],
+            log:Vec::with_capacity(MAX_LOG_SIZE),
             log_offset:0,
             start: SystemTime::now(),
             queue: Arc::new((Mutex::new(SerialWorker::<AllocU8, AllocCommand>::new(mcommand)), Condvar::new())),
+            job: None,
+        }
+    }
+    // the logged events of this side, timed from when the queue was created;
+    // empty unless built with the threadlog feature
+    pub fn trace_events(&self) -> Vec<TraceEvent> {
+        let logged = &self.log[..];
+        let mut ret = Vec::with_capacity(logged.len());
+        for (index, entry) in logged.iter().enumerate() {
+            let duration = match logged.get(index + 1) {
+                Some(next) if entry.0.is_wait() && next.2 > entry.2 => next.2 - entry.2,
+                _ => Duration::new(0, 0),
+            };
+            ret.push(TraceEvent{
+                event: entry.0,
+                quantity: entry.1,
+                start: entry.2,
+                duration,
+            });
+        }
+        ret
+    }
+    // number of events that did not fit in the log
+    pub fn dropped_trace_events(&self) -> usize {
+        (self.log_offset as usize).saturating_sub(self.log.len())
+    }
+    pub fn set_job(&mut self, job: Arc<ResumableJob>) {
+        self.job = Some(job);
+    }
+    // must not be called with the queue locked: the executor may run the job inline
+    fn wake_job(&self) {
+        if let Some(ref job) = self.job {
+            ResumableJob::wake(job);
         }
     }
     fn broadcast_err_internal(&mut self, err: ErrMsg, _thread_event_type: ThreadEventType) {
@@ -130,7 +165,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MultiWorker<A
             cvar.notify_one();
         }
         let ret = worker.broadcast_err_internal(err);
-        thread_debug!(_thread_event_type, output.len(), self, _elapsed);
+        thread_debug!(_thread_event_type, 0, self, _elapsed);
         return ret;        
     }
     pub fn free(&mut self, m8: &mut RepurposingAlloc<u8, AllocU8>, mcommand: &mut AllocCommand) {
@@ -157,7 +192,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
     type CommandOutputType= <SerialWorker<AllocU8, AllocCommand> as MainToThread<AllocU8>>::CommandOutputType;
     #[inline(always)]
     fn push_context_map(&mut self, cm: PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>) -> Result<(),()> {
-        
+        let mut woke_job = false;
         loop { // FIXME: should this loop forever? We should never run out of context map room
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
@@ -167,12 +202,20 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
                 if worker.waiters != 0 {
                     cvar.notify_one();
                 }
-                return worker.push_context_map(cm);
+                let ret = worker.push_context_map(cm);
+                core::mem::drop(worker);
+                self.wake_job();
+                return ret;
+            } else if self.job.is_some() && !woke_job {
+                core::mem::drop(worker);
+                self.wake_job();
+                woke_job = true;
             } else {
                 thread_debug!(ThreadEventType::M_WAIT_PUSH_CONTEXT_MAP, 0, self, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker); // always safe to loop around again
                 _ign.unwrap().waiters -= 1;
+                woke_job = false;
             }
         }
     }
@@ -188,6 +231,8 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
                 if worker.waiters != 0 {
                     cvar.notify_one();
                 }
+                core::mem::drop(worker);
+                self.wake_job();
                 return Ok(());
             },
             err => {
@@ -206,6 +251,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
             output:&mut Self::CommandOutputType,
             consumed_data:&mut [AllocatedMemoryRange<u8, AllocU8>;NUM_DATA_BUFFERED],
             pm:&mut [PredictionModeContextMap<AllocatedMemoryPrefix<u8, AllocU8>>; 2]) -> CommandResult {
+        let mut woke_job = false;
         loop {
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
@@ -216,12 +262,19 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
                 }
                 let ret = worker.pull(output, consumed_data, pm);
                 thread_debug!(ThreadEventType::M_PULL_COMMAND_RESULT, output.len(), self, _elapsed);
+                core::mem::drop(worker);
+                self.wake_job(); // there is room for more results now
                 return ret;
+            } else if worker.err.is_none() && self.job.is_some() && !woke_job {
+                core::mem::drop(worker);
+                self.wake_job();
+                woke_job = true;
             } else if worker.err.is_none() {
                 thread_debug!(ThreadEventType::M_WAIT_PULL_COMMAND_RESULT, 0, self, _elapsed);
                 worker.waiters += 1;
                 let _ign = cvar.wait(worker);
                 _ign.unwrap().waiters -= 1;
+                woke_job = false;
                 //return CommandResult::ProcessedData(AllocatedMemoryRange::<u8, AllocU8>::default()); // FIXME: busy wait
             } else {
                 return CommandResult::Err(worker.err.unwrap());
@@ -243,9 +296,9 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
             let mut worker = lock.lock().unwrap();
-            if worker.data_ready() {
+            if worker.data_ready() || self.job.is_some() {
                 let ret = worker.pull_data();
-                thread_debug!(ThreadEventType::W_PULL_DATA, match ret {ThreadData::Data(ref d) => d.len(), ThreadData::Yield => 0, ThreadData::Eof=> 99999999,}, self, _elapsed);
+                thread_debug!(ThreadEventType::W_PULL_DATA, match ret {ThreadData::Data(ref d) => d.len(), ThreadData::Yield => 0, ThreadData::Eof=> 99999999, ThreadData::Err(_) => 0,}, self, _elapsed);
                 return ret;
             } else {
                 thread_debug!(ThreadEventType::W_WAIT_PULL_DATA, 0, self, _elapsed);
@@ -262,7 +315,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
             let mut worker = lock.lock().unwrap();
-            if worker.cm_ready() {
+            if worker.cm_ready() || self.job.is_some() {
                 if worker.waiters != 0 {
                     cvar.notify_one();
                 }
@@ -290,7 +343,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
             let mut worker = lock.lock().unwrap();
-            if worker.result_space_ready() {
+            if worker.result_space_ready() || self.job.is_some() {
                 thread_debug!(ThreadEventType::W_PUSH_CMD, 1, self, _elapsed);
                 if worker.waiters != 0 {
                     cvar.notify_one();
@@ -314,7 +367,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
             let mut worker = lock.lock().unwrap();
-            if worker.result_space_ready() {
+            if worker.result_space_ready() || self.job.is_some() {
                 if worker.waiters != 0 {
                     cvar.notify_one();
                 }
@@ -335,7 +388,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
             let _elapsed = unguarded_debug_time!(self);
             let &(ref lock, ref cvar) = &*self.queue;
             let mut worker = lock.lock().unwrap();
-            if worker.result_space_ready() {
+            if worker.result_space_ready() || self.job.is_some() {
                 if worker.waiters != 0 {
                     cvar.notify_one();
                 }
@@ -359,6 +412,7 @@ pub struct BufferedMultiWorker<AllocU8:Allocator<u8>, AllocCommand:Allocator<Sta
     pub worker: MultiWorker<AllocU8, AllocCommand>,
     buffer: AllocatedMemoryPrefix<StaticCommand, AllocCommand>,
     min_buffer_push_len: usize,
+    low_latency: bool,
 }
 /*
 impl<AllocU8:Allocator<u8>, AllocCommand: Allocator<StaticCommand>> PullAllocatedCommand<AllocU8, AllocCommand> for BufferedMultiWorker<AllocU8, AllocCommand> {
@@ -375,6 +429,7 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
         let worker = MultiWorker::<AllocU8, AllocCommand>::new(mc);
         Self {
             min_buffer_push_len: 2,
+            low_latency: false,
             worker:worker,
             buffer: AllocatedMemoryPrefix::realloc(mc.alloc_cell(NUM_SERIAL_COMMANDS_BUFFERED), 0),
         }
@@ -433,6 +488,10 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> BufferedMulti
             }
         }
     }
+    // a low latency stream hands over its batch whenever the worker runs out of input
+    pub fn set_mux_policy(&mut self, policy: MuxPolicy) {
+        self.low_latency = policy == MuxPolicy::LowLatency;
+    }
     pub fn free(&mut self, m8: &mut RepurposingAlloc<u8, AllocU8>, mc: &mut AllocCommand) {
         mc.free_cell(core::mem::replace(&mut self.buffer.0,
                                         AllocCommand::AllocatedMemory::default()));
@@ -444,6 +503,10 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     const ISOLATED:bool = true;
     #[inline(always)]
     fn pull_data(&mut self) -> ThreadData<AllocU8> {
+        if self.low_latency && self.buffer.1 != 0 {
+            // a failure is recorded in the worker and reported by the next push
+            let _ = self.force_push(false, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None);
+        }
         self.worker.pull_data()
     }
     #[inline(always)]
@@ -455,12 +518,16 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     fn push_cmd<Specialization:EncoderOrDecoderRecoderSpecialization>(
         &mut self,
         cmd:&mut Command<AllocatedMemoryPrefix<u8, AllocU8>>,
-        _m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
-        _recoder: Option<&mut DivansRecodeState<AllocU8::AllocatedMemory>>,
-        _specialization: &mut Specialization,
-        _output:&mut [u8],
-        _output_offset: &mut usize,
+        m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
+        recoder: Option<&mut DivansRecodeState<AllocU8::AllocatedMemory>>,
+        specialization: &mut Specialization,
+        output:&mut [u8],
+        output_offset: &mut usize,
     ) -> DivansOutputResult {
+        if self.worker.job.is_some() {
+            // a pooled job cannot wait for room to flush its batch, so commands go straight to the queue
+            return self.worker.push_cmd(cmd, m8, recoder, specialization, output, output_offset);
+        }
         let (static_command, pm) = downcast_command(cmd);
         self.buffer.0.slice_mut()[self.buffer.1 as usize] = static_command;
         self.buffer.1 += 1;
@@ -476,13 +543,19 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> ThreadToMain<
     #[inline(always)]
     fn push_consumed_data(&mut self,
                     data:&mut AllocatedMemoryRange<u8, AllocU8>,
-                    _m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
+                    m8: Option<&mut RepurposingAlloc<u8, AllocU8>>,
     ) -> DivansOutputResult {
+        if self.worker.job.is_some() {
+            return self.worker.push_consumed_data(data, m8);
+        }
         DivansOutputResult::from(self.force_push(false, data, None))
     }
    #[inline(always)]
     fn push_eof(&mut self,
     ) -> DivansOutputResult {
+        if self.worker.job.is_some() {
+            return self.worker.push_eof();
+        }
         DivansOutputResult::from(self.force_push(true, &mut AllocatedMemoryRange::<u8, AllocU8>::default(), None))
     }
    #[inline(always)]
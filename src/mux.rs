@@ -5,7 +5,7 @@ use core;
 use alloc::{Allocator, SliceWrapper, SliceWrapperMut};
 use super::slice_util;
 use slice_util::AllocatedMemoryRange;
-pub use interface::{StreamID, StreamMuxer, StreamDemuxer, NUM_STREAMS, STREAM_ID_MASK, ReadableBytes, WritableBytes};
+pub use interface::{StreamID, StreamMuxer, StreamDemuxer, StreamCount, MuxPolicy, NUM_STREAMS, MAX_NUM_STREAMS, STREAM_ID_MASK, ReadableBytes, WritableBytes};
 enum BytesToDeserialize {
     None,
     Some(StreamID, u32),
@@ -25,10 +25,12 @@ const MAX_FLUSH_VARIANCE: usize = 131073;
 
 
 pub struct Mux<AllocU8:Allocator<u8> > {
-   buf: [AllocatedMemoryRange<u8, AllocU8>; NUM_STREAMS as usize],
+   buf: [AllocatedMemoryRange<u8, AllocU8>; MAX_NUM_STREAMS],
+   num_streams: usize,
+   policy: MuxPolicy,
    cur_stream_bytes_avail: u32,
    cur_stream:StreamID,
-   last_flush:[usize; NUM_STREAMS as usize],
+   last_flush:[usize; MAX_NUM_STREAMS],
    bytes_flushed: usize,
    bytes_to_deserialize:BytesToDeserialize,
    eof: StreamState,
@@ -83,10 +85,21 @@ impl<AllocU8:Allocator<u8> > Mux<AllocU8> {
     pub fn write_cursor(&self, index: usize) -> usize {
         self.buf[index].1.end
     }
+    pub fn new(num_streams: StreamCount) -> Self {
+        Mux::<AllocU8> {
+            num_streams: num_streams.get(),
+            ..Mux::default()
+        }
+    }
+    pub fn num_streams(&self) -> StreamCount {
+        StreamCount::new(self.num_streams).unwrap_or_default()
+    }
 }
 impl<AllocU8:Allocator<u8> > Default for Mux<AllocU8> {
     fn default() -> Self {
         Mux::<AllocU8> {
+            num_streams: NUM_STREAMS,
+            policy: MuxPolicy::default(),
             bytes_to_deserialize:BytesToDeserialize::None,
             cur_stream: 0,
             cur_stream_bytes_avail: 0,
@@ -94,23 +107,22 @@ impl<AllocU8:Allocator<u8> > Default for Mux<AllocU8> {
             buf:[
                 AllocatedMemoryRange::<u8, AllocU8>::default(),
                 AllocatedMemoryRange::<u8, AllocU8>::default(),
-/*              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),
-              AllocU8::AllocatedMemory::default(),*/
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
+                AllocatedMemoryRange::<u8, AllocU8>::default(),
             ],
-            last_flush:[0;NUM_STREAMS as usize],
+            last_flush:[0;MAX_NUM_STREAMS],
             bytes_flushed: 0,
         }
     }
@@ -119,20 +131,12 @@ impl<AllocU8: Allocator<u8> > StreamDemuxer<AllocU8> for Mux<AllocU8>{
     fn write_linear(&mut self, data:&[u8], m8: &mut AllocU8) -> usize {
         self.deserialize(data, m8)
     }
-    fn read_buffer(&mut self) -> [ReadableBytes; NUM_STREAMS] {
-        let (s0array, s1array) = self.buf.split_at_mut(1);
-        let s0 = &mut s0array[0];
-        let s1 = &mut s1array[0];
-        [
-            ReadableBytes{
-                data:s0.0.slice().split_at(s0.1.end).0,
-                read_offset:&mut s0.1.start,
-            },
-            ReadableBytes{
-                data:s1.0.slice().split_at(s1.1.end).0,
-                read_offset:&mut s1.1.start,
-            },
-        ]
+    fn read_buffer(&mut self, stream_id: StreamID) -> ReadableBytes<'_> {
+        let s = &mut self.buf[usize::from(stream_id)];
+        ReadableBytes{
+            data:s.0.slice().split_at(s.1.end).0,
+            read_offset:&mut s.1.start,
+        }
     }
     fn data_ready(&self, stream_id:StreamID) -> usize {
         self.how_much_data_avail(stream_id)
@@ -166,24 +170,13 @@ impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for Mux<AllocU8> {
         self.push_data(stream_id, data, m8);
         data.len()
     }
-    fn write_buffer(&mut self, m8: &mut AllocU8) -> [WritableBytes; NUM_STREAMS] { // FIXME: make this generic WRT NUM_STREAMS
+    fn write_buffer(&mut self, stream_id: StreamID, m8: &mut AllocU8) -> WritableBytes<'_> {
         const MIN_BYTES:usize = 16;
-        for index in 0..NUM_STREAMS {
-            self.prep_push_for_n_bytes(index as StreamID, MIN_BYTES, m8);
-        }
-        let (s0array, s1array) = self.buf.split_at_mut(1);
-        let s0 = &mut s0array[0];
-        let s1 = &mut s1array[0];
-        [
-            WritableBytes{
-                data:s0.0.slice_mut(),
-                write_offset:&mut s0.1.end,
-            },
-            WritableBytes{
-                data:s1.0.slice_mut(),
-                write_offset:&mut s1.1.end,
-            },
-        ]
+        let (buf, write_offset) = self.prep_push_for_n_bytes(stream_id, MIN_BYTES, m8);
+        WritableBytes{
+            data:buf.slice_mut(),
+            write_offset,
+        }
     }
     fn linearize(&mut self, output:&mut[u8]) -> usize {
         self.serialize(output)
@@ -197,6 +190,18 @@ impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for Mux<AllocU8> {
     fn free_mux(&mut self, m8: &mut AllocU8) {
         self.free(m8);
     }
+    fn set_policy(&mut self, policy: MuxPolicy) {
+        self.policy = policy;
+    }
+    fn set_stream_count(&mut self, count: StreamCount) {
+        self.num_streams = count.get();
+    }
+    fn linearize_pending(&mut self, output:&mut[u8]) -> usize {
+        match self.policy {
+            MuxPolicy::Throughput => 0,
+            MuxPolicy::LowLatency => self.flush_internal(output),
+        }
+    }
 }
 impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
    #[inline(always)]
@@ -211,7 +216,8 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
       self.buf[usize::from(stream_id)].1.start += count;
    }
     pub fn is_eof(&self) -> bool {
-        for index in 0..NUM_STREAMS as usize {
+        // also covers streams beyond num_streams, so stray chunks for them are never silently dropped
+        for index in 0..MAX_NUM_STREAMS {
             if self.read_cursor(index)  != self.write_cursor(index) {
                 return false;
             }
@@ -226,7 +232,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
        *write_cursor += data.len();
    }
    pub fn prealloc(&mut self, m8: &mut AllocU8, amount_per_stream: usize) {
-       for buf in self.buf.iter_mut() {
+       for buf in self.buf[..self.num_streams].iter_mut() {
            assert_eq!(buf.0.slice().len(), 0);
            let mfd = core::mem::replace(&mut buf.0, m8.alloc_cell(amount_per_stream));
            m8.free_cell(mfd);
@@ -451,7 +457,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
            let mut flushed_any = false;
            let mut last_flush = self.last_flush[0];
            let mut max_flush = self.last_flush[0];
-           for lf in self.last_flush[1..].iter() {
+           for lf in self.last_flush[1..self.num_streams].iter() {
                if *lf < last_flush {
                   last_flush = *lf;
                }
@@ -459,7 +465,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
                    max_flush = *lf;
                }
            }
-           for index in 0..(NUM_STREAMS as usize) {
+           for index in 0..self.num_streams {
                let mut is_lagging = max_flush  > MAX_FLUSH_VARIANCE + self.last_flush[index];
                if self.write_cursor(index) - self.read_cursor(index) >= chunk_size(self.last_flush[index],
                                                              is_lagging) && self.last_flush[index] <= last_flush + MAX_FLUSH_VARIANCE {
@@ -525,7 +531,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
         while output_offset < output.len() {
             let mut flushed_any = false;
             let mut last_flush: Option<usize> = None;
-            for (lf, buf) in self.last_flush.iter().zip(self.buf.iter()) {
+            for (lf, buf) in self.last_flush[..self.num_streams].iter().zip(self.buf[..self.num_streams].iter()) {
                 let rc = buf.1.start;
                 let wc = buf.1.end;
                 if match last_flush {
@@ -535,7 +541,7 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
                     last_flush = Some(*lf);
                 }
             }
-            for index in 0..(NUM_STREAMS as usize) {
+            for index in 0..self.num_streams {
                 if match last_flush {
                     None => true,
                     Some(last_flush_some) => self.last_flush[index] <= last_flush_some + MAX_FLUSH_VARIANCE,
@@ -562,14 +568,14 @@ impl<AllocU8:Allocator<u8>> Mux<AllocU8> {
 }
 
 pub struct DevNull<AllocU8:Allocator<u8>> {
-    cursor: [usize; NUM_STREAMS as usize],
+    cursor: usize,
     empty: slice_util::AllocatedMemoryRange<u8, AllocU8>,
     _placeholder: core::marker::PhantomData<AllocU8>
 }
 impl<AllocU8: Allocator<u8> > Default for DevNull<AllocU8>{
     fn default() ->Self {
         DevNull::<AllocU8> {
-            cursor:[0;NUM_STREAMS as usize],
+            cursor:0,
             empty: slice_util::AllocatedMemoryRange::<u8, AllocU8>::default(),
             _placeholder: core::marker::PhantomData::<AllocU8>::default(),
         }
@@ -580,18 +586,11 @@ impl<AllocU8: Allocator<u8> > StreamDemuxer<AllocU8> for DevNull<AllocU8>{
         debug_assert_eq!(data.len(), 0);
         0
     }
-    fn read_buffer(&mut self) -> [ReadableBytes; NUM_STREAMS] {
-        let (s0loc, s1loc) = self.cursor.split_at_mut(1);
-        [
-            ReadableBytes{
-                data:&[],
-                read_offset:&mut s0loc[0],
-            },
-            ReadableBytes{
-                data:&[],
-                read_offset:&mut s1loc[0],
-            },
-        ]
+    fn read_buffer(&mut self, _stream_id: StreamID) -> ReadableBytes<'_> {
+        ReadableBytes{
+            data:&[],
+            read_offset:&mut self.cursor,
+        }
     }
     fn data_ready(&self, _stream_id:StreamID) -> usize {
         0
@@ -617,18 +616,11 @@ impl<AllocU8: Allocator<u8> > StreamDemuxer<AllocU8> for DevNull<AllocU8>{
 
 
 impl<AllocU8:Allocator<u8> > StreamMuxer<AllocU8> for DevNull<AllocU8> {
-    fn write_buffer(&mut self, _m8: &mut AllocU8) -> [WritableBytes; NUM_STREAMS] {
-        let (s0loc, s1loc) = self.cursor.split_at_mut(1);
-        [
-            WritableBytes{
-                data:&mut [],
-                write_offset:&mut s0loc[0],
-            },
-            WritableBytes{
-                data:&mut [],
-                write_offset:&mut s1loc[0],
-            },
-        ]
+    fn write_buffer(&mut self, _stream_id: StreamID, _m8: &mut AllocU8) -> WritableBytes<'_> {
+        WritableBytes{
+            data:&mut [],
+            write_offset:&mut self.cursor,
+        }
     }
     fn write(&mut self, _stream_id: StreamID, data: &[u8], _m8: &mut AllocU8) -> usize {
         debug_assert_eq!(data.len(), 0);
@@ -2,7 +2,7 @@
 use core;
 use core::hash::Hasher;
 use ::interface;
-use ::interface::{NewWithAllocator};
+use ::interface::{NewWithAllocator, EntropyCoderSelection, CDFFamily, StreamCount, MuxPolicy};
 use ::DecoderSpecialization;
 use ::codec;
 use std::sync::{Arc, Mutex};
@@ -16,20 +16,28 @@ use ::interface::{DivansResult, DivansInputResult, ErrMsg};
 use ::ArithmeticEncoderOrDecoder;
 use ::alloc::{Allocator};
 use std::thread;
+use std::vec::Vec;
+use std::boxed::Box;
+use std::sync::Weak;
+use thread_pool::ResumableJob;
+use thread_trace::ThreadTrace;
+use filter::FilterChain;
 use super::divans_decompressor::StaticCommand;
 
+type WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> = codec::DivansCodec<DefaultDecoder,
+                                                                                          DecoderSpecialization,
+                                                                                          ThreadToMainDemuxer<AllocU8, BufferedMultiWorker<AllocU8, AllocCommand>>,
+                                                                                          DevNull<AllocU8>,
+                                                                                          interface::DefaultCDF16,
+                                                                                          AllocU8,
+                                                                                          AllocCDF16>;
+type SharedWorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> = Mutex<Option<WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>>>;
+
 pub struct ParallelDivansProcess<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8>,
                                  AllocU8:Allocator<u8>,
                                  AllocCDF16:Allocator<interface::DefaultCDF16>,
                                  AllocCommand:Allocator<StaticCommand>> {
-    codec: Arc<Mutex<Option<codec::DivansCodec<DefaultDecoder,
-                                         DecoderSpecialization,
-                                         ThreadToMainDemuxer<AllocU8, BufferedMultiWorker<AllocU8, AllocCommand>>,
-                                         DevNull<AllocU8>,
-                                         interface::DefaultCDF16,
-                                         AllocU8,
-                                         AllocCDF16>>>,
-               >,
+    codec: Arc<SharedWorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>>,
     worker: MultiWorker<AllocU8, AllocCommand>,
     literal_decoder: Option<DivansDecoderCodec<interface::DefaultCDF16,
                                                AllocU8,
@@ -64,14 +72,20 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
         let mut mc: AllocCommand;
         let raw_header:[u8; interface::HEADER_LENGTH];
         let skip_crc:bool;
+        let capture_ir = header.capture_ir;
         m8 = header.m8.take().unwrap();
         raw_header = header.header;
         skip_crc = header.skip_crc;
         mcdf16 = header.mcdf16.take().unwrap();
         mc = header.mcommand.take().unwrap();
+        // the header parser has already rejected unknown entropy coders, CDF families, stream counts and mux policies
+        let entropy_coder = EntropyCoderSelection::from_header(&raw_header).unwrap_or_default();
+        let cdf_family = CDFFamily::from_header(&raw_header).unwrap_or_default();
+        let stream_count = StreamCount::from_header(&raw_header).unwrap_or_default();
+        let mux_policy = MuxPolicy::from_header(&raw_header).unwrap_or_default();
         //update this if you change the SelectedArithmeticDecoder macro
-        let cmd_decoder = DefaultDecoder::new(&mut m8);
-        let lit_decoder = DefaultDecoder::new(&mut m8);
+        let cmd_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, entropy_coder);
+        let lit_decoder = DefaultDecoder::new_with_entropy_coder(&mut m8, entropy_coder);
         let linear_input_bytes = ThreadToMainDemuxer::<AllocU8,BufferedMultiWorker<AllocU8, AllocCommand>>::new(
             BufferedMultiWorker::<AllocU8, AllocCommand>::new(&mut mc));
         let mut codec = codec::DivansCodec::<DefaultDecoder,
@@ -92,45 +106,47 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                                                               None,
                                                           true,
                                                               codec::StrideSelection::UseBrotliRec,
-                                                              skip_crc);
+                                                              skip_crc,
+                                                              cdf_family);
         if !skip_crc {
             codec.get_crc().write(&raw_header[..]);
         }
-        let main_thread_codec = codec.fork(&mut mc);
+        codec.demuxer().worker.set_mux_policy(mux_policy);
+        let mut main_thread_codec = codec.fork(&mut mc);
         assert_eq!(*codec.get_crc(), main_thread_codec.crc);
-        let multi_worker = (codec.demuxer().worker).worker.clone();
+        main_thread_codec.demuxer = Mux::new(stream_count);
+        // the header parser has already rejected unknown filters
+        main_thread_codec.set_filters(FilterChain::from_header(&raw_header).unwrap_or_default());
+        main_thread_codec.set_command_log(capture_ir);
+        let mut multi_worker = (codec.demuxer().worker).worker.clone();
         let thread_codec = Arc::new(Mutex::new(Some(codec)));
         let worker_codec = thread_codec.clone();
-        thread::spawn(move || {
-            let mut guard = thread_codec.lock().unwrap();
-            if let Some(ref mut process_codec) = *guard {
-                let mut unused_out = 0usize;
-                let mut unused_in = 0usize;
-                let mut unused = 0usize;
-                loop {
-                    match process_codec.encode_or_decode(
-                        &[],
-                        &mut unused_in,
-                        &mut [],
-                        &mut unused_out,
-                        &codec::EmptyCommandArray::default(),
-                        &mut unused) {
-                        DivansResult::Success => break, // DONE
-                        DivansResult::Failure(e) => {
-                            
-                            process_codec.demuxer().broadcast_err(e); // HANDLE FAILURE BY TELLING MAIN THREAD
-                            return;
-                        },
-                        DivansResult::NeedsMoreInput => {
-                            //eprintln!("W_RETRY_PULL");//unimplemented!(); // we should block here--- maybe this is an error
-                        },
-                        DivansResult::NeedsMoreOutput => {}, // lets make room for more output
+        if let Some(executor) = header.executor.take() {
+            let job_codec = Arc::downgrade(&thread_codec);
+            let job = ResumableJob::new(executor, Box::new(move || step_pooled_worker(&job_codec)));
+            if let Some(ref mut process_codec) = *thread_codec.lock().unwrap() {
+                process_codec.demuxer().worker.worker.set_job(job.clone());
+            }
+            multi_worker.set_job(job.clone());
+            ResumableJob::wake(&job);
+        } else {
+            thread::spawn(move || {
+                let mut guard = thread_codec.lock().unwrap();
+                if let Some(ref mut process_codec) = *guard {
+                    loop {
+                        match step_worker(process_codec) {
+                            DivansResult::Success | DivansResult::Failure(_) => return,
+                            DivansResult::NeedsMoreInput => {
+                                //eprintln!("W_RETRY_PULL");//unimplemented!(); // we should block here--- maybe this is an error
+                            },
+                            DivansResult::NeedsMoreOutput => {}, // lets make room for more output
+                        }
                     }
+                } else {
+                    panic!("Thread started with None-process_codec")
                 }
-            } else {
-                panic!("Thread started with None-process_codec")
-            }
-        });
+            });
+        }
         ParallelDivansProcess::<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand> {
             mcommand:mc,
             codec:worker_codec,
@@ -151,6 +167,25 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
             codec.free_ref();
         }
     }
+    pub fn take_ir_commands(&mut self, commands: &mut Vec<::ir::IrCommand>) {
+        if let Some(ref mut literal_decoder) = self.literal_decoder {
+            literal_decoder.take_logged_commands(commands);
+        }
+    }
+    // blocks until the worker side releases the codec, so call it once decoding has finished
+    pub fn thread_trace(&self) -> ThreadTrace {
+        let mut trace = ThreadTrace {
+            main: self.worker.trace_events(),
+            worker: Vec::new(),
+            dropped: self.worker.dropped_trace_events(),
+        };
+        if let Some(ref mut codec) = *self.codec.lock().unwrap() {
+            let worker = &codec.demuxer().worker.worker;
+            trace.worker = worker.trace_events();
+            trace.dropped += worker.dropped_trace_events();
+        }
+        trace
+    }
     pub fn free(mut self) -> (AllocU8, AllocCDF16, AllocCommand) {
         use codec::NUM_ARITHMETIC_CODERS;
         self.worker.broadcast_err(ErrMsg::UnexpectedEof); // in case we still have a worker holding the lock, not done
@@ -187,7 +222,7 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
                     break; // we have successfully delivered a buffer to our worker and then can, at worst pull the result
                 }
             }
-            let retval = literal_decoder.decode_process_output(
+            let retval = literal_decoder.decode_process_filtered_output(
                 &mut self.worker,
                 output,
                 output_offset);
@@ -206,3 +241,43 @@ impl<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + in
     }
 }
 
+fn step_worker<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
+               AllocU8:Allocator<u8>,
+               AllocCDF16:Allocator<interface::DefaultCDF16>,
+               AllocCommand:Allocator<StaticCommand>>(
+    process_codec: &mut WorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>) -> DivansResult {
+    let mut unused_out = 0usize;
+    let mut unused_in = 0usize;
+    let mut unused = 0usize;
+    let ret = process_codec.encode_or_decode(
+        &[],
+        &mut unused_in,
+        &mut [],
+        &mut unused_out,
+        &codec::EmptyCommandArray::default(),
+        &mut unused);
+    if let DivansResult::Failure(e) = ret {
+        process_codec.demuxer().broadcast_err(e); // HANDLE FAILURE BY TELLING MAIN THREAD
+    }
+    ret
+}
+
+// runs the worker until it would have to wait on the main thread; true once it has finished
+fn step_pooled_worker<DefaultDecoder: ArithmeticEncoderOrDecoder + NewWithAllocator<AllocU8> + interface::BillingCapability,
+                      AllocU8:Allocator<u8>,
+                      AllocCDF16:Allocator<interface::DefaultCDF16>,
+                      AllocCommand:Allocator<StaticCommand>>(
+    job_codec: &Weak<SharedWorkerCodec<DefaultDecoder, AllocU8, AllocCDF16, AllocCommand>>) -> bool {
+    let thread_codec = match job_codec.upgrade() {
+        Some(thread_codec) => thread_codec,
+        None => return true, // the decompressor was dropped
+    };
+    let mut guard = thread_codec.lock().unwrap();
+    match *guard {
+        Some(ref mut process_codec) => match step_worker(process_codec) {
+            DivansResult::Success | DivansResult::Failure(_) => true,
+            DivansResult::NeedsMoreInput | DivansResult::NeedsMoreOutput => false,
+        },
+        None => true, // the decompressor was freed
+    }
+}
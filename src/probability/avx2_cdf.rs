@@ -0,0 +1,81 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// AVX2 versions of the FrequentistCDF16 updates, selected at runtime by cpu_features.
+// Each one mirrors the scalar wrapping i16/i32 arithmetic lane for lane.
+use core;
+use core::arch::x86_64::*;
+use super::interface::{Prob, Speed, BLEND_FIXED_POINT_PRECISION};
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn blend(cdf: &mut [Prob; 16], symbol: u8, speed: Speed) {
+    let ptr = cdf.as_mut_ptr() as *mut __m256i;
+    let index = _mm256_setr_epi16(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+    let at_or_after_symbol = _mm256_cmpgt_epi16(index, _mm256_set1_epi16(i16::from(symbol) - 1));
+    let increment = _mm256_and_si256(at_or_after_symbol, _mm256_set1_epi16(speed.inc()));
+    let mut data = _mm256_add_epi16(_mm256_loadu_si256(ptr), increment);
+    _mm256_storeu_si256(ptr, data);
+    if cdf[15] >= speed.lim() {
+        let bias = _mm256_add_epi16(index, _mm256_set1_epi16(1));
+        data = _mm256_add_epi16(data, bias);
+        data = _mm256_sub_epi16(data, _mm256_srai_epi16(data, 2));
+        _mm256_storeu_si256(ptr, data);
+    }
+}
+
+// eight lanes of FrequentistCDF16::average, widened to i32
+#[target_feature(enable = "avx2")]
+#[inline]
+unsafe fn mix_half(ours: __m128i, theirs: __m128i,
+                   (othermax, ourmax, mix_rate, inv_mix_rate): (__m256i, __m256i, __m256i, __m256i),
+                   desired_shift: __m128i) -> __m256i {
+    let rescaled_self = _mm256_sra_epi32(_mm256_mullo_epi32(_mm256_cvtepi16_epi32(ours), othermax), desired_shift);
+    let rescaled_other = _mm256_sra_epi32(_mm256_mullo_epi32(_mm256_cvtepi16_epi32(theirs), ourmax), desired_shift);
+    let mixed = _mm256_add_epi32(_mm256_add_epi32(_mm256_mullo_epi32(rescaled_self, mix_rate),
+                                                  _mm256_mullo_epi32(rescaled_other, inv_mix_rate)),
+                                 _mm256_set1_epi32(1));
+    // keep the low 16 bits so the unsigned pack truncates like `as Prob`
+    _mm256_and_si256(_mm256_srai_epi32(mixed, BLEND_FIXED_POINT_PRECISION as i32), _mm256_set1_epi32(0xffff))
+}
+
+#[target_feature(enable = "avx2")]
+pub unsafe fn average(ours: &[Prob; 16], theirs: &[Prob; 16], mix_rate: i32) -> [Prob; 16] {
+    let ourmax = i32::from(ours[15]);
+    let othermax = i32::from(theirs[15]);
+    let leading_zeros_combo = core::cmp::min((ourmax * othermax).leading_zeros(), 17);
+    let desired_shift = _mm_cvtsi32_si128((17 - leading_zeros_combo) as i32);
+    let inv_mix_rate = (1 << BLEND_FIXED_POINT_PRECISION) - mix_rate;
+    let our_data = _mm256_loadu_si256(ours.as_ptr() as *const __m256i);
+    let their_data = _mm256_loadu_si256(theirs.as_ptr() as *const __m256i);
+    let factors = (_mm256_set1_epi32(othermax), _mm256_set1_epi32(ourmax),
+                   _mm256_set1_epi32(mix_rate), _mm256_set1_epi32(inv_mix_rate));
+    let low = mix_half(_mm256_castsi256_si128(our_data), _mm256_castsi256_si128(their_data),
+                       factors, desired_shift);
+    let high = mix_half(_mm256_extracti128_si256(our_data, 1), _mm256_extracti128_si256(their_data, 1),
+                        factors, desired_shift);
+    let packed = _mm256_permute4x64_epi64(_mm256_packus_epi32(low, high), 0b11_01_10_00);
+    let mut retval = [0 as Prob; 16];
+    _mm256_storeu_si256(retval.as_mut_ptr() as *mut __m256i, packed);
+    retval
+}
+
+// the first symbol whose cdf exceeds offset, considering only the first 15 entries
+#[target_feature(enable = "avx2")]
+pub unsafe fn offset_to_sym(cdf: &[Prob; 16], rescaled_cdf_offset: Prob) -> u8 {
+    let data = _mm256_loadu_si256(cdf.as_ptr() as *const __m256i);
+    let above = _mm256_cmpgt_epi16(data, _mm256_set1_epi16(rescaled_cdf_offset));
+    let above_bytes = _mm_packs_epi16(_mm256_castsi256_si128(above), _mm256_extracti128_si256(above, 1));
+    let mask = _mm_movemask_epi8(above_bytes) as u32;
+    (mask | 0x8000).trailing_zeros() as u8
+}
@@ -1,3 +1,4 @@
+use interface::CDFFamily;
 use super::interface::{Prob, BaseCDF, Speed, CDF_MAX, CDF16, BLEND_FIXED_POINT_PRECISION};
 
 
@@ -183,6 +184,9 @@ impl CDF16 for BlendCDF16 {
         retval.blend_internal(other.cdf, mix_rate);
         retval
     }
+    fn family(&self) -> CDFFamily {
+        CDFFamily::Blend
+    }
     fn blend(&mut self, symbol:u8, speed: Speed) {
         self.count = self.count.wrapping_add(1);
         let _mix_rate = match speed {
@@ -1,5 +1,7 @@
 use core;
 use super::numeric;
+use interface::CDFFamily;
+use cpu_features::CpuFeatures;
 pub type Prob = i16; // can be i32
 pub const
 MAX_FREQUENTIST_PROB: Prob = 0xa00;
@@ -421,6 +423,31 @@ impl core::str::FromStr for Speed {
 pub trait CDF16: Sized + Default + Copy + BaseCDF {
     fn blend(&mut self, symbol: u8, dyn:Speed);
     fn average(&self, other: &Self, mix_rate: i32) ->Self;
+    // the model family recorded in the stream header
+    fn family(&self) -> CDFFamily {
+        CDFFamily::Frequentist
+    }
+    // an untrained CDF of the given family, or None if this type cannot model it
+    fn new_in_family(family: CDFFamily) -> Option<Self> {
+        let cdf = Self::default();
+        if cdf.family() == family {
+            Some(cdf)
+        } else {
+            None
+        }
+    }
+    // an untrained CDF of the same family as this one
+    fn untrained(&self) -> Self {
+        Self::new_in_family(self.family()).unwrap_or_default().with_kernels(self.kernels())
+    }
+    // the SIMD kernels this CDF updates with
+    fn kernels(&self) -> CpuFeatures {
+        CpuFeatures::default()
+    }
+    // the same CDF, updating with the given SIMD kernels where it has any
+    fn with_kernels(self, _kernels: CpuFeatures) -> Self {
+        self
+    }
 }
 
 pub const BLEND_FIXED_POINT_PRECISION : i8 = 15;
@@ -478,6 +505,18 @@ impl<Cdf16> CDF16 for DebugWrapperCDF16<Cdf16> where Cdf16: CDF16 {
             rolling_entropy_sum: (self.rolling_entropy_sum + other.rolling_entropy_sum)
         }
     }
+    fn family(&self) -> CDFFamily {
+        self.cdf.family()
+    }
+    fn new_in_family(family: CDFFamily) -> Option<Self> {
+        Cdf16::new_in_family(family).map(|cdf| Self { cdf, ..Self::default() })
+    }
+    fn kernels(&self) -> CpuFeatures {
+        self.cdf.kernels()
+    }
+    fn with_kernels(self, kernels: CpuFeatures) -> Self {
+        Self { cdf: self.cdf.with_kernels(kernels), ..self }
+    }
 }
 
 #[cfg(feature="debug_entropy")]
@@ -28,6 +28,9 @@ mod variant_speed_cdf;
 #[cfg(feature="simd")]
 pub mod simd_frequentist_cdf;
 pub mod opt_frequentist_cdf;
+pub mod selectable_cdf;
+#[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+mod avx2_cdf;
 
 pub use self::interface::{BaseCDF, CDF16, CDF2, Speed, SpeedPalette, Prob, LOG2_SCALE, BLEND_FIXED_POINT_PRECISION, ProbRange, SPEED_PALETTE_SIZE};
 #[cfg(feature="debug_entropy")]
@@ -38,4 +41,5 @@ pub use self::external_cdf::ExternalProbCDF16;
 #[cfg(feature="simd")]
 pub use self::simd_frequentist_cdf::SIMDFrequentistCDF16;
 pub use self::opt_frequentist_cdf::OptFrequentistCDF16;
+pub use self::selectable_cdf::SelectableCDF16;
 pub use self::variant_speed_cdf::VariantSpeedCDF;
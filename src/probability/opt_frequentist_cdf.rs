@@ -1,7 +1,10 @@
 use core;
-use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, LOG2_SCALE, CDF_BITS};
+use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, LOG2_SCALE, CDF_BITS, SymStartFreq};
 use super::frequentist_cdf::FrequentistCDF16;
 use super::numeric;
+#[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+use super::avx2_cdf;
+use cpu_features::{self, CpuFeatures};
 fn to_bit_i32(val: i32, shift_val: u8) -> u32 {
     if val != 0 {
         1 << shift_val
@@ -56,20 +59,23 @@ fn movemask_epi8_i32(data:[i32;8]) -> u32{
 pub struct OptFrequentistCDF16 {
     pub cdf: FrequentistCDF16,
     pub inv_max_and_bitlen: (i64, u8),
+    // picked by the codec that owns this CDF
+    avx2: bool,
 }
 
 impl OptFrequentistCDF16 {
-    fn new(input:FrequentistCDF16) -> Self {
+    fn new(input:FrequentistCDF16, avx2: bool) -> Self {
         OptFrequentistCDF16{
             cdf:input,
             inv_max_and_bitlen: numeric::lookup_divisor(input.max()),
+            avx2,
         }
     }
 }
 
 impl Default for OptFrequentistCDF16 {
     fn default() -> Self {
-        Self::new(FrequentistCDF16::default())
+        Self::new(FrequentistCDF16::default(), cpu_features::detected().avx2)
     }
 }
 
@@ -100,6 +106,19 @@ impl BaseCDF for OptFrequentistCDF16 {
         assert!(LOG2_SCALE as usize + CDF_BITS <= numeric::LOG_MAX_NUMERATOR);
         numeric::fast_divide_30bit_by_16bit(num, self.inv_max_and_bitlen)
     }
+    #[inline(always)]
+    fn cdf_offset_to_sym_start_and_freq(&self, cdf_offset_p: Prob) -> SymStartFreq {
+        let rescaled_cdf_offset = ((i32::from(cdf_offset_p) * i32::from(self.max())) >> LOG2_SCALE) as Prob;
+        #[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+        {
+            if self.avx2 {
+                let sym = unsafe { avx2_cdf::offset_to_sym(&self.cdf.cdf, rescaled_cdf_offset) };
+                return self.sym_to_start_and_freq(sym);
+            }
+        }
+        let sym = self.cdf.cdf[..15].iter().position(|c| rescaled_cdf_offset < *c).unwrap_or(15);
+        self.sym_to_start_and_freq(sym as u8)
+    }
 }
 
 fn k16bit_length(d:i16) -> u8 {
@@ -109,13 +128,35 @@ const LOG_MAX_NUMERATOR: usize = LOG2_SCALE as usize + CDF_BITS;
 
 impl CDF16 for OptFrequentistCDF16 {
     fn average(&self, other:&Self, mix_rate:i32) -> Self {
+        #[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+        {
+            if self.avx2 {
+                let cdf = unsafe { avx2_cdf::average(&self.cdf.cdf, &other.cdf.cdf, mix_rate) };
+                return Self::new(FrequentistCDF16 { cdf }, self.avx2);
+            }
+        }
         let ret = self.cdf.average(&other.cdf, mix_rate);
-        Self::new(ret)
+        Self::new(ret, self.avx2)
     }
     fn blend(&mut self, symbol: u8, speed: Speed) {
+        #[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+        {
+            if self.avx2 {
+                unsafe { avx2_cdf::blend(&mut self.cdf.cdf, symbol, speed) };
+                self.inv_max_and_bitlen = numeric::lookup_divisor(self.max());
+                return;
+            }
+        }
         self.cdf.blend(symbol, speed);
         self.inv_max_and_bitlen = numeric::lookup_divisor(self.max());
     }
+    fn kernels(&self) -> CpuFeatures {
+        CpuFeatures { avx2: self.avx2, ..CpuFeatures::default() }
+    }
+    fn with_kernels(self, kernels: CpuFeatures) -> Self {
+        // the AVX2 kernels only run where the CPU has them
+        Self { avx2: kernels.avx2 && cpu_features::detected().avx2, ..self }
+    }
 }
 
 #[cfg(test)]
@@ -132,4 +173,43 @@ mod test {
                                             &mut OptFrequentistCDF16::default(),
                                             &mut OptFrequentistCDF16::default());
     }
+    #[cfg(all(not(feature="safe"), target_arch="x86_64"))]
+    #[test]
+    fn test_cdf_avx2_eq_scalar() {
+        use super::{avx2_cdf, FrequentistCDF16, CDF16, BaseCDF, Speed};
+        use super::super::interface::BLEND_FIXED_POINT_PRECISION;
+        if !::cpu_features::detected().avx2 {
+            return;
+        }
+        let speeds = [Speed::GEOLOGIC, Speed::GLACIAL, Speed::MUD, Speed::SLOW,
+                      Speed::MED, Speed::FAST, Speed::PLANE, Speed::ROCKET];
+        let mut state = 0x2545_f491u32;
+        let mut rand = move || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            state >> 8
+        };
+        let mut scalar = [FrequentistCDF16::default(); 2];
+        let mut simd = scalar;
+        for round in 0..20000 {
+            let which = (round & 1) as usize;
+            let symbol = (rand() & 0xf) as u8;
+            let speed = speeds[rand() as usize % speeds.len()];
+            scalar[which].blend(symbol, speed);
+            unsafe { avx2_cdf::blend(&mut simd[which].cdf, symbol, speed) };
+            assert_eq!(scalar[which].cdf, simd[which].cdf);
+            if round % 97 == 0 {
+                let mix_rate = (rand() % ((1 << BLEND_FIXED_POINT_PRECISION) + 1)) as i32;
+                let expected = scalar[0].average(&scalar[1], mix_rate);
+                assert_eq!(expected.cdf, unsafe { avx2_cdf::average(&simd[0].cdf, &simd[1].cdf, mix_rate) });
+            }
+            if round % 13 == 0 {
+                let cdf = &scalar[which].cdf;
+                for offset in 0..cdf[15] + 1 {
+                    let expected = cdf[..15].iter().position(|c| offset < *c).unwrap_or(15) as u8;
+                    assert_eq!(expected, unsafe { avx2_cdf::offset_to_sym(cdf, offset) });
+                }
+            }
+        }
+        assert!(scalar[0].valid() && scalar[1].valid());
+    }
 }
@@ -0,0 +1,155 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// A CDF16 whose model family is picked per stream, from the options when compressing
+// and from the stream header when decompressing.
+use interface::CDFFamily;
+use cpu_features::CpuFeatures;
+use super::interface::{Prob, BaseCDF, Speed, CDF16, SymStartFreq};
+use super::blend_cdf::BlendCDF16;
+
+// The frequentist implementation is still controlled by feature flags; they all produce the same stream.
+#[cfg(feature="uncached_frequentist")]
+type FrequentistImpl = super::FrequentistCDF16;
+#[cfg(all(not(feature="uncached_frequentist"), feature="simd"))]
+type FrequentistImpl = super::SIMDFrequentistCDF16;
+#[cfg(not(any(feature="uncached_frequentist", feature="simd")))]
+type FrequentistImpl = super::OptFrequentistCDF16;
+
+#[derive(Clone,Copy)]
+pub enum SelectableCDF16 {
+    Frequentist(FrequentistImpl),
+    Blend(BlendCDF16),
+}
+
+impl Default for SelectableCDF16 {
+    fn default() -> Self {
+        SelectableCDF16::Frequentist(FrequentistImpl::default())
+    }
+}
+
+macro_rules! dispatch_cdf(
+    ($slf: expr, $cdf: ident => $action: expr) => {
+        match $slf {
+            SelectableCDF16::Frequentist(ref $cdf) => $action,
+            SelectableCDF16::Blend(ref $cdf) => $action,
+        }
+    };
+);
+
+impl BaseCDF for SelectableCDF16 {
+    fn num_symbols() -> u8 { 16 }
+    #[inline(always)]
+    fn cdf(&self, symbol: u8) -> Prob {
+        dispatch_cdf!(*self, cdf => cdf.cdf(symbol))
+    }
+    #[inline(always)]
+    fn div_by_max(&self, val: i32) -> i32 {
+        dispatch_cdf!(*self, cdf => cdf.div_by_max(val))
+    }
+    #[inline(always)]
+    fn max(&self) -> Prob {
+        dispatch_cdf!(*self, cdf => cdf.max())
+    }
+    fn log_max(&self) -> Option<i8> {
+        dispatch_cdf!(*self, cdf => cdf.log_max())
+    }
+    fn used(&self) -> bool {
+        dispatch_cdf!(*self, cdf => cdf.used())
+    }
+    fn valid(&self) -> bool {
+        dispatch_cdf!(*self, cdf => cdf.valid())
+    }
+    #[inline(always)]
+    fn sym_to_start_and_freq(&self, sym: u8) -> SymStartFreq {
+        dispatch_cdf!(*self, cdf => cdf.sym_to_start_and_freq(sym))
+    }
+    #[inline(always)]
+    fn rescaled_cdf(&self, sym: u8) -> i32 {
+        dispatch_cdf!(*self, cdf => cdf.rescaled_cdf(sym))
+    }
+    #[inline(always)]
+    fn cdf_offset_to_sym_start_and_freq(&self, cdf_offset_p: Prob) -> SymStartFreq {
+        dispatch_cdf!(*self, cdf => cdf.cdf_offset_to_sym_start_and_freq(cdf_offset_p))
+    }
+}
+
+impl CDF16 for SelectableCDF16 {
+    #[inline(always)]
+    fn blend(&mut self, symbol: u8, speed: Speed) {
+        match *self {
+            SelectableCDF16::Frequentist(ref mut cdf) => cdf.blend(symbol, speed),
+            SelectableCDF16::Blend(ref mut cdf) => cdf.blend(symbol, speed),
+        }
+    }
+    #[inline(always)]
+    fn average(&self, other: &Self, mix_rate: i32) -> Self {
+        match (self, other) {
+            (SelectableCDF16::Frequentist(ours), SelectableCDF16::Frequentist(theirs)) =>
+                SelectableCDF16::Frequentist(ours.average(theirs, mix_rate)),
+            (SelectableCDF16::Blend(ours), SelectableCDF16::Blend(theirs)) =>
+                SelectableCDF16::Blend(ours.average(theirs, mix_rate)),
+            // every CDF of a stream belongs to the stream's family
+            _ => unreachable!(),
+        }
+    }
+    fn family(&self) -> CDFFamily {
+        match *self {
+            SelectableCDF16::Frequentist(_) => CDFFamily::Frequentist,
+            SelectableCDF16::Blend(_) => CDFFamily::Blend,
+        }
+    }
+    fn new_in_family(family: CDFFamily) -> Option<Self> {
+        Some(match family {
+            CDFFamily::Frequentist => SelectableCDF16::Frequentist(FrequentistImpl::default()),
+            CDFFamily::Blend => SelectableCDF16::Blend(BlendCDF16::default()),
+        })
+    }
+    fn kernels(&self) -> CpuFeatures {
+        dispatch_cdf!(*self, cdf => cdf.kernels())
+    }
+    fn with_kernels(self, kernels: CpuFeatures) -> Self {
+        match self {
+            SelectableCDF16::Frequentist(cdf) => SelectableCDF16::Frequentist(cdf.with_kernels(kernels)),
+            SelectableCDF16::Blend(cdf) => SelectableCDF16::Blend(cdf.with_kernels(kernels)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SelectableCDF16;
+    declare_common_tests!(SelectableCDF16);
+
+    #[test]
+    fn test_cdf_selectable_eq_members() {
+        use super::{BlendCDF16, CDF16, CDFFamily, FrequentistImpl, Speed};
+        use super::super::common_tests;
+        common_tests::operation_test_helper(&mut FrequentistImpl::default(),
+                                            &mut FrequentistImpl::default(),
+                                            &mut SelectableCDF16::new_in_family(CDFFamily::Frequentist).unwrap(),
+                                            &mut SelectableCDF16::new_in_family(CDFFamily::Frequentist).unwrap());
+        // BlendCDF16 does not average towards either input, so only compare the two directly
+        let mut blend = [BlendCDF16::default(); 2];
+        let mut selectable = [SelectableCDF16::new_in_family(CDFFamily::Blend).unwrap(); 2];
+        for i in 0..200 {
+            let which = i & 1;
+            blend[which].blend((i * 7 & 0xf) as u8, Speed::MED);
+            selectable[which].blend((i * 7 & 0xf) as u8, Speed::MED);
+            let averaged = selectable[0].average(&selectable[1], 1 << 13);
+            common_tests::assert_cdf_eq(&blend[0].average(&blend[1], 1 << 13), &averaged);
+            common_tests::assert_cdf_eq(&blend[which], &selectable[which]);
+        }
+    }
+}
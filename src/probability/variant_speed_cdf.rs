@@ -1,4 +1,6 @@
 use core;
+use interface::CDFFamily;
+use cpu_features::CpuFeatures;
 use super::interface::{Prob, BaseCDF, Speed, CDF16, BLEND_FIXED_POINT_PRECISION, SPEED_PALETTE_SIZE, SymStartFreq};
 use brotli::enc::util::FastLog2u16;
 
@@ -31,6 +33,24 @@ impl<ChildCDF:BaseCDF+CDF16+Sized+Default> CDF16 for VariantSpeedCDF<ChildCDF> {
         ret.cdf[0] = self.cdf[0].average(&other.cdf[0], mix_rate);
         ret
     }
+    fn family(&self) -> CDFFamily {
+        self.cdf[0].family()
+    }
+    fn new_in_family(family: CDFFamily) -> Option<Self> {
+        ChildCDF::new_in_family(family).map(|child| VariantSpeedCDF {
+            cdf: [child; SPEED_PALETTE_SIZE + 1],
+            cost: [0.0; SPEED_PALETTE_SIZE + 1],
+        })
+    }
+    fn kernels(&self) -> CpuFeatures {
+        self.cdf[0].kernels()
+    }
+    fn with_kernels(mut self, kernels: CpuFeatures) -> Self {
+        for cdf in self.cdf.iter_mut() {
+            *cdf = cdf.with_kernels(kernels);
+        }
+        self
+    }
 }
 
 impl<ChildCDF:BaseCDF+CDF16+Sized> BaseCDF for VariantSpeedCDF<ChildCDF> {
@@ -0,0 +1,243 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// A carry-less range coder (Subbotin style): instead of propagating carries the range is
+// shrunk whenever it straddles a byte boundary while small, costing a little precision.
+use core;
+use probability::{CDF16, Prob, ProbRange, LOG2_SCALE};
+use super::interface::ArithmeticEncoderOrDecoder;
+use super::DivansResult;
+use super::arithmetic_coder::{
+    EntropyEncoder,
+    EntropyDecoder,
+    ByteQueue,
+};
+
+const TOP: u32 = 1 << 24;
+const BOT: u32 = 1 << 16;
+// the most bytes a single symbol can shift out, with room for two symbols between drains
+const QUEUE_SIZE: usize = 16;
+
+#[derive(Clone)]
+pub struct ArrayQueue {
+    data: [u8; QUEUE_SIZE],
+    start: u8,
+    end: u8,
+}
+
+impl Default for ArrayQueue {
+    fn default() -> Self {
+        ArrayQueue {
+            data: [0; QUEUE_SIZE],
+            start: 0,
+            end: 0,
+        }
+    }
+}
+
+impl ByteQueue for ArrayQueue {
+    fn num_push_bytes_avail(&self) -> usize {
+        QUEUE_SIZE - usize::from(self.end)
+    }
+    fn num_pop_bytes_avail(&self) -> usize {
+        usize::from(self.end - self.start)
+    }
+    fn push_data(&mut self, data: &[u8]) -> usize {
+        let count = core::cmp::min(self.num_push_bytes_avail(), data.len());
+        let end = usize::from(self.end);
+        self.data[end..end + count].clone_from_slice(&data[..count]);
+        self.end += count as u8;
+        count
+    }
+    fn pop_data(&mut self, data: &mut [u8]) -> usize {
+        let count = core::cmp::min(self.num_pop_bytes_avail(), data.len());
+        let start = usize::from(self.start);
+        data[..count].clone_from_slice(&self.data[start..start + count]);
+        self.start += count as u8;
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+        count
+    }
+}
+
+// calls shift_out for every byte the coder settles, leaving range at least BOT
+#[inline(always)]
+fn normalize<F: FnMut(u32)>(low: &mut u32, range: &mut u32, mut shift_out: F) {
+    loop {
+        if (*low ^ low.wrapping_add(*range)) >= TOP {
+            if *range >= BOT {
+                return;
+            }
+            *range = low.wrapping_neg() & (BOT - 1);
+        }
+        shift_out(*low >> 24);
+        *low <<= 8;
+        *range <<= 8;
+    }
+}
+
+pub struct RangeEncoder {
+    low: u32,
+    range: u32,
+    queue: ArrayQueue,
+}
+
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        RangeEncoder {
+            low: 0,
+            range: 0xffff_ffff,
+            queue: ArrayQueue::default(),
+        }
+    }
+}
+
+impl RangeEncoder {
+    fn encode(&mut self, start: u32, freq: u32, log_total: u32) {
+        debug_assert!(freq != 0);
+        let r = self.range >> log_total;
+        self.low = self.low.wrapping_add(start * r);
+        self.range = freq * r;
+        let queue = &mut self.queue;
+        normalize(&mut self.low, &mut self.range, |byte| {
+            let pushed = queue.push_data(&[byte as u8]);
+            debug_assert_eq!(pushed, 1);
+        });
+    }
+    fn mov_internal(&mut self) -> Self {
+        core::mem::take(self)
+    }
+}
+
+impl EntropyEncoder for RangeEncoder {
+    type Queue = ArrayQueue;
+    fn get_internal_buffer_mut(&mut self) -> &mut ArrayQueue {
+        &mut self.queue
+    }
+    fn get_internal_buffer(&self) -> &ArrayQueue {
+        &self.queue
+    }
+    fn put_bit(&mut self, bit: bool, prob_of_false: u8) {
+        let prob = u32::from(core::cmp::max(prob_of_false, 1));
+        if bit {
+            self.encode(prob, 256 - prob, 8);
+        } else {
+            self.encode(0, prob, 8);
+        }
+    }
+    fn put_nibble<C: CDF16>(&mut self, nibble: u8, prob: &C) -> ProbRange {
+        let start_freq = prob.sym_to_start_and_freq(nibble).range;
+        self.encode(start_freq.start as u32, start_freq.freq as u32, LOG2_SCALE);
+        start_freq
+    }
+    fn flush(&mut self) {
+        for _ in 0..4 {
+            let byte = [(self.low >> 24) as u8];
+            self.queue.push_data(&byte[..]);
+            self.low <<= 8;
+        }
+    }
+}
+
+impl ArithmeticEncoderOrDecoder for RangeEncoder {
+    arithmetic_encoder_or_decoder_methods!();
+}
+
+#[derive(Clone)]
+pub struct RangeDecoder {
+    low: u32,
+    range: u32,
+    code: u32,
+    // bytes to shift into code before the next symbol
+    bytes_required: u8,
+}
+
+impl Default for RangeDecoder {
+    fn default() -> Self {
+        RangeDecoder {
+            low: 0,
+            range: 0xffff_ffff,
+            code: 0,
+            bytes_required: 4,
+        }
+    }
+}
+
+impl RangeDecoder {
+    // the offset of code within the range, scaled to 1 << log_total
+    fn offset(&self, log_total: u32) -> (u32, u32) {
+        debug_assert_eq!(self.bytes_required, 0);
+        let r = self.range >> log_total;
+        (core::cmp::min(self.code.wrapping_sub(self.low) / r, (1 << log_total) - 1), r)
+    }
+    fn advance(&mut self, start: u32, freq: u32, r: u32) {
+        self.low = self.low.wrapping_add(start * r);
+        self.range = freq * r;
+        let mut shifted = 0u8;
+        normalize(&mut self.low, &mut self.range, |_| shifted += 1);
+        self.bytes_required = shifted;
+    }
+}
+
+impl ByteQueue for RangeDecoder {
+    fn num_push_bytes_avail(&self) -> usize {
+        usize::from(self.bytes_required)
+    }
+    fn num_pop_bytes_avail(&self) -> usize {
+        0
+    }
+    fn push_data(&mut self, data: &[u8]) -> usize {
+        let count = core::cmp::min(data.len(), usize::from(self.bytes_required));
+        for byte in data[..count].iter() {
+            self.code = (self.code << 8) | u32::from(*byte);
+        }
+        self.bytes_required -= count as u8;
+        count
+    }
+    fn pop_data(&mut self, _data: &mut [u8]) -> usize {
+        unreachable!()
+    }
+}
+
+impl EntropyDecoder for RangeDecoder {
+    type Queue = Self;
+    fn get_internal_buffer_mut(&mut self) -> &mut Self {
+        self
+    }
+    fn get_internal_buffer(&self) -> &Self {
+        self
+    }
+    fn get_bit(&mut self, prob_of_false: u8) -> bool {
+        let prob = u32::from(core::cmp::max(prob_of_false, 1));
+        let (offset, r) = self.offset(8);
+        let bit = offset >= prob;
+        if bit {
+            self.advance(prob, 256 - prob, r);
+        } else {
+            self.advance(0, prob, r);
+        }
+        bit
+    }
+    fn get_nibble<C: CDF16>(&mut self, prob: &C) -> (u8, ProbRange) {
+        let (offset, r) = self.offset(LOG2_SCALE);
+        let sym_start_freq = prob.cdf_offset_to_sym_start_and_freq(offset as Prob);
+        self.advance(sym_start_freq.range.start as u32, sym_start_freq.range.freq as u32, r);
+        (sym_start_freq.sym, sym_start_freq.range)
+    }
+    fn flush(&mut self) -> DivansResult {
+        DivansResult::Success
+    }
+}
@@ -15,18 +15,98 @@
 use core;
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 
+const HASH_BITS: u32 = 16;
+const HASH_MUL: u32 = 0x1e35_a7bd;
+pub const MIN_MATCH_LEN: u32 = 4;
 
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Match {
+    pub distance: u32,
+    pub len: u32,
+}
+
+// Hash chain over 4 byte prefixes. Positions are absolute stream offsets truncated to u32;
+// every candidate is verified against the ring buffer, so stale entries only cost time.
 pub struct HashMatch<AllocU32:Allocator<u32> > {
-    ht: AllocU32::AllocatedMemory,
+    head: AllocU32::AllocatedMemory,
+    chain: AllocU32::AllocatedMemory,
+    max_chain: u32,
 }
 impl<AllocU32:Allocator<u32> > HashMatch<AllocU32> {
-    pub fn new(m32: &mut AllocU32) -> Self {
+    pub fn new(m32: &mut AllocU32, window_size: u8, quality: u16) -> Self {
         HashMatch {
-          ht:m32.alloc_cell(128),
+          head:m32.alloc_cell(1 << HASH_BITS),
+          chain:m32.alloc_cell(1 << window_size),
+          max_chain: 1 << (core::cmp::min(quality, 11) / 2 + 1),
         }
     }
     pub fn free(&mut self, m32: &mut AllocU32) {
-       m32.free_cell(core::mem::replace(&mut self.ht, AllocU32::AllocatedMemory::default()));
+       m32.free_cell(core::mem::take(&mut self.head));
+       m32.free_cell(core::mem::take(&mut self.chain));
+    }
+    #[inline(always)]
+    fn hash(ring: &[u8], pos: u64) -> usize {
+        let mask = ring.len() as u64 - 1;
+        let word = u32::from(ring[(pos & mask) as usize])
+            | (u32::from(ring[((pos + 1) & mask) as usize]) << 8)
+            | (u32::from(ring[((pos + 2) & mask) as usize]) << 16)
+            | (u32::from(ring[((pos + 3) & mask) as usize]) << 24);
+        (word.wrapping_mul(HASH_MUL) >> (32 - HASH_BITS)) as usize
+    }
+    #[inline(always)]
+    fn match_len(ring: &[u8], pos: u64, distance: u32, max_len: u32) -> u32 {
+        let mask = ring.len() as u64 - 1;
+        let mut len = 0u32;
+        while len < max_len
+            && ring[((pos + u64::from(len)) & mask) as usize] == ring[((pos + u64::from(len) - u64::from(distance)) & mask) as usize] {
+            len += 1;
+        }
+        len
+    }
+    // records that the MIN_MATCH_LEN bytes starting at pos are present in the ring buffer
+    pub fn insert(&mut self, ring: &[u8], pos: u64) {
+        let h = Self::hash(ring, pos);
+        let chain_mask = self.chain.slice().len() as u64 - 1;
+        self.chain.slice_mut()[(pos & chain_mask) as usize] = self.head.slice()[h];
+        self.head.slice_mut()[h] = pos as u32;
+    }
+    // finds the longest match for pos that was inserted before pos, trying hint_distance first
+    // so that repeated distances win ties; returns a zero length Match if none is long enough
+    pub fn longest_match(&self, ring: &[u8], pos: u64, max_len: u32, max_distance: u32, hint_distance: u32) -> Match {
+        let mut best = Match::default();
+        if max_len < MIN_MATCH_LEN {
+            return best;
+        }
+        let max_distance = core::cmp::min(u64::from(max_distance), pos) as u32;
+        if hint_distance != 0 && hint_distance <= max_distance {
+            let len = Self::match_len(ring, pos, hint_distance, max_len);
+            if len >= MIN_MATCH_LEN {
+                best = Match{distance: hint_distance, len};
+            }
+        }
+        let chain_mask = self.chain.slice().len() as u64 - 1;
+        let mut candidate = self.head.slice()[Self::hash(ring, pos)];
+        let mut last_distance = 0u32;
+        for _ in 0..self.max_chain {
+            let distance = (pos as u32).wrapping_sub(candidate);
+            if distance <= last_distance || distance > max_distance {
+                break; // chain ran past the window or into entries that were overwritten
+            }
+            last_distance = distance;
+            let len = Self::match_len(ring, pos, distance, max_len);
+            if len > best.len && worth_copying(len, distance) {
+                best = Match{distance, len};
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.chain.slice()[(u64::from(candidate) & chain_mask) as usize];
+        }
+        best
     }
 }
 
+// short copies from far away cost more to describe than the literals they replace
+fn worth_copying(len: u32, distance: u32) -> bool {
+    len >= MIN_MATCH_LEN + (32 - distance.leading_zeros()) / 8
+}
@@ -13,44 +13,72 @@
 //   limitations under the License.
 
 use core;
-mod hash_match;
-use self::hash_match::HashMatch;
+pub mod hash_match;
+use self::hash_match::{HashMatch, Match};
 use brotli::InputReference;
 pub use alloc::{AllocatedStackMemory, Allocator, SliceWrapper, SliceWrapperMut, StackAllocator};
 pub use super::slice_util::SliceReference;
 pub use interface::{DivansResult, DivansOutputResult};
 pub use super::interface::{PredictionModeContextMap, Command, Compressor, LiteralCommand, CopyCommand, DictCommand, FeatureFlagSliceType};
+// the decoder only guarantees copies this far back in its 1 << window_size ring buffer
+const WINDOW_GAP: u32 = 16;
+const MAX_MATCH_LEN: u32 = 1 << 12;
+
 pub struct RawToCmdState<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>,
     AllocU32:Allocator<u32>>{
+    // twice the window: a full window of history stays behind the bytes still to be parsed
     pub ring_buffer: RingBuffer,
-    ring_buffer_decode_index: u32,
-    ring_buffer_output_index: u32,
+    // absolute stream offsets
+    bytes_in: u64,
+    cursor: u64,
+    literal_start: u64,
+    max_distance: u32,
+    max_match_len: u32,
+    max_literal_run: u64,
+    lazy_matching: bool,
+    last_distance: u32,
+    pending_copy: Option<CopyCommand>,
     hash_match: HashMatch<AllocU32>,
     pub has_produced_header: bool,
 }
 
 impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>> RawToCmdState<RingBuffer, AllocU32> {
-    pub fn new(m32:&mut AllocU32, rb:RingBuffer) -> Self {
+    pub fn new(m32:&mut AllocU32, rb:RingBuffer, window_size: u8, quality: u16) -> Self {
+        assert_eq!(rb.slice().len(), 2 << window_size);
+        let window = 1u32 << window_size;
         RawToCmdState {
             ring_buffer: rb,
-            ring_buffer_decode_index: 0,
-            ring_buffer_output_index: 0,
-            hash_match:HashMatch::<AllocU32>::new(m32),
+            bytes_in: 0,
+            cursor: 0,
+            literal_start: 0,
+            max_distance: window - WINDOW_GAP,
+            max_match_len: core::cmp::min(MAX_MATCH_LEN, window >> 2),
+            max_literal_run: u64::from(window >> 1),
+            lazy_matching: quality >= 5,
+            last_distance: 0,
+            pending_copy: None,
+            hash_match:HashMatch::<AllocU32>::new(m32, window_size, quality),
             has_produced_header: false, // only produce header if no ir_translation
         }
     }
     pub fn raw_input_ir_mode(&mut self) {
         self.has_produced_header = true; // do not wish an additional prediction mode command at the end
     }
-    /*
-    fn freeze_dry<SliceType:SliceWrapper<u8>+Default>(&mut self, input:&[Command<SliceType>]) {
-        
-    }
-    fn thaw<SliceType:SliceWrapper<u8>+Default>(&mut self, input:&[Command<SliceType>]) {
-        
-    }*/
-    pub fn ring_buffer_full(&self) -> bool {
-        self.ring_buffer_decode_index as usize == self.ring_buffer.slice().len() || self.ring_buffer_decode_index + 1 == self.ring_buffer_output_index
+    // copies as much input as fits without clobbering unemitted literals or the match window
+    fn fill(&mut self, input:&[u8], input_offset:&mut usize) {
+        let ring_len = self.ring_buffer.slice().len() as u64;
+        let limit = core::cmp::min(self.literal_start,
+                                   self.cursor.saturating_sub(u64::from(self.max_distance))) + ring_len;
+        let mut max_copy = core::cmp::min(limit - self.bytes_in, (input.len() - *input_offset) as u64) as usize;
+        while max_copy != 0 {
+            let ring_index = (self.bytes_in & (ring_len - 1)) as usize;
+            let to_copy = core::cmp::min(max_copy, ring_len as usize - ring_index);
+            self.ring_buffer.slice_mut()[ring_index..(ring_index + to_copy)].clone_from_slice(
+                &input[*input_offset..(*input_offset + to_copy)]);
+            *input_offset += to_copy;
+            self.bytes_in += to_copy as u64;
+            max_copy -= to_copy;
+        }
     }
     pub fn stream<'a>(&'a mut self,
                       input:&[u8],
@@ -60,46 +88,16 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
                       literal_context_map: &'a mut[u8],
                       prediction_mode_backing:&'a mut[u8],
     ) -> DivansResult {
-        if self.ring_buffer_decode_index >= self.ring_buffer_output_index {
-            let max_copy = core::cmp::min(self.ring_buffer.slice().len() - self.ring_buffer_decode_index as usize,
-                                          input.len() - *input_offset);
-            self.ring_buffer.slice_mut()[(self.ring_buffer_decode_index as usize)..(self.ring_buffer_decode_index as usize + max_copy)].clone_from_slice(&input[*input_offset..(*input_offset + max_copy)]);
-            *input_offset += max_copy;
-            self.ring_buffer_decode_index += max_copy as u32;
-            if self.ring_buffer_output_index != 0 {
-               self.ring_buffer_decode_index = 0;
-            }
+        self.fill(input, input_offset);
+        match self.parse(output, output_offset, literal_context_map, prediction_mode_backing, false) {
+            DivansOutputResult::Success => {},
+            res => return DivansResult::from(res),
         }
-        if self.ring_buffer_decode_index < self.ring_buffer_output_index {
-           let max_copy = core::cmp::min(self.ring_buffer_output_index as usize - 1 - self.ring_buffer_decode_index as usize,
-                                         input.len() - *input_offset);
-           debug_assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
-           debug_assert!(self.ring_buffer_decode_index + max_copy  as u32 <= self.ring_buffer.slice().len() as u32);
-           debug_assert!(*input_offset as u32 + max_copy as u32 <= input.len() as u32);
-           self.ring_buffer.slice_mut()[(self.ring_buffer_decode_index as usize)..(self.ring_buffer_decode_index as usize + max_copy)].clone_from_slice(&input[*input_offset..(*input_offset + max_copy)]);
-            *input_offset += max_copy;
-            self.ring_buffer_decode_index += max_copy as u32;
-        }
-        if *output_offset < output.len() && self.ring_buffer_full() {
-            match self.flush(output, output_offset, literal_context_map, prediction_mode_backing) {
-                DivansOutputResult::NeedsMoreOutput => {
-                  return DivansResult::NeedsMoreOutput;
-                }
-                DivansOutputResult::Failure(m) => {
-                    return DivansResult::Failure(m);
-                },
-                _ => {
-                    if *input_offset != input.len() {
-                        // not really true: we may be able to consume more input, but ourr
-                        // ring buffer is borrowed
-                        return DivansResult::NeedsMoreOutput;
-                    }
-                },
-            }
-        } else if *output_offset == output.len() {
+        if *input_offset != input.len() {
+            // not really true: we may be able to consume more input, but our
+            // ring buffer is borrowed
             return DivansResult::NeedsMoreOutput;
         }
-        assert_eq!(*input_offset, input.len());
         DivansResult::NeedsMoreInput
     }
     pub fn flush<'a>(
@@ -108,7 +106,16 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
               output_offset:&mut usize,
               literal_context_map: &'a mut[u8],
               prediction_mode_backing:&'a mut[u8]) -> DivansOutputResult {
-
+        self.parse(output, output_offset, literal_context_map, prediction_mode_backing, true)
+    }
+    // turns buffered bytes into literal and copy commands; unless is_final, stops short of
+    // the last max_match_len bytes so matches are not cut off at the end of the buffer
+    fn parse<'a>(&'a mut self,
+                 output: &mut [Command<InputReference<'a>>],
+                 output_offset:&mut usize,
+                 literal_context_map: &'a mut[u8],
+                 prediction_mode_backing:&'a mut[u8],
+                 is_final: bool) -> DivansOutputResult {
         if *output_offset == output.len() {
            return DivansOutputResult::NeedsMoreOutput;
         }
@@ -135,49 +142,90 @@ impl<RingBuffer: SliceWrapperMut<u8> + SliceWrapper<u8>, AllocU32:Allocator<u32>
                     },
                     });
             *output_offset += 1;
+        }
+        let ring: &'a [u8] = self.ring_buffer.slice();
+        let ring_mask = ring.len() as u64 - 1;
+        let parse_limit = if is_final {
+            self.bytes_in
+        } else {
+            self.bytes_in.saturating_sub(u64::from(self.max_match_len))
+        };
+        loop {
             if *output_offset == output.len() {
                 return DivansOutputResult::NeedsMoreOutput;
             }
+            if let Some(copy) = self.pending_copy.take() {
+                output[*output_offset] = Command::Copy(copy);
+                *output_offset += 1;
+                continue;
+            }
+            let ring_end = (self.literal_start | ring_mask) + 1;
+            if self.cursor == ring_end || self.cursor - self.literal_start >= self.max_literal_run
+                || (self.cursor >= parse_limit && is_final && self.cursor != self.literal_start) {
+                let end = self.literal_end(ring_mask);
+                output[*output_offset] = Self::literal(ring, &mut self.literal_start, end);
+                *output_offset += 1;
+                continue;
+            }
+            if self.cursor >= parse_limit {
+                return DivansOutputResult::Success;
+            }
+            let mut best = self.find_match(ring, self.cursor);
+            if best.len != 0 && self.lazy_matching && self.cursor + 1 < parse_limit {
+                let next = self.find_match(ring, self.cursor + 1);
+                if next.len > best.len {
+                    best = Match::default(); // a longer match starts at the next byte
+                }
+            }
+            if best.len == 0 {
+                Self::insert(&mut self.hash_match, ring, self.cursor, self.bytes_in);
+                self.cursor += 1;
+                continue;
+            }
+            if self.cursor != self.literal_start {
+                let end = self.literal_end(ring_mask);
+                output[*output_offset] = Self::literal(ring, &mut self.literal_start, end);
+                *output_offset += 1;
+            }
+            for pos in self.cursor..(self.cursor + u64::from(best.len)) {
+                Self::insert(&mut self.hash_match, ring, pos, self.bytes_in);
+            }
+            self.cursor += u64::from(best.len);
+            self.literal_start = self.cursor;
+            self.last_distance = best.distance;
+            self.pending_copy = Some(CopyCommand{
+                distance: best.distance,
+                num_bytes: best.len,
+            });
         }
-        if self.ring_buffer_decode_index < self.ring_buffer_output_index {
-           let max_copy = self.ring_buffer.slice().len() - self.ring_buffer_output_index as usize;
-           if max_copy != 0 {
-               output[*output_offset] = Command::Literal(
-                   LiteralCommand::<InputReference<'a> >{
-                       data: InputReference{
-                           data:self.ring_buffer.slice().split_at(self.ring_buffer_output_index as usize).1.split_at(max_copy).0,
-                           orig_offset:self.ring_buffer_output_index as usize,
-                       },
-                       prob: FeatureFlagSliceType::<InputReference>::default(),
-                       high_entropy: false,
-                   });
-               *output_offset += 1;
-           }
-           if self.ring_buffer_decode_index as usize == self.ring_buffer.slice().len() {
-               self.ring_buffer_decode_index = 0;
-           }
-           self.ring_buffer_output_index = 0
-        }
-        if self.ring_buffer_decode_index != self.ring_buffer_output_index {
-           if *output_offset == output.len() {
-               return DivansOutputResult::NeedsMoreOutput;
-           }
-           let max_copy = self.ring_buffer_decode_index as usize - self.ring_buffer_output_index as usize;
-           output[*output_offset] = Command::Literal(
-               LiteralCommand::<InputReference<'a>>{
-                   data: InputReference{
-                       data:self.ring_buffer.slice().split_at(self.ring_buffer_output_index as usize).1.split_at(max_copy).0,
-                       orig_offset:self.ring_buffer_output_index as usize,
-                   },
-                   prob: FeatureFlagSliceType::<InputReference>::default(),
-                   high_entropy: false,
-               });
-           *output_offset += 1;
-           assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
-           self.ring_buffer_output_index = self.ring_buffer_decode_index;
-           assert!(self.ring_buffer_output_index <= self.ring_buffer.slice().len() as u32);
+    }
+    fn find_match(&self, ring: &[u8], pos: u64) -> Match {
+        let max_len = core::cmp::min(u64::from(self.max_match_len), self.bytes_in - pos) as u32;
+        self.hash_match.longest_match(ring, pos, max_len, self.max_distance, self.last_distance)
+    }
+    fn insert(hash_match: &mut HashMatch<AllocU32>, ring: &[u8], pos: u64, bytes_in: u64) {
+        if pos + u64::from(hash_match::MIN_MATCH_LEN) <= bytes_in {
+            hash_match.insert(ring, pos);
         }
-        DivansOutputResult::Success
+    }
+    // literals stop at the end of the ring buffer so they stay contiguous
+    fn literal_end(&self, ring_mask: u64) -> u64 {
+        let ring_end = (self.literal_start | ring_mask) + 1;
+        core::cmp::min(core::cmp::min(self.cursor, ring_end), self.literal_start + self.max_literal_run)
+    }
+    fn literal<'a>(ring: &'a [u8], literal_start: &mut u64, end: u64) -> Command<InputReference<'a>> {
+        let start_index = (*literal_start & (ring.len() as u64 - 1)) as usize;
+        let len = (end - *literal_start) as usize;
+        *literal_start = end;
+        Command::Literal(
+            LiteralCommand::<InputReference<'a>>{
+                data: InputReference{
+                    data:&ring[start_index..(start_index + len)],
+                    orig_offset:start_index,
+                },
+                prob: FeatureFlagSliceType::<InputReference>::default(),
+                high_entropy: false,
+            })
     }
     pub fn free(&mut self, m32: &mut AllocU32) {
         self.hash_match.free(m32);
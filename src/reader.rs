@@ -296,24 +296,42 @@ impl<R:Read> Read for DivansDecompressorReader<R> {
     }
 }
 impl<R:Read> DivansDecompressorReader<R> {
-    pub fn new(reader: R, mut buffer_size: usize, skip_crc:bool, multithread:bool) -> Self {
-       if buffer_size == 0 {
-          buffer_size = 4096;
-       }
+    pub fn new(reader: R, buffer_size: usize, skip_crc:bool, multithread:bool) -> Self {
        let mut m8 = HeapAlloc::<u8>::new(0);
-       let buffer = m8.alloc_cell(buffer_size);
+       let buffer = m8.alloc_cell(if buffer_size == 0 {4096} else {buffer_size});
+       Self::new_with_decompressor(reader,
+                                   buffer,
+                                   StandardDivansDecompressorFactory::new(
+                                       m8,
+                                       HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+                                       HeapAlloc::<StaticCommand>::new(::StaticCommand::nop()),
+                                       skip_crc,
+                                       multithread,
+                                   ))
+    }
+    /// Decodes in parallel on the threads of `executor` rather than on a thread of its own.
+    pub fn new_with_executor(reader: R, buffer_size: usize, skip_crc:bool, executor: ::ExecutorHandle) -> Self {
+       let mut m8 = HeapAlloc::<u8>::new(0);
+       let buffer = m8.alloc_cell(if buffer_size == 0 {4096} else {buffer_size});
+       Self::new_with_decompressor(reader,
+                                   buffer,
+                                   StandardDivansDecompressorFactory::new_with_executor(
+                                       m8,
+                                       HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
+                                       HeapAlloc::<StaticCommand>::new(::StaticCommand::nop()),
+                                       skip_crc,
+                                       executor,
+                                   ))
+    }
+    fn new_with_decompressor(reader: R,
+                             buffer: <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory,
+                             decompressor: DivansConstructedDecompressor) -> Self {
        DivansDecompressorReader::<R>(
            GenReader::<R,
                        DivansConstructedDecompressor,
                        <HeapAlloc<u8> as Allocator<u8>>::AllocatedMemory>::new(
                           reader,
-                          StandardDivansDecompressorFactory::new(
-                              m8,
-                              HeapAlloc::<::DefaultCDF16>::new(::DefaultCDF16::default()),
-                              HeapAlloc::<StaticCommand>::new(::StaticCommand::nop()),
-                              skip_crc,
-                              multithread,
-                          ),
+                          decompressor,
                           buffer,
                           false,
                        ))
@@ -449,6 +467,42 @@ mod test {
         assert!(ub.data.len() < data.len());
         print!("Compressed {} to {}...\n", ub.data.len(), data.len());
     }
+    fn compress_for_pool_tst(data:&[u8], window_size: i32) -> Vec<u8> {
+        let mut opts = interface::DivansCompressorOptions::default();
+        opts.window_size = Some(window_size);
+        let mut compress = ::DivansBrotliHybridCompressorReader::<UnlimitedBuffer>::new(UnlimitedBuffer::new(data), opts, 4096);
+        let mut compressed = Vec::<u8>::new();
+        compress.read_to_end(&mut compressed).unwrap();
+        compressed
+    }
+    #[test]
+    fn test_pooled_readers_interleaved_on_one_thread() {
+        let inputs: [&[u8]; 3] = [include_bytes!("../testdata/alice29"),
+                                  include_bytes!("../testdata/random_then_unicode"),
+                                  include_bytes!("../testdata/asyoulik")];
+        let executor: ::ExecutorHandle = ::std::sync::Arc::new(::ThreadPool::new(1));
+        let mut readers = Vec::new();
+        for (index, input) in inputs.iter().enumerate() {
+            let compressed = compress_for_pool_tst(input, 16 + index as i32);
+            readers.push(super::DivansDecompressorReader::new_with_executor(UnlimitedBuffer::new(&compressed), 1024, false, executor.clone()));
+        }
+        let mut outputs = vec![Vec::<u8>::new(); inputs.len()];
+        let mut done = [false; 3];
+        let mut local_buffer = [0u8; 517];
+        while !done.iter().all(|d| *d) {
+            for (index, reader) in readers.iter_mut().enumerate() {
+                if done[index] {
+                    continue;
+                }
+                let size = reader.read(&mut local_buffer[..]).unwrap();
+                outputs[index].extend(&local_buffer[..size]);
+                done[index] = size == 0;
+            }
+        }
+        for (output, input) in outputs.iter().zip(inputs.iter()) {
+            assert_eq!(&output[..], *input);
+        }
+    }
     #[test]
     fn test_hybrid_reader_compressor_on_alice_small_buffer() {
         hy_reader_tst(include_bytes!("../testdata/alice29"),
@@ -469,6 +523,12 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: Some(2),
                            divans_ir_optimizer:0,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        1);
     }
@@ -492,6 +552,12 @@ mod test {
                            speed_detection_quality: None,
                            stride_detection_quality: None,
                            divans_ir_optimizer:1,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        4095);
     }
@@ -515,6 +581,12 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: None,
                            divans_ir_optimizer:0,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        4095);
     }
@@ -538,6 +610,12 @@ mod test {
                            stride_detection_quality: Some(1),
                            prior_bitmask_detection: 1,
                            divans_ir_optimizer:1,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        310000);
     }
@@ -0,0 +1,142 @@
+// Copyright 2017 Dropbox, Inc
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+// Entropy coders whose backend is picked at runtime, from the options when compressing
+// and from the stream header when decompressing.
+use alloc::Allocator;
+use probability::{CDF16, ProbRange};
+use interface::{ArithmeticEncoderOrDecoder, BillingCapability, EntropyCoderSelection, NewWithAllocator,
+                ReadableBytes, WritableBytes};
+use super::DivansResult;
+use ans::{ANSEncoder, ANSDecoder};
+use range_coder::{RangeEncoder, RangeDecoder};
+use binary_coder::{BinaryEncoder, BinaryDecoder};
+
+pub enum SelectableEncoder<AllocU8: Allocator<u8>> {
+    Ans(ANSEncoder<AllocU8>),
+    Range(RangeEncoder),
+    Binary(BinaryEncoder),
+}
+
+#[derive(Clone)]
+pub enum SelectableDecoder {
+    Ans(ANSDecoder),
+    Range(RangeDecoder),
+    Binary(BinaryDecoder),
+}
+
+macro_rules! dispatch(
+    ($coder_type: ident, $slf: expr, $coder: ident => $action: expr) => {
+        match $slf {
+            $coder_type::Ans(ref mut $coder) => $action,
+            $coder_type::Range(ref mut $coder) => $action,
+            $coder_type::Binary(ref mut $coder) => $action,
+        }
+    };
+);
+
+macro_rules! dispatch_ref(
+    ($coder_type: ident, $slf: expr, $coder: ident => $action: expr) => {
+        match $slf {
+            $coder_type::Ans(ref $coder) => $action,
+            $coder_type::Range(ref $coder) => $action,
+            $coder_type::Binary(ref $coder) => $action,
+        }
+    };
+);
+
+macro_rules! selectable_coder_methods(
+    ($coder_type: ident) => {
+        #[inline(always)]
+        fn mov(&mut self) -> Self {
+            match *self {
+                $coder_type::Ans(ref mut coder) => $coder_type::Ans(coder.mov()),
+                $coder_type::Range(ref mut coder) => $coder_type::Range(coder.mov()),
+                $coder_type::Binary(ref mut coder) => $coder_type::Binary(coder.mov()),
+            }
+        }
+        #[inline(always)]
+        fn has_data_to_drain_or_fill(&self) -> bool {
+            dispatch_ref!($coder_type, *self, coder => coder.has_data_to_drain_or_fill())
+        }
+        #[inline(always)]
+        fn drain_or_fill_internal_buffer_unchecked(&mut self,
+                                                   input: &mut ReadableBytes,
+                                                   output: &mut WritableBytes) -> DivansResult {
+            dispatch!($coder_type, *self, coder => coder.drain_or_fill_internal_buffer_unchecked(input, output))
+        }
+        #[inline(always)]
+        fn get_or_put_bit_without_billing(&mut self,
+                                          bit: &mut bool,
+                                          prob_of_false: u8) {
+            dispatch!($coder_type, *self, coder => coder.get_or_put_bit_without_billing(bit, prob_of_false))
+        }
+        #[inline(always)]
+        fn get_or_put_nibble_without_billing<C: CDF16>(&mut self,
+                                                       nibble: &mut u8,
+                                                       prob: &C) -> ProbRange {
+            dispatch!($coder_type, *self, coder => coder.get_or_put_nibble_without_billing(nibble, prob))
+        }
+        fn close(&mut self) -> DivansResult {
+            dispatch!($coder_type, *self, coder => coder.close())
+        }
+    };
+);
+
+impl<AllocU8: Allocator<u8>> ArithmeticEncoderOrDecoder for SelectableEncoder<AllocU8> {
+    selectable_coder_methods!(SelectableEncoder);
+}
+
+impl ArithmeticEncoderOrDecoder for SelectableDecoder {
+    selectable_coder_methods!(SelectableDecoder);
+}
+
+impl<AllocU8: Allocator<u8>> NewWithAllocator<AllocU8> for SelectableEncoder<AllocU8> {
+    fn new(m8: &mut AllocU8) -> Self {
+        Self::new_with_entropy_coder(m8, EntropyCoderSelection::default())
+    }
+    fn new_with_entropy_coder(m8: &mut AllocU8, coder: EntropyCoderSelection) -> Self {
+        match coder {
+            EntropyCoderSelection::Ans => SelectableEncoder::Ans(ANSEncoder::new(m8)),
+            EntropyCoderSelection::Range => SelectableEncoder::Range(RangeEncoder::default()),
+            EntropyCoderSelection::Binary => SelectableEncoder::Binary(BinaryEncoder::default()),
+        }
+    }
+    fn free(&mut self, m8: &mut AllocU8) {
+        if let SelectableEncoder::Ans(ref mut coder) = *self {
+            coder.free(m8);
+        }
+    }
+}
+
+impl<AllocU8: Allocator<u8>> NewWithAllocator<AllocU8> for SelectableDecoder {
+    fn new(m8: &mut AllocU8) -> Self {
+        Self::new_with_entropy_coder(m8, EntropyCoderSelection::default())
+    }
+    fn new_with_entropy_coder(_m8: &mut AllocU8, coder: EntropyCoderSelection) -> Self {
+        match coder {
+            EntropyCoderSelection::Ans => SelectableDecoder::Ans(ANSDecoder::default()),
+            EntropyCoderSelection::Range => SelectableDecoder::Range(RangeDecoder::default()),
+            EntropyCoderSelection::Binary => SelectableDecoder::Binary(BinaryDecoder::default()),
+        }
+    }
+    fn free(&mut self, _m8: &mut AllocU8) {
+    }
+}
+
+impl<AllocU8: Allocator<u8>> BillingCapability for SelectableEncoder<AllocU8> {
+}
+
+impl BillingCapability for SelectableDecoder {
+}
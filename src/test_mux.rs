@@ -10,11 +10,15 @@ use interface::StreamDemuxer;
 use super::mux;
 
 fn help_test_mux(i0:&[u8], i1:&[u8], copy_pattern: &[(mux::StreamID, usize)], in_buf_size: usize, out_buf_size: usize) {
+    help_test_mux_k(mux::StreamCount::default(), &[i0, i1], copy_pattern, in_buf_size, out_buf_size)
+}
+
+fn help_test_mux_k(num_streams: mux::StreamCount, inputs: &[&[u8]], copy_pattern: &[(mux::StreamID, usize)], in_buf_size: usize, out_buf_size: usize) {
     let mut m8 = HeapAlloc::<u8>::new(0);
     let mut v = Vec::<u8>::new();
-    let mut mux = mux::Mux::<HeapAlloc<u8>>::default();
+    let mut mux = mux::Mux::<HeapAlloc<u8>>::new(num_streams);
     let mut buf = m8.alloc_cell(in_buf_size);
-    let mut input = [i0, i1];
+    let mut input = inputs.to_vec();
     for (index, copy) in copy_pattern.iter().enumerate() {
         let sl = input[usize::from(copy.0)];
         if sl.len() < copy.1 {
@@ -41,8 +45,8 @@ fn help_test_mux(i0:&[u8], i1:&[u8], copy_pattern: &[(mux::StreamID, usize)], in
     assert_eq!(&v[v.len() - 3..], &super::mux::EOF_MARKER[..]);
     m8.free_cell(buf);
     buf = m8.alloc_cell(out_buf_size);
-    input = [i0, i1];
-    mux = mux::Mux::<HeapAlloc<u8>>::default();
+    input = inputs.to_vec();
+    mux = mux::Mux::<HeapAlloc<u8>>::new(num_streams);
     let mut dv = &v[..];
     loop {
         let actually_deserialized = mux.deserialize(&dv[..core::cmp::min(out_buf_size, dv.len())], &mut m8);
@@ -63,7 +67,9 @@ fn help_test_mux(i0:&[u8], i1:&[u8], copy_pattern: &[(mux::StreamID, usize)], in
         }
     }
     m8.free_cell(buf);
-    assert_eq!(input, [&[], &[]]);
+    for remaining in input.iter() {
+        assert_eq!(remaining.len(), 0);
+    }
     assert_eq!(mux.is_eof(), true);
     mux.free(&mut m8);
 }
@@ -84,6 +90,73 @@ fn test_real_mux() {
                   4096, 4096);
 }
 #[test]
+fn test_four_stream_mux() {
+    help_test_mux_k(mux::StreamCount::new(4).unwrap(),
+                    &[&rand(27657,1)[..], &rand(27797,2)[..], &rand(5000,3)[..], &rand(70000,4)[..]],
+                    &[(0,8189),(3,1),(1,8189),(2,5000),(1,17996),(3,69999),(0,19468),(1,1612)],
+                    4096, 4096);
+}
+#[test]
+fn test_max_stream_mux() {
+    let inputs:Vec<Vec<u8>> = (0..mux::MAX_NUM_STREAMS).map(|i| rand(1000 + 3000 * i, i as u32)).collect();
+    let input_slices:Vec<&[u8]> = inputs.iter().map(|v| &v[..]).collect();
+    let copy_pattern:Vec<(mux::StreamID, usize)> = inputs.iter().enumerate().rev().map(|(i, v)| (i as mux::StreamID, v.len())).collect();
+    help_test_mux_k(mux::StreamCount::new(mux::MAX_NUM_STREAMS).unwrap(),
+                    &input_slices[..],
+                    &copy_pattern[..],
+                    373, 3021);
+}
+#[test]
+fn test_stream_count_header() {
+    let mut header = [0u8; ::interface::HEADER_LENGTH];
+    mux::StreamCount::default().write_header(&mut header);
+    assert_eq!(header, [0u8; ::interface::HEADER_LENGTH]);
+    assert_eq!(mux::StreamCount::from_header(&header).unwrap().get(), mux::NUM_STREAMS);
+    mux::StreamCount::new(5).unwrap().write_header(&mut header);
+    assert_eq!(mux::StreamCount::from_header(&header).unwrap().get(), 5);
+    assert!(mux::StreamCount::new(1).is_err());
+    assert!(mux::StreamCount::new(mux::MAX_NUM_STREAMS + 1).is_err());
+}
+#[test]
+fn test_low_latency_linearize_pending() {
+    use interface::StreamMuxer;
+    let inputs = [rand(700, 1), rand(5, 2)];
+    for policy in [mux::MuxPolicy::Throughput, mux::MuxPolicy::LowLatency].iter() {
+        let mut m8 = HeapAlloc::<u8>::new(0);
+        let mut mux = mux::Mux::<HeapAlloc<u8>>::default();
+        mux.set_policy(*policy);
+        let mut output = vec![0u8; 1024];
+        let mut output_offset = 0;
+        for (stream_id, input) in inputs.iter().enumerate() {
+            mux.write(stream_id as mux::StreamID, &input[..], &mut m8);
+            // too little for a chunk, so only the low latency policy lets it out
+            output_offset += mux.linearize(&mut output[output_offset..]);
+            assert_eq!(output_offset, 0);
+        }
+        output_offset += mux.linearize_pending(&mut output[output_offset..]);
+        // each stream went out as one variable sized chunk with a three byte header
+        assert_eq!(output_offset, if *policy == mux::MuxPolicy::LowLatency {700 + 5 + 3 * 2} else {0});
+        output_offset += mux.flush(&mut output[output_offset..]);
+        let mut demux = mux::Mux::<HeapAlloc<u8>>::default();
+        assert_eq!(demux.deserialize(&output[..output_offset], &mut m8), output_offset);
+        for (stream_id, input) in inputs.iter().enumerate() {
+            assert_eq!(demux.data_avail(stream_id as mux::StreamID), &input[..]);
+        }
+        mux.free(&mut m8);
+        demux.free(&mut m8);
+    }
+}
+#[test]
+fn test_mux_policy_header() {
+    let mut header = [0u8; ::interface::HEADER_LENGTH];
+    mux::MuxPolicy::default().write_header(&mut header);
+    assert_eq!(header, [0u8; ::interface::HEADER_LENGTH]);
+    mux::MuxPolicy::LowLatency.write_header(&mut header);
+    assert_eq!(mux::MuxPolicy::from_header(&header).unwrap(), mux::MuxPolicy::LowLatency);
+    header[11] = 2;
+    assert!(mux::MuxPolicy::from_header(&header).is_err());
+}
+#[test]
 fn test_tiny_mux() {
     help_test_mux(&rand(8189,1)[..],
                   &rand(8189,2)[..],
@@ -1205,3 +1278,30 @@ fn unit_test_decode_mux() {
     assert_eq!(mux.edit(0).slice(), &to_decode[3..3+16]);
     assert_eq!(mux.edit(1).slice(), &to_decode[6+16..6+32]);
 }
+
+#[test]
+fn unit_test_decode_mux_stream_beyond_count() {
+    let to_decode:[u8;13] = [0x3, 0x3, 0x0, // 4 bytes on stream 3
+                             0x1, 0x2, 0x3, 0x4,
+                             0x0, 0x1, 0x0, // 2 bytes on stream 0
+                             0x5, 0x6,
+                             0xff];
+    let mut m8 = HeapAlloc::<u8>::new(0);
+    let mut mux = mux::Mux::<HeapAlloc<u8>>::new(mux::StreamCount::new(4).unwrap());
+    let _actually_deserialized = mux.deserialize(&to_decode[..], &mut m8);
+    assert_eq!(mux.edit(3).slice(), &to_decode[3..7]);
+    assert_eq!(mux.edit(0).slice(), &to_decode[10..12]);
+    mux.free(&mut m8);
+    // the same chunks in a two-stream mux leave stream 3 unconsumed, so the mux never reaches eof
+    let to_decode_eof:[u8;15] = [0x3, 0x3, 0x0,
+                                 0x1, 0x2, 0x3, 0x4,
+                                 0x0, 0x1, 0x0,
+                                 0x5, 0x6,
+                                 0xff, 0xfe, 0xff];
+    mux = mux::Mux::<HeapAlloc<u8>>::default();
+    let _actually_deserialized = mux.deserialize(&to_decode_eof[..], &mut m8);
+    mux.consume_data(0, 2);
+    assert!(mux.encountered_eof());
+    assert!(!mux.is_eof());
+    mux.free(&mut m8);
+}
@@ -0,0 +1,138 @@
+#![cfg(feature="std")]
+// Lets many parallel decompressors share a bounded set of worker threads: instead of owning a
+// thread, each decompressor's command decoder runs as a job that steps until it would block on
+// the MultiWorker queue and is resubmitted once the main thread pushes or pulls.
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+
+pub trait Executor: Send + Sync {
+    // jobs may block briefly on a decompressor's lock but never wait for another job
+    fn execute(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+pub type ExecutorHandle = Arc<dyn Executor>;
+
+struct PoolJobs {
+    queue: VecDeque<Box<dyn FnOnce() + Send>>,
+    shutdown: bool,
+}
+
+struct PoolQueue {
+    jobs: Mutex<PoolJobs>,
+    cvar: Condvar,
+}
+
+// a fixed number of threads draining one queue; they exit once the pool is dropped and the queue is empty
+pub struct ThreadPool {
+    queue: Arc<PoolQueue>,
+}
+
+impl ThreadPool {
+    pub fn new(num_threads: usize) -> Self {
+        let queue = Arc::new(PoolQueue {
+            jobs: Mutex::new(PoolJobs {
+                queue: VecDeque::new(),
+                shutdown: false,
+            }),
+            cvar: Condvar::new(),
+        });
+        for _ in 0..core::cmp::max(num_threads, 1) {
+            let thread_queue = queue.clone();
+            thread::spawn(move || run_pool_thread(&thread_queue));
+        }
+        ThreadPool {
+            queue,
+        }
+    }
+}
+
+fn run_pool_thread(queue: &PoolQueue) {
+    loop {
+        let job = {
+            let mut jobs = queue.jobs.lock().unwrap();
+            loop {
+                if let Some(job) = jobs.queue.pop_front() {
+                    break job;
+                }
+                if jobs.shutdown {
+                    return;
+                }
+                jobs = queue.cvar.wait(jobs).unwrap();
+            }
+        };
+        job();
+    }
+}
+
+impl Executor for ThreadPool {
+    fn execute(&self, job: Box<dyn FnOnce() + Send>) {
+        self.queue.jobs.lock().unwrap().queue.push_back(job);
+        self.queue.cvar.notify_one();
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.queue.jobs.lock().unwrap().shutdown = true;
+        self.queue.cvar.notify_all();
+    }
+}
+
+const JOB_IDLE: usize = 0;
+const JOB_QUEUED: usize = 1;
+const JOB_RUNNING: usize = 2;
+const JOB_RUNNING_WOKEN: usize = 3;
+const JOB_DONE: usize = 4;
+
+// A job that is run in steps on an executor. Each step returns true once the job has finished;
+// a wake that arrives while a step is running makes the job take another step instead of idling.
+pub struct ResumableJob {
+    state: AtomicUsize,
+    executor: ExecutorHandle,
+    step: Box<dyn Fn() -> bool + Send + Sync>,
+}
+
+impl ResumableJob {
+    pub fn new(executor: ExecutorHandle, step: Box<dyn Fn() -> bool + Send + Sync>) -> Arc<Self> {
+        Arc::new(ResumableJob {
+            state: AtomicUsize::new(JOB_IDLE),
+            executor,
+            step,
+        })
+    }
+    pub fn wake(job: &Arc<Self>) {
+        loop {
+            match job.state.load(Ordering::Acquire) {
+                JOB_IDLE => {
+                    if job.state.compare_exchange(JOB_IDLE, JOB_QUEUED, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                        let queued_job = job.clone();
+                        job.executor.execute(Box::new(move || ResumableJob::run(&queued_job)));
+                        return;
+                    }
+                },
+                JOB_RUNNING => {
+                    if job.state.compare_exchange(JOB_RUNNING, JOB_RUNNING_WOKEN, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                        return;
+                    }
+                },
+                _ => return,
+            }
+        }
+    }
+    fn run(job: &Arc<Self>) {
+        job.state.store(JOB_RUNNING, Ordering::Release);
+        loop {
+            if (job.step)() {
+                job.state.store(JOB_DONE, Ordering::Release);
+                return;
+            }
+            if job.state.compare_exchange(JOB_RUNNING, JOB_IDLE, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                return;
+            }
+            job.state.store(JOB_RUNNING, Ordering::Release);
+        }
+    }
+}
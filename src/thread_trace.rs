@@ -0,0 +1,128 @@
+#![cfg(feature="std")]
+// Exports the threadlog event logs of a parallel decompressor: both sides of the MultiWorker queue
+// share one start time, so the main thread and worker logs line up on a single timeline.
+use std::time::Duration;
+use std::io;
+use std::io::Write;
+use std::vec::Vec;
+pub use multithreading::ThreadEventType;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub event: ThreadEventType,
+    pub quantity: u32,
+    pub start: Duration,
+    // nonzero only for wait events
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTotal {
+    pub event: ThreadEventType,
+    pub count: usize,
+    pub total: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ThreadTrace {
+    pub main: Vec<TraceEvent>,
+    pub worker: Vec<TraceEvent>,
+    // events that arrived after the fixed size log filled up
+    pub dropped: usize,
+}
+
+const MAIN_TID: u32 = 1;
+const WORKER_TID: u32 = 2;
+
+fn write_micros<W: Write>(w: &mut W, d: Duration) -> io::Result<()> {
+    write!(w, "{}.{:03}", d.as_secs() * 1_000_000 + u64::from(d.subsec_micros()), d.subsec_nanos() % 1000)
+}
+
+fn write_thread_events<W: Write>(w: &mut W, tid: u32, events: &[TraceEvent]) -> io::Result<()> {
+    for event in events.iter() {
+        // waits become complete events spanning the blocked interval, everything else an instant
+        if event.event.is_wait() {
+            write!(w, ",\n{{\"name\":\"{:?}\",\"cat\":\"wait\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":", event.event, tid)?;
+            write_micros(w, event.start)?;
+            write!(w, ",\"dur\":")?;
+            write_micros(w, event.duration)?;
+        } else {
+            write!(w, ",\n{{\"name\":\"{:?}\",\"cat\":\"queue\",\"ph\":\"i\",\"s\":\"t\",\"pid\":1,\"tid\":{},\"ts\":", event.event, tid)?;
+            write_micros(w, event.start)?;
+        }
+        write!(w, ",\"args\":{{\"quantity\":{}}}}}", event.quantity)?;
+    }
+    Ok(())
+}
+
+impl ThreadTrace {
+    // the Trace Event Format read by chrome://tracing and Perfetto; timestamps are in microseconds
+    pub fn write_chrome_trace<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{{\"traceEvents\":[")?;
+        write!(w, "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"main\"}}}}", MAIN_TID)?;
+        write!(w, ",\n{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"worker\"}}}}", WORKER_TID)?;
+        write_thread_events(w, MAIN_TID, &self.main)?;
+        write_thread_events(w, WORKER_TID, &self.worker)?;
+        writeln!(w, "\n],\"displayTimeUnit\":\"ns\",\"otherData\":{{\"dropped_events\":{}}}}}", self.dropped)
+    }
+    // count and total blocked time of each wait event type on either side, longest first
+    pub fn wait_summary(&self) -> Vec<WaitTotal> {
+        let mut ret = Vec::<WaitTotal>::new();
+        for event in self.main.iter().chain(self.worker.iter()) {
+            if !event.event.is_wait() {
+                continue;
+            }
+            match ret.iter().position(|total| total.event == event.event) {
+                Some(index) => {
+                    ret[index].count += 1;
+                    ret[index].total += event.duration;
+                },
+                None => ret.push(WaitTotal{
+                    event: event.event,
+                    count: 1,
+                    total: event.duration,
+                }),
+            }
+        }
+        ret.sort_by_key(|total| core::cmp::Reverse(total.total));
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use std::vec::Vec;
+    use super::{ThreadTrace, TraceEvent, ThreadEventType};
+    fn event(event: ThreadEventType, start_us: u32, duration_us: u32) -> TraceEvent {
+        TraceEvent {
+            event: event,
+            quantity: 7,
+            start: Duration::new(0, start_us * 1000),
+            duration: Duration::new(0, duration_us * 1000),
+        }
+    }
+    #[test]
+    fn test_wait_summary() {
+        let trace = ThreadTrace {
+            main: vec![event(ThreadEventType::M_PUSH_DATA, 1, 0),
+                       event(ThreadEventType::M_WAIT_PULL_COMMAND_RESULT, 2, 10),
+                       event(ThreadEventType::M_WAIT_PULL_COMMAND_RESULT, 20, 15)],
+            worker: vec![event(ThreadEventType::W_WAIT_PULL_DATA, 0, 40),
+                         event(ThreadEventType::W_PUSH_CMD, 41, 0)],
+            dropped: 0,
+        };
+        let summary = trace.wait_summary();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].event, ThreadEventType::W_WAIT_PULL_DATA);
+        assert_eq!(summary[0].total, Duration::new(0, 40000));
+        assert_eq!(summary[1].event, ThreadEventType::M_WAIT_PULL_COMMAND_RESULT);
+        assert_eq!(summary[1].count, 2);
+        assert_eq!(summary[1].total, Duration::new(0, 25000));
+        let mut json = Vec::<u8>::new();
+        trace.write_chrome_trace(&mut json).unwrap();
+        let json = ::std::string::String::from_utf8(json).unwrap();
+        assert!(json.contains("{\"name\":\"M_WAIT_PULL_COMMAND_RESULT\",\"cat\":\"wait\",\"ph\":\"X\",\"pid\":1,\"tid\":1,\"ts\":20.000,\"dur\":15.000,\"args\":{\"quantity\":7}}"));
+        assert!(json.contains("{\"name\":\"W_PUSH_CMD\",\"cat\":\"queue\",\"ph\":\"i\",\"s\":\"t\",\"pid\":1,\"tid\":2,\"ts\":41.000,\"args\":{\"quantity\":7}}"));
+    }
+}
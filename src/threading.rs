@@ -1,6 +1,6 @@
 use core;
 #[allow(unused_imports)]
-use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType, StreamDemuxer, ReadableBytes, StreamID, NUM_STREAMS, EncoderOrDecoderRecoderSpecialization};
+use interface::{DivansCompressorFactory, BlockSwitch, LiteralBlockSwitch, Command, Compressor, CopyCommand, Decompressor, DictCommand, LiteralCommand, Nop, NewWithAllocator, ArithmeticEncoderOrDecoder, LiteralPredictionModeNibble, PredictionModeContextMap, free_cmd, FeatureFlagSliceType, StreamDemuxer, ReadableBytes, StreamID, EncoderOrDecoderRecoderSpecialization};
 use ::interface::{DivansOutputResult, ErrMsg};
 use slice_util::{AllocatedMemoryRange, AllocatedMemoryPrefix, SlicePlaceholder32};
 use alloc::{SliceWrapper, SliceWrapperMut, Allocator};
@@ -260,8 +260,10 @@ impl<AllocU8:Allocator<u8>, AllocCommand:Allocator<StaticCommand>> MainToThread<
     }
 }
 type NopUsize = usize;
+// the worker's view of the mux: the main thread forwards it the chunks of a single stream
 pub struct ThreadToMainDemuxer<AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>>{
     pub worker: WorkerInterface,
+    stream_id: StreamID,
     slice: AllocatedMemoryRange<u8, AllocU8>,
     unused: NopUsize,
     eof: bool,
@@ -274,8 +276,13 @@ impl<AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>+Default> Defau
 impl <AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>> ThreadToMainDemuxer<AllocU8, WorkerInterface> {
     #[inline(always)]
     pub fn new(w:WorkerInterface) -> Self {
+        Self::new_for_stream(w, 0)
+    }
+    #[inline(always)]
+    pub fn new_for_stream(w:WorkerInterface, stream_id: StreamID) -> Self {
         Self{
             worker:w,
+            stream_id,
             slice: AllocatedMemoryRange::<u8, AllocU8>::default(),
             unused: NopUsize::default(),
             eof: false,
@@ -324,34 +331,35 @@ impl<AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>> StreamDemuxer
         unimplemented!();
     }
     #[inline(always)]
-    fn read_buffer(&mut self) -> [ReadableBytes; NUM_STREAMS] {
+    fn read_buffer(&mut self, stream_id: StreamID) -> ReadableBytes<'_> {
+        if stream_id != self.stream_id {
+            return ReadableBytes{data:&[], read_offset:&mut self.unused};
+        }
         self.pull_if_necessary();
         let data = self.slice.0.slice().split_at(self.slice.1.end).0;
-        [ReadableBytes{data:data, read_offset:&mut self.slice.1.start},
-         ReadableBytes{data:&[], read_offset:&mut self.unused},
-         ]
+        ReadableBytes{data, read_offset:&mut self.slice.1.start}
     }
     #[inline(always)]
     fn data_ready(&self, stream_id:StreamID) -> usize {
-        if stream_id != 0 {
+        if stream_id != self.stream_id {
             return 0;
         }
         self.slice.slice().len()
     }
     #[inline(always)]
     fn peek(&self, stream_id: StreamID) -> &[u8] {
-        assert_eq!(stream_id, 0);
+        assert_eq!(stream_id, self.stream_id);
         self.slice.slice()
     }
     #[inline(always)]
     fn edit(&mut self, stream_id: StreamID) -> &mut AllocatedMemoryRange<u8, AllocU8> {
-        assert_eq!(stream_id, 0);
+        assert_eq!(stream_id, self.stream_id);
         self.pull_if_necessary();
         &mut self.slice
     }
     #[inline(always)]
     fn consume(&mut self, stream_id: StreamID, count: usize) {
-        assert_eq!(stream_id, 0);
+        assert_eq!(stream_id, self.stream_id);
         self.slice.1.start += count;
         self.send_any_empty_data_buffer_to_main();
     }
@@ -365,8 +373,8 @@ impl<AllocU8:Allocator<u8>, WorkerInterface:ThreadToMain<AllocU8>> StreamDemuxer
     }
     #[inline(always)]
     fn free_demux(&mut self, m8: &mut AllocU8){
+        // freed here rather than handed back: the worker may already have released its buffers
         if self.slice.0.slice().len() != 0 {
-            self.worker.push_consumed_data(&mut self.slice, None);
             m8.free_cell(core::mem::replace(&mut self.slice.0, AllocU8::AllocatedMemory::default()));
         }
     }
@@ -439,6 +439,12 @@ mod test {
                            stride_detection_quality: Some(2),
                            prior_bitmask_detection: 1,
                            divans_ir_optimizer:1,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        1);
     }
@@ -462,6 +468,12 @@ mod test {
                            prior_bitmask_detection: 0,
                            stride_detection_quality: None,
                            divans_ir_optimizer:0,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        4095);
     }
@@ -485,6 +497,12 @@ mod test {
                            prior_bitmask_detection: 1,
                            stride_detection_quality: None,
                            divans_ir_optimizer:1,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        4095);
     }
@@ -508,6 +526,12 @@ mod test {
                            speed_detection_quality: None,
                            stride_detection_quality: Some(1),
                            divans_ir_optimizer:0,
+                           block_split:false,
+                           entropy_coder:interface::EntropyCoderSelection::default(),
+                           cdf_family:interface::CDFFamily::default(),
+                           mux_policy:interface::MuxPolicy::default(),
+                           stream_count:interface::StreamCount::default(),
+                           filters: interface::FilterSelection::default(),
                        },
                        3);
     }